@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    cache::{mark_stale, pin_entry, remove_entry, unpin_entry},
+    config::{AppCfg, TenantCfg},
+    error::SvcError,
+    error_journal, report,
+    server::{resolve_cache_paths, CombinedState},
+};
+
+/// Require the admin token (`Authorization: Bearer <ADMIN_TOKEN>`) for admin-only features
+pub fn authorize_admin(cfg: &AppCfg, req_headers: &HeaderMap) -> Result<(), SvcError> {
+    let Some(ref token) = cfg.admin_token else {
+        return Err(SvcError::Forbidden("admin features are disabled"));
+    };
+    if bearer_token(req_headers).is_some_and(|provided| token_eq(provided, token)) {
+        Ok(())
+    } else {
+        Err(SvcError::Forbidden("invalid admin token"))
+    }
+}
+
+/// Compare a provided secret with a configured one in constant time (for equal lengths)
+pub fn token_eq(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(req_headers: &HeaderMap) -> Option<&str> {
+    req_headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// GET /admin/cache-report - most recent scheduled cache report
+pub async fn handle_cache_report(
+    State(state): State<CombinedState>,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    authorize_admin(&state.app.cfg, &req_headers)?;
+    Ok(Json(report::last_report()).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorsQuery {
+    /// Only sources with at least this many failures (default 2: repeat offenders)
+    min_failures: Option<u64>,
+    /// Only sources from this host (`blossom` for /thumb blobs)
+    host: Option<String>,
+    /// Most sources listed (default 100)
+    limit: Option<usize>,
+}
+
+/// GET /admin/errors - sources whose processing keeps failing, from the error journal
+pub async fn handle_errors(
+    State(state): State<CombinedState>,
+    req_headers: HeaderMap,
+    Query(query): Query<ErrorsQuery>,
+) -> Result<Response, SvcError> {
+    authorize_admin(&state.app.cfg, &req_headers)?;
+    let report = error_journal::report(
+        query.min_failures.unwrap_or(2),
+        query.host.as_deref(),
+        query.limit.unwrap_or(100),
+    );
+    Ok(Json(report).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClearErrorsQuery {
+    /// Forget only this source (default: all)
+    source: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClearErrorsResult {
+    removed: usize,
+}
+
+/// DELETE /admin/errors - forget journaled failures, e.g. after fixing an upstream
+pub async fn handle_clear_errors(
+    State(state): State<CombinedState>,
+    req_headers: HeaderMap,
+    Query(query): Query<ClearErrorsQuery>,
+) -> Result<Response, SvcError> {
+    authorize_admin(&state.app.cfg, &req_headers)?;
+    let removed = error_journal::clear(query.source.as_deref());
+    Ok(Json(ClearErrorsResult { removed }).into_response())
+}
+
+/// Purge semantics
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PurgeMode {
+    /// Mark the processed entry stale: it is served once more while a background refresh
+    /// re-fetches the source and regenerates it
+    Soft,
+    /// Delete the processed entry and the cached original
+    #[default]
+    Hard,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeRequest {
+    /// Request path as clients use it, e.g. `/insecure/.../plain/...` or `/thumb/<sha256>.jpg?rs=...`
+    pub path: String,
+    #[serde(default)]
+    pub mode: PurgeMode,
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResult {
+    mode: PurgeMode,
+    processed: bool,
+    original: bool,
+}
+
+/// POST /admin/purge - soft (revalidate) or hard purge of a cached request
+pub async fn handle_purge(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    req_headers: HeaderMap,
+    Json(req): Json<PurgeRequest>,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    authorize_admin(&state.app.cfg, &req_headers)?;
+
+    let (processed_paths, original_path) = resolve_cache_paths(&state.app.cfg, &req.path)?;
+
+    // f:auto requests have one processed entry per negotiated format
+    let mut processed = false;
+    for path in &processed_paths {
+        processed |= match req.mode {
+            PurgeMode::Soft => mark_stale(&state.app, path).await?,
+            PurgeMode::Hard => remove_entry(&state.app, path).await?,
+        };
+    }
+
+    let result = match req.mode {
+        PurgeMode::Soft => PurgeResult {
+            mode: req.mode,
+            processed,
+            // The background refresh re-fetches the source, replacing the original too
+            original: false,
+        },
+        PurgeMode::Hard => PurgeResult {
+            mode: req.mode,
+            processed,
+            original: remove_entry(&state.app, &original_path).await?,
+        },
+    };
+
+    tracing::info!("purged {} ({:?}): {:?}", req.path, req.mode, result);
+    Ok(Json(result).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinRequest {
+    /// Request path as clients use it, as for purge
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PinResult {
+    /// Whether any processed variant was (un)pinned
+    processed: bool,
+    original: bool,
+}
+
+/// POST /admin/pin - keep a cached request (processed variants and original) from eviction
+///
+/// Only entries on disk can be pinned; entries held in Redis follow Redis expiry.
+pub async fn handle_pin(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    req_headers: HeaderMap,
+    Json(req): Json<PinRequest>,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    authorize_admin(&state.app.cfg, &req_headers)?;
+
+    let (processed_paths, original_path) = resolve_cache_paths(&state.app.cfg, &req.path)?;
+    let mut processed = false;
+    for path in &processed_paths {
+        processed |= pin_entry(path).await?;
+    }
+    let result = PinResult {
+        processed,
+        original: pin_entry(&original_path).await?,
+    };
+
+    tracing::info!("pinned {}: {:?}", req.path, result);
+    Ok(Json(result).into_response())
+}
+
+/// POST /admin/unpin - make a pinned request evictable again
+pub async fn handle_unpin(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    req_headers: HeaderMap,
+    Json(req): Json<PinRequest>,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    authorize_admin(&state.app.cfg, &req_headers)?;
+
+    let (processed_paths, original_path) = resolve_cache_paths(&state.app.cfg, &req.path)?;
+    let mut processed = false;
+    for path in &processed_paths {
+        processed |= unpin_entry(path).await?;
+    }
+    let result = PinResult {
+        processed,
+        original: unpin_entry(&original_path).await?,
+    };
+
+    tracing::info!("unpinned {}: {:?}", req.path, result);
+    Ok(Json(result).into_response())
+}
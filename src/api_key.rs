@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{HeaderMap, Uri},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+use crate::{
+    admin::{bearer_token, token_eq},
+    config::{AppCfg, TenantCfg},
+    error::SvcError,
+    server::CombinedState,
+};
+
+#[derive(Debug, Deserialize)]
+struct KeyQuery {
+    key: Option<String>,
+}
+
+/// Require one of `API_KEYS` on processing endpoints, when any are configured
+///
+/// The key comes as `Authorization: Bearer <key>` or, for `<img>` tags that can't send
+/// headers, as `?key=<key>`; it is never part of a cache key. Runs after tenant selection, so a
+/// tenant's own keys apply to its requests. The admin token also passes, so `?debug=1`
+/// traces need only one `Authorization` header.
+pub async fn require_api_key(
+    State(state): State<CombinedState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, SvcError> {
+    let authorized = {
+        let cfg = match req.extensions().get::<Arc<TenantCfg>>() {
+            Some(tenant) => &tenant.cfg,
+            None => &state.app.cfg,
+        };
+        is_authorized(cfg, req.headers(), req.uri())
+    };
+    if !authorized {
+        return Err(SvcError::Forbidden("missing or invalid API key"));
+    }
+    Ok(next.run(req).await)
+}
+
+fn is_authorized(cfg: &AppCfg, headers: &HeaderMap, uri: &Uri) -> bool {
+    if cfg.api_keys.is_empty() {
+        return true;
+    }
+    if let Some(token) = bearer_token(headers) {
+        let admin = cfg.admin_token.as_deref().is_some_and(|admin| token_eq(token, admin));
+        if cfg.api_keys.iter().any(|key| token_eq(token, key)) || admin {
+            return true;
+        }
+    }
+    Query::<KeyQuery>::try_from_uri(uri)
+        .ok()
+        .and_then(|Query(query)| query.key)
+        .is_some_and(|key| cfg.api_keys.iter().any(|api_key| token_eq(&key, api_key)))
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use moka::sync::Cache;
+
+use crate::blossom::server_origin;
+
+/// Upper bound on remembered (server, blob) pairs
+const MAX_ENTRIES: u64 = 100_000;
+
+/// Short-lived memory of which Blossom servers have or lack a blob
+///
+/// Repeated variant requests for the same blob skip servers that just answered 404
+/// and try the server that just served it first, instead of re-probing the whole list.
+/// Entries expire after `BLOB_AVAILABILITY_TTL_SECS` so new uploads are picked up.
+#[derive(Clone)]
+pub struct BlobAvailability {
+    /// (server origin, blob hash) -> whether the server had the blob
+    entries: Cache<(String, String), bool>,
+}
+
+impl BlobAvailability {
+    pub fn new(ttl: Duration) -> Self {
+        let entries = Cache::builder()
+            .max_capacity(MAX_ENTRIES)
+            .time_to_live(ttl)
+            .build();
+        Self { entries }
+    }
+
+    /// Remember whether the server behind `url` had `hash`
+    pub fn record(&self, url: &str, hash: &str, available: bool) {
+        self.entries
+            .insert((server_origin(url), hash.to_ascii_lowercase()), available);
+    }
+
+    /// Servers worth trying for `hash`: known holders first, known misses dropped
+    pub fn candidates(&self, servers: &[String], hash: &str) -> Vec<String> {
+        let hash = hash.to_ascii_lowercase();
+        let mut holders = Vec::new();
+        let mut unknown = Vec::new();
+        for server in servers {
+            match self.entries.get(&(server_origin(server), hash.clone())) {
+                Some(true) => holders.push(server.clone()),
+                Some(false) => {}
+                None => unknown.push(server.clone()),
+            }
+        }
+        holders.extend(unknown);
+        holders
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let availability = BlobAvailability::new(Duration::from_secs(60));
+        let servers = vec![
+            "https://a.example".to_string(),
+            "https://b.example".to_string(),
+            "https://c.example".to_string(),
+        ];
+        availability.record("https://a.example/ABC.jpg", "ABC", false);
+        availability.record("https://c.example/abc.jpg", "abc", true);
+
+        assert_eq!(
+            availability.candidates(&servers, "abc"),
+            vec!["https://c.example", "https://b.example"]
+        );
+        // Other blobs are unaffected
+        assert_eq!(availability.candidates(&servers, "def"), servers);
+    }
+}
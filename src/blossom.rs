@@ -6,7 +6,7 @@ use tokio::sync::RwLock;
 use tracing::{debug, info, warn};
 
 /// Seed relays for fetching user server lists (kind 10063)
-const SEED_RELAYS: &[&str] = &[
+pub const SEED_RELAYS: &[&str] = &[
     "wss://nos.lol",
     "wss://nostr.mom",
     "wss://purplepag.es",
@@ -27,19 +27,41 @@ struct CacheEntry {
     cached_at: Instant,
 }
 
+/// Display name and image URLs from an author's metadata (kind 0); only http(s) URLs are kept
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// `display_name`, else `name`
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub banner: Option<String>,
+}
+
+/// Cache entry for an author's profile
+#[derive(Clone, Debug)]
+struct ProfileEntry {
+    profile: Profile,
+    cached_at: Instant,
+}
+
 /// State for Blossom server resolution with caching
 pub struct BlossomState {
     /// Cache of author pubkey -> server list
     server_list_cache: Arc<RwLock<HashMap<PublicKey, CacheEntry>>>,
+    /// Cache of author pubkey -> kind 0 profile, for `/avatar`, `/banner` and `/card`
+    profile_cache: Arc<RwLock<HashMap<PublicKey, ProfileEntry>>>,
     /// Cache TTL duration (default: 24 hours)
     cache_ttl: Duration,
     /// Nostr client for querying relays
     client: Client,
+    /// Authors whose lists never expire and are refreshed in the background (`PINNED_PUBKEYS`)
+    pinned: HashSet<PublicKey>,
 }
 
 impl BlossomState {
-    /// Create new BlossomState with configurable cache TTL
-    pub async fn new(cache_ttl_hours: u64) -> Self {
+    /// Create new BlossomState with configurable cache TTL and pinned authors
+    ///
+    /// `pinned_pubkeys` are validated at startup, so unparsable entries are skipped here.
+    pub async fn new(cache_ttl_hours: u64, pinned_pubkeys: &[String]) -> Self {
         let cache_ttl = Duration::from_secs(cache_ttl_hours * 3600);
 
         // Initialize Nostr client with seed relays
@@ -55,15 +77,22 @@ impl BlossomState {
         // Connect to relays
         client.connect().await;
 
+        let pinned = pinned_pubkeys
+            .iter()
+            .filter_map(|p| Self::parse_pubkey(p).ok())
+            .collect();
+
         Self {
             server_list_cache: Arc::new(RwLock::new(HashMap::new())),
+            profile_cache: Arc::new(RwLock::new(HashMap::new())),
             cache_ttl,
             client,
+            pinned,
         }
     }
 
     /// Parse pubkey from string (supports both npub and hex formats)
-    fn parse_pubkey(pubkey_str: &str) -> Result<PublicKey, String> {
+    pub fn parse_pubkey(pubkey_str: &str) -> Result<PublicKey, String> {
         // Try parsing as npub (Bech32) first
         if let Ok(pubkey) = PublicKey::from_bech32(pubkey_str) {
             return Ok(pubkey);
@@ -139,8 +168,8 @@ impl BlossomState {
         {
             let cache = self.server_list_cache.read().await;
             if let Some(entry) = cache.get(&pubkey) {
-                // Check if cache is still valid
-                if entry.cached_at.elapsed() < self.cache_ttl {
+                // Check if cache is still valid; pinned lists are kept fresh by refresh_pinned
+                if self.pinned.contains(&pubkey) || entry.cached_at.elapsed() < self.cache_ttl {
                     debug!("Cache hit for pubkey {}", pubkey);
                     return Ok(entry.servers.clone());
                 } else {
@@ -164,6 +193,138 @@ impl BlossomState {
 
         Ok(servers)
     }
+
+    /// Fetch author's profile from their metadata (kind 0)
+    ///
+    /// Relay failures and timeouts are errors, so they aren't cached as an empty profile.
+    async fn fetch_author_profile(&self, pubkey: &PublicKey) -> Result<Profile, String> {
+        debug!("Fetching profile metadata for pubkey: {}", pubkey);
+
+        let filter = Filter::new().kind(Kind::Metadata).author(*pubkey).limit(10);
+        let timeout = Duration::from_secs(10);
+        let events = match tokio::time::timeout(
+            timeout,
+            self.client.fetch_events_from(SEED_RELAYS.to_vec(), vec![filter], Some(timeout))
+        ).await {
+            Ok(Ok(events)) => events,
+            Ok(Err(e)) => return Err(format!("failed to fetch profile from Nostr: {:?}", e)),
+            Err(_) => return Err("timeout fetching profile from Nostr".to_string()),
+        };
+
+        // Get the most recent event
+        let Some(event) = events.iter().max_by_key(|e| e.created_at) else {
+            debug!("No metadata events found for pubkey {}", pubkey);
+            return Ok(Profile::default());
+        };
+        let metadata = match Metadata::from_json(&event.content) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("Unparsable metadata event {}: {}", event.id, e);
+                return Ok(Profile::default());
+            }
+        };
+        let http_url = |url: Option<String>| {
+            url.map(|url| url.trim().to_string())
+                .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+        };
+        let name = metadata
+            .display_name
+            .filter(|name| !name.trim().is_empty())
+            .or(metadata.name)
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+        let profile = Profile {
+            name,
+            picture: http_url(metadata.picture),
+            banner: http_url(metadata.banner),
+        };
+
+        debug!("Profile for pubkey {}: {:?}", pubkey, profile);
+        Ok(profile)
+    }
+
+    /// Get author's profile (with caching for `ttl`)
+    pub async fn get_author_profile(&self, pubkey: &PublicKey, ttl: Duration) -> Result<Profile, String> {
+        {
+            let cache = self.profile_cache.read().await;
+            if let Some(entry) = cache.get(pubkey).filter(|entry| entry.cached_at.elapsed() < ttl) {
+                debug!("Profile cache hit for pubkey {}", pubkey);
+                return Ok(entry.profile.clone());
+            }
+        }
+
+        let profile = self.fetch_author_profile(pubkey).await?;
+
+        {
+            let mut cache = self.profile_cache.write().await;
+            cache.retain(|_, entry| entry.cached_at.elapsed() < ttl);
+            cache.insert(*pubkey, ProfileEntry {
+                profile: profile.clone(),
+                cached_at: Instant::now(),
+            });
+        }
+
+        Ok(profile)
+    }
+
+    /// Fetch one event by id from the seed relays (None = no relay has it)
+    pub async fn fetch_event(&self, id: EventId) -> Result<Option<Event>, String> {
+        let filter = Filter::new().id(id);
+        let timeout = Duration::from_secs(10);
+        match tokio::time::timeout(
+            timeout,
+            self.client.fetch_events_from(SEED_RELAYS.to_vec(), vec![filter], Some(timeout))
+        ).await {
+            Ok(Ok(events)) => Ok(events.into_iter().find(|e| e.id == id)),
+            Ok(Err(e)) => Err(format!("failed to fetch event from Nostr: {:?}", e)),
+            Err(_) => Err("timeout fetching event from Nostr".to_string()),
+        }
+    }
+
+    /// Parse an event id from hex, `note1...` or `nevent1...`
+    pub fn parse_event_id(id: &str) -> Result<EventId, String> {
+        if let Ok(id) = EventId::parse(id) {
+            return Ok(id);
+        }
+        Nip19Event::from_bech32(id)
+            .map(|nevent| nevent.event_id)
+            .map_err(|_| format!("Invalid event id format: {}", id))
+    }
+
+    /// Re-fetch every pinned author's server list
+    ///
+    /// An empty result (relays down or timing out) keeps the previous list.
+    pub async fn refresh_pinned(&self) {
+        for pubkey in &self.pinned {
+            let servers = match self.fetch_author_servers(pubkey).await {
+                Ok(servers) => servers,
+                Err(e) => {
+                    warn!("Failed to refresh pinned server list for {}: {}", pubkey, e);
+                    continue;
+                }
+            };
+            let mut cache = self.server_list_cache.write().await;
+            if servers.is_empty() && cache.contains_key(pubkey) {
+                continue;
+            }
+            cache.insert(*pubkey, CacheEntry {
+                servers,
+                cached_at: Instant::now(),
+            });
+        }
+    }
+
+    /// Warm pinned server lists at startup, then refresh them every `interval`
+    pub async fn pinned_refresh_loop(self: Arc<Self>, interval: Duration) {
+        if self.pinned.is_empty() {
+            return;
+        }
+        loop {
+            self.refresh_pinned().await;
+            debug!("refreshed {} pinned server lists", self.pinned.len());
+            tokio::time::sleep(interval).await;
+        }
+    }
 }
 
 /// Normalize server URL (add https:// if missing, remove trailing slash)
@@ -177,6 +338,14 @@ pub fn normalize_server_url(url: &str) -> String {
     url.trim_end_matches('/').to_string()
 }
 
+/// Origin (`scheme://host[:port]`) of a URL, used to report which server supplied a blob
+pub fn server_origin(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(u) => u.origin().ascii_serialization(),
+        Err(_) => normalize_server_url(url),
+    }
+}
+
 /// Combine and deduplicate server lists in priority order
 /// Priority: xs (highest) -> as -> fallback (lowest)
 pub fn combine_server_lists(
@@ -224,6 +393,18 @@ mod tests {
         assert_eq!(normalize_server_url("http://example.com"), "http://example.com");
     }
 
+    #[test]
+    fn test_server_origin() {
+        assert_eq!(
+            server_origin("https://cdn.example.com/abc.jpg"),
+            "https://cdn.example.com"
+        );
+        assert_eq!(
+            server_origin("http://localhost:3000/abc.jpg"),
+            "http://localhost:3000"
+        );
+    }
+
     #[test]
     fn test_combine_server_lists() {
         let xs = vec!["server1.com".to_string()];
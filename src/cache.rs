@@ -1,74 +1,604 @@
 use std::{
+    borrow::Cow,
     fs,
     io::Write,
     path::{Path, PathBuf},
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
     body::Body,
-    http::{header, HeaderValue, StatusCode},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::Response,
 };
+use bytes::Bytes;
 use http::HeaderName;
+use moka::sync::Cache;
 use sha2::{Digest, Sha256};
 use tokio::{fs as tokio_fs, time::sleep};
-use tracing::error;
+use tracing::{error, info};
 use walkdir::WalkDir;
 
-use crate::{config::AppCfg, error::SvcError, transform::OutFmt};
+use crate::{
+    cache_crypto::CacheCipher,
+    chaos,
+    config::{AppCfg, AppState},
+    error::SvcError,
+    metrics, report,
+    transform::{image_dimensions, OutFmt},
+};
 
 /// Generate cache file path for processed images
+///
+/// Operators' directive defaults are part of the key, so changing one never serves results
+/// rendered under the old value.
 pub fn cache_path_for(cfg: &AppCfg, request_url: &str, fmt: &OutFmt) -> PathBuf {
-    let mut hasher = Sha256::new();
-    hasher.update(request_url.as_bytes());
-    let hash = hex::encode(hasher.finalize());
+    let hash = match cfg.directive_defaults.cache_tag() {
+        Some(tag) => namespaced_hash(cfg, &format!("{}\0{}", request_url, tag)),
+        None => namespaced_hash(cfg, request_url),
+    };
 
     cfg.cache_dir
         .join("processed")
         .join(format!("{}.{}", hash, fmt.extension()))
 }
 
+/// Cache file path for an animated video preview, kept apart from processed images
+pub fn preview_cache_path_for(cfg: &AppCfg, request_url: &str) -> PathBuf {
+    let hash = namespaced_hash(cfg, request_url);
+
+    cfg.cache_dir.join("preview").join(format!("{}.webp", hash))
+}
+
+/// Cache file path for a storyboard sprite (`jpg`) or its WebVTT (`vtt`)
+pub fn storyboard_cache_path_for(cfg: &AppCfg, request_url: &str, ext: &str) -> PathBuf {
+    let hash = namespaced_hash(cfg, request_url);
+
+    cfg.cache_dir.join("storyboard").join(format!("{}.{}", hash, ext))
+}
+
+/// Cache file path for a /probe metadata report
+pub fn probe_cache_path_for(cfg: &AppCfg, request_url: &str) -> PathBuf {
+    let hash = namespaced_hash(cfg, request_url);
+
+    cfg.cache_dir.join("probe").join(format!("{}.json", hash))
+}
+
 /// Generate cache file path for original images
 pub fn original_cache_path_for(cfg: &AppCfg, source_url: &str) -> PathBuf {
-    let mut hasher = Sha256::new();
-    hasher.update(source_url.as_bytes());
-    let hash = hex::encode(hasher.finalize());
+    let hash = namespaced_hash(cfg, source_url);
 
     cfg.cache_dir.join("original").join(hash)
 }
 
-/// Try to serve a response from cache
-pub async fn try_serve_cache(path: &Path, mime: &str) -> Result<Option<Response>, SvcError> {
-    if let Ok(bytes) = tokio_fs::read(path).await {
-        let mut resp = Response::new(Body::from(bytes));
-        *resp.status_mut() = StatusCode::OK;
-        let headers = resp.headers_mut();
-        headers.insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_str(mime).unwrap(),
-        );
-        headers.insert(
-            header::CACHE_CONTROL,
-            HeaderValue::from_static("public, max-age=31536000, immutable"),
-        );
-        headers.insert(
-            HeaderName::from_static("x-cache"),
-            HeaderValue::from_static("hit"),
+/// Hex SHA-256 of a cache key within the tenant's namespace
+///
+/// The default (empty) namespace hashes the bare key, so existing entries stay valid.
+fn namespaced_hash(cfg: &AppCfg, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    if !cfg.cache_namespace.is_empty() {
+        hasher.update(cfg.cache_namespace.as_bytes());
+        hasher.update(b"\0");
+    }
+    hasher.update(key.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Processed image held in the memory tier
+#[derive(Clone)]
+struct MemoryEntry {
+    bytes: Bytes,
+    etag: String,
+    modified: SystemTime,
+    dimensions: Option<(u32, u32)>,
+}
+
+/// In-memory LRU tier in front of the processed disk cache, keyed by processed cache path
+#[derive(Clone)]
+pub struct MemoryCache {
+    entries: Cache<PathBuf, MemoryEntry>,
+}
+
+impl MemoryCache {
+    /// Memory tier bounded by total image bytes; entries expire with the processed disk tier
+    pub fn new(max_bytes: u64, ttl: Duration) -> Self {
+        let entries = Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(|_, e: &MemoryEntry| e.bytes.len().try_into().unwrap_or(u32::MAX))
+            .time_to_live(ttl)
+            .build();
+        Self { entries }
+    }
+
+    pub fn insert(&self, path: &Path, bytes: Bytes, modified: SystemTime, dimensions: Option<(u32, u32)>) {
+        self.entries.insert(
+            path.to_path_buf(),
+            MemoryEntry {
+                etag: etag_for(&bytes),
+                bytes,
+                modified,
+                dimensions,
+            },
         );
+    }
+
+    pub fn invalidate(&self, path: &Path) {
+        self.entries.invalidate(path);
+    }
+
+    /// Bytes and entries currently held
+    pub fn usage(&self) -> (u64, u64) {
+        self.entries.run_pending_tasks();
+        (self.entries.weighted_size(), self.entries.entry_count())
+    }
+
+    /// Cache paths of the entries currently held
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.iter().map(|(path, _)| (*path).clone()).collect()
+    }
+}
+
+/// Load processed entries from disk into the memory tier, e.g. the hot set of a previous run
+///
+/// Missing, soft-purged and expired entries are skipped; returns how many were loaded.
+pub async fn warm_memory_cache(app: &AppState, paths: &[PathBuf]) -> usize {
+    let Some(ref memory) = app.memory_cache else {
+        return 0;
+    };
+    let mut loaded = 0;
+    for path in paths {
+        if tokio_fs::try_exists(stale_marker_path(path)).await.unwrap_or(true) {
+            continue;
+        }
+        let Ok(meta) = tokio_fs::metadata(path).await else {
+            continue;
+        };
+        let Ok(modified) = meta.created().or_else(|_| meta.modified()) else {
+            continue;
+        };
+        if modified.elapsed().unwrap_or_default() >= app.cfg.processed_cache_ttl {
+            continue;
+        }
+        let Some(bytes) = tokio_fs::read(path).await.ok().and_then(|b| unseal(app, b)) else {
+            continue;
+        };
+        let dimensions = image_dimensions(&bytes);
+        memory.insert(path, Bytes::from(bytes), modified, dimensions);
+        loaded += 1;
+    }
+    loaded
+}
+
+/// Build an image response with the standard caching headers
+pub fn build_image_response(
+    bytes: impl Into<Bytes>,
+    mime: &str,
+    cache_status: &'static str,
+    dimensions: Option<(u32, u32)>,
+) -> Response {
+    let mut resp = Response::new(Body::from(bytes.into()));
+    *resp.status_mut() = StatusCode::OK;
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(mime).unwrap(),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=31536000, immutable"),
+    );
+    headers.insert(
+        HeaderName::from_static("x-cache"),
+        HeaderValue::from_static(cache_status),
+    );
+    // Final output dimensions so clients can lay out images before decoding
+    if let Some((w, h)) = dimensions {
+        headers.insert(HeaderName::from_static("x-width"), HeaderValue::from(w));
+        headers.insert(HeaderName::from_static("x-height"), HeaderValue::from(h));
+    }
+    resp
+}
+
+/// Set the Last-Modified header from a cache entry timestamp
+pub fn set_last_modified(resp: &mut Response, modified: SystemTime) {
+    let value = httpdate::fmt_http_date(modified);
+    resp.headers_mut().insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&value).unwrap(),
+    );
+}
+
+/// Strong ETag for a processed entry, derived from the bytes served
+///
+/// A regenerated entry with different content gets a new tag, so clients never revalidate
+/// an outdated copy.
+pub fn etag_for(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    format!("\"{}\"", hex::encode(&digest[..16]))
+}
+
+/// Set the ETag header
+pub fn set_etag(resp: &mut Response, etag: &str) {
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        resp.headers_mut().insert(header::ETAG, value);
+    }
+}
+
+/// Whether an If-None-Match list matches `etag` (weak comparison, as RFC 9110 requires)
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Check whether the client's validators are still fresh
+///
+/// If-None-Match takes precedence; If-Modified-Since is only used without it.
+pub fn is_not_modified(req_headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = req_headers.get(header::IF_NONE_MATCH) {
+        return if_none_match
+            .to_str()
+            .is_ok_and(|v| etag_matches(v, etag));
+    }
+
+    let Some(since) = req_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+    else {
+        return false;
+    };
+
+    // HTTP dates have second precision, so drop sub-second parts before comparing
+    let modified_secs = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(modified_secs) <= since
+}
+
+/// Build a 304 Not Modified response for a cached entry
+pub fn not_modified_response(etag: &str, modified: SystemTime, cache_status: &'static str) -> Response {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::NOT_MODIFIED;
+    resp.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=31536000, immutable"),
+    );
+    resp.headers_mut().insert(
+        HeaderName::from_static("x-cache"),
+        HeaderValue::from_static(cache_status),
+    );
+    set_etag(&mut resp, etag);
+    set_last_modified(&mut resp, modified);
+    resp
+}
+
+/// Sidecar marker that flags a cache entry as soft-purged
+///
+/// Appended rather than replacing the extension, so rewriting one format variant of a key
+/// leaves its siblings' flags alone.
+pub fn stale_marker_path(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".stale");
+    PathBuf::from(marker)
+}
+
+/// Sidecar marker that keeps a cache file from janitor eviction
+///
+/// Appended rather than replacing the extension, so format variants of one key pin separately.
+pub fn pin_marker_path(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".pin");
+    PathBuf::from(marker)
+}
+
+/// Pin an entry on disk so the janitor never evicts it; false if there is no disk entry
+pub async fn pin_entry(path: &Path) -> Result<bool, SvcError> {
+    if !tokio_fs::try_exists(path).await? {
+        return Ok(false);
+    }
+    tokio_fs::write(pin_marker_path(path), b"").await?;
+    Ok(true)
+}
+
+/// Remove a pin; false if the entry wasn't pinned
+pub async fn unpin_entry(path: &Path) -> Result<bool, SvcError> {
+    match tokio_fs::remove_file(pin_marker_path(path)).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Pin freshly written entries of a source matching `JANITOR_EXCLUDE_KEY_PREFIXES`
+///
+/// `source` is the source URL (or `<sha256>.<ext>` for /thumb), the only key that is
+/// still readable once entries are stored under hashed file names.
+pub async fn pin_if_excluded(cfg: &AppCfg, source: &str, paths: &[&Path]) {
+    if !cfg.janitor_exclude_key_prefixes.iter().any(|p| source.starts_with(p.as_str())) {
+        return;
+    }
+    for path in paths {
+        if let Err(e) = pin_entry(path).await {
+            error!(?e, "failed to pin {}", path.display());
+        }
+    }
+}
+
+/// Whether a cache file matches a `JANITOR_EXCLUDE_PATHS` glob (relative to the cache dir)
+fn is_excluded_path(cfg: &AppCfg, path: &Path) -> bool {
+    let Ok(rel) = path.strip_prefix(&cfg.cache_dir) else {
+        return false;
+    };
+    let rel = rel.to_string_lossy().replace('\\', "/");
+    cfg.janitor_exclude_paths.iter().any(|pattern| glob_match(pattern, &rel))
+}
+
+/// Minimal glob: `*` matches any run of characters (including `/`), `?` exactly one
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    // Position of the last `*` and the text index it is currently matched up to
+    let mut backtrack = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Soft purge: mark an existing entry stale so it is served once more while being refreshed
+pub async fn mark_stale(app: &AppState, path: &Path) -> Result<bool, SvcError> {
+    // Memory copies never carry the stale flag, so drop them
+    if let Some(ref memory) = app.memory_cache {
+        memory.invalidate(path);
+    }
+    if let Some(ref redis) = app.redis {
+        if redis.mark_stale(&app.cfg.cache_dir, path).await {
+            return Ok(true);
+        }
+    }
+    if !tokio_fs::try_exists(path).await? {
+        return Ok(false);
+    }
+    tokio_fs::write(stale_marker_path(path), b"").await?;
+    Ok(true)
+}
+
+/// Hard purge: delete an entry and any stale marker from every tier
+pub async fn remove_entry(app: &AppState, path: &Path) -> Result<bool, SvcError> {
+    if let Some(ref memory) = app.memory_cache {
+        memory.invalidate(path);
+    }
+    let in_redis = match app.redis {
+        Some(ref redis) => redis.remove(&app.cfg.cache_dir, path).await,
+        None => false,
+    };
+    let _ = tokio_fs::remove_file(stale_marker_path(path)).await;
+    let _ = tokio_fs::remove_file(pin_marker_path(path)).await;
+    match tokio_fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(in_redis),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Encrypt an entry for storage when `CACHE_ENCRYPTION_KEY` is set
+fn seal<'a>(app: &AppState, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, SvcError> {
+    match app.cfg.cache_encryption {
+        Some(ref cipher) => Ok(Cow::Owned(cipher.encrypt(bytes)?)),
+        None => Ok(Cow::Borrowed(bytes)),
+    }
+}
+
+/// Decrypt a stored entry; plaintext entries pass through, so enabling encryption keeps
+/// existing entries readable. `None` if it cannot be decrypted with the current key.
+fn unseal(app: &AppState, bytes: Vec<u8>) -> Option<Vec<u8>> {
+    if !CacheCipher::is_encrypted(&bytes) {
+        return Some(bytes);
+    }
+    let decrypted = app.cfg.cache_encryption.as_ref()?.decrypt(&bytes);
+    if decrypted.is_none() {
+        metrics::record_processing_error("cache_decrypt");
+    }
+    decrypted
+}
+
+/// Build the response for a cached processed entry, promoting fresh entries to memory
+fn cached_response(
+    memory: Option<&MemoryCache>,
+    path: &Path,
+    bytes: Vec<u8>,
+    mime: &str,
+    cache_status: &'static str,
+    modified: Option<SystemTime>,
+) -> Response {
+    // Only the image header is parsed here, not the full image
+    let dimensions = image_dimensions(&bytes);
+    let etag = etag_for(&bytes);
+    let bytes = Bytes::from(bytes);
+    // Promote to the memory tier; stale entries stay where they are until refreshed
+    if let (Some(memory), Some(modified), "hit") = (memory, modified, cache_status) {
+        memory.insert(path, bytes.clone(), modified, dimensions);
+    }
+    let mut resp = build_image_response(bytes, mime, cache_status, dimensions);
+    set_etag(&mut resp, &etag);
+    if let Some(modified) = modified {
+        set_last_modified(&mut resp, modified);
+    }
+    resp
+}
+
+/// Try to serve a response from cache
+///
+/// Tiers are checked in order: memory, Redis, disk. Soft-purged entries are still served,
+/// tagged `X-Cache: stale`; callers use that to trigger a background refresh.
+pub async fn try_serve_cache(
+    app: &AppState,
+    path: &Path,
+    mime: &str,
+    req_headers: &HeaderMap,
+) -> Result<Option<Response>, SvcError> {
+    let memory = app.memory_cache.as_ref();
+
+    // Hot entries are served without touching the disk
+    if let Some(entry) = memory.and_then(|m| m.entries.get(path)) {
+        metrics::record_cache_hit("memory");
+        if is_not_modified(req_headers, &entry.etag, entry.modified) {
+            return Ok(Some(not_modified_response(&entry.etag, entry.modified, "hit")));
+        }
+        let mut resp = build_image_response(entry.bytes, mime, "hit", entry.dimensions);
+        set_etag(&mut resp, &entry.etag);
+        set_last_modified(&mut resp, entry.modified);
         return Ok(Some(resp));
     }
-    Ok(None)
+
+    if let Some(ref redis) = app.redis {
+        let entry = redis.get(&app.cfg.cache_dir, path).await.and_then(|mut e| {
+            e.bytes = unseal(app, e.bytes)?;
+            Some(e)
+        });
+        if let Some(entry) = entry {
+            metrics::record_cache_hit("redis");
+            let cache_status = if entry.stale { "stale" } else { "hit" };
+            let etag = etag_for(&entry.bytes);
+            if is_not_modified(req_headers, &etag, entry.modified) {
+                return Ok(Some(not_modified_response(&etag, entry.modified, cache_status)));
+            }
+            return Ok(Some(cached_response(
+                memory,
+                path,
+                entry.bytes,
+                mime,
+                cache_status,
+                Some(entry.modified),
+            )));
+        }
+    }
+
+    let Ok(meta) = tokio_fs::metadata(path).await else {
+        return Ok(None);
+    };
+    let modified = meta.created().or_else(|_| meta.modified()).ok();
+    let cache_status = if tokio_fs::try_exists(stale_marker_path(path)).await.unwrap_or(false) {
+        "stale"
+    } else {
+        "hit"
+    };
+
+    // The tag is derived from the content, so the entry is read even for a revalidation
+    let Some(bytes) = tokio_fs::read(path).await.ok().and_then(|b| unseal(app, b)) else {
+        return Ok(None);
+    };
+    touch_accessed(path);
+    if let Some(modified) = modified {
+        let etag = etag_for(&bytes);
+        if is_not_modified(req_headers, &etag, modified) {
+            return Ok(Some(not_modified_response(&etag, modified, cache_status)));
+        }
+    }
+    Ok(Some(cached_response(memory, path, bytes, mime, cache_status, modified)))
 }
 
+/// Store a freshly processed image in every enabled tier; returns its modification time
+///
+/// Small entries go to Redis when configured, everything else to disk.
+pub async fn write_processed_cache(
+    app: &AppState,
+    path: &Path,
+    bytes: &Bytes,
+    dimensions: Option<(u32, u32)>,
+) -> Result<SystemTime, SvcError> {
+    chaos::cache_write_fault()?;
+    let stored = seal(app, bytes)?;
+    let stored_in_redis = match app.redis {
+        Some(ref redis) if redis.accepts(stored.len()) => {
+            redis
+                .put(&app.cfg.cache_dir, path, &stored, app.cfg.processed_cache_ttl)
+                .await
+        }
+        _ => false,
+    };
+    if !stored_in_redis {
+        write_cache_atomic(path, &stored).await?;
+    }
+
+    let modified = SystemTime::now();
+    if let Some(ref memory) = app.memory_cache {
+        memory.insert(path, bytes.clone(), modified, dimensions);
+    }
+    Ok(modified)
+}
+
+/// zstd frame magic number, used to recognize compressed originals on read
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
 /// Try to read original image from cache
-pub async fn try_read_original_cache(path: &Path) -> Result<Option<Vec<u8>>, SvcError> {
-    match tokio_fs::read(path).await {
-        Ok(bytes) => Ok(Some(bytes)),
-        Err(_) => Ok(None),
+///
+/// Entries written with `CACHE_COMPRESS_ORIGINALS` or `CACHE_ENCRYPTION_KEY` are transparently
+/// decrypted and decompressed, so toggling either option does not invalidate existing entries.
+pub async fn try_read_original_cache(app: &AppState, path: &Path) -> Result<Option<Vec<u8>>, SvcError> {
+    let stored = match app.redis {
+        Some(ref redis) => redis.get(&app.cfg.cache_dir, path).await.map(|e| e.bytes),
+        None => None,
+    };
+    let stored = match stored {
+        Some(bytes) => Some(bytes),
+        None => {
+            let bytes = tokio_fs::read(path).await.ok();
+            if bytes.is_some() {
+                touch_accessed(path);
+            }
+            bytes
+        }
+    };
+    match stored.and_then(|b| unseal(app, b)) {
+        Some(bytes) if bytes.starts_with(&ZSTD_MAGIC) => Ok(Some(zstd::decode_all(&bytes[..])?)),
+        other => Ok(other),
     }
 }
 
+/// Write an original to cache, zstd-compressing it when enabled and worthwhile
+///
+/// Small originals go to Redis when configured, everything else to disk.
+pub async fn write_original_cache(app: &AppState, path: &Path, bytes: &[u8]) -> Result<(), SvcError> {
+    chaos::cache_write_fault()?;
+    let mut payload = Cow::Borrowed(bytes);
+    if let Some(level) = app.cfg.original_compression_level {
+        let compressed = zstd::encode_all(bytes, level)?;
+        // Already-compressed formats (JPEG, WebP, ...) rarely shrink; keep those raw
+        if compressed.len() < bytes.len() {
+            payload = Cow::Owned(compressed);
+        }
+    }
+    // Compress before encrypting; ciphertext does not compress
+    let payload = seal(app, &payload)?;
+
+    if let Some(ref redis) = app.redis {
+        if redis.accepts(payload.len())
+            && redis
+                .put(&app.cfg.cache_dir, path, &payload, app.cfg.original_cache_ttl)
+                .await
+        {
+            return Ok(());
+        }
+    }
+    write_cache_atomic(path, &payload).await
+}
+
 /// Write data to cache atomically
 pub async fn write_cache_atomic(path: &Path, bytes: &[u8]) -> Result<(), SvcError> {
     // Ensure parent directory exists
@@ -76,7 +606,10 @@ pub async fn write_cache_atomic(path: &Path, bytes: &[u8]) -> Result<(), SvcErro
         tokio_fs::create_dir_all(parent).await?;
     }
 
-    let tmp = path.with_extension("tmp");
+    // Per variant, so format variants of one key written at once don't share a temp file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
 
     // Sync write via std::fs to ensure durability
     {
@@ -84,7 +617,10 @@ pub async fn write_cache_atomic(path: &Path, bytes: &[u8]) -> Result<(), SvcErro
         f.write_all(bytes)?;
         f.sync_all()?;
     }
-    fs::rename(&tmp, &path)?;
+    fs::rename(&tmp, path)?;
+
+    // A fresh write supersedes any soft purge
+    let _ = fs::remove_file(stale_marker_path(path));
     Ok(())
 }
 
@@ -99,14 +635,31 @@ pub async fn janitor_loop(cfg: AppCfg) {
 }
 
 /// Run a single cleanup pass
+///
+/// Expired files are removed first; if `CACHE_MAX_BYTES` is set and the remaining files
+/// still exceed it, the least recently accessed files are evicted until under budget.
+/// Pinned files and files matching `JANITOR_EXCLUDE_PATHS` are never evicted and don't
+/// count toward the budget.
 async fn run_cleanup(cfg: &AppCfg) -> Result<(), std::io::Error> {
     let now = SystemTime::now();
     
-    // Clean both original and processed cache directories
+    // Clean the original, processed, preview, storyboard and probe cache directories
     let original_dir = cfg.cache_dir.join("original");
     let processed_dir = cfg.cache_dir.join("processed");
+    let preview_dir = cfg.cache_dir.join("preview");
+    let storyboard_dir = cfg.cache_dir.join("storyboard");
+    let probe_dir = cfg.cache_dir.join("probe");
+
+    // Surviving files as (last access, size, path, tier) for size-based eviction
+    let mut survivors = Vec::new();
     
-    for cache_dir in [original_dir, processed_dir] {
+    for (tier, cache_dir, ttl) in [
+        ("original", original_dir, cfg.original_cache_ttl),
+        ("processed", processed_dir, cfg.processed_cache_ttl),
+        ("preview", preview_dir, cfg.processed_cache_ttl),
+        ("storyboard", storyboard_dir, cfg.processed_cache_ttl),
+        ("probe", probe_dir, cfg.processed_cache_ttl),
+    ] {
         if !cache_dir.exists() {
             continue;
         }
@@ -119,13 +672,57 @@ async fn run_cleanup(cfg: &AppCfg) -> Result<(), std::io::Error> {
                 continue;
             }
             let p = entry.path();
+            if p.extension().is_some_and(|ext| ext == "pin")
+                || pin_marker_path(p).exists()
+                || is_excluded_path(cfg, p)
+            {
+                continue;
+            }
             let meta = fs::metadata(p)?;
             let created = meta.created().or_else(|_| meta.modified())?;
-            if now.duration_since(created).unwrap_or(Duration::ZERO) > cfg.cache_ttl {
-                let _ = fs::remove_file(p);
+            if now.duration_since(created).unwrap_or(Duration::ZERO) > ttl {
+                if fs::remove_file(p).is_ok() {
+                    report::record_eviction(tier);
+                }
+                continue;
             }
+            // Cache hits refresh atime explicitly, so this works on noatime mounts too
+            let accessed = meta.accessed().unwrap_or(created).max(created);
+            survivors.push((accessed, meta.len(), p.to_path_buf(), tier));
         }
     }
+
+    if cfg.cache_max_bytes > 0 {
+        evict_to_budget(cfg.cache_max_bytes, survivors);
+    }
     Ok(())
 }
 
+/// Evict least recently accessed files until the total size fits in `max_bytes`
+fn evict_to_budget(max_bytes: u64, mut files: Vec<(SystemTime, u64, PathBuf, &'static str)>) {
+    let mut total: u64 = files.iter().map(|f| f.1).sum();
+    if total <= max_bytes {
+        return;
+    }
+
+    files.sort_by_key(|f| f.0);
+    let (mut evicted, mut freed) = (0usize, 0u64);
+    for (_, size, path, tier) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            let _ = fs::remove_file(stale_marker_path(&path));
+            total -= size;
+            freed += size;
+            evicted += 1;
+            report::record_eviction(tier);
+        }
+    }
+    info!(evicted, freed, total, max_bytes, "evicted cache files over size budget");
+}
+
+/// Mark a cache file as recently used for size-based eviction
+fn touch_accessed(path: &Path) {
+    let _ = filetime::set_file_atime(path, filetime::FileTime::now());
+}
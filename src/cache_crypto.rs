@@ -0,0 +1,130 @@
+use aes_gcm::{
+    aead::{
+        generic_array::GenericArray,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit, OsRng,
+    },
+    Aes256Gcm,
+};
+
+use crate::error::SvcError;
+
+/// Marks an encrypted cache entry: magic + format version
+const MAGIC: &[u8; 5] = b"IPXE\x01";
+/// Nonce prefix size for the STREAM construction (12-byte nonce minus 5 bytes of counter)
+const NONCE_PREFIX_LEN: usize = 7;
+/// Plaintext bytes per authenticated chunk
+const CHUNK_LEN: usize = 64 * 1024;
+/// AES-GCM tag appended to every chunk
+const TAG_LEN: usize = 16;
+
+/// AES-256-GCM encryption of cache entries at rest (`CACHE_ENCRYPTION_KEY`)
+///
+/// Entries are split into chunks with the STREAM construction, so each chunk is
+/// authenticated and truncation or reordering is detected. Layout:
+/// `MAGIC | nonce prefix | chunk ciphertexts (each with tag)`.
+#[derive(Clone)]
+pub struct CacheCipher {
+    cipher: Aes256Gcm,
+}
+
+impl CacheCipher {
+    /// Build from a hex-encoded 32-byte key
+    pub fn from_hex(key: &str) -> Result<Self, String> {
+        let key = hex::decode(key.trim())
+            .map_err(|e| format!("CACHE_ENCRYPTION_KEY is not valid hex: {}", e))?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| "CACHE_ENCRYPTION_KEY must be 32 bytes (64 hex characters)".to_string())?;
+        Ok(Self { cipher })
+    }
+
+    /// Whether `data` was written by `encrypt`
+    pub fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, SvcError> {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        let mut encryptor =
+            EncryptorBE32::from_aead(self.cipher.clone(), GenericArray::from_slice(&prefix));
+
+        let chunks = plaintext.len().div_ceil(CHUNK_LEN).max(1);
+        let mut out = Vec::with_capacity(MAGIC.len() + prefix.len() + plaintext.len() + chunks * TAG_LEN);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&prefix);
+
+        let mut rest = plaintext;
+        while rest.len() > CHUNK_LEN {
+            let (chunk, tail) = rest.split_at(CHUNK_LEN);
+            let sealed = encryptor
+                .encrypt_next(chunk)
+                .map_err(|_| SvcError::InternalError("cache encryption failed".to_string()))?;
+            out.extend_from_slice(&sealed);
+            rest = tail;
+        }
+        let sealed = encryptor
+            .encrypt_last(rest)
+            .map_err(|_| SvcError::InternalError("cache encryption failed".to_string()))?;
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    /// Decrypt an entry; `None` if it is malformed or was written with another key
+    pub fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let body = data.strip_prefix(MAGIC.as_slice())?;
+        if body.len() < NONCE_PREFIX_LEN + TAG_LEN {
+            return None;
+        }
+        let (prefix, mut rest) = body.split_at(NONCE_PREFIX_LEN);
+        let mut decryptor =
+            DecryptorBE32::from_aead(self.cipher.clone(), GenericArray::from_slice(prefix));
+
+        let mut out = Vec::with_capacity(rest.len());
+        while rest.len() > CHUNK_LEN + TAG_LEN {
+            let (chunk, tail) = rest.split_at(CHUNK_LEN + TAG_LEN);
+            out.extend_from_slice(&decryptor.decrypt_next(chunk).ok()?);
+            rest = tail;
+        }
+        out.extend_from_slice(&decryptor.decrypt_last(rest).ok()?);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn test_roundtrip() {
+        let cipher = CacheCipher::from_hex(KEY).unwrap();
+        for len in [0, 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = cipher.encrypt(&plaintext).unwrap();
+            assert!(CacheCipher::is_encrypted(&sealed));
+            assert_eq!(cipher.decrypt(&sealed).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_tamper_and_wrong_key() {
+        let cipher = CacheCipher::from_hex(KEY).unwrap();
+        let mut sealed = cipher.encrypt(b"cached image bytes").unwrap();
+
+        let other = CacheCipher::from_hex(&"ff".repeat(32)).unwrap();
+        assert!(other.decrypt(&sealed).is_none());
+
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(cipher.decrypt(&sealed).is_none());
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        assert!(CacheCipher::from_hex("abcd").is_err());
+        assert!(CacheCipher::from_hex("not hex").is_err());
+    }
+}
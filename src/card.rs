@@ -0,0 +1,291 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Response,
+    Extension,
+};
+use image::{DynamicImage, Rgb, RgbImage};
+use nostr_sdk::{Event, EventId};
+use serde::Deserialize;
+
+use crate::{
+    blossom::BlossomState,
+    cache::cache_path_for,
+    config::TenantCfg,
+    error::SvcError,
+    profile::{set_window_cache_control, ttl_window},
+    server::{
+        check_max_dimension, generate_insecure, generate_still, journaled, negotiate_format, parse_thumb_params,
+        set_vary_accept, try_serve_processed, with_deadline, CombinedState, ServerHintsQuery, ThumbQuery,
+    },
+    singleflight::namespaced_key,
+    text::{draw_text_at, wrap_text},
+    transform::{Card, Directives, Gravity, OutFmt, Resize, ResizeMode, TextOverlay},
+};
+
+/// Open Graph image size
+const CARD_WIDTH: u32 = 1200;
+const CARD_HEIGHT: u32 = 630;
+/// Canvas color for cards without a background image
+const CARD_BACKGROUND: [u8; 3] = [0x1f, 0x23, 0x33];
+/// Brightness kept on the background so white text stays readable
+const SHADE: f32 = 0.45;
+/// Distance of the text from the card edges
+const MARGIN: u32 = 64;
+const TITLE_SIZE: f32 = 64.0;
+const MAX_TITLE_LINES: usize = 4;
+const AUTHOR_SIZE: f32 = 36.0;
+/// Longest title and author taken from a query or an event, in characters
+const MAX_TITLE_CHARS: usize = 300;
+const MAX_AUTHOR_CHARS: usize = 100;
+
+/// Query parameters for /card
+#[derive(Debug, Deserialize)]
+pub struct CardQuery {
+    /// Headline (default: the event's `title` tag or first line of content)
+    title: Option<String>,
+    /// Byline (default: the event author's profile name)
+    author: Option<String>,
+    /// Background image URL (default: the event's image, then the author's banner)
+    bg: Option<String>,
+    /// Nostr event (hex, note1 or nevent1) the defaults come from
+    event: Option<String>,
+    /// Output format (e.g., "jpeg", "webp", "auto")
+    #[serde(rename = "f")]
+    format: Option<String>,
+}
+
+/// GET /card - a 1200x630 social preview card: shaded background plus title and author
+///
+/// Cards built from `event=` depend on relay data, so they are cached in `PROFILE_TTL_SECS`
+/// windows like `/avatar`; cards from query parameters alone never change and are immutable.
+pub async fn handle_card(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    Query(query): Query<CardQuery>,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    let cfg = &state.app.cfg;
+    let event_id = query
+        .event
+        .as_deref()
+        .map(BlossomState::parse_event_id)
+        .transpose()
+        .map_err(|_| SvcError::BadRequest("invalid event id"))?;
+    if query.bg.as_deref().is_some_and(|bg| !is_http_url(bg)) {
+        return Err(SvcError::BadRequest("bg must be an http(s) URL"));
+    }
+
+    let mut dirs = parse_thumb_params(&ThumbQuery::default(), &cfg.directive_defaults)?;
+    if let Some(ref fmt) = query.format {
+        dirs.out_fmt = OutFmt::from_name(fmt)?;
+    }
+    dirs.resize = Resize {
+        mode: ResizeMode::Fill,
+        w: CARD_WIDTH,
+        h: CARD_HEIGHT,
+    };
+    check_max_dimension(cfg, &dirs)?;
+    let negotiated = negotiate_format(&mut dirs, &req_headers);
+
+    let window = event_id.map(|_| ttl_window(cfg));
+    // Debug formatting quotes every value, so no title can imitate another parameter
+    let cache_key = format!(
+        "/card#{:?}",
+        (
+            &query.title,
+            &query.author,
+            &query.bg,
+            event_id.map(|id| id.to_hex()),
+            dirs.out_fmt.name(),
+            window.map(|(index, _)| index),
+        )
+    );
+    let cache_path = cache_path_for(cfg, &cache_key, &dirs.out_fmt);
+
+    let cached = try_serve_processed(&state.app, &cache_path, &dirs, &req_headers).await?;
+    let mut resp = match cached {
+        Some(resp) => resp,
+        None => {
+            let inflight_key = namespaced_key(&cfg.cache_namespace, &format!("{}#{}", cache_key, dirs.out_fmt.name()));
+            let pipeline = with_deadline(
+                cfg.request_timeout,
+                generate_card(state.clone(), query, event_id, dirs, cache_path),
+            );
+            state.inflight.run(inflight_key, pipeline).await
+        }
+    };
+
+    if let Some((_, secs_left)) = window {
+        set_window_cache_control(&mut resp, secs_left);
+    }
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    Ok(resp)
+}
+
+/// Resolve the card's content, then render and cache it after a processed-cache miss
+async fn generate_card(
+    state: CombinedState,
+    query: CardQuery,
+    event_id: Option<EventId>,
+    mut dirs: Directives,
+    cache_path: PathBuf,
+) -> Result<Response, SvcError> {
+    let (mut title, mut author, mut bg) = (query.title, query.author, query.bg);
+    if let Some(id) = event_id {
+        let event = state
+            .blossom
+            .fetch_event(id)
+            .await
+            .map_err(|e| {
+                tracing::warn!("event lookup for {} failed: {}", id, e);
+                SvcError::UpstreamError(504)
+            })?
+            .ok_or(SvcError::NotFound("event not found on relays"))?;
+        title = title.or_else(|| event_title(&event));
+        bg = bg.or_else(|| event_image(&event));
+        if author.is_none() || bg.is_none() {
+            // The card still works without the profile, so relay trouble only costs the byline
+            match state.blossom.get_author_profile(&event.pubkey, state.app.cfg.profile_ttl).await {
+                Ok(profile) => {
+                    author = author.or(profile.name);
+                    bg = bg.or(profile.banner);
+                }
+                Err(e) => tracing::warn!("profile lookup for {} failed: {}", event.pubkey, e),
+            }
+        }
+    }
+    dirs.card = Some(Card {
+        title: title.map(|t| truncated(&t, MAX_TITLE_CHARS)),
+        author: author.map(|a| truncated(&a, MAX_AUTHOR_CHARS)),
+    });
+
+    match bg {
+        Some(src_url) => {
+            let pipeline = generate_insecure(state, src_url.clone(), dirs, ServerHintsQuery::default(), cache_path, false);
+            journaled(src_url, pipeline).await
+        }
+        None => generate_plain_card(state, dirs, cache_path).await,
+    }
+}
+
+/// Render a card on the plain `CARD_BACKGROUND` and cache it
+async fn generate_plain_card(
+    state: CombinedState,
+    dirs: Directives,
+    cache_path: PathBuf,
+) -> Result<Response, SvcError> {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(CARD_WIDTH, CARD_HEIGHT, Rgb(CARD_BACKGROUND)));
+    generate_still(&state.app, img, &dirs, &cache_path).await
+}
+
+/// Shade the background, then draw the title top left and the author bottom left
+///
+/// Returns how many characters no font could render. The image is already filled to the card
+/// size by the resize step.
+pub fn draw_card(img: DynamicImage, card: &Card) -> (DynamicImage, usize) {
+    let mut rgba = img.into_rgba8();
+    for px in rgba.pixels_mut() {
+        for channel in &mut px.0[..3] {
+            *channel = (*channel as f32 * SHADE).round() as u8;
+        }
+    }
+    let mut img = DynamicImage::ImageRgba8(rgba);
+    let text_width = img.width().saturating_sub(2 * MARGIN) as f32;
+
+    let mut missing = 0;
+    let texts = [
+        (&card.title, TITLE_SIZE, MAX_TITLE_LINES, [255, 255, 255, 255], Gravity::NorthWest),
+        (&card.author, AUTHOR_SIZE, 1, [210, 214, 224, 255], Gravity::SouthWest),
+    ];
+    for (text, size, max_lines, color, gravity) in texts {
+        let Some(text) = text.as_deref().filter(|t| !t.trim().is_empty()) else {
+            continue;
+        };
+        let overlay = TextOverlay {
+            text: wrap_text(text, size, text_width, max_lines),
+            size,
+            color,
+            gravity,
+        };
+        let (drawn, text_missing) = draw_text_at(img, &overlay, MARGIN);
+        img = drawn;
+        missing += text_missing;
+    }
+    (img, missing)
+}
+
+/// `title` tag (long-form articles, videos), else the first non-empty line of content
+fn event_title(event: &Event) -> Option<String> {
+    tag_value(event, "title").or_else(|| {
+        event
+            .content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+    })
+}
+
+/// `image` or `thumb` tag, else the `image` of the first `imeta` tag that has one
+fn event_image(event: &Event) -> Option<String> {
+    tag_value(event, "image")
+        .or_else(|| tag_value(event, "thumb"))
+        .or_else(|| {
+            event
+                .tags
+                .iter()
+                .map(|tag| tag.as_slice())
+                .filter(|tag| tag.first().is_some_and(|name| name == "imeta"))
+                .flat_map(|tag| tag.iter().skip(1))
+                .find_map(|entry| entry.strip_prefix("image ").map(str::to_string))
+        })
+        .filter(|url| is_http_url(url))
+}
+
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .map(|tag| tag.as_slice())
+        .find(|tag| tag.len() >= 2 && tag[0] == name && !tag[1].trim().is_empty())
+        .map(|tag| tag[1].trim().to_string())
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// At most `max` characters, ending in an ellipsis when cut
+fn truncated(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(max - 1).collect();
+    cut.push('\u{2026}');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(truncated("hello", 5), "hello");
+        assert_eq!(truncated("hello world", 6), "hello\u{2026}");
+    }
+
+    #[test]
+    fn test_draw_card_shades_background() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([200, 100, 0])));
+        let (out, missing) = draw_card(img, &Card::default());
+        assert_eq!(missing, 0);
+        assert_eq!(out.to_rgba8().get_pixel(0, 0).0, [90, 45, 0, 255]);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{io, time::Duration};
+
+/// Faults injected on purpose, from `CHAOS_*`; all zero outside resilience tests
+///
+/// Only builds with the `chaos` feature act on them, so a production binary can't be made to
+/// fail by its environment.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultRates {
+    /// Share of upstream fetch attempts answered with a fake `503` instead of being sent
+    pub upstream_failure: f64,
+    /// Share of upstream fetch attempts held back by `delay` first
+    pub slow: f64,
+    pub delay: Duration,
+    /// Share of cache writes failing with an I/O error
+    pub cache_write_failure: f64,
+}
+
+impl FaultRates {
+    /// Whether any fault can fire
+    pub fn is_active(&self) -> bool {
+        self.upstream_failure > 0.0 || (self.slow > 0.0 && !self.delay.is_zero()) || self.cache_write_failure > 0.0
+    }
+}
+
+#[cfg(feature = "chaos")]
+static RATES: std::sync::OnceLock<FaultRates> = std::sync::OnceLock::new();
+
+/// Arm fault injection; call once at startup
+#[cfg(feature = "chaos")]
+pub fn init(rates: FaultRates) {
+    if rates.is_active() {
+        tracing::warn!("fault injection is active: {:?}", rates);
+        let _ = RATES.set(rates);
+    }
+}
+
+#[cfg(not(feature = "chaos"))]
+pub fn init(_rates: FaultRates) {}
+
+/// Stand-in for an upstream fetch attempt, after any injected delay; None sends the real request
+///
+/// The fake answer is a `503`, so it takes the same retry and fallback paths as a failing CDN.
+#[cfg(feature = "chaos")]
+pub async fn upstream_fault(url: &str) -> Option<reqwest::Response> {
+    let rates = RATES.get()?;
+    if roll(rates.slow) {
+        crate::metrics::record_fault_injected("slow_upstream");
+        tokio::time::sleep(rates.delay).await;
+    }
+    if !roll(rates.upstream_failure) {
+        return None;
+    }
+    tracing::debug!("injected upstream failure for {}", url);
+    crate::metrics::record_fault_injected("upstream_failure");
+    let resp = http::Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .body(Vec::new())
+        .expect("static response");
+    Some(resp.into())
+}
+
+#[cfg(not(feature = "chaos"))]
+pub async fn upstream_fault(_url: &str) -> Option<reqwest::Response> {
+    None
+}
+
+/// Err when this cache write should fail
+#[cfg(feature = "chaos")]
+pub fn cache_write_fault() -> io::Result<()> {
+    match RATES.get() {
+        Some(rates) if roll(rates.cache_write_failure) => {
+            crate::metrics::record_fault_injected("cache_write");
+            Err(io::Error::other("injected cache write failure"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "chaos"))]
+pub fn cache_write_fault() -> io::Result<()> {
+    Ok(())
+}
+
+/// True for roughly `rate` of calls
+#[cfg(feature = "chaos")]
+fn roll(rate: f64) -> bool {
+    use std::hash::BuildHasher;
+
+    let draw = std::collections::hash_map::RandomState::new().hash_one(std::time::Instant::now());
+    rate >= 1.0 || (draw as f64 / u64::MAX as f64) < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_active() {
+        assert!(!FaultRates::default().is_active());
+        let slow_without_delay = FaultRates {
+            slow: 1.0,
+            ..Default::default()
+        };
+        assert!(!slow_without_delay.is_active());
+        let failing = FaultRates {
+            cache_write_failure: 0.1,
+            ..Default::default()
+        };
+        assert!(failing.is_active());
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn test_roll_bounds() {
+        assert!(roll(1.0));
+        assert!(!roll(0.0));
+    }
+}
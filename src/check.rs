@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::{blossom::SEED_RELAYS, config::AppCfg, redis_cache::RedisCache};
+
+/// Time allowed for each external check (tool spawn, relay, Redis)
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of one `--check-config` step
+enum Status {
+    Ok,
+    /// Degraded but serviceable, e.g. some relays unreachable
+    Warn,
+    Fail,
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn line(&mut self, status: Status, what: &str, detail: impl AsRef<str>) {
+        let label = match status {
+            Status::Ok => "ok  ",
+            Status::Warn => "warn",
+            Status::Fail => {
+                self.failed = true;
+                "FAIL"
+            }
+        };
+        println!("[{}] {}: {}", label, what, detail.as_ref());
+    }
+}
+
+/// Check a deployment without serving: configuration, ffmpeg, relays, Redis and the cache dir
+///
+/// Prints one line per check and returns the process exit code (0 when nothing failed),
+/// so deployment manifests can be verified in CI.
+pub async fn run() -> i32 {
+    let cfg = match AppCfg::load() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            print!("{}", e);
+            println!("[FAIL] config: {} problem(s), see above", e.0.len());
+            return 1;
+        }
+    };
+    let mut report = Report { failed: false };
+
+    // load() already created and probed the cache directories
+    report.line(Status::Ok, "config", "all values valid");
+    report.line(Status::Ok, "cache dir", format!("{} is writable", cfg.cache_dir.display()));
+    report.line(Status::Ok, "bind address", &cfg.bind_addr);
+
+    if cfg.video_support {
+        for tool in ["ffmpeg", "ffprobe"] {
+            match tool_version(tool).await {
+                Ok(version) => report.line(Status::Ok, tool, version),
+                Err(e) => report.line(Status::Fail, tool, e),
+            }
+        }
+    } else {
+        report.line(Status::Ok, "ffmpeg", "not required (VIDEO_SUPPORT=off)");
+    }
+
+    check_relays(&mut report).await;
+
+    if let Some(ref url) = cfg.redis_url {
+        let connect = RedisCache::connect(url, cfg.redis_max_item_bytes);
+        match tokio::time::timeout(CHECK_TIMEOUT, connect).await {
+            Ok(Ok(_)) => report.line(Status::Ok, "redis", "connected"),
+            Ok(Err(e)) => report.line(Status::Fail, "redis", e.to_string()),
+            Err(_) => report.line(Status::Fail, "redis", "connection timed out"),
+        }
+    }
+
+    println!();
+    println!("fallback servers: {}", cfg.blossom_fallback_servers.join(", "));
+    println!(
+        "signed URLs: {}, cache encryption: {}, admin API: {}",
+        on_off(cfg.url_signing.is_some()),
+        on_off(cfg.cache_encryption.is_some()),
+        on_off(cfg.admin_token.is_some()),
+    );
+    for tenant in &cfg.tenants {
+        println!(
+            "tenant {}: hosts [{}], prefix {}, signed URLs: {}",
+            tenant.name,
+            tenant.hosts.join(", "),
+            tenant.path_prefix.as_deref().unwrap_or("-"),
+            on_off(tenant.cfg.url_signing.is_some()),
+        );
+    }
+
+    if report.failed {
+        println!("configuration check failed");
+        1
+    } else {
+        println!("configuration check passed");
+        0
+    }
+}
+
+/// First line of `<tool> -version`, e.g. "ffmpeg version 6.1.1"
+async fn tool_version(tool: &str) -> Result<String, String> {
+    let output = Command::new(tool).arg("-version").kill_on_drop(true).output();
+    let output = match tokio::time::timeout(CHECK_TIMEOUT, output).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("not runnable: {}", e)),
+        Err(_) => return Err("timed out".to_string()),
+    };
+    if !output.status.success() {
+        return Err(format!("exited with {}", output.status));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first = stdout.lines().next().unwrap_or_default();
+    // Drop the "Copyright ..." tail
+    Ok(first.split(" Copyright").next().unwrap_or(first).trim().to_string())
+}
+
+/// Reach each seed relay over HTTPS (NIP-11 relay info)
+///
+/// Unreachable relays are warnings; only losing all of them breaks server list lookups.
+async fn check_relays(report: &mut Report) {
+    let client = match reqwest::Client::builder().timeout(CHECK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            report.line(Status::Fail, "relays", e.to_string());
+            return;
+        }
+    };
+
+    // Probe all relays concurrently, report in list order
+    let probes: Vec<_> = SEED_RELAYS
+        .iter()
+        .map(|relay| {
+            let client = client.clone();
+            let url = relay.replacen("wss://", "https://", 1);
+            tokio::spawn(async move {
+                client
+                    .get(&url)
+                    .header(reqwest::header::ACCEPT, "application/nostr+json")
+                    .send()
+                    .await
+                    .map(|resp| resp.status())
+            })
+        })
+        .collect();
+
+    let mut reachable = 0;
+    for (relay, probe) in SEED_RELAYS.iter().zip(probes) {
+        match probe.await {
+            Ok(Ok(status)) => {
+                reachable += 1;
+                report.line(Status::Ok, "relay", format!("{} ({})", relay, status));
+            }
+            Ok(Err(e)) => report.line(Status::Warn, "relay", format!("{}: {}", relay, e)),
+            Err(e) => report.line(Status::Warn, "relay", format!("{}: {}", relay, e)),
+        }
+    }
+
+    if reachable == 0 {
+        report.line(Status::Fail, "relays", "no seed relay reachable");
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
@@ -1,18 +1,246 @@
-use std::{path::PathBuf, time::Duration};
-use reqwest::Client;
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    fmt, fs,
+    hash::BuildHasher,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use reqwest::{Client, StatusCode};
+
+use crate::{
+    blob_availability::BlobAvailability,
+    blossom::BlossomState,
+    cache::{glob_match, MemoryCache},
+    cache_crypto::CacheCipher,
+    chaos::{self, FaultRates},
+    memory, metrics,
+    preview::PREVIEW_WIDTHS,
+    profile::{AVATAR_SIZES, BANNER_WIDTHS},
+    rate_limit::IpNet,
+    redis_cache::RedisCache,
+    server_stats::ServerStats,
+    signature::SigningKey,
+    thumbnail::DEFAULT_VIDEO_SEEK,
+    transform::{DirectiveDefaults, OutFmt, ResizeMode, MAX_VIDEO_SEEK_SECS},
+    upstream_auth::{self, UpstreamAuth},
+    upstream_status::UpstreamStatusMap,
+    variants::VariantIndex,
+    video_range::MIN_PARTIAL_FETCH_BYTES,
+    work_pool::WorkPool,
+};
 
 #[derive(Clone)]
 pub struct AppCfg {
     pub bind_addr: String,
     pub cache_dir: PathBuf,
-    pub cache_ttl: Duration,
+    /// TTL for downloaded originals (expensive to re-download)
+    pub original_cache_ttl: Duration,
+    /// TTL for processed variants (cheap to regenerate from cached originals)
+    pub processed_cache_ttl: Duration,
     pub fetch_timeout: Duration,
+    /// Deadline for a whole processing pipeline: fetch, decode, transform, encode (zero = none)
+    pub request_timeout: Duration,
     pub max_image_bytes: usize,
+    /// Largest source resolution decoded, in megapixels (0 = unlimited)
+    pub max_src_resolution: f64,
+    /// Host globs sources must match (empty = any host), lowercased
+    pub allowed_source_hosts: Vec<String>,
+    /// Host globs sources must not match; checked before the allowlist
+    pub denied_source_hosts: Vec<String>,
+    /// Maximum source video size in bytes (0 disables the check)
+    pub max_video_bytes: u64,
+    /// Maximum source video duration in seconds (0 disables the check)
+    pub max_video_duration_secs: u64,
+    /// Bytes of an MP4 fetched by range request for frame thumbnails, plus its `moov` (0 = ffmpeg reads the URL)
+    pub video_partial_fetch_bytes: u64,
     pub blossom_fallback_servers: Vec<String>,
+    /// Try fallback servers fastest-first by measured latency instead of in configured order
+    pub dynamic_fallback_order: bool,
+    /// How long "server has/lacks blob" answers are remembered (zero disables)
+    pub blob_availability_ttl: Duration,
+    /// `HEAD` each Blossom server before downloading a blob, skipping those that lack it (BUD-01)
+    pub blossom_head_check: bool,
+    /// How long discovered Blossom server lists are cached
+    pub blossom_server_list_cache_ttl_hours: u64,
+    /// Authors (npub or hex) whose server lists never expire and are refreshed proactively
+    pub pinned_pubkeys: Vec<String>,
+    /// How often pinned authors' server lists are re-fetched
+    pub pinned_refresh_interval: Duration,
+    /// Edge length of `/avatar` images without `size=`
+    pub avatar_size: u32,
+    /// Width of `/banner` images without `size=` (height is a third of it)
+    pub banner_width: u32,
+    /// How long `/avatar` and `/banner` results and resolved profiles are reused
+    pub profile_ttl: Duration,
+    /// Whether video sources are thumbnailed with ffmpeg (VIDEO_SUPPORT=off disables)
+    pub video_support: bool,
+    /// Cap for the shorter side of extracted video frames (0 = source resolution)
+    pub video_thumb_max_side: u32,
+    /// Second video frames are taken at when a request has no `t:`
+    pub video_thumb_second: f32,
+    /// Default width of /preview animations
+    pub preview_width: u32,
+    /// Length of /preview animations, in seconds
+    pub preview_secs: f32,
+    /// Largest /preview animation kept; larger ones are refused
+    pub preview_max_bytes: usize,
+    pub max_ffmpeg_concurrent: usize,
+    /// ffmpeg wait queue bound; beyond it requests get 503 with Retry-After (0 = unbounded)
+    pub max_ffmpeg_queue: usize,
+    /// Image pipelines decoding and encoding at once
+    pub max_image_concurrent: usize,
+    /// Image pipeline wait queue bound; beyond it requests get 503 with Retry-After (0 = unbounded)
+    pub max_image_queue: usize,
+    /// ffprobe gets its own smaller pool so metadata queries aren't starved by extractions
+    pub max_ffprobe_concurrent: usize,
+    pub ffprobe_timeout: Duration,
+    /// ffmpeg runs still going after this are killed, so a stalled source can't hold a permit forever
+    pub ffmpeg_timeout: Duration,
+    /// Retry-After sent with 503s when a queue is full
+    pub retry_after_secs: u64,
+    /// Serve originals unchanged when they are valid images the decoder can't handle
+    pub passthrough_undecodable: bool,
+    /// Byte budget for the disk cache, enforced by the janitor (0 = unlimited)
+    pub cache_max_bytes: u64,
+    /// Globs (relative to the cache dir) the janitor never evicts
+    pub janitor_exclude_paths: Vec<String>,
+    /// Source URL / blob prefixes whose cache entries are pinned when written
+    pub janitor_exclude_key_prefixes: Vec<String>,
+    /// Byte budget for the in-memory processed tier (0 = disabled)
+    pub memory_cache_max_bytes: u64,
+    /// RSS above which new processing work is shed with 503 (0 = no limit)
+    pub memory_soft_limit_bytes: u64,
+    /// Serve a cached variant up to this many percent off the requested size (0 = exact sizes only)
+    pub variant_size_tolerance_percent: f32,
+    /// Serve a cached variant up to this many quality points off (0 = exact quality only)
+    pub variant_quality_tolerance: u8,
+    /// Redis URL for the shared cache backend (None = disk only)
+    pub redis_url: Option<String>,
+    /// Entries up to this size are stored in Redis, larger ones on disk
+    pub redis_max_item_bytes: usize,
+    /// Encryption of cache entries at rest (None = stored in plaintext)
+    pub cache_encryption: Option<CacheCipher>,
+    /// zstd level for cached originals (None = store uncompressed)
+    pub original_compression_level: Option<i32>,
+    /// Bearer token for admin-only features (None = admin features disabled)
+    pub admin_token: Option<String>,
+    /// Bearer token for `POST /process` uploads (None = endpoint disabled)
+    pub upload_token: Option<String>,
+    /// Keys one of which processing endpoints require (empty = open to everyone)
+    pub api_keys: Vec<String>,
+    /// Largest request body accepted by `POST /process`
+    pub max_upload_bytes: usize,
+    /// Sustained requests per second allowed per client IP (0 = no rate limit)
+    pub rate_limit_rps: f64,
+    /// Requests a client may make at once before `rate_limit_rps` applies
+    pub rate_limit_burst: u32,
+    /// Proxies whose `X-Forwarded-For` is believed when finding the client IP
+    pub trusted_proxies: Vec<IpNet>,
+    /// Key for signed URLs (None = signatures are not checked)
+    pub url_signing: Option<SigningKey>,
+    /// Interval between scheduled cache reports (zero disables reporting)
+    pub cache_report_interval: Duration,
+    /// Number of sources listed in each top-N section of the cache report
+    pub cache_report_top_n: usize,
+    /// Sources tracked by the processing-error journal (0 disables it)
+    pub error_journal_max_sources: usize,
+    /// Oldest shutdown snapshot restored at startup (zero disables snapshots)
+    pub state_snapshot_max_age: Duration,
+    /// Speak HTTP/2 to upstreams without ALPN negotiation (all upstreams must support h2)
+    pub fetch_http2_prior_knowledge: bool,
+    /// How long idle upstream connections are kept for reuse
+    pub fetch_pool_idle_timeout: Duration,
+    /// Max idle upstream connections kept per host
+    pub fetch_pool_max_idle_per_host: usize,
+    /// Retries of upstream GETs that failed to connect or answered 5xx (0 = no retries)
+    pub fetch_retries: u32,
+    /// Wait before the first retry; doubles with each further one
+    pub fetch_retry_base: Duration,
+    /// Statuses answered in place of upstream error statuses (empty = passed through)
+    pub upstream_status_map: UpstreamStatusMap,
+    /// Per-host signing of upstream requests to private mirrors (empty = nothing signed)
+    pub upstream_auth: UpstreamAuth,
+    /// Base URL of a secondary instance sampled requests are mirrored to (None = no mirroring)
+    pub shadow_url: Option<String>,
+    /// Fraction of processing requests mirrored to `shadow_url` (0-1)
+    pub shadow_sample_rate: f64,
+    /// How long a mirrored request may take before it counts as an error
+    pub shadow_timeout: Duration,
+    /// Faults injected for resilience testing (acted on only by `chaos` builds)
+    pub chaos: FaultRates,
+    /// Operator overrides for directives a request leaves out
+    pub directive_defaults: DirectiveDefaults,
+    /// Concurrent pipelines allowed per source image/video (0 = unlimited)
+    pub max_concurrent_per_source: usize,
+    /// Requests waiting on one source before returning 503 (0 = unbounded)
+    pub max_queue_per_source: usize,
+    /// Animations with more frames are reduced to their first frame under `frames:all`
+    pub max_animation_frames: usize,
+    /// SVG sources with more elements (after `<use>` expansion) are refused; 0 disables SVG input
+    pub svg_max_elements: usize,
+    /// Font files for `txt:` overlays, in fallback order (empty disables `txt:`)
+    pub text_fonts: Vec<String>,
+    /// How often `TEXT_FONTS` files are checked for changes to reload (zero = never)
+    pub asset_watch_interval: Duration,
+    /// Largest width or height a request may ask for (0 = unlimited)
+    pub max_dimension: u32,
+    /// Mixed into cache keys so tenants never share entries (empty for the default tenant)
+    pub cache_namespace: String,
+    /// Tenant this configuration was derived for (None = the global configuration)
+    pub tenant: Option<String>,
+    /// Named tenants with their own policies, selected by Host header or path prefix
+    pub tenants: Vec<Arc<TenantCfg>>,
+}
+
+/// A named tenant: how its requests are recognized and the configuration they run with
+pub struct TenantCfg {
+    pub name: String,
+    /// Host header values (lowercase, without port) that select this tenant
+    pub hosts: Vec<String>,
+    /// Path prefix that selects this tenant and is stripped before routing (e.g. "/app")
+    pub path_prefix: Option<String>,
+    /// The global configuration with this tenant's overrides applied
+    pub cfg: Arc<AppCfg>,
 }
 
 impl AppCfg {
-    pub fn from_env() -> Self {
+    /// Whether `DENIED_SOURCE_HOSTS` or `ALLOWED_SOURCE_HOSTS` is set
+    pub fn restricts_source_hosts(&self) -> bool {
+        !self.allowed_source_hosts.is_empty() || !self.denied_source_hosts.is_empty()
+    }
+
+    /// Whether a source URL's host passes `DENIED_SOURCE_HOSTS` and `ALLOWED_SOURCE_HOSTS`
+    pub fn source_host_allowed(&self, url: &str) -> bool {
+        if !self.restricts_source_hosts() {
+            return true;
+        }
+        let url = reqwest::Url::parse(url).ok();
+        host_allowed(&self.allowed_source_hosts, &self.denied_source_hosts, url.as_ref().and_then(|u| u.host_str()))
+    }
+
+    /// `tenant` label for metrics recorded under this configuration
+    pub fn metrics_label(&self) -> &str {
+        self.tenant.as_deref().unwrap_or(metrics::DEFAULT_TENANT)
+    }
+
+    /// Read the configuration and check it as a whole, reporting every problem at once
+    pub fn load() -> Result<Self, ConfigError> {
+        let cfg = Self::from_env()?;
+        let problems = cfg.validate();
+        if problems.is_empty() {
+            Ok(cfg)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    /// Parse the environment; malformed values are collected rather than silently defaulted
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut env = EnvReader::default();
+
         // Default Blossom CDN fallback servers
         let default_fallbacks = vec![
             "https://cdn.satellite.earth".to_string(),
@@ -20,51 +248,772 @@ impl AppCfg {
             "https://nostr.download".to_string(),
             "https://cdn.hzrd149.com".to_string(),
         ];
+        let blossom_fallback_servers =
+            fallback_servers_from_env("BLOSSOM_FALLBACK_SERVERS").unwrap_or(default_fallbacks);
 
-        let blossom_fallback_servers = std::env::var("BLOSSOM_FALLBACK_SERVERS")
-            .ok()
-            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect())
-            .unwrap_or(default_fallbacks);
+        // CACHE_TTL_SECS is the default for both tiers; each can be overridden
+        let default_cache_ttl_secs = env.parse("CACHE_TTL_SECS", 86400);
 
-        Self {
-            bind_addr: std::env::var("BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".into()),
-            cache_dir: PathBuf::from(std::env::var("CACHE_DIR").unwrap_or_else(|_| "cache".into())),
-            cache_ttl: Duration::from_secs(
-                std::env::var("CACHE_TTL_SECS")
-                    .ok()
-                    .and_then(|v| v.parse().ok())
-                    .unwrap_or(86400),
-            ),
-            fetch_timeout: Duration::from_secs(
-                std::env::var("FETCH_TIMEOUT_SECS")
-                    .ok()
-                    .and_then(|v| v.parse().ok())
-                    .unwrap_or(10),
-            ),
-            max_image_bytes: std::env::var("MAX_IMAGE_BYTES")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(16 * 1024 * 1024),
+        // Signed URLs are enforced as soon as a key/salt pair is configured
+        let url_signing = url_signing_from_env(&mut env, "");
+
+        let cache_encryption = env.string("CACHE_ENCRYPTION_KEY").and_then(|key| {
+            env.check(
+                CacheCipher::from_hex(&key)
+                    .map_err(|e| format!("invalid cache encryption config: {}", e)),
+            )
+        });
+
+        let original_compression_level = env
+            .flag("CACHE_COMPRESS_ORIGINALS", false)
+            .then(|| env.parse("CACHE_ZSTD_LEVEL", 3));
+
+        let directive_defaults = directive_defaults_from_env(&mut env);
+
+        let cfg = Self {
+            bind_addr: env.string("BIND_ADDR").unwrap_or_else(|| "127.0.0.1:8080".into()),
+            cache_dir: PathBuf::from(env.string("CACHE_DIR").unwrap_or_else(|| "cache".into())),
+            original_cache_ttl: env.secs("ORIGINAL_CACHE_TTL_SECS", default_cache_ttl_secs),
+            processed_cache_ttl: env.secs("PROCESSED_CACHE_TTL_SECS", default_cache_ttl_secs),
+            fetch_timeout: env.secs("FETCH_TIMEOUT_SECS", 10),
+            request_timeout: env.secs("REQUEST_TIMEOUT_SECS", 60),
+            max_image_bytes: env.parse("MAX_IMAGE_BYTES", 16 * 1024 * 1024),
+            max_src_resolution: env.parse("MAX_SRC_RESOLUTION", 50.0),
+            allowed_source_hosts: host_patterns(env.list("ALLOWED_SOURCE_HOSTS")),
+            denied_source_hosts: host_patterns(env.list("DENIED_SOURCE_HOSTS")),
+            max_video_bytes: env.parse("MAX_VIDEO_BYTES", 2 * 1024 * 1024 * 1024),
+            max_video_duration_secs: env.parse("MAX_VIDEO_DURATION_SECS", 2 * 3600),
+            video_partial_fetch_bytes: env.parse("VIDEO_PARTIAL_FETCH_BYTES", 0),
             blossom_fallback_servers,
+            dynamic_fallback_order: env.flag("DYNAMIC_FALLBACK_ORDER", true),
+            blob_availability_ttl: env.secs("BLOB_AVAILABILITY_TTL_SECS", 60),
+            blossom_head_check: env.flag("BLOSSOM_HEAD_CHECK", false),
+            blossom_server_list_cache_ttl_hours: env.parse("BLOSSOM_SERVER_LIST_CACHE_TTL_HOURS", 24),
+            pinned_pubkeys: env.list("PINNED_PUBKEYS"),
+            pinned_refresh_interval: env.secs("PINNED_REFRESH_INTERVAL_SECS", 3600),
+            avatar_size: env.parse("AVATAR_SIZE", 128),
+            banner_width: env.parse("BANNER_WIDTH", 960),
+            profile_ttl: env.secs("PROFILE_TTL_SECS", 3600),
+            video_support: env.flag("VIDEO_SUPPORT", true),
+            video_thumb_max_side: env.parse("VIDEO_THUMB_MAX_SIDE", 720),
+            video_thumb_second: env.parse("VIDEO_THUMB_SECOND", DEFAULT_VIDEO_SEEK),
+            preview_width: env.parse("PREVIEW_WIDTH", 320),
+            preview_secs: env.parse("PREVIEW_SECS", 3.0),
+            preview_max_bytes: env.parse("PREVIEW_MAX_BYTES", 4 * 1024 * 1024),
+            max_ffmpeg_concurrent: env.parse("MAX_FFMPEG_CONCURRENT", 8),
+            max_ffmpeg_queue: env.parse("MAX_FFMPEG_QUEUE", 32),
+            max_image_concurrent: env.parse("MAX_IMAGE_CONCURRENT", default_image_concurrency()),
+            max_image_queue: env.parse("MAX_IMAGE_QUEUE", 64),
+            max_ffprobe_concurrent: env.parse("MAX_FFPROBE_CONCURRENT", 4),
+            ffprobe_timeout: env.secs("FFPROBE_TIMEOUT_SECS", 10),
+            ffmpeg_timeout: env.secs("FFMPEG_TIMEOUT_SECS", 120),
+            retry_after_secs: env.parse("RETRY_AFTER_SECS", 5),
+            passthrough_undecodable: env.flag("PASSTHROUGH_UNDECODABLE", false),
+            cache_max_bytes: env.parse("CACHE_MAX_BYTES", 0),
+            janitor_exclude_paths: env.list("JANITOR_EXCLUDE_PATHS"),
+            janitor_exclude_key_prefixes: env.list("JANITOR_EXCLUDE_KEY_PREFIXES"),
+            memory_cache_max_bytes: env.parse("MEMORY_CACHE_MAX_BYTES", 0),
+            memory_soft_limit_bytes: env.parse("MEMORY_SOFT_LIMIT_BYTES", 0),
+            variant_size_tolerance_percent: env.parse("VARIANT_SIZE_TOLERANCE_PERCENT", 0.0),
+            variant_quality_tolerance: env.parse("VARIANT_QUALITY_TOLERANCE", 0),
+            redis_url: env.string("REDIS_URL"),
+            redis_max_item_bytes: env.parse("REDIS_MAX_ITEM_BYTES", 256 * 1024),
+            cache_encryption,
+            original_compression_level,
+            admin_token: env.string("ADMIN_TOKEN"),
+            upload_token: env.string("UPLOAD_TOKEN"),
+            api_keys: env.list("API_KEYS"),
+            max_upload_bytes: env.parse("MAX_UPLOAD_BYTES", 64 * 1024 * 1024),
+            rate_limit_rps: env.parse("RATE_LIMIT_RPS", 0.0),
+            rate_limit_burst: env.parse("RATE_LIMIT_BURST", 20),
+            trusted_proxies: env
+                .list("TRUSTED_PROXIES")
+                .iter()
+                .filter_map(|p| env.check(p.parse().map_err(|e| format!("TRUSTED_PROXIES: {}", e))))
+                .collect(),
+            url_signing,
+            cache_report_interval: env.secs("CACHE_REPORT_INTERVAL_SECS", 3600),
+            cache_report_top_n: env.parse("CACHE_REPORT_TOP_N", 10),
+            error_journal_max_sources: env.parse("ERROR_JOURNAL_MAX_SOURCES", 10_000),
+            state_snapshot_max_age: env.secs("STATE_SNAPSHOT_MAX_AGE_SECS", 3600),
+            fetch_http2_prior_knowledge: env.flag("FETCH_HTTP2_PRIOR_KNOWLEDGE", false),
+            fetch_pool_idle_timeout: env.secs("FETCH_POOL_IDLE_TIMEOUT_SECS", 90),
+            fetch_pool_max_idle_per_host: env.parse("FETCH_POOL_MAX_IDLE_PER_HOST", 32),
+            fetch_retries: env.parse("FETCH_RETRIES", 2),
+            fetch_retry_base: Duration::from_millis(env.parse("FETCH_RETRY_BASE_MS", 100)),
+            upstream_status_map: env
+                .string("UPSTREAM_STATUS_MAP")
+                .and_then(|map| env.check(map.parse().map_err(|e| format!("UPSTREAM_STATUS_MAP: {}", e))))
+                .unwrap_or_default(),
+            upstream_auth: env
+                .string("UPSTREAM_AUTH")
+                .and_then(|auth| env.check(auth.parse().map_err(|e| format!("UPSTREAM_AUTH: {}", e))))
+                .unwrap_or_default(),
+            shadow_url: env.string("SHADOW_URL"),
+            shadow_sample_rate: env.parse("SHADOW_SAMPLE_RATE", 0.01),
+            shadow_timeout: env.secs("SHADOW_TIMEOUT_SECS", 30),
+            chaos: FaultRates {
+                upstream_failure: env.parse("CHAOS_UPSTREAM_FAILURE_RATE", 0.0),
+                slow: env.parse("CHAOS_SLOW_RATE", 0.0),
+                delay: Duration::from_millis(env.parse("CHAOS_DELAY_MS", 2000)),
+                cache_write_failure: env.parse("CHAOS_CACHE_WRITE_FAILURE_RATE", 0.0),
+            },
+            directive_defaults,
+            max_concurrent_per_source: env.parse("MAX_CONCURRENT_PER_SOURCE", 0),
+            max_queue_per_source: env.parse("MAX_QUEUE_PER_SOURCE", 0),
+            max_animation_frames: env.parse("MAX_ANIMATION_FRAMES", 300),
+            svg_max_elements: env.parse("SVG_MAX_ELEMENTS", 10_000),
+            text_fonts: env.list("TEXT_FONTS"),
+            asset_watch_interval: env.secs("ASSET_WATCH_INTERVAL_SECS", 10),
+            max_dimension: env.parse("MAX_DIMENSION", 0),
+            cache_namespace: String::new(),
+            tenant: None,
+            tenants: Vec::new(),
+        };
+        let cfg = Self {
+            tenants: tenants_from_env(&mut env, &cfg),
+            ..cfg
+        };
+
+        if env.errors.is_empty() {
+            Ok(cfg)
+        } else {
+            Err(ConfigError(env.errors))
+        }
+    }
+
+    /// Check ranges, cross-option conflicts and the environment (addresses, URLs, cache dir)
+    ///
+    /// Creates the cache directories as a side effect, since that is the writability check.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Err(e) = self.bind_addr.to_socket_addrs() {
+            problems.push(format!("BIND_ADDR={}: {}", self.bind_addr, e));
+        }
+
+        check_server_urls("BLOSSOM_FALLBACK_SERVERS", &self.blossom_fallback_servers, &mut problems);
+        if let Some(ref url) = self.shadow_url {
+            check_server_urls("SHADOW_URL", std::slice::from_ref(url), &mut problems);
+            if !(0.0..=1.0).contains(&self.shadow_sample_rate) {
+                problems.push(format!("SHADOW_SAMPLE_RATE must be between 0 and 1, got {}", self.shadow_sample_rate));
+            }
+            if self.shadow_timeout.is_zero() {
+                problems.push("SHADOW_TIMEOUT_SECS must be greater than 0 when SHADOW_URL is set".into());
+            }
+        }
+        self.validate_tenants(&mut problems);
+        self.validate_chaos(&mut problems);
+
+        for pubkey in &self.pinned_pubkeys {
+            if let Err(e) = BlossomState::parse_pubkey(pubkey) {
+                problems.push(format!("PINNED_PUBKEYS: {}", e));
+            }
+        }
+        for (name, patterns) in [
+            ("ALLOWED_SOURCE_HOSTS", &self.allowed_source_hosts),
+            ("DENIED_SOURCE_HOSTS", &self.denied_source_hosts),
+        ] {
+            for pattern in patterns.iter().filter(|p| p.contains('/')) {
+                problems.push(format!("{}: {} must be a host pattern, not a URL", name, pattern));
+            }
+        }
+        if !self.pinned_pubkeys.is_empty() && self.pinned_refresh_interval.is_zero() {
+            problems.push("PINNED_REFRESH_INTERVAL_SECS must be greater than 0 when PINNED_PUBKEYS is set".into());
+        }
+        for (name, value, range) in [
+            ("AVATAR_SIZE", self.avatar_size, AVATAR_SIZES),
+            ("BANNER_WIDTH", self.banner_width, BANNER_WIDTHS),
+            ("PREVIEW_WIDTH", self.preview_width, PREVIEW_WIDTHS),
+        ] {
+            if !range.contains(&value) {
+                problems.push(format!(
+                    "{} must be between {} and {}, got {}",
+                    name,
+                    range.start(),
+                    range.end(),
+                    value
+                ));
+            }
+        }
+
+        if !(0.0..=MAX_VIDEO_SEEK_SECS).contains(&self.video_thumb_second) {
+            problems.push(format!("VIDEO_THUMB_SECOND must be 0-86400, got {}", self.video_thumb_second));
+        }
+        if !(0.0..=25.0).contains(&self.variant_size_tolerance_percent) {
+            problems.push(format!(
+                "VARIANT_SIZE_TOLERANCE_PERCENT must be between 0 and 25, got {}",
+                self.variant_size_tolerance_percent
+            ));
+        }
+        if self.variant_quality_tolerance > 20 {
+            problems.push(format!(
+                "VARIANT_QUALITY_TOLERANCE must be between 0 and 20, got {}",
+                self.variant_quality_tolerance
+            ));
+        }
+        if !self.rate_limit_rps.is_finite() || self.rate_limit_rps < 0.0 {
+            problems.push(format!("RATE_LIMIT_RPS must be 0 or more, got {}", self.rate_limit_rps));
+        }
+        if self.rate_limit_rps > 0.0 && self.rate_limit_burst == 0 {
+            problems.push("RATE_LIMIT_BURST must be greater than 0 when RATE_LIMIT_RPS is set".into());
+        }
+
+        if let Some(ref url) = self.redis_url {
+            if let Err(e) = redis::Client::open(url.as_str()) {
+                problems.push(format!("REDIS_URL: {}", e));
+            }
+            if self.redis_max_item_bytes == 0 {
+                problems.push("REDIS_MAX_ITEM_BYTES must be greater than 0 when REDIS_URL is set".into());
+            }
+        }
+
+        for dir in ["original", "processed", "preview", "storyboard", "probe"] {
+            if let Err(e) = check_writable_dir(&self.cache_dir.join(dir)) {
+                problems.push(format!(
+                    "CACHE_DIR={}: {} is not writable: {}",
+                    self.cache_dir.display(),
+                    dir,
+                    e
+                ));
+            }
+        }
+
+        let must_be_positive = [
+            ("FETCH_TIMEOUT_SECS", self.fetch_timeout.as_secs()),
+            ("MAX_IMAGE_BYTES", self.max_image_bytes as u64),
+            ("ORIGINAL_CACHE_TTL_SECS", self.original_cache_ttl.as_secs()),
+            ("PROCESSED_CACHE_TTL_SECS", self.processed_cache_ttl.as_secs()),
+            ("PROFILE_TTL_SECS", self.profile_ttl.as_secs()),
+            ("PREVIEW_MAX_BYTES", self.preview_max_bytes as u64),
+            ("MAX_IMAGE_CONCURRENT", self.max_image_concurrent as u64),
+        ];
+        for (name, value) in must_be_positive {
+            if value == 0 {
+                problems.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        if self.video_support {
+            for (name, value) in [
+                ("MAX_FFMPEG_CONCURRENT", self.max_ffmpeg_concurrent as u64),
+                ("MAX_FFPROBE_CONCURRENT", self.max_ffprobe_concurrent as u64),
+                ("FFPROBE_TIMEOUT_SECS", self.ffprobe_timeout.as_secs()),
+                ("FFMPEG_TIMEOUT_SECS", self.ffmpeg_timeout.as_secs()),
+            ] {
+                if value == 0 {
+                    problems.push(format!("{} must be greater than 0 when VIDEO_SUPPORT is on", name));
+                }
+            }
+            if self.video_partial_fetch_bytes > 0 && self.video_partial_fetch_bytes < MIN_PARTIAL_FETCH_BYTES {
+                problems.push(format!(
+                    "VIDEO_PARTIAL_FETCH_BYTES must be 0 or at least {}, got {}",
+                    MIN_PARTIAL_FETCH_BYTES, self.video_partial_fetch_bytes
+                ));
+            }
+            if !(1.0..=10.0).contains(&self.preview_secs) {
+                problems.push(format!("PREVIEW_SECS must be between 1 and 10, got {}", self.preview_secs));
+            }
+        }
+
+        if let Some(level) = self.original_compression_level {
+            let range = zstd::compression_level_range();
+            if !range.contains(&level) {
+                problems.push(format!(
+                    "CACHE_ZSTD_LEVEL={} is outside {}..={}",
+                    level,
+                    range.start(),
+                    range.end()
+                ));
+            }
+        }
+
+        if !self.cache_report_interval.is_zero() && self.cache_report_top_n == 0 {
+            problems.push("CACHE_REPORT_TOP_N must be greater than 0 while cache reports are enabled".into());
+        }
+
+        if self.max_queue_per_source > 0 && self.max_concurrent_per_source == 0 {
+            problems.push(
+                "MAX_QUEUE_PER_SOURCE has no effect without MAX_CONCURRENT_PER_SOURCE".into(),
+            );
+        }
+
+        if self.memory_soft_limit_bytes > 0 && memory::read_rss_bytes().is_none() {
+            problems.push("MEMORY_SOFT_LIMIT_BYTES needs /proc/self/status to measure RSS".into());
+        }
+
+        if self.max_src_resolution.is_nan() || self.max_src_resolution < 0.0 {
+            problems.push(format!(
+                "MAX_SRC_RESOLUTION={} must be a megapixel count of 0 or more",
+                self.max_src_resolution
+            ));
+        }
+
+        if self.upload_token.is_some() && self.max_upload_bytes == 0 {
+            problems.push("MAX_UPLOAD_BYTES must be greater than 0 when UPLOAD_TOKEN is set".into());
+        }
+
+        if self.cache_max_bytes > 0 && self.cache_max_bytes < self.max_image_bytes as u64 {
+            problems.push(format!(
+                "CACHE_MAX_BYTES={} is smaller than MAX_IMAGE_BYTES={}, so large originals can never be cached",
+                self.cache_max_bytes, self.max_image_bytes
+            ));
+        }
+
+        for path in &self.text_fonts {
+            if let Err(e) = crate::text::read_font(std::path::Path::new(path)) {
+                problems.push(format!("TEXT_FONTS: {}: {}", path, e));
+            }
+        }
+
+        problems
+    }
+
+    /// Tenants need a selector, and no two tenants may claim the same host or prefix
+    fn validate_tenants(&self, problems: &mut Vec<String>) {
+        let mut names = HashSet::new();
+        let mut hosts = HashSet::new();
+        let mut prefixes = HashSet::new();
+        for tenant in &self.tenants {
+            let var = tenant_var_prefix(&tenant.name);
+            if !names.insert(tenant.name.to_ascii_lowercase()) {
+                problems.push(format!("TENANTS: {} is listed twice", tenant.name));
+            }
+            if tenant.name.eq_ignore_ascii_case(metrics::DEFAULT_TENANT) {
+                problems.push(format!("TENANTS: {} is reserved for requests matching no tenant", tenant.name));
+            }
+            if tenant.hosts.is_empty() && tenant.path_prefix.is_none() {
+                problems.push(format!("{}HOSTS or {}PATH_PREFIX must be set", var, var));
+            }
+            for host in &tenant.hosts {
+                if !hosts.insert(host.as_str()) {
+                    problems.push(format!("{}HOSTS: {} is already used by another tenant", var, host));
+                }
+            }
+            if let Some(ref prefix) = tenant.path_prefix {
+                let first_segment = prefix.split('/').nth(1).unwrap_or_default();
+                if !prefix.starts_with('/') || first_segment.is_empty() {
+                    problems.push(format!("{}PATH_PREFIX={} must look like /name", var, prefix));
+                } else if RESERVED_SEGMENTS.contains(&first_segment) {
+                    problems.push(format!("{}PATH_PREFIX={} collides with a built-in route", var, prefix));
+                }
+                if !prefixes.insert(prefix.as_str()) {
+                    problems.push(format!("{}PATH_PREFIX: {} is already used by another tenant", var, prefix));
+                }
+            }
+            check_server_urls(
+                &format!("{}BLOSSOM_FALLBACK_SERVERS", var),
+                &tenant.cfg.blossom_fallback_servers,
+                problems,
+            );
+        }
+    }
+
+    /// Rates must be shares, and a build without the `chaos` feature must not silently ignore them
+    fn validate_chaos(&self, problems: &mut Vec<String>) {
+        for (name, rate) in [
+            ("CHAOS_UPSTREAM_FAILURE_RATE", self.chaos.upstream_failure),
+            ("CHAOS_SLOW_RATE", self.chaos.slow),
+            ("CHAOS_CACHE_WRITE_FAILURE_RATE", self.chaos.cache_write_failure),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                problems.push(format!("{} must be between 0 and 1, got {}", name, rate));
+            }
+        }
+        if self.chaos.is_active() && !cfg!(feature = "chaos") {
+            problems.push("CHAOS_* fault injection needs a build with the `chaos` feature".into());
+        }
+    }
+}
+
+/// Host patterns compare against lowercased hosts
+fn host_patterns(patterns: Vec<String>) -> Vec<String> {
+    patterns.into_iter().map(|p| p.to_ascii_lowercase()).collect()
+}
+
+/// Host check behind `source_host_allowed`; a denied match wins, and a missing host fails
+fn host_allowed(allowed: &[String], denied: &[String], host: Option<&str>) -> bool {
+    let Some(host) = host.map(str::to_ascii_lowercase) else {
+        return false;
+    };
+    let matches = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, &host));
+    !matches(denied) && (allowed.is_empty() || matches(allowed))
+}
+
+/// Redirects followed per fetch, as with reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+/// First path segments of built-in routes, which tenant prefixes must not shadow
+const RESERVED_SEGMENTS: &[&str] = &[
+    "insecure", "thumb", "preview", "storyboard", "probe", "avatar", "banner", "card", "qr", "identicon", "health",
+    "version", "metrics", "admin",
+];
+
+/// Every entry must be an http(s) URL
+fn check_server_urls(name: &str, servers: &[String], problems: &mut Vec<String>) {
+    for server in servers {
+        match reqwest::Url::parse(server) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            Ok(_) => problems.push(format!("{}: {} is not an http(s) URL", name, server)),
+            Err(e) => problems.push(format!("{}: {}: {}", name, server, e)),
+        }
+    }
+}
+
+/// Create `dir` if needed and prove a file can be written to it
+fn check_writable_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".write-check");
+    fs::write(&probe, b"ok")?;
+    fs::remove_file(&probe)
+}
+
+/// Every problem found in the configuration, reported together at startup
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.0.len();
+        writeln!(f, "invalid configuration ({} problem{}):", n, if n == 1 { "" } else { "s" })?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
         }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// `base * 2^attempt`, its upper half randomized so clients that failed together don't retry together
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let half = base.saturating_mul(1 << attempt.min(16)) / 2;
+    let jitter = RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64;
+    half + half.mul_f64(jitter)
+}
+
+/// One image pipeline per CPU: decoding and encoding are CPU-bound
+fn default_image_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// Environment lookups that record malformed values instead of falling back silently
+#[derive(Default)]
+struct EnvReader {
+    errors: Vec<String>,
+}
+
+impl EnvReader {
+    /// Variable value; unset and empty are treated the same
+    fn string(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok().filter(|v| !v.trim().is_empty())
     }
+
+    /// Comma-separated values, empty entries dropped
+    fn list(&self, name: &str) -> Vec<String> {
+        self.string(name)
+            .map(|v| {
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, default: T) -> T {
+        let Some(v) = self.string(name) else {
+            return default;
+        };
+        match v.trim().parse() {
+            Ok(value) => value,
+            Err(_) => {
+                self.errors.push(format!("{}={} is not a valid number", name, v));
+                default
+            }
+        }
+    }
+
+    fn secs(&mut self, name: &str, default: u64) -> Duration {
+        Duration::from_secs(self.parse(name, default))
+    }
+
+    fn flag(&mut self, name: &str, default: bool) -> bool {
+        let Some(v) = self.string(name) else {
+            return default;
+        };
+        match v.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "on" | "yes" => true,
+            "0" | "false" | "off" | "no" => false,
+            _ => {
+                self.errors.push(format!("{}={} must be on/off, true/false or 1/0", name, v));
+                default
+            }
+        }
+    }
+
+    /// Keep the value, or record the error
+    fn check<T>(&mut self, result: Result<T, String>) -> Option<T> {
+        result.map_err(|e| self.errors.push(e)).ok()
+    }
+}
+
+/// Fallback server list from `name`; set but empty disables fallbacks, unset returns None
+fn fallback_servers_from_env(name: &str) -> Option<Vec<String>> {
+    std::env::var(name).ok().map(|s| {
+        s.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
+
+/// `<prefix>IMGPROXY_KEY`/`_SALT`/`_SIGNATURE_SIZE`, None when neither key nor salt is set
+fn url_signing_from_env(env: &mut EnvReader, prefix: &str) -> Option<SigningKey> {
+    let key = env.string(&format!("{}IMGPROXY_KEY", prefix));
+    let salt = env.string(&format!("{}IMGPROXY_SALT", prefix));
+    if key.is_none() && salt.is_none() {
+        return None;
+    }
+    let signature_size = env.parse(&format!("{}IMGPROXY_SIGNATURE_SIZE", prefix), 32);
+    let key = SigningKey::from_hex(&key.unwrap_or_default(), &salt.unwrap_or_default(), signature_size);
+    env.check(key.map_err(|e| format!("invalid URL signing config ({}IMGPROXY_KEY/_SALT): {}", prefix, e)))
+}
+
+/// Variable prefix for a tenant's settings, e.g. `TENANT_MY_APP_` for "my-app"
+fn tenant_var_prefix(name: &str) -> String {
+    format!("TENANT_{}_", name.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// Read the tenants listed in `TENANTS`, each starting from the global configuration
+///
+/// Only signing keys, fallback servers, `MAX_DIMENSION` and the cache namespace can be
+/// overridden; everything else (cache dir, limits on upstream fetches) stays shared.
+fn tenants_from_env(env: &mut EnvReader, global: &AppCfg) -> Vec<Arc<TenantCfg>> {
+    let mut tenants = Vec::new();
+    for name in env.list("TENANTS") {
+        let var = tenant_var_prefix(&name);
+        let mut cfg = global.clone();
+
+        if let Some(key) = url_signing_from_env(env, &var) {
+            cfg.url_signing = Some(key);
+        }
+        if let Some(servers) = fallback_servers_from_env(&format!("{}BLOSSOM_FALLBACK_SERVERS", var)) {
+            cfg.blossom_fallback_servers = servers;
+        }
+        cfg.max_dimension = env.parse(&format!("{}MAX_DIMENSION", var), global.max_dimension);
+        let api_keys = env.list(&format!("{}API_KEYS", var));
+        if !api_keys.is_empty() {
+            cfg.api_keys = api_keys;
+        }
+        cfg.cache_namespace = env
+            .string(&format!("{}CACHE_NAMESPACE", var))
+            .unwrap_or_else(|| name.clone());
+        cfg.tenant = Some(name.clone());
+
+        let hosts = env
+            .list(&format!("{}HOSTS", var))
+            .into_iter()
+            .map(|h| h.to_ascii_lowercase())
+            .collect();
+        let path_prefix = env
+            .string(&format!("{}PATH_PREFIX", var))
+            .map(|p| p.trim().trim_end_matches('/').to_string());
+
+        tenants.push(Arc::new(TenantCfg {
+            name,
+            hosts,
+            path_prefix,
+            cfg: Arc::new(cfg),
+        }));
+    }
+    tenants
+}
+
+/// Read `DEFAULT_*` / `THUMB_DEFAULT_*` overrides on top of the built-in directive defaults
+fn directive_defaults_from_env(env: &mut EnvReader) -> DirectiveDefaults {
+    let mut defaults = DirectiveDefaults::default();
+
+    if let Some(v) = env.string("DEFAULT_FORMAT") {
+        let format = OutFmt::from_name(&v).map_err(|e| format!("DEFAULT_FORMAT={}: {}", v, e));
+        if let Some(format) = env.check(format) {
+            defaults.format = format;
+        }
+    }
+    if let Some(v) = env.string("THUMB_DEFAULT_FORMAT") {
+        let format = OutFmt::from_name(&v).map_err(|e| format!("THUMB_DEFAULT_FORMAT={}: {}", v, e));
+        if let Some(format) = env.check(format) {
+            defaults.thumb_format = format;
+        }
+    }
+    defaults.quality = env.parse("DEFAULT_QUALITY", defaults.quality);
+    if defaults.quality > 100 {
+        env.errors.push(format!("DEFAULT_QUALITY={} must be 0-100", defaults.quality));
+    }
+    if let Some(v) = env.string("DEFAULT_RESIZE_MODE") {
+        let mode = ResizeMode::from_name(&v).map_err(|e| format!("DEFAULT_RESIZE_MODE={}: {}", v, e));
+        if let Some(mode) = env.check(mode) {
+            defaults.resize_mode = mode;
+        }
+    }
+    defaults.keep_metadata = env.flag("KEEP_METADATA", defaults.keep_metadata);
+    defaults.embed_srgb_profile = env.flag("EMBED_SRGB_PROFILE", defaults.embed_srgb_profile);
+    defaults.deterministic = env.flag("DETERMINISTIC_OUTPUT", defaults.deterministic);
+    defaults.progressive = env.flag("JPEG_PROGRESSIVE", defaults.progressive);
+    if let Some(v) = env.string("JPEG_ALPHA_FORMAT") {
+        let format = match v.to_ascii_lowercase().as_str() {
+            "off" => Ok(None),
+            "webp" => Ok(Some(OutFmt::Webp)),
+            "png" => Ok(Some(OutFmt::Png)),
+            _ => Err(format!("JPEG_ALPHA_FORMAT={}: expected off, webp or png", v)),
+        };
+        if let Some(format) = env.check(format) {
+            defaults.jpeg_alpha_format = format;
+        }
+    }
+    defaults.thumb_width = env.parse("THUMB_DEFAULT_WIDTH", defaults.thumb_width);
+    defaults.thumb_height = env.parse("THUMB_DEFAULT_HEIGHT", defaults.thumb_height);
+    if defaults.thumb_width == 0 && defaults.thumb_height == 0 {
+        env.errors
+            .push("THUMB_DEFAULT_WIDTH and THUMB_DEFAULT_HEIGHT cannot both be 0".to_string());
+    }
+    defaults
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub cfg: AppCfg,
+    /// Global configuration; handlers swap in the selected tenant's
+    pub cfg: Arc<AppCfg>,
     pub http: Client,
+    /// Hot processed images, checked before the disk cache
+    pub memory_cache: Option<MemoryCache>,
+    /// Shared Redis tier for small entries; connected at startup when `REDIS_URL` is set
+    pub redis: Option<RedisCache>,
+    /// Upstream response times, for ordering fallback servers
+    pub server_stats: Arc<ServerStats>,
+    /// Recent per-server blob lookups, so variants don't re-probe every server
+    pub blob_availability: Option<BlobAvailability>,
+    /// Cached variants by request family, for `VARIANT_*_TOLERANCE` reuse
+    pub variants: Option<VariantIndex>,
+    /// Image decoding and encoding, with 503 once `MAX_IMAGE_QUEUE` requests wait
+    pub image_pool: Arc<WorkPool>,
 }
 
 impl AppState {
     pub fn new(cfg: AppCfg) -> Self {
-        let http = Client::builder()
+        // Keep connections to Blossom CDNs warm so bursts of small fetches skip TLS handshakes
+        let mut builder = Client::builder()
             .timeout(cfg.fetch_timeout)
             .user_agent("rust-imgproxy/0.1")
-            .build()
-            .expect("reqwest client");
+            .pool_idle_timeout(cfg.fetch_pool_idle_timeout)
+            .pool_max_idle_per_host(cfg.fetch_pool_max_idle_per_host)
+            .http2_adaptive_window(true)
+            .connector_layer(metrics::ConnectionCountLayer);
+        if cfg.fetch_http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        // Redirects must not lead sources to hosts the source host lists exclude
+        if cfg.restricts_source_hosts() {
+            let (allowed, denied) = (cfg.allowed_source_hosts.clone(), cfg.denied_source_hosts.clone());
+            builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if !host_allowed(&allowed, &denied, attempt.url().host_str()) {
+                    attempt.error("redirect to an excluded source host")
+                } else {
+                    attempt.follow()
+                }
+            }));
+        }
+        let http = builder.build().expect("reqwest client");
+
+        let memory_cache = (cfg.memory_cache_max_bytes > 0)
+            .then(|| MemoryCache::new(cfg.memory_cache_max_bytes, cfg.processed_cache_ttl));
+        let blob_availability = (!cfg.blob_availability_ttl.is_zero())
+            .then(|| BlobAvailability::new(cfg.blob_availability_ttl));
+        let variants = (cfg.variant_size_tolerance_percent > 0.0 || cfg.variant_quality_tolerance > 0).then(|| {
+            let size_tolerance = cfg.variant_size_tolerance_percent / 100.0;
+            VariantIndex::new(size_tolerance, cfg.variant_quality_tolerance, cfg.processed_cache_ttl)
+        });
+        let image_pool = Arc::new(WorkPool::new(
+            "image",
+            cfg.max_image_concurrent,
+            cfg.max_image_queue,
+            cfg.retry_after_secs,
+            metrics::update_image_semaphore_metrics,
+        ));
+
+        Self {
+            cfg: Arc::new(cfg),
+            http,
+            memory_cache,
+            redis: None,
+            server_stats: Arc::new(ServerStats::default()),
+            blob_availability,
+            variants,
+            image_pool,
+        }
+    }
 
-        Self { cfg, http }
+    /// Servers worth trying for a blob: recent holders first, recent 404s and excluded hosts skipped
+    pub fn blob_candidates(&self, servers: &[String], hash: &str) -> Vec<String> {
+        let mut candidates = match self.blob_availability {
+            Some(ref availability) => availability.candidates(servers, hash),
+            None => servers.to_vec(),
+        };
+        candidates.retain(|server| self.cfg.source_host_allowed(server));
+        candidates
+    }
+
+    /// Remember a server's answer for a blob (success = has it, 404 = lacks it)
+    pub fn record_blob_status(&self, url: &str, hash: &str, status: StatusCode) {
+        let Some(ref availability) = self.blob_availability else {
+            return;
+        };
+        if status.is_success() {
+            availability.record(url, hash, true);
+        } else if status == StatusCode::NOT_FOUND {
+            availability.record(url, hash, false);
+        }
+    }
+
+    /// GET `url`, retrying connect errors and 5xx answers up to `FETCH_RETRIES` times
+    ///
+    /// Failed attempts count against the server's stats; the last attempt is returned as is,
+    /// so callers see the final 5xx or error.
+    pub async fn get_with_retry(&self, url: &str) -> reqwest::Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = match chaos::upstream_fault(url).await {
+                Some(fault) => Ok(fault),
+                None => upstream_auth::sign(self.http.get(url), url).send().await,
+            };
+            let transient = match result {
+                Ok(ref resp) => resp.status().is_server_error(),
+                Err(ref e) => e.is_connect(),
+            };
+            if !transient || attempt >= self.cfg.fetch_retries {
+                return result;
+            }
+            match result {
+                Ok(ref resp) => self.server_stats.record_response(url, resp.status(), started.elapsed()),
+                Err(_) => self.server_stats.record_error(url),
+            }
+            metrics::record_processing_error("fetch_retry");
+            tracing::debug!(attempt = attempt + 1, "retrying {}", url);
+            tokio::time::sleep(retry_delay(self.cfg.fetch_retry_base, attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Configured Blossom fallback servers, fastest healthy first unless dynamic ordering is off
+    pub fn fallback_servers(&self) -> Vec<String> {
+        if self.cfg.dynamic_fallback_order {
+            self.server_stats.order(&self.cfg.blossom_fallback_servers)
+        } else {
+            self.cfg.blossom_fallback_servers.clone()
+        }
     }
 }
 
@@ -0,0 +1,151 @@
+use std::{cell::RefCell, collections::BTreeMap, future::Future, time::Instant};
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::SvcError;
+
+tokio::task_local! {
+    /// Trace for the current request; only set when `?debug=1` was requested
+    static TRACE: RefCell<RequestTrace>;
+}
+
+/// Query parameter enabling the debug trace
+#[derive(Debug, Default, Deserialize)]
+pub struct DebugQuery {
+    pub debug: Option<String>,
+}
+
+impl DebugQuery {
+    pub fn enabled(&self) -> bool {
+        matches!(self.debug.as_deref(), Some("1") | Some("true"))
+    }
+}
+
+/// A single step in the processing decision path
+#[derive(Debug, Serialize)]
+struct TraceEvent {
+    at_ms: f64,
+    stage: &'static str,
+    detail: String,
+}
+
+/// An upstream fetch attempt (primary, fallback or Blossom server)
+#[derive(Debug, Serialize)]
+struct ServerAttempt {
+    at_ms: f64,
+    url: String,
+    outcome: String,
+    elapsed_ms: f64,
+}
+
+/// Collected trace for one request
+#[derive(Debug, Serialize)]
+struct RequestTrace {
+    #[serde(skip)]
+    started: Instant,
+    events: Vec<TraceEvent>,
+    servers: Vec<ServerAttempt>,
+}
+
+impl Default for RequestTrace {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            events: Vec::new(),
+            servers: Vec::new(),
+        }
+    }
+}
+
+impl RequestTrace {
+    fn elapsed_ms(&self) -> f64 {
+        self.started.elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// JSON document returned instead of the image in debug mode
+#[derive(Debug, Serialize)]
+struct DebugReport {
+    status: u16,
+    error: Option<String>,
+    total_ms: f64,
+    headers: BTreeMap<String, String>,
+    #[serde(flatten)]
+    trace: RequestTrace,
+}
+
+/// Whether the current task is running a traced request
+pub fn is_active() -> bool {
+    TRACE.try_with(|_| ()).is_ok()
+}
+
+/// Record a decision-path event; the detail closure only runs when tracing is active
+pub fn event(stage: &'static str, detail: impl FnOnce() -> String) {
+    let _ = TRACE.try_with(|t| {
+        let mut t = t.borrow_mut();
+        let at_ms = t.elapsed_ms();
+        t.events.push(TraceEvent {
+            at_ms,
+            stage,
+            detail: detail(),
+        });
+    });
+}
+
+/// Record the outcome of an upstream request
+pub fn server_attempt(url: &str, outcome: impl FnOnce() -> String, started: Instant) {
+    let _ = TRACE.try_with(|t| {
+        let mut t = t.borrow_mut();
+        let at_ms = t.elapsed_ms();
+        t.servers.push(ServerAttempt {
+            at_ms,
+            url: url.to_string(),
+            outcome: outcome(),
+            elapsed_ms: started.elapsed().as_secs_f64() * 1000.0,
+        });
+    });
+}
+
+/// Run a request pipeline with tracing enabled and return the JSON trace instead of the image
+pub async fn run_traced<F>(pipeline: F) -> Response
+where
+    F: Future<Output = Result<Response, SvcError>>,
+{
+    let (result, trace) = TRACE
+        .scope(RefCell::new(RequestTrace::default()), async {
+            let result = pipeline.await;
+            let trace = TRACE.with(|t| t.take());
+            (result, trace)
+        })
+        .await;
+
+    let total_ms = trace.elapsed_ms();
+    let (status, error, headers) = match result {
+        Ok(resp) => {
+            let headers = resp
+                .headers()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+                .collect();
+            (resp.status().as_u16(), None, headers)
+        }
+        Err(e) => {
+            let message = e.to_string();
+            let status = e.into_response().status().as_u16();
+            (status, Some(message), BTreeMap::new())
+        }
+    };
+
+    Json(DebugReport {
+        status,
+        error,
+        total_ms,
+        headers,
+        trace,
+    })
+    .into_response()
+}
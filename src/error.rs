@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderName, StatusCode},
     response::{IntoResponse, Response},
 };
 use thiserror::Error;
@@ -8,8 +8,20 @@ use thiserror::Error;
 pub enum SvcError {
     #[error("bad request: {0}")]
     BadRequest(&'static str),
+    #[error("forbidden: {0}")]
+    Forbidden(&'static str),
+    #[error("not found: {0}")]
+    NotFound(&'static str),
+    #[error("unsupported media type: {0}")]
+    UnsupportedMedia(&'static str),
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(&'static str),
+    #[error("unprocessable: {0}")]
+    Unprocessable(&'static str),
     #[error("upstream returned status {0}")]
     UpstreamError(u16),
+    #[error("source content does not match the expected sha256")]
+    HashMismatch,
     #[error("fetch failed")]
     Fetch(#[from] reqwest::Error),
     #[error("decode failed")]
@@ -18,26 +30,101 @@ pub enum SvcError {
     Io(#[from] std::io::Error),
     #[error("internal error: {0}")]
     InternalError(String),
+    #[error("server overloaded ({queue_depth} queued)")]
+    Overloaded { retry_after_secs: u64, queue_depth: usize },
+    #[error("video: {0}")]
+    Video(#[from] VideoError),
+    #[error("request timed out")]
+    Timeout,
+    #[error("rate limited")]
+    RateLimited { retry_after_secs: u64 },
+}
+
+/// Video thumbnail failures, split so clients can tell permanent ones (4xx) from retryable ones
+#[derive(Debug, Error)]
+pub enum VideoError {
+    /// ffmpeg ran but produced no frame: corrupt or truncated container, or an unknown failure
+    #[error("thumbnail extraction failed: {0}")]
+    ExtractionFailed(String),
+    #[error("unsupported codec: {0}")]
+    UnsupportedCodec(String),
+    /// Over `MAX_VIDEO_BYTES` or `MAX_VIDEO_DURATION_SECS`
+    #[error("{0}")]
+    TooLarge(&'static str),
+    /// ffmpeg gave up connecting to or reading from the source
+    #[error("source timed out")]
+    Timeout,
+    /// ffmpeg was still running at `FFMPEG_TIMEOUT_SECS` and was killed
+    #[error("ffmpeg killed after {0}s")]
+    Killed(u64),
+}
+
+impl VideoError {
+    /// Metric and journal label
+    pub fn label(&self) -> &'static str {
+        match self {
+            VideoError::ExtractionFailed(_) => "extraction_failed",
+            VideoError::UnsupportedCodec(_) => "unsupported_codec",
+            VideoError::TooLarge(_) => "too_large",
+            VideoError::Timeout => "timeout",
+            VideoError::Killed(_) => "killed",
+        }
+    }
 }
 
 impl IntoResponse for SvcError {
     fn into_response(self) -> Response {
+        if let SvcError::Overloaded { retry_after_secs, queue_depth } = self {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [
+                    (header::RETRY_AFTER, retry_after_secs.to_string()),
+                    (HeaderName::from_static("x-queue-depth"), queue_depth.to_string()),
+                ],
+                "Server busy, retry later".to_string(),
+            )
+                .into_response();
+        }
+        if let SvcError::UpstreamError(code) = self {
+            return crate::upstream_status::upstream_response(code);
+        }
+        if let SvcError::RateLimited { retry_after_secs } = self {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after_secs.to_string())],
+                "Too many requests, retry later".to_string(),
+            )
+                .into_response();
+        }
+
         let (status, message) = match self {
             SvcError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
-            SvcError::UpstreamError(code) => {
-                // Map upstream status codes to appropriate responses
-                let status_code = StatusCode::from_u16(code).unwrap_or(StatusCode::BAD_GATEWAY);
-                let message = match code {
-                    404 => "Source image not found".to_string(),
-                    403 => "Source image forbidden".to_string(),
-                    _ => format!("Upstream server returned status {}", code),
-                };
-                (status_code, message)
-            }
+            SvcError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg.to_string()),
+            SvcError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.to_string()),
+            SvcError::UnsupportedMedia(msg) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg.to_string()),
+            SvcError::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg.to_string()),
+            SvcError::Unprocessable(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg.to_string()),
+            SvcError::HashMismatch => (
+                StatusCode::BAD_GATEWAY,
+                "Source content does not match the expected sha256".to_string(),
+            ),
             SvcError::Fetch(_) => (StatusCode::BAD_GATEWAY, "Failed to fetch source image".to_string()),
             SvcError::Decode(_) => (StatusCode::UNPROCESSABLE_ENTITY, "Failed to decode image".to_string()),
             SvcError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string()),
             SvcError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            SvcError::Video(e) => match e {
+                VideoError::ExtractionFailed(_) => {
+                    (StatusCode::UNPROCESSABLE_ENTITY, "Failed to extract video thumbnail".to_string())
+                }
+                VideoError::UnsupportedCodec(_) => (StatusCode::UNPROCESSABLE_ENTITY, "Unsupported video codec".to_string()),
+                VideoError::TooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg.to_string()),
+                VideoError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "Source video timed out".to_string()),
+                VideoError::Killed(_) => (StatusCode::GATEWAY_TIMEOUT, "Video processing timed out".to_string()),
+            },
+            SvcError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "Processing timed out".to_string()),
+            SvcError::Overloaded { .. } | SvcError::RateLimited { .. } | SvcError::UpstreamError(_) => {
+                unreachable!() // Handled above
+            }
         };
         (status, message).into_response()
     }
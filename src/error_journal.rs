@@ -0,0 +1,257 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::{config::AppCfg, error::SvcError};
+
+/// How often a changed journal is written to disk
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Journal file, relative to `CACHE_DIR`
+const JOURNAL_FILE: &str = "error-journal.json";
+
+lazy_static! {
+    static ref JOURNAL: Mutex<Journal> = Mutex::new(Journal::default());
+}
+
+#[derive(Default)]
+struct Journal {
+    sources: HashMap<String, SourceFailures>,
+    /// Most sources tracked (0 = journal disabled)
+    max_sources: usize,
+    /// Changed since the last flush
+    dirty: bool,
+}
+
+/// Failure history of one source (URL for /insecure, `<sha256>.<ext>` for /thumb)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SourceFailures {
+    pub failures: u64,
+    /// Failures by error type (`upstream_404`, `fetch`, `decode`, ...)
+    pub errors: HashMap<String, u64>,
+    /// Unix timestamps (seconds)
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub last_error: String,
+}
+
+/// A source in the admin listing
+#[derive(Debug, Serialize)]
+pub struct JournalEntry {
+    pub source: String,
+    /// Source host, or `blossom` for /thumb blobs
+    pub host: String,
+    #[serde(flatten)]
+    pub failures: SourceFailures,
+}
+
+/// Filtered view of the journal for `/admin/errors`
+#[derive(Debug, Serialize)]
+pub struct JournalReport {
+    /// Sources with at least `min_failures` failures, most failures first
+    pub sources: Vec<JournalEntry>,
+    /// Failures of the matching sources, by host and by error type
+    pub by_host: HashMap<String, u64>,
+    pub by_error: HashMap<String, u64>,
+}
+
+/// Journal key for an error, or None for errors that say nothing about the source
+///
+/// Client mistakes, overload and local I/O problems aren't recorded.
+fn error_type(err: &SvcError) -> Option<String> {
+    let kind = match err {
+        SvcError::UpstreamError(code) => return Some(format!("upstream_{}", code)),
+        SvcError::Video(e) => return Some(format!("video_{}", e.label())),
+        SvcError::HashMismatch => "hash_mismatch",
+        SvcError::Fetch(_) => "fetch",
+        SvcError::Decode(_) => "decode",
+        SvcError::UnsupportedMedia(_) => "unsupported_media",
+        SvcError::PayloadTooLarge(_) | SvcError::Unprocessable(_) => "too_large",
+        SvcError::InternalError(_) => "internal",
+        SvcError::Timeout => "timeout",
+        SvcError::BadRequest(_)
+        | SvcError::Forbidden(_)
+        | SvcError::NotFound(_)
+        | SvcError::Io(_)
+        | SvcError::Overloaded { .. }
+        | SvcError::RateLimited { .. } => return None,
+    };
+    Some(kind.to_string())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+fn source_host(source: &str) -> String {
+    reqwest::Url::parse(source)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_else(|| "blossom".to_string())
+}
+
+fn journal_path(cfg: &AppCfg) -> PathBuf {
+    cfg.cache_dir.join(JOURNAL_FILE)
+}
+
+/// Size the journal and load the entries persisted by a previous run
+pub fn init(cfg: &AppCfg) {
+    let mut journal = JOURNAL.lock().unwrap();
+    journal.max_sources = cfg.error_journal_max_sources;
+    if journal.max_sources == 0 {
+        return;
+    }
+    let path = journal_path(cfg);
+    match std::fs::read(&path) {
+        Ok(bytes) => match serde_json::from_slice::<HashMap<String, SourceFailures>>(&bytes) {
+            Ok(sources) => {
+                info!(sources = sources.len(), "loaded processing-error journal");
+                journal.sources = sources;
+            }
+            Err(e) => warn!(path = %path.display(), "ignoring unreadable error journal: {}", e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(path = %path.display(), "failed to read error journal: {}", e),
+    }
+}
+
+/// Record a failed pipeline run for a source
+pub fn record_failure(source: &str, err: &SvcError) {
+    let Some(kind) = error_type(err) else {
+        return;
+    };
+    let mut journal = JOURNAL.lock().unwrap();
+    if journal.max_sources == 0 {
+        return;
+    }
+    if !journal.sources.contains_key(source) && journal.sources.len() >= journal.max_sources {
+        // Full: forget the source that failed least recently
+        let oldest = journal
+            .sources
+            .iter()
+            .min_by_key(|(_, f)| f.last_seen)
+            .map(|(source, _)| source.clone());
+        if let Some(oldest) = oldest {
+            journal.sources.remove(&oldest);
+        }
+    }
+
+    let now = unix_now();
+    let entry = journal.sources.entry(source.to_string()).or_insert_with(|| SourceFailures {
+        first_seen: now,
+        ..Default::default()
+    });
+    entry.failures += 1;
+    *entry.errors.entry(kind).or_default() += 1;
+    entry.last_seen = now;
+    entry.last_error = err.to_string();
+    journal.dirty = true;
+}
+
+/// Sources with at least `min_failures` failures, optionally from one host
+pub fn report(min_failures: u64, host: Option<&str>, limit: usize) -> JournalReport {
+    let journal = JOURNAL.lock().unwrap();
+    let mut sources: Vec<JournalEntry> = journal
+        .sources
+        .iter()
+        .filter(|(_, f)| f.failures >= min_failures)
+        .map(|(source, f)| JournalEntry {
+            source: source.clone(),
+            host: source_host(source),
+            failures: f.clone(),
+        })
+        .filter(|e| host.is_none_or(|host| e.host.eq_ignore_ascii_case(host)))
+        .collect();
+    drop(journal);
+
+    let mut by_host: HashMap<String, u64> = HashMap::new();
+    let mut by_error: HashMap<String, u64> = HashMap::new();
+    for entry in &sources {
+        *by_host.entry(entry.host.clone()).or_default() += entry.failures.failures;
+        for (kind, count) in &entry.failures.errors {
+            *by_error.entry(kind.clone()).or_default() += count;
+        }
+    }
+    sources.sort_by_key(|s| Reverse(s.failures.failures));
+    sources.truncate(limit);
+    JournalReport { sources, by_host, by_error }
+}
+
+/// Forget one source, or every source when None; returns how many were removed
+pub fn clear(source: Option<&str>) -> usize {
+    let mut journal = JOURNAL.lock().unwrap();
+    let removed = match source {
+        Some(source) => journal.sources.remove(source).map_or(0, |_| 1),
+        None => std::mem::take(&mut journal.sources).len(),
+    };
+    if removed > 0 {
+        journal.dirty = true;
+    }
+    removed
+}
+
+/// Background loop that persists the journal under `CACHE_DIR` when it changed
+pub async fn flush_loop(cfg: AppCfg) {
+    let path = journal_path(&cfg);
+    loop {
+        sleep(FLUSH_INTERVAL).await;
+        let snapshot = {
+            let mut journal = JOURNAL.lock().unwrap();
+            if !journal.dirty {
+                continue;
+            }
+            journal.dirty = false;
+            serde_json::to_vec(&journal.sources)
+        };
+        let result = match snapshot {
+            Ok(bytes) => write_atomic(&path, &bytes).await,
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        if let Err(e) = result {
+            warn!(path = %path.display(), "failed to persist error journal: {}", e);
+            JOURNAL.lock().unwrap().dirty = true;
+        }
+    }
+}
+
+async fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, bytes).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_type() {
+        assert_eq!(error_type(&SvcError::UpstreamError(404)).as_deref(), Some("upstream_404"));
+        assert_eq!(error_type(&SvcError::UnsupportedMedia("x")).as_deref(), Some("unsupported_media"));
+        assert_eq!(error_type(&SvcError::BadRequest("x")), None);
+        assert_eq!(
+            error_type(&SvcError::Overloaded {
+                retry_after_secs: 1,
+                queue_depth: 0
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_source_host() {
+        assert_eq!(source_host("https://Example.com/a.png"), "example.com");
+        assert_eq!(source_host("abc123.jpg"), "blossom");
+    }
+}
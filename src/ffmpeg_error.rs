@@ -0,0 +1,144 @@
+use std::fmt;
+
+use crate::error::{SvcError, VideoError};
+
+/// stderr lines kept in an ffmpeg failure
+const MAX_DETAIL_LINES: usize = 4;
+/// Longest failure detail kept, in bytes
+const MAX_DETAIL_BYTES: usize = 512;
+
+/// Why an ffmpeg run failed, classified from its stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfmpegFailure {
+    /// Connecting to or reading from the source timed out
+    Timeout,
+    /// The source server answered with an HTTP error status
+    Upstream(u16),
+    /// DNS failures, refused or reset connections
+    Network,
+    /// No decoder for the video's codec
+    UnsupportedCodec,
+    /// Unreadable or truncated container
+    CorruptInput,
+    Other,
+}
+
+impl FfmpegFailure {
+    /// Classify ffmpeg's stderr; the first matching rule wins
+    pub fn classify(stderr: &str) -> Self {
+        let stderr = stderr.to_ascii_lowercase();
+        if let Some(code) = upstream_status(&stderr) {
+            return FfmpegFailure::Upstream(code);
+        }
+        let has = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+        if has(&["timed out", "timeout"]) {
+            FfmpegFailure::Timeout
+        } else if has(&[
+            "connection refused",
+            "could not resolve",
+            "failed to resolve",
+            "connection reset",
+            "network is unreachable",
+        ]) {
+            FfmpegFailure::Network
+        } else if has(&["decoder (codec", "unknown decoder", "no decoder", "codec not currently supported"]) {
+            FfmpegFailure::UnsupportedCodec
+        } else if has(&[
+            "invalid data found when processing input",
+            "moov atom not found",
+            "could not find codec parameters",
+            "end of file",
+        ]) {
+            FfmpegFailure::CorruptInput
+        } else {
+            FfmpegFailure::Other
+        }
+    }
+
+    /// Error returned to the client; upstream and network failures look like failed image fetches
+    pub fn into_error(self, detail: String) -> SvcError {
+        match self {
+            FfmpegFailure::Timeout => VideoError::Timeout.into(),
+            FfmpegFailure::Upstream(code) => SvcError::UpstreamError(code),
+            FfmpegFailure::Network => SvcError::UpstreamError(502),
+            FfmpegFailure::UnsupportedCodec => VideoError::UnsupportedCodec(detail).into(),
+            FfmpegFailure::CorruptInput | FfmpegFailure::Other => VideoError::ExtractionFailed(detail).into(),
+        }
+    }
+
+    /// Metric label
+    pub fn label(self) -> &'static str {
+        match self {
+            FfmpegFailure::Timeout => "timeout",
+            FfmpegFailure::Upstream(_) => "upstream",
+            FfmpegFailure::Network => "network",
+            FfmpegFailure::UnsupportedCodec => "unsupported_codec",
+            FfmpegFailure::CorruptInput => "corrupt_input",
+            FfmpegFailure::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for FfmpegFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FfmpegFailure::Upstream(code) => write!(f, "upstream {}", code),
+            other => f.write_str(other.label()),
+        }
+    }
+}
+
+/// `Server returned 404 Not Found`; `5XX` replies count as 502
+fn upstream_status(stderr: &str) -> Option<u16> {
+    let (_, rest) = stderr.split_once("server returned ")?;
+    let code = rest.get(..3)?;
+    match code {
+        "5xx" => Some(502),
+        "4xx" => Some(400),
+        _ => code.parse().ok().filter(|code| (400..600).contains(code)),
+    }
+}
+
+/// Last few non-empty stderr lines, bounded for logs and error messages
+pub fn stderr_detail(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let mut detail = lines[lines.len().saturating_sub(MAX_DETAIL_LINES)..].join(" | ");
+    if detail.len() > MAX_DETAIL_BYTES {
+        let mut end = MAX_DETAIL_BYTES;
+        while !detail.is_char_boundary(end) {
+            end -= 1;
+        }
+        detail.truncate(end);
+        detail.push('\u{2026}');
+    }
+    detail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            ("[https @ 0x1] HTTP error 404 Not Found\nServer returned 404 Not Found", FfmpegFailure::Upstream(404)),
+            ("Server returned 5XX Server Error reply", FfmpegFailure::Upstream(502)),
+            ("[tcp @ 0x1] Connection to tcp://host:443 failed: Connection timed out", FfmpegFailure::Timeout),
+            ("Failed to resolve hostname nope.invalid", FfmpegFailure::Network),
+            ("Decoder (codec av1) not found for input stream #0:0", FfmpegFailure::UnsupportedCodec),
+            ("[mov,mp4 @ 0x1] moov atom not found\nin.mp4: Invalid data found", FfmpegFailure::CorruptInput),
+            ("Output file is empty, nothing was encoded", FfmpegFailure::Other),
+        ];
+        for (stderr, expected) in cases {
+            assert_eq!(FfmpegFailure::classify(stderr), expected, "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn test_stderr_detail() {
+        let stderr = "a\n\nb\nc\nd\ne\n";
+        assert_eq!(stderr_detail(stderr), "b | c | d | e");
+        let long = "x".repeat(1000);
+        assert_eq!(stderr_detail(&long).len(), MAX_DETAIL_BYTES + '\u{2026}'.len_utf8());
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use reqwest::Url;
+use tempfile::TempDir;
+
+use crate::{
+    config::AppState,
+    error::{SvcError, VideoError},
+    metrics,
+    server::check_source_host,
+    upstream_auth,
+};
+
+/// Largest playlist read; real ones stay far below
+pub const MAX_PLAYLIST_BYTES: usize = 1024 * 1024;
+/// Segments, keys and init sections copied from one playlist at most
+const MAX_FILES: usize = 2000;
+/// Tags whose `URI` ffmpeg needs to play a media playlist; other tags naming URIs are dropped
+const COPIED_URI_TAGS: &[&str] = &["#EXT-X-KEY", "#EXT-X-MAP"];
+
+/// Local copy of an HLS stream: one media playlist and every file it names
+///
+/// The directory and its files go away on drop.
+pub struct HlsCopy {
+    _dir: TempDir,
+    playlist: PathBuf,
+}
+
+impl HlsCopy {
+    /// The rewritten media playlist, whose URIs are files next to it
+    pub fn playlist(&self) -> &Path {
+        &self.playlist
+    }
+}
+
+/// A media playlist with its URIs swapped for local file names
+#[derive(Debug, PartialEq)]
+struct Rewritten {
+    playlist: String,
+    /// What to download under each local name
+    files: Vec<(Url, String)>,
+}
+
+/// Copy the stream of `playlist`, fetched from `url` after redirects
+///
+/// A master playlist is followed to its highest-bandwidth variant. Every URI of both playlists
+/// must pass the source host lists, and everything is fetched with the app client, signed and
+/// within `MAX_VIDEO_BYTES`, so ffmpeg only reads local files.
+pub async fn copy(app: &AppState, url: &Url, playlist: &str) -> Result<HlsCopy, SvcError> {
+    let (url, media) = if is_master(playlist) {
+        let uris = master_uris(url, playlist)?;
+        for uri in &uris {
+            check_source_host(&app.cfg, uri.as_str())?;
+        }
+        let variant = best_variant(url, playlist)?.ok_or(SvcError::Unprocessable("playlist has no variants"))?;
+        fetch_playlist(app, &variant).await?
+    } else {
+        (url.clone(), playlist.to_string())
+    };
+    if is_master(&media) {
+        return Err(SvcError::Unprocessable("variant is a master playlist"));
+    }
+
+    let rewritten = rewrite(&url, &media)?;
+    if rewritten.files.len() > MAX_FILES {
+        return Err(VideoError::TooLarge("playlist has too many segments").into());
+    }
+    for (uri, _) in &rewritten.files {
+        check_source_host(&app.cfg, uri.as_str())?;
+    }
+
+    let dir = TempDir::new()?;
+    let mut downloaded = 0u64;
+    for (uri, name) in &rewritten.files {
+        let request = upstream_auth::sign(app.http.get(uri.clone()), uri.as_str());
+        let mut resp = request.timeout(app.cfg.ffmpeg_timeout).send().await?;
+        metrics::record_upstream_response(resp.version());
+        if !resp.status().is_success() {
+            tracing::debug!("copying {} of {}: status {}", uri, url, resp.status());
+            return Err(SvcError::UpstreamError(resp.status().as_u16()));
+        }
+        let mut file = std::fs::File::create(dir.path().join(name))?;
+        while let Some(chunk) = resp.chunk().await? {
+            downloaded += chunk.len() as u64;
+            if app.cfg.max_video_bytes > 0 && downloaded > app.cfg.max_video_bytes {
+                tracing::info!("refusing playlist {}: more than {} bytes", url, app.cfg.max_video_bytes);
+                metrics::record_processing_error("video_too_large");
+                return Err(VideoError::TooLarge("video too large").into());
+            }
+            file.write_all(&chunk)?;
+        }
+    }
+    metrics::record_bytes_downloaded(app.cfg.metrics_label(), "video_copy", downloaded as usize);
+
+    let playlist = dir.path().join("index.m3u8");
+    std::fs::write(&playlist, rewritten.playlist)?;
+    tracing::debug!("copied {} files, {} bytes of playlist {}", rewritten.files.len(), downloaded, url);
+    Ok(HlsCopy { _dir: dir, playlist })
+}
+
+/// Fetch a playlist, returning it with its URL after redirects
+async fn fetch_playlist(app: &AppState, url: &Url) -> Result<(Url, String), SvcError> {
+    let mut resp = upstream_auth::sign(app.http.get(url.clone()), url.as_str()).send().await?;
+    metrics::record_upstream_response(resp.version());
+    if !resp.status().is_success() {
+        return Err(SvcError::UpstreamError(resp.status().as_u16()));
+    }
+    let base = resp.url().clone();
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_PLAYLIST_BYTES {
+            return Err(SvcError::Unprocessable("playlist too large"));
+        }
+    }
+    let text = String::from_utf8(body).map_err(|_| SvcError::Unprocessable("playlist is not UTF-8"))?;
+    if !text.starts_with("#EXTM3U") {
+        return Err(SvcError::Unprocessable("variant is not a playlist"));
+    }
+    Ok((base, text))
+}
+
+/// Whether `playlist` lists variant streams rather than segments
+fn is_master(playlist: &str) -> bool {
+    playlist.lines().any(|line| line.starts_with("#EXT-X-STREAM-INF"))
+}
+
+/// Every URI a master playlist names: variants, renditions and I-frame playlists
+fn master_uris(base: &Url, playlist: &str) -> Result<Vec<Url>, SvcError> {
+    let mut uris = Vec::new();
+    for line in playlist.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(tag) = line.strip_prefix('#') {
+            if let Some(uri) = uri_attribute(tag) {
+                uris.push(resolve(base, uri)?);
+            }
+        } else {
+            uris.push(resolve(base, line)?);
+        }
+    }
+    Ok(uris)
+}
+
+/// The variant with the highest `BANDWIDTH`, which ffmpeg would pick itself
+fn best_variant(base: &Url, playlist: &str) -> Result<Option<Url>, SvcError> {
+    let mut best: Option<(u64, &str)> = None;
+    let mut lines = playlist.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") else {
+            continue;
+        };
+        let bandwidth = attribute(attributes, "BANDWIDTH").and_then(|b| b.parse().ok()).unwrap_or(0);
+        let Some(uri) = lines.by_ref().find(|line| !line.is_empty() && !line.starts_with('#')) else {
+            break;
+        };
+        if best.is_none_or(|(most, _)| bandwidth > most) {
+            best = Some((bandwidth, uri));
+        }
+    }
+    best.map(|(_, uri)| resolve(base, uri)).transpose()
+}
+
+/// Swap the URIs of a media playlist for local names of the files to download
+///
+/// Segments and the `URI`s of `COPIED_URI_TAGS` are kept, deduplicated so byte-range segments
+/// share one file; other tags naming URIs (partial segments, preload hints) are dropped. A
+/// closing `#EXT-X-ENDLIST` makes the copy of a live playlist end where it was taken.
+fn rewrite(base: &Url, playlist: &str) -> Result<Rewritten, SvcError> {
+    let mut names: HashMap<Url, String> = HashMap::new();
+    let mut files = Vec::new();
+    let mut local = |uri: &str| -> Result<String, SvcError> {
+        let uri = resolve(base, uri)?;
+        if let Some(name) = names.get(&uri) {
+            return Ok(name.clone());
+        }
+        let name = format!("{}.{}", files.len(), extension(&uri));
+        names.insert(uri.clone(), name.clone());
+        files.push((uri, name.clone()));
+        Ok(name)
+    };
+
+    let mut out = String::with_capacity(playlist.len());
+    let mut ended = false;
+    for line in playlist.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(tag) = line.strip_prefix('#') {
+            ended |= line == "#EXT-X-ENDLIST";
+            match uri_attribute(tag) {
+                None => out.push_str(line),
+                Some(uri) if COPIED_URI_TAGS.iter().any(|t| line.starts_with(&format!("{}:", t))) => {
+                    out.push_str(&line.replacen(&format!("URI=\"{}\"", uri), &format!("URI=\"{}\"", local(uri)?), 1));
+                }
+                Some(_) => continue,
+            }
+        } else {
+            out.push_str(&local(line)?);
+        }
+        out.push('\n');
+    }
+    if !ended {
+        out.push_str("#EXT-X-ENDLIST\n");
+    }
+    Ok(Rewritten { playlist: out, files })
+}
+
+/// The quoted `URI` attribute of a tag line (without its `#`)
+fn uri_attribute(tag: &str) -> Option<&str> {
+    attribute(tag.split_once(':')?.1, "URI")
+}
+
+/// Value of `name` in an attribute list (`A=1,URI="x.ts"`), unquoted
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, next) = quoted.split_once('"')?;
+                (value, next.strip_prefix(',').unwrap_or(next))
+            }
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = next;
+    }
+}
+
+/// `uri` against the playlist URL; only `http` and `https` may be copied
+fn resolve(base: &Url, uri: &str) -> Result<Url, SvcError> {
+    let url = base.join(uri).map_err(|_| SvcError::Unprocessable("playlist names an invalid URI"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(SvcError::Unprocessable("playlist names a non-HTTP URI"));
+    }
+    Ok(url)
+}
+
+/// File extension for the local copy of `uri`, which ffmpeg checks against the segment format
+fn extension(uri: &Url) -> &str {
+    let name = uri.path().rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() && ext.len() <= 8 && ext.chars().all(|c| c.is_ascii_alphanumeric()) => ext,
+        _ => "bin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example.com/live/index.m3u8").unwrap()
+    }
+
+    #[test]
+    fn test_attribute() {
+        let attrs = r#"BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2",URI="a,b.m3u8""#;
+        assert_eq!(attribute(attrs, "BANDWIDTH"), Some("1280000"));
+        assert_eq!(attribute(attrs, "CODECS"), Some("avc1.4d401f,mp4a.40.2"));
+        assert_eq!(attribute(attrs, "URI"), Some("a,b.m3u8"));
+        assert_eq!(attribute(attrs, "RESOLUTION"), None);
+    }
+
+    #[test]
+    fn test_best_variant_and_master_uris() {
+        let master = "#EXTM3U\n\
+            #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"a\",URI=\"https://audio.example.net/a.m3u8\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=800000\nlow.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=2400000\n/hd/high.m3u8\n";
+        assert!(is_master(master));
+        let best = best_variant(&base(), master).unwrap().unwrap();
+        assert_eq!(best.as_str(), "https://cdn.example.com/hd/high.m3u8");
+        let hosts: Vec<_> = master_uris(&base(), master)
+            .unwrap()
+            .iter()
+            .map(|u| u.host_str().unwrap().to_string())
+            .collect();
+        assert_eq!(hosts, ["audio.example.net", "cdn.example.com", "cdn.example.com"]);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let media = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.org/k\"\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n\
+            #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part.m4s\"\n\
+            #EXTINF:6.0,\nseg0.m4s\n#EXTINF:6.0,\nhttp://169.254.169.254/seg1.m4s\n#EXTINF:6.0,\nseg0.m4s\n";
+        let rewritten = rewrite(&base(), media).unwrap();
+        let urls: Vec<_> = rewritten.files.iter().map(|(u, n)| (u.as_str(), n.as_str())).collect();
+        assert_eq!(
+            urls,
+            [
+                ("https://keys.example.org/k", "0.bin"),
+                ("https://cdn.example.com/live/init.mp4", "1.mp4"),
+                ("https://cdn.example.com/live/seg0.m4s", "2.m4s"),
+                ("http://169.254.169.254/seg1.m4s", "3.m4s"),
+            ]
+        );
+        assert_eq!(
+            rewritten.playlist,
+            "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-KEY:METHOD=AES-128,URI=\"0.bin\"\n\
+             #EXT-X-MAP:URI=\"1.mp4\"\n#EXTINF:6.0,\n2.m4s\n#EXTINF:6.0,\n3.m4s\n#EXTINF:6.0,\n2.m4s\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_rejects_other_schemes() {
+        let media = "#EXTM3U\n#EXTINF:6.0,\nfile:///etc/passwd\n";
+        assert!(rewrite(&base(), media).is_err());
+        let media = "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://key\"\n#EXTINF:6.0,\na.ts\n";
+        assert!(rewrite(&base(), media).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::OnceLock;
+
+use image::DynamicImage;
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions, Xyzd};
+
+/// How far a profile's colorants may be from sRGB's and still count as sRGB
+const SRGB_COLORANT_TOLERANCE: f64 = 0.002;
+
+/// Convert pixels tagged with an embedded ICC profile to sRGB
+///
+/// Untagged sources are assumed to be sRGB already, and sRGB-tagged ones are left alone.
+/// Only RGB profiles are applied: the decoders hand out RGB for CMYK JPEGs, and gray
+/// profiles barely differ from sRGB's curve. Unreadable profiles are ignored. Converted
+/// images come out with 8 bits per channel, which every output format uses anyway.
+pub fn to_srgb(img: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let profile = match ColorProfile::new_from_slice(icc) {
+        Ok(profile) => profile,
+        Err(e) => {
+            tracing::debug!("ignoring unreadable ICC profile: {:?}", e);
+            return img;
+        }
+    };
+    if profile.color_space != DataColorSpace::Rgb || !img.color().has_color() || is_srgb(&profile) {
+        return img;
+    }
+
+    let has_alpha = img.color().has_alpha();
+    let layout = if has_alpha { Layout::Rgba } else { Layout::Rgb };
+    let transform =
+        match profile.create_transform_8bit(layout, &ColorProfile::new_srgb(), layout, TransformOptions::default()) {
+            Ok(transform) => transform,
+            Err(e) => {
+                tracing::debug!("ignoring unsupported ICC profile: {:?}", e);
+                return img;
+            }
+        };
+
+    let converted = if has_alpha {
+        let src = img.to_rgba8();
+        let mut dst = src.clone();
+        transform.transform(&src, &mut dst).map(|_| DynamicImage::ImageRgba8(dst))
+    } else {
+        let src = img.to_rgb8();
+        let mut dst = src.clone();
+        transform.transform(&src, &mut dst).map(|_| DynamicImage::ImageRgb8(dst))
+    };
+    converted.unwrap_or_else(|e| {
+        tracing::debug!("ICC conversion failed: {:?}", e);
+        img
+    })
+}
+
+/// Whether a profile uses sRGB primaries (its tone curve is taken on trust)
+fn is_srgb(profile: &ColorProfile) -> bool {
+    let srgb = ColorProfile::new_srgb();
+    let close = |a: &Xyzd, b: &Xyzd| {
+        (a.x - b.x).abs() < SRGB_COLORANT_TOLERANCE
+            && (a.y - b.y).abs() < SRGB_COLORANT_TOLERANCE
+            && (a.z - b.z).abs() < SRGB_COLORANT_TOLERANCE
+    };
+    close(&profile.red_colorant, &srgb.red_colorant)
+        && close(&profile.green_colorant, &srgb.green_colorant)
+        && close(&profile.blue_colorant, &srgb.blue_colorant)
+}
+
+/// Serialized sRGB profile for `EMBED_SRGB_PROFILE` (None if encoding it failed)
+pub fn srgb_profile() -> Option<&'static [u8]> {
+    static PROFILE: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+    PROFILE
+        .get_or_init(|| ColorProfile::new_srgb().encode().ok())
+        .as_deref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_srgb() {
+        assert!(is_srgb(&ColorProfile::new_srgb()));
+        assert!(!is_srgb(&ColorProfile::new_display_p3()));
+        assert!(!is_srgb(&ColorProfile::new_adobe_rgb()));
+    }
+
+    #[test]
+    fn test_display_p3_red_is_more_saturated_in_srgb() {
+        let icc = ColorProfile::new_display_p3().encode().unwrap();
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([200, 40, 40])));
+        let out = to_srgb(img, &icc).to_rgb8();
+        let [r, g, b] = out.get_pixel(0, 0).0;
+        // P3 red lies outside sRGB, so the same values map to a stronger sRGB red
+        assert!(r >= 200 && g < 40 && b < 40, "got {:?}", (r, g, b));
+    }
+
+    #[test]
+    fn test_srgb_and_garbage_profiles_are_ignored() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(1, 1, image::Rgb([10, 20, 30])));
+        let srgb = srgb_profile().unwrap();
+        assert_eq!(to_srgb(img.clone(), srgb).to_rgb8().get_pixel(0, 0).0, [10, 20, 30]);
+        assert_eq!(to_srgb(img, b"not a profile").to_rgb8().get_pixel(0, 0).0, [10, 20, 30]);
+    }
+}
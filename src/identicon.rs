@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path as AxPath, Query, State},
+    http::HeaderMap,
+    response::Response,
+    Extension,
+};
+use image::{DynamicImage, Rgb, RgbImage};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    blossom::BlossomState,
+    cache::cache_path_for,
+    config::TenantCfg,
+    error::SvcError,
+    profile::{ProfileImageQuery, AVATAR_SIZES},
+    server::{
+        check_max_dimension, generate_still, negotiate_format, parse_thumb_params, set_vary_accept,
+        try_serve_processed, with_deadline, CombinedState, ThumbQuery,
+    },
+    singleflight::namespaced_key,
+    transform::{OutFmt, Resize, ResizeMode},
+};
+
+/// Cells per side; the left half is mirrored onto the right
+const GRID: usize = 5;
+/// Space around the grid, in cells
+const PADDING: f32 = 0.5;
+const BACKGROUND: [u8; 3] = [0xf0, 0xf0, 0xf0];
+
+/// GET /identicon/{pubkey} - a deterministic geometric avatar for authors without a picture
+///
+/// The pattern and color come from the pubkey alone, so results are immutable and every
+/// client shows the same fallback. Takes `size=` and `f=` like /avatar.
+pub async fn handle_identicon(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath(pubkey): AxPath<String>,
+    Query(query): Query<ProfileImageQuery>,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    let cfg = &state.app.cfg;
+    let pubkey = BlossomState::parse_pubkey(&pubkey).map_err(|_| SvcError::BadRequest("invalid pubkey"))?;
+    let size = query.size.unwrap_or(cfg.avatar_size);
+    if !AVATAR_SIZES.contains(&size) {
+        return Err(SvcError::BadRequest("size must be 16-1024"));
+    }
+
+    let mut dirs = parse_thumb_params(&ThumbQuery::default(), &cfg.directive_defaults)?;
+    if let Some(ref fmt) = query.format {
+        dirs.out_fmt = OutFmt::from_name(fmt)?;
+    }
+    dirs.resize = Resize {
+        mode: ResizeMode::Fit,
+        w: size,
+        h: size,
+    };
+    check_max_dimension(cfg, &dirs)?;
+    let negotiated = negotiate_format(&mut dirs, &req_headers);
+
+    let cache_key = format!("/identicon/{}?size={}&f={}", pubkey.to_hex(), size, dirs.out_fmt.name());
+    let cache_path = cache_path_for(cfg, &cache_key, &dirs.out_fmt);
+
+    let cached = try_serve_processed(&state.app, &cache_path, &dirs, &req_headers).await?;
+    let mut resp = match cached {
+        Some(resp) => resp,
+        None => {
+            let inflight_key = namespaced_key(&cfg.cache_namespace, &format!("{}#{}", cache_key, dirs.out_fmt.name()));
+            let (app, seed) = (state.app.clone(), pubkey.to_bytes());
+            let pipeline = with_deadline(cfg.request_timeout, async move {
+                let img = DynamicImage::ImageRgb8(draw_identicon(&seed, size));
+                generate_still(&app, img, &dirs, &cache_path).await
+            });
+            state.inflight.run(inflight_key, pipeline).await
+        }
+    };
+
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    Ok(resp)
+}
+
+/// Mirrored 5x5 pattern in one color derived from `seed`, on a light background
+fn draw_identicon(seed: &[u8], size: u32) -> RgbImage {
+    let hash = Sha256::digest(seed);
+    let half = GRID.div_ceil(2);
+    // One bit per cell of the left half and middle column
+    let filled = |row: usize, col: usize| {
+        let bit = row * half + col.min(GRID - 1 - col);
+        (hash[bit / 8] >> (bit % 8)) & 1 == 1
+    };
+    // Bytes the pattern doesn't use pick the color
+    let hue = u16::from_be_bytes([hash[28], hash[29]]) as f32 / 65536.0 * 360.0;
+    let saturation = 0.45 + hash[30] as f32 / 255.0 * 0.2;
+    let lightness = 0.45 + hash[31] as f32 / 255.0 * 0.15;
+    let color = hsl_to_rgb(hue, saturation, lightness);
+
+    let span = GRID as f32 + 2.0 * PADDING;
+    let cell_at = |px: u32| {
+        let pos = (px as f32 + 0.5) / size as f32 * span - PADDING;
+        (0.0..GRID as f32).contains(&pos).then_some(pos as usize)
+    };
+    RgbImage::from_fn(size, size, |x, y| match (cell_at(x), cell_at(y)) {
+        (Some(col), Some(row)) if filled(row, col) => Rgb(color),
+        _ => Rgb(BACKGROUND),
+    })
+}
+
+/// `hue` in degrees, `saturation` and `lightness` in 0..=1
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    [r, g, b].map(|c| ((c + m) * 255.0).round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identicon_is_deterministic_and_mirrored() {
+        let a = draw_identicon(b"alice", 60);
+        assert_eq!(a, draw_identicon(b"alice", 60));
+        assert_ne!(a, draw_identicon(b"bob", 60));
+        for y in 0..60 {
+            for x in 0..60 {
+                assert_eq!(a.get_pixel(x, y), a.get_pixel(59 - x, y));
+            }
+        }
+        // Padding stays background
+        assert_eq!(a.get_pixel(0, 0).0, BACKGROUND);
+    }
+
+    #[test]
+    fn test_hsl_to_rgb() {
+        assert_eq!(hsl_to_rgb(0.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(120.0, 1.0, 0.5), [0, 255, 0]);
+        assert_eq!(hsl_to_rgb(0.0, 0.0, 1.0), [255, 255, 255]);
+    }
+}
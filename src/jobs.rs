@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use axum::{
+    extract::{Path as AxPath, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{error::SvcError, server::CombinedState};
+
+/// How long a job's progress stays subscribable after it was created
+const JOB_RETENTION: Duration = Duration::from_secs(600);
+
+/// Progress of an async job, as streamed by `/jobs/{id}/events`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum JobStage {
+    /// Accepted, waiting to start
+    Queued,
+    /// Pulling a frame out of a video with FFmpeg (includes waiting for an FFmpeg slot)
+    Extracting,
+    /// Decoding, transforming and encoding the image
+    Encoding,
+    /// Result cached; `url` serves it
+    Done { url: String },
+    Failed { error: String },
+}
+
+impl JobStage {
+    /// SSE event name
+    fn name(&self) -> &'static str {
+        match self {
+            JobStage::Queued => "queued",
+            JobStage::Extracting => "extracting",
+            JobStage::Encoding => "encoding",
+            JobStage::Done { .. } => "done",
+            JobStage::Failed { .. } => "failed",
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        matches!(self, JobStage::Done { .. } | JobStage::Failed { .. })
+    }
+}
+
+/// Reports progress of one job to its subscribers
+pub struct JobHandle {
+    tx: Arc<watch::Sender<JobStage>>,
+}
+
+impl JobHandle {
+    pub fn set(&self, stage: JobStage) {
+        tracing::debug!(stage = stage.name(), "job progress");
+        self.tx.send_replace(stage);
+    }
+}
+
+struct JobEntry {
+    tx: Arc<watch::Sender<JobStage>>,
+    created: Instant,
+}
+
+/// Progress channels of recent async jobs, by unguessable job id
+///
+/// Subscribers only see the latest stage, so a slow client may skip intermediate ones but
+/// always gets the terminal event.
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, JobEntry>>,
+}
+
+impl Jobs {
+    /// Register a new job in the `queued` stage
+    pub fn create(&self) -> (String, JobHandle) {
+        let mut raw = [0u8; 16];
+        OsRng.fill_bytes(&mut raw);
+        let id = hex::encode(raw);
+        let tx = Arc::new(watch::Sender::new(JobStage::Queued));
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, entry| entry.created.elapsed() < JOB_RETENTION);
+        jobs.insert(
+            id.clone(),
+            JobEntry {
+                tx: tx.clone(),
+                created: Instant::now(),
+            },
+        );
+        (id, JobHandle { tx })
+    }
+
+    fn subscribe(&self, id: &str) -> Option<watch::Receiver<JobStage>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id)
+            .filter(|entry| entry.created.elapsed() < JOB_RETENTION)
+            .map(|entry| entry.tx.subscribe())
+    }
+}
+
+/// GET /jobs/{id}/events - stream a job's progress as server-sent events
+///
+/// Sends the current stage right away, then every change; the stream ends after `done` or
+/// `failed`. The job id is the only credential, so browsers can use a plain `EventSource`.
+pub async fn handle_job_events(
+    State(state): State<CombinedState>,
+    AxPath(id): AxPath<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, SvcError> {
+    let rx = state.jobs.subscribe(&id).ok_or(SvcError::NotFound("unknown or expired job"))?;
+
+    let events = stream::unfold(Some((rx, true)), |next| async move {
+        let (mut rx, first) = next?;
+        if !first && rx.changed().await.is_err() {
+            return None;
+        }
+        let stage = rx.borrow_and_update().clone();
+        let event = Event::default().event(stage.name()).json_data(&stage);
+        Some((event, (!stage.is_terminal()).then_some((rx, false))))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
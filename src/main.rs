@@ -1,18 +1,59 @@
-use std::{fs, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 use tracing::info;
 
+mod admin;
+mod api_key;
+mod blob_availability;
 mod blossom;
 mod cache;
+mod card;
+mod chaos;
+mod check;
+mod cache_crypto;
 mod config;
+mod debug_trace;
 mod error;
+mod error_journal;
+mod ffmpeg_error;
+mod hls;
+mod icc;
+mod identicon;
+mod jobs;
+mod memory;
+mod metadata;
 mod metrics;
+mod preview;
+mod probe;
+mod process;
+mod profile;
+mod profiling;
+mod qr;
+mod rate_limit;
+mod redis_cache;
+mod report;
 mod server;
+mod server_stats;
+mod shadow;
+mod signature;
+mod singleflight;
+mod snapshot;
+mod source_limit;
+mod storyboard;
+mod svg;
+mod tenant;
+mod text;
 mod thumbnail;
 mod transform;
+mod upstream_auth;
+mod upstream_status;
+mod variants;
+mod video_range;
+mod work_pool;
 
 use blossom::BlossomState;
 use cache::janitor_loop;
 use config::{AppCfg, AppState};
+use redis_cache::RedisCache;
 use server::create_router;
 use thumbnail::ThumbnailState;
 
@@ -20,28 +61,86 @@ use thumbnail::ThumbnailState;
 async fn main() {
     init_tracing();
 
-    let cfg = AppCfg::from_env();
-    
-    // Create cache directories
-    fs::create_dir_all(cfg.cache_dir.join("original")).expect("create original cache dir");
-    fs::create_dir_all(cfg.cache_dir.join("processed")).expect("create processed cache dir");
+    if std::env::args().any(|arg| arg == "--check-config") {
+        std::process::exit(check::run().await);
+    }
+
+    // Validates everything (and creates the cache directories) before serving anything
+    let cfg = match AppCfg::load() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    for tenant in &cfg.tenants {
+        info!(
+            tenant = %tenant.name,
+            hosts = ?tenant.hosts,
+            path_prefix = ?tenant.path_prefix,
+            "tenant configured"
+        );
+    }
 
     let bind_addr = cfg.bind_addr.clone();
-    let state = AppState::new(cfg.clone());
-
-    // Create thumbnail state with max concurrent ffmpeg processes
-    let max_ffmpeg_concurrent = std::env::var("MAX_FFMPEG_CONCURRENT")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(8);
-    let thumbnail_state = Arc::new(ThumbnailState::new(max_ffmpeg_concurrent));
-
-    // Create blossom state with configurable cache TTL
-    let blossom_cache_ttl_hours = std::env::var("BLOSSOM_SERVER_LIST_CACHE_TTL_HOURS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(24);
-    let blossom_state = Arc::new(BlossomState::new(blossom_cache_ttl_hours).await);
+    let mut state = AppState::new(cfg.clone());
+    if let Some(ref url) = cfg.redis_url {
+        let redis = RedisCache::connect(url, cfg.redis_max_item_bytes)
+            .await
+            .expect("connect to Redis");
+        info!(max_item_bytes = cfg.redis_max_item_bytes, "redis cache enabled");
+        state.redis = Some(redis);
+    }
+
+    // Server health and hot entries from before a graceful restart
+    snapshot::restore(&state).await;
+    let snapshot_state = state.clone();
+
+    let thumbnail_state = Arc::new(ThumbnailState::new(
+        cfg.max_ffmpeg_concurrent,
+        cfg.max_ffmpeg_queue,
+        cfg.retry_after_secs,
+        cfg.max_ffprobe_concurrent,
+        cfg.ffprobe_timeout,
+    ));
+
+    let blossom_state = Arc::new(
+        BlossomState::new(cfg.blossom_server_list_cache_ttl_hours, &cfg.pinned_pubkeys).await,
+    );
+
+    // Keep pinned authors' server lists warm
+    let pinned_state = blossom_state.clone();
+    let pinned_interval = cfg.pinned_refresh_interval;
+    tokio::spawn(async move { pinned_state.pinned_refresh_loop(pinned_interval).await });
+
+    // Spawn scheduled cache reports
+    if !cfg.cache_report_interval.is_zero() {
+        let interval = cfg.cache_report_interval;
+        let top_n = cfg.cache_report_top_n;
+        tokio::spawn(async move { report::report_loop(interval, top_n).await });
+    }
+
+    text::load_fonts(&cfg.text_fonts).expect("load TEXT_FONTS");
+    if !cfg.text_fonts.is_empty() && !cfg.asset_watch_interval.is_zero() {
+        let (paths, interval) = (cfg.text_fonts.clone(), cfg.asset_watch_interval);
+        tokio::spawn(async move { text::watch_loop(paths, interval).await });
+    }
+    upstream_status::init(cfg.upstream_status_map.clone(), cfg.retry_after_secs);
+    upstream_auth::init(cfg.upstream_auth.clone());
+    shadow::init(&cfg);
+    chaos::init(cfg.chaos);
+
+    // Load the processing-error journal and persist it periodically
+    error_journal::init(&cfg);
+    if cfg.error_journal_max_sources > 0 {
+        let journal_cfg = cfg.clone();
+        tokio::spawn(async move { error_journal::flush_loop(journal_cfg).await });
+    }
+
+    // Spawn memory sampling (RSS, memory cache occupancy, soft limit)
+    let memory_cache = state.memory_cache.clone();
+    tokio::spawn(async move { memory::sample_loop(memory_cache).await });
 
     // Spawn janitor
     tokio::spawn(async move { janitor_loop(cfg).await });
@@ -53,11 +152,13 @@ async fn main() {
         .await
         .unwrap();
     
-    axum::serve(listener, app)
+    // Peer addresses for the rate limiter
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
-    
+
+    snapshot::save(&snapshot_state).await;
     info!("server shutdown complete");
 }
 
@@ -0,0 +1,112 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::sleep;
+
+use crate::{cache::MemoryCache, config::AppCfg, error::SvcError, metrics};
+
+/// How often RSS and cache occupancy are sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latest sampled resident set size (0 = unknown)
+static RSS_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Pixel bytes of images decoded by in-flight pipelines
+static DECODED_IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+
+/// Memory figures for the detailed health endpoint
+#[derive(Debug, Serialize)]
+pub struct MemoryStatus {
+    /// Resident set size (None where /proc is unavailable)
+    pub rss_bytes: Option<u64>,
+    pub decoded_bytes_in_flight: u64,
+    pub memory_cache_bytes: u64,
+    pub memory_cache_entries: u64,
+    /// MEMORY_SOFT_LIMIT_BYTES (None = no limit)
+    pub soft_limit_bytes: Option<u64>,
+    /// Whether new processing work is currently being rejected
+    pub shedding: bool,
+}
+
+/// Current resident set size from /proc/self/status (Linux only)
+pub fn read_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Sample RSS and memory cache occupancy into metrics and the soft-limit check
+pub async fn sample_loop(memory_cache: Option<MemoryCache>) {
+    loop {
+        if let Some(rss) = read_rss_bytes() {
+            RSS_BYTES.store(rss, Ordering::Relaxed);
+            metrics::MEMORY_RSS_BYTES.set(rss as f64);
+        }
+        if let Some(ref cache) = memory_cache {
+            let (bytes, entries) = cache.usage();
+            metrics::MEMORY_CACHE_BYTES.set(bytes as f64);
+            metrics::MEMORY_CACHE_ENTRIES.set(entries as f64);
+        }
+        sleep(SAMPLE_INTERVAL).await;
+    }
+}
+
+/// Reject new processing work while RSS is above `MEMORY_SOFT_LIMIT_BYTES`
+///
+/// Cache hits are still served; only pipelines that would decode images are shed.
+pub fn check_soft_limit(cfg: &AppCfg) -> Result<(), SvcError> {
+    if !over_soft_limit(cfg) {
+        return Ok(());
+    }
+    metrics::MEMORY_SHED_TOTAL.inc();
+    tracing::warn!(
+        rss_bytes = RSS_BYTES.load(Ordering::Relaxed),
+        limit = cfg.memory_soft_limit_bytes,
+        "over memory soft limit, shedding request"
+    );
+    Err(SvcError::Overloaded {
+        retry_after_secs: cfg.retry_after_secs,
+        queue_depth: 0,
+    })
+}
+
+fn over_soft_limit(cfg: &AppCfg) -> bool {
+    let rss = RSS_BYTES.load(Ordering::Relaxed);
+    cfg.memory_soft_limit_bytes > 0 && rss > cfg.memory_soft_limit_bytes
+}
+
+/// Current figures for `/health/details`
+pub fn status(cfg: &AppCfg, memory_cache: Option<&MemoryCache>) -> MemoryStatus {
+    let (memory_cache_bytes, memory_cache_entries) = memory_cache.map(MemoryCache::usage).unwrap_or_default();
+    let rss = RSS_BYTES.load(Ordering::Relaxed);
+    MemoryStatus {
+        rss_bytes: (rss > 0).then_some(rss),
+        decoded_bytes_in_flight: DECODED_IN_FLIGHT.load(Ordering::Relaxed),
+        memory_cache_bytes,
+        memory_cache_entries,
+        soft_limit_bytes: (cfg.memory_soft_limit_bytes > 0).then_some(cfg.memory_soft_limit_bytes),
+        shedding: over_soft_limit(cfg),
+    }
+}
+
+/// Count a decoded image as in flight until the guard is dropped
+pub fn track_decoded(bytes: usize) -> DecodedGuard {
+    let bytes = bytes as u64;
+    let total = DECODED_IN_FLIGHT.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    metrics::DECODED_IMAGE_BYTES_IN_FLIGHT.set(total as f64);
+    DecodedGuard { bytes }
+}
+
+pub struct DecodedGuard {
+    bytes: u64,
+}
+
+impl Drop for DecodedGuard {
+    fn drop(&mut self) {
+        let total = DECODED_IN_FLIGHT.fetch_sub(self.bytes, Ordering::Relaxed) - self.bytes;
+        metrics::DECODED_IMAGE_BYTES_IN_FLIGHT.set(total as f64);
+    }
+}
@@ -0,0 +1,149 @@
+/// EXIF tags preserved by `keep_metadata`; GPS, camera serials and the rest are dropped
+const KEPT_TAGS: &[u16] = &[
+    0x013B, // Artist
+    0x8298, // Copyright
+];
+
+/// TIFF field type for NUL-terminated ASCII strings
+const TYPE_ASCII: u16 = 2;
+
+/// Reduce a raw EXIF block (TIFF structure, no `Exif\0\0` prefix) to its copyright fields
+///
+/// Outputs are re-encoded from pixels and carry no metadata by default; with `keep_metadata`
+/// this freshly built block is the only metadata written. Returns a new little-endian TIFF
+/// block, or None when the source has none of the fields or can't be parsed.
+pub fn copyright_exif(exif: &[u8]) -> Option<Vec<u8>> {
+    let reader = TiffReader::new(exif)?;
+    let ifd = reader.u32(4)? as usize;
+    let count = reader.u16(ifd)? as usize;
+
+    let mut fields: Vec<(u16, &[u8])> = Vec::new();
+    for i in 0..count {
+        let entry = ifd + 2 + i * 12;
+        let tag = reader.u16(entry)?;
+        if !KEPT_TAGS.contains(&tag) || reader.u16(entry + 2)? != TYPE_ASCII {
+            continue;
+        }
+        let len = reader.u32(entry + 4)? as usize;
+        let start = if len <= 4 { entry + 8 } else { reader.u32(entry + 8)? as usize };
+        let value = exif.get(start..start.checked_add(len)?)?;
+        fields.push((tag, value));
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    // IFD entries must be sorted by tag
+    fields.sort_by_key(|(tag, _)| *tag);
+
+    let mut out = b"II*\0".to_vec();
+    out.extend_from_slice(&8u32.to_le_bytes());
+    out.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+    let mut data_offset = 8 + 2 + fields.len() * 12 + 4;
+    let mut data = Vec::new();
+    for (tag, value) in &fields {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&TYPE_ASCII.to_le_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if value.len() <= 4 {
+            let mut inline = [0u8; 4];
+            inline[..value.len()].copy_from_slice(value);
+            out.extend_from_slice(&inline);
+        } else {
+            out.extend_from_slice(&(data_offset as u32).to_le_bytes());
+            data.extend_from_slice(value);
+            // Values start on word boundaries
+            if value.len() % 2 == 1 {
+                data.push(0);
+            }
+            data_offset = 8 + 2 + fields.len() * 12 + 4 + data.len();
+        }
+    }
+    // No next IFD
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&data);
+    Some(out)
+}
+
+/// Bounds-checked reads from a TIFF block in its declared byte order
+struct TiffReader<'a> {
+    buf: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    fn new(buf: &'a [u8]) -> Option<Self> {
+        let little_endian = match buf.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Self { buf, little_endian })
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.buf.get(at..at.checked_add(2)?)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.buf.get(at..at.checked_add(4)?)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Big-endian TIFF with Make, Artist, Copyright and a GPS IFD pointer
+    fn sample_exif() -> Vec<u8> {
+        let mut exif = b"MM\0*".to_vec();
+        exif.extend_from_slice(&8u32.to_be_bytes());
+        exif.extend_from_slice(&4u16.to_be_bytes());
+        let data_start = 8 + 2 + 4 * 12 + 4;
+        let entries: [(u16, u16, u32, u32); 4] = [
+            (0x010F, TYPE_ASCII, 4, u32::from_be_bytes(*b"Cam\0")),
+            (0x013B, TYPE_ASCII, 4, u32::from_be_bytes(*b"Ann\0")),
+            (0x8298, TYPE_ASCII, 11, data_start as u32),
+            (0x8825, 4, 1, 0x1234),
+        ];
+        for (tag, ty, count, value) in entries {
+            exif.extend_from_slice(&tag.to_be_bytes());
+            exif.extend_from_slice(&ty.to_be_bytes());
+            exif.extend_from_slice(&count.to_be_bytes());
+            exif.extend_from_slice(&value.to_be_bytes());
+        }
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        exif.extend_from_slice(b"(c) Nobody\0");
+        exif
+    }
+
+    #[test]
+    fn test_keeps_only_copyright_fields() {
+        let kept = copyright_exif(&sample_exif()).unwrap();
+        let reader = TiffReader::new(&kept).unwrap();
+        assert_eq!(reader.u16(8), Some(2));
+        assert_eq!(reader.u16(10), Some(0x013B));
+        assert_eq!(&kept[18..22], b"Ann\0");
+        assert_eq!(reader.u16(22), Some(0x8298));
+        let offset = reader.u32(30).unwrap() as usize;
+        assert_eq!(&kept[offset..offset + 11], b"(c) Nobody\0");
+    }
+
+    #[test]
+    fn test_no_copyright_fields() {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend_from_slice(&8u32.to_le_bytes());
+        exif.extend_from_slice(&0u16.to_le_bytes());
+        assert_eq!(copyright_exif(&exif), None);
+        assert_eq!(copyright_exif(b"garbage"), None);
+    }
+}
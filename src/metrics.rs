@@ -1,22 +1,37 @@
 use lazy_static::lazy_static;
 use prometheus::{
-    register_counter_vec, register_gauge, register_histogram_vec, CounterVec, Gauge, HistogramVec,
-    TextEncoder, Encoder,
+    register_counter, register_counter_vec, register_gauge, register_histogram_vec, Counter,
+    CounterVec, Gauge, HistogramVec, TextEncoder, Encoder,
+};
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::Response,
 };
 
+use crate::config::TenantCfg;
+
 lazy_static! {
     // HTTP request metrics
     pub static ref HTTP_REQUESTS_TOTAL: CounterVec = register_counter_vec!(
         "imgproxy_http_requests_total",
-        "Total number of HTTP requests by endpoint and status",
-        &["endpoint", "method", "status"]
+        "Total number of HTTP requests by tenant, endpoint and status",
+        &["tenant", "endpoint", "method", "status"]
     )
     .unwrap();
 
     pub static ref HTTP_REQUEST_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
         "imgproxy_http_request_duration_seconds",
         "HTTP request latencies in seconds",
-        &["endpoint", "method"],
+        &["tenant", "endpoint", "method"],
         vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
     )
     .unwrap();
@@ -51,6 +66,14 @@ lazy_static! {
     )
     .unwrap();
 
+    pub static ref PROCESSING_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "imgproxy_processing_duration_seconds",
+        "Time spent fetching, transforming and encoding cache misses, in seconds",
+        &["tenant", "endpoint", "output_format"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap();
+
     pub static ref PROCESSING_ERRORS_TOTAL: CounterVec = register_counter_vec!(
         "imgproxy_processing_errors_total",
         "Total number of processing errors by type",
@@ -71,6 +94,18 @@ lazy_static! {
     )
     .unwrap();
 
+    pub static ref IMAGE_SEMAPHORE_PERMITS_AVAILABLE: Gauge = register_gauge!(
+        "imgproxy_image_semaphore_permits_available",
+        "Number of available image decode/encode permits"
+    )
+    .unwrap();
+
+    pub static ref IMAGE_SEMAPHORE_WAITERS: Gauge = register_gauge!(
+        "imgproxy_image_semaphore_waiters",
+        "Number of tasks waiting to decode and encode an image"
+    )
+    .unwrap();
+
     pub static ref FFMPEG_EXTRACTIONS_TOTAL: CounterVec = register_counter_vec!(
         "imgproxy_ffmpeg_extractions_total",
         "Total number of FFmpeg thumbnail extractions",
@@ -82,14 +117,106 @@ lazy_static! {
     pub static ref BYTES_DOWNLOADED_TOTAL: CounterVec = register_counter_vec!(
         "imgproxy_bytes_downloaded_total",
         "Total bytes downloaded from source URLs",
-        &["source_type"]
+        &["tenant", "source_type"]
     )
     .unwrap();
 
     pub static ref BYTES_SERVED_TOTAL: CounterVec = register_counter_vec!(
         "imgproxy_bytes_served_total",
         "Total bytes served to clients",
-        &["content_type"]
+        &["tenant", "content_type"]
+    )
+    .unwrap();
+
+    // Upstream connection metrics
+    pub static ref UPSTREAM_CONNECTIONS_TOTAL: Counter = register_counter!(
+        "imgproxy_upstream_connections_total",
+        "Total new upstream connections opened (TCP/TLS handshakes)"
+    )
+    .unwrap();
+
+    pub static ref UPSTREAM_RESPONSES_TOTAL: CounterVec = register_counter_vec!(
+        "imgproxy_upstream_responses_total",
+        "Total upstream responses by HTTP version",
+        &["version"]
+    )
+    .unwrap();
+
+    // Memory metrics
+    pub static ref MEMORY_RSS_BYTES: Gauge = register_gauge!(
+        "imgproxy_memory_rss_bytes",
+        "Resident set size of the process in bytes"
+    )
+    .unwrap();
+
+    pub static ref DECODED_IMAGE_BYTES_IN_FLIGHT: Gauge = register_gauge!(
+        "imgproxy_decoded_image_bytes_in_flight",
+        "Pixel bytes of images currently decoded for processing"
+    )
+    .unwrap();
+
+    pub static ref MEMORY_CACHE_BYTES: Gauge = register_gauge!(
+        "imgproxy_memory_cache_bytes",
+        "Bytes held by the in-memory processed cache"
+    )
+    .unwrap();
+
+    pub static ref MEMORY_CACHE_ENTRIES: Gauge = register_gauge!(
+        "imgproxy_memory_cache_entries",
+        "Entries in the in-memory processed cache"
+    )
+    .unwrap();
+
+    pub static ref MEMORY_SHED_TOTAL: Counter = register_counter!(
+        "imgproxy_memory_shed_total",
+        "Requests rejected because the process was over MEMORY_SOFT_LIMIT_BYTES"
+    )
+    .unwrap();
+
+    pub static ref ASSET_RELOADS_TOTAL: CounterVec = register_counter_vec!(
+        "imgproxy_asset_reloads_total",
+        "Reloads of changed TEXT_FONTS files by outcome (ok, failed)",
+        &["result"]
+    )
+    .unwrap();
+
+    pub static ref VARIANTS_REUSED_TOTAL: Counter = register_counter!(
+        "imgproxy_variants_reused_total",
+        "Requests served a cached variant within VARIANT_SIZE_TOLERANCE_PERCENT / VARIANT_QUALITY_TOLERANCE"
+    )
+    .unwrap();
+
+    pub static ref BLOSSOM_HEAD_CHECKS_TOTAL: CounterVec = register_counter_vec!(
+        "imgproxy_blossom_head_checks_total",
+        "BLOSSOM_HEAD_CHECK pre-checks of Blossom servers by outcome (confirmed, missing, too_large, failed)",
+        &["result"]
+    )
+    .unwrap();
+
+    // Shadow traffic metrics
+    pub static ref SHADOW_COMPARISONS_TOTAL: CounterVec = register_counter_vec!(
+        "imgproxy_shadow_comparisons_total",
+        "Requests mirrored to SHADOW_URL by outcome (match, divergent, error)",
+        &["result"]
+    )
+    .unwrap();
+
+    pub static ref SHADOW_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "imgproxy_shadow_duration_seconds",
+        "Latency of mirrored requests on this instance and on the shadow, in seconds",
+        &["instance"],
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
+}
+
+// Fault injection metrics, only in builds with the `chaos` feature
+#[cfg(feature = "chaos")]
+lazy_static! {
+    pub static ref FAULTS_INJECTED_TOTAL: CounterVec = register_counter_vec!(
+        "imgproxy_faults_injected_total",
+        "Faults injected by CHAOS_* settings by kind",
+        &["kind"]
     )
     .unwrap();
 }
@@ -103,20 +230,72 @@ pub fn encode_metrics() -> Result<String, Box<dyn std::error::Error>> {
     Ok(String::from_utf8(buffer)?)
 }
 
+/// Tenant label for requests that matched no tenant
+pub const DEFAULT_TENANT: &str = "default";
+
 /// Record HTTP request
-pub fn record_http_request(endpoint: &str, method: &str, status: u16) {
+pub fn record_http_request(tenant: &str, endpoint: &str, method: &str, status: u16) {
     HTTP_REQUESTS_TOTAL
-        .with_label_values(&[endpoint, method, &status.to_string()])
+        .with_label_values(&[tenant, endpoint, method, &status.to_string()])
         .inc();
 }
 
 /// Record HTTP request duration
-pub fn observe_http_duration(endpoint: &str, method: &str, duration_secs: f64) {
+pub fn observe_http_duration(tenant: &str, endpoint: &str, method: &str, duration_secs: f64) {
     HTTP_REQUEST_DURATION_SECONDS
-        .with_label_values(&[endpoint, method])
+        .with_label_values(&[tenant, endpoint, method])
         .observe(duration_secs);
 }
 
+/// Endpoint label for a matched route, e.g. `/insecure/{*rest}` -> `/insecure`
+fn endpoint_label(matched: &str) -> &str {
+    match matched.find("/{") {
+        Some(0) => "/signed",
+        Some(i) => &matched[..i],
+        None => matched,
+    }
+}
+
+/// Middleware recording request count, duration, processed cache outcome and bytes served
+///
+/// Installed with `route_layer` so the matched route is available as the endpoint label;
+/// the tenant was attached by `tenant::select_tenant` before routing.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().as_str().to_string();
+    let tenant = req
+        .extensions()
+        .get::<Arc<TenantCfg>>()
+        .map(|t| t.name.clone())
+        .unwrap_or_else(|| DEFAULT_TENANT.to_string());
+    let endpoint = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| endpoint_label(p.as_str()).to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let resp = next.run(req).await;
+
+    observe_http_duration(&tenant, &endpoint, &method, start.elapsed().as_secs_f64());
+    record_http_request(&tenant, &endpoint, &method, resp.status().as_u16());
+
+    match resp.headers().get("x-cache").map(|v| v.as_bytes()) {
+        Some(b"hit") | Some(b"stale") => record_cache_hit("processed"),
+        Some(b"miss") => record_cache_miss("processed"),
+        Some(b"coalesced") => record_cache_hit("coalesced"),
+        _ => {}
+    }
+
+    if let (Some(content_type), Some(len)) = (
+        resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+        resp.body().size_hint().exact(),
+    ) {
+        record_bytes_served(&tenant, content_type, len as usize);
+    }
+
+    resp
+}
+
 /// Record cache hit
 pub fn record_cache_hit(cache_type: &str) {
     CACHE_HITS_TOTAL.with_label_values(&[cache_type]).inc();
@@ -141,6 +320,13 @@ pub fn record_video_processed(output_format: &str) {
         .inc();
 }
 
+/// Record the duration of a processing pipeline (cache miss to encoded output)
+pub fn observe_processing_duration(tenant: &str, endpoint: &str, output_format: &str, duration_secs: f64) {
+    PROCESSING_DURATION_SECONDS
+        .with_label_values(&[tenant, endpoint, output_format])
+        .observe(duration_secs);
+}
+
 /// Record processing error
 pub fn record_processing_error(error_type: &str) {
     PROCESSING_ERRORS_TOTAL
@@ -157,21 +343,103 @@ pub fn record_ffmpeg_extraction(success: bool) {
 }
 
 /// Record bytes downloaded
-pub fn record_bytes_downloaded(source_type: &str, bytes: usize) {
+pub fn record_bytes_downloaded(tenant: &str, source_type: &str, bytes: usize) {
     BYTES_DOWNLOADED_TOTAL
-        .with_label_values(&[source_type])
+        .with_label_values(&[tenant, source_type])
         .inc_by(bytes as f64);
 }
 
 /// Record bytes served
-pub fn record_bytes_served(content_type: &str, bytes: usize) {
+pub fn record_bytes_served(tenant: &str, content_type: &str, bytes: usize) {
     BYTES_SERVED_TOTAL
-        .with_label_values(&[content_type])
+        .with_label_values(&[tenant, content_type])
         .inc_by(bytes as f64);
 }
 
+/// Record the outcome of a Blossom `HEAD` pre-check
+pub fn record_blossom_head_check(result: &str) {
+    BLOSSOM_HEAD_CHECKS_TOTAL.with_label_values(&[result]).inc();
+}
+
+/// Record the outcome of a mirrored request and both sides' latencies
+pub fn record_shadow_comparison(result: &str, primary_secs: f64, shadow_secs: Option<f64>) {
+    SHADOW_COMPARISONS_TOTAL.with_label_values(&[result]).inc();
+    SHADOW_DURATION_SECONDS.with_label_values(&["primary"]).observe(primary_secs);
+    if let Some(secs) = shadow_secs {
+        SHADOW_DURATION_SECONDS.with_label_values(&["shadow"]).observe(secs);
+    }
+}
+
+/// Record a fault injected by `CHAOS_*`
+#[cfg(feature = "chaos")]
+pub fn record_fault_injected(kind: &str) {
+    FAULTS_INJECTED_TOTAL.with_label_values(&[kind]).inc();
+}
+
+/// Record a reload of changed asset files
+pub fn record_asset_reload(result: &str) {
+    ASSET_RELOADS_TOTAL.with_label_values(&[result]).inc();
+}
+
 /// Update FFmpeg semaphore metrics
 pub fn update_ffmpeg_semaphore_metrics(permits_available: usize, waiters: usize) {
     FFMPEG_SEMAPHORE_PERMITS_AVAILABLE.set(permits_available as f64);
     FFMPEG_SEMAPHORE_WAITERS.set(waiters as f64);
 }
+
+/// Update image pipeline semaphore metrics
+pub fn update_image_semaphore_metrics(permits_available: usize, waiters: usize) {
+    IMAGE_SEMAPHORE_PERMITS_AVAILABLE.set(permits_available as f64);
+    IMAGE_SEMAPHORE_WAITERS.set(waiters as f64);
+}
+
+/// Record an upstream response; compare against connections to derive the reuse ratio
+pub fn record_upstream_response(version: reqwest::Version) {
+    let version = match version {
+        reqwest::Version::HTTP_09 => "0.9",
+        reqwest::Version::HTTP_10 => "1.0",
+        reqwest::Version::HTTP_11 => "1.1",
+        reqwest::Version::HTTP_2 => "2",
+        reqwest::Version::HTTP_3 => "3",
+        _ => "other",
+    };
+    UPSTREAM_RESPONSES_TOTAL.with_label_values(&[version]).inc();
+}
+
+/// Connector layer counting new upstream connections
+///
+/// reqwest only invokes the connector when no pooled connection can be reused,
+/// so every call corresponds to a fresh TCP (and TLS) handshake.
+#[derive(Clone, Copy)]
+pub struct ConnectionCountLayer;
+
+impl<S> tower::Layer<S> for ConnectionCountLayer {
+    type Service = ConnectionCount<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectionCount { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionCount<S> {
+    inner: S,
+}
+
+impl<S, R> tower::Service<R> for ConnectionCount<S>
+where
+    S: tower::Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        UPSTREAM_CONNECTIONS_TOTAL.inc();
+        self.inner.call(req)
+    }
+}
@@ -0,0 +1,157 @@
+use std::{ops::RangeInclusive, path::PathBuf, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{Path as AxPath, Query, State},
+    http::{HeaderMap, Uri},
+    response::Response,
+    Extension,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::{
+    cache::{
+        build_image_response, etag_for, preview_cache_path_for, set_etag, set_last_modified, try_serve_cache,
+        write_processed_cache,
+    },
+    config::TenantCfg,
+    error::{SvcError, VideoError},
+    metrics, report,
+    server::{journaled, parse_list_param, resolve_blossom_servers, set_source_server, with_deadline, CombinedState},
+    singleflight::namespaced_key,
+    thumbnail::{extract_video_preview, is_video_extension},
+};
+
+/// Widths accepted for `w=` and `PREVIEW_WIDTH`
+pub const PREVIEW_WIDTHS: RangeInclusive<u32> = 64..=640;
+const WEBP_MIME: &str = "image/webp";
+
+/// Query parameters for /preview
+#[derive(Debug, Default, Deserialize)]
+pub struct PreviewQuery {
+    /// Server hints from `xs`, filled in by `PreviewQuery::from_uri`
+    #[serde(skip)]
+    server_hints: Vec<String>,
+    /// Author pubkey for Nostr-based lookup
+    #[serde(rename = "as")]
+    author_pubkey: Option<String>,
+    /// Width in pixels (default `PREVIEW_WIDTH`); never upscaled
+    w: Option<u32>,
+}
+
+impl PreviewQuery {
+    fn from_uri(uri: &Uri) -> Result<Self, SvcError> {
+        let Query(mut params) =
+            Query::<PreviewQuery>::try_from_uri(uri).map_err(|_| SvcError::BadRequest("invalid query"))?;
+        params.server_hints = parse_list_param(uri.query(), "xs");
+        Ok(params)
+    }
+}
+
+/// GET /preview/<sha256>.<ext> - a short looping WebP sampled across a Blossom video
+///
+/// Meant for hover previews. Blobs are found like on /thumb (`xs`, `as`, fallback servers);
+/// since the blob is content-addressed, only the width is part of the cache key.
+pub async fn handle_preview(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath(filename): AxPath<String>,
+    uri: Uri,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    let cfg = &state.app.cfg;
+    let (hash, ext) = parse_filename(&filename)?;
+    let (hash, ext) = (hash.to_ascii_lowercase(), ext.to_ascii_lowercase());
+    if !cfg.video_support {
+        metrics::record_processing_error("video_disabled");
+        return Err(SvcError::UnsupportedMedia("video support is disabled"));
+    }
+    let query = PreviewQuery::from_uri(&uri)?;
+    let width = query.w.unwrap_or(cfg.preview_width);
+    if !PREVIEW_WIDTHS.contains(&width) {
+        return Err(SvcError::BadRequest("w must be 64-640"));
+    }
+
+    let cache_key = format!("/preview/{}.{}?w={}", hash, ext, width);
+    let cache_path = preview_cache_path_for(cfg, &cache_key);
+    if let Some(resp) = try_serve_cache(&state.app, &cache_path, WEBP_MIME, &req_headers).await? {
+        return Ok(resp);
+    }
+
+    let pipeline = journaled(
+        filename,
+        with_deadline(cfg.request_timeout, generate_preview(state.clone(), hash, ext, query, width, cache_path)),
+    );
+    Ok(state.inflight.run(namespaced_key(&cfg.cache_namespace, &cache_key), pipeline).await)
+}
+
+/// Split `<sha256>.<ext>`, which must name a video
+pub(crate) fn parse_filename(filename: &str) -> Result<(&str, &str), SvcError> {
+    let (hash, ext) = filename
+        .rsplit_once('.')
+        .ok_or(SvcError::BadRequest("invalid filename format, expected <sha256>.<ext>"))?;
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(SvcError::BadRequest("invalid SHA256 hash"));
+    }
+    if !is_video_extension(ext) {
+        return Err(SvcError::UnsupportedMedia("expected a video blob"));
+    }
+    Ok((hash, ext))
+}
+
+/// Render, check and cache a preview after a cache miss
+async fn generate_preview(
+    state: CombinedState,
+    hash: String,
+    ext: String,
+    query: PreviewQuery,
+    width: u32,
+    cache_path: PathBuf,
+) -> Result<Response, SvcError> {
+    let cfg = &state.app.cfg;
+    let started = Instant::now();
+
+    // Bound the variants of one blob processed at once
+    let _source_permit = state.source_limiter.acquire(&hash).await?;
+
+    let servers = resolve_blossom_servers(&state, &query.server_hints, query.author_pubkey.as_deref()).await;
+    let servers = state.app.blob_candidates(&servers, &hash);
+    let Some((primary, fallbacks)) = servers.split_first() else {
+        return Err(SvcError::UpstreamError(404));
+    };
+    let video_url = format!("{}/{}.{}", primary.trim_end_matches('/'), hash, ext);
+    let (bytes, source_server) =
+        extract_video_preview(&video_url, width, &state.thumbnail, &state.app, fallbacks).await?;
+    if bytes.len() > cfg.preview_max_bytes {
+        metrics::record_processing_error("preview_too_large");
+        return Err(VideoError::TooLarge("preview too large").into());
+    }
+
+    metrics::observe_processing_duration(cfg.metrics_label(), "/preview", "webp", started.elapsed().as_secs_f64());
+    metrics::record_video_processed("webp");
+    report::record_source_served(&format!("{}.{}", hash, ext), bytes.len());
+
+    let body = Bytes::from(bytes);
+    let modified = write_processed_cache(&state.app, &cache_path, &body, None).await?;
+    let etag = etag_for(&body);
+    let mut resp = build_image_response(body, WEBP_MIME, "miss", None);
+    set_etag(&mut resp, &etag);
+    set_last_modified(&mut resp, modified);
+    set_source_server(&mut resp, &source_server);
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filename() {
+        let hash = "a".repeat(64);
+        assert!(matches!(parse_filename(&format!("{}.mp4", hash)), Ok((_, "mp4"))));
+        assert!(matches!(parse_filename(&format!("{}.jpg", hash)), Err(SvcError::UnsupportedMedia(_))));
+        assert!(matches!(parse_filename("abc.mp4"), Err(SvcError::BadRequest(_))));
+        assert!(matches!(parse_filename(&hash), Err(SvcError::BadRequest(_))));
+    }
+}
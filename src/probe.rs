@@ -0,0 +1,310 @@
+use std::{io::Cursor, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Path as AxPath, Query, State},
+    http::{HeaderMap, Uri},
+    response::Response,
+    Extension,
+};
+use bytes::Bytes;
+use image::{metadata::Orientation, ImageDecoder, ImageError, ImageReader};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blossom::server_origin,
+    cache::{
+        build_image_response, etag_for, probe_cache_path_for, set_etag, set_last_modified, try_serve_cache,
+        write_processed_cache,
+    },
+    config::TenantCfg,
+    error::SvcError,
+    metrics,
+    server::{
+        fetch_from_blossom_servers, journaled, parse_list_param, resolve_blossom_servers, set_source_server,
+        with_deadline, CombinedState,
+    },
+    singleflight::namespaced_key,
+    thumbnail::{is_video_extension, probe_video_report},
+};
+
+const JSON_MIME: &str = "application/json";
+
+/// Query parameters for /probe
+#[derive(Debug, Default, Deserialize)]
+pub struct ProbeQuery {
+    /// Server hints from `xs`, filled in by `ProbeQuery::from_uri`
+    #[serde(skip)]
+    server_hints: Vec<String>,
+    /// Author pubkey for Nostr-based lookup
+    #[serde(rename = "as")]
+    author_pubkey: Option<String>,
+}
+
+impl ProbeQuery {
+    fn from_uri(uri: &Uri) -> Result<Self, SvcError> {
+        let Query(mut params) =
+            Query::<ProbeQuery>::try_from_uri(uri).map_err(|_| SvcError::BadRequest("invalid query"))?;
+        params.server_hints = parse_list_param(uri.query(), "xs");
+        Ok(params)
+    }
+}
+
+/// What /probe reports about a blob
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MediaInfo {
+    /// `image` or `video`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Seconds, for videos
+    duration: Option<f64>,
+    /// Video codec (`h264`, `vp9`, ...) or image format (`jpeg`, `png`, ...)
+    codec: Option<String>,
+    /// Clockwise degrees to turn the stored pixels for display (0, 90, 180 or 270)
+    rotation: u16,
+    /// Blob size in bytes, when known
+    size: Option<u64>,
+    /// Origin of the server that supplied the blob when it was probed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+/// GET /probe/<sha256>.<ext> - media metadata of a Blossom blob as JSON
+///
+/// Lets clients lay out players and galleries before downloading anything. Videos are read with
+/// ffprobe, images by decoding their header; the extension picks which. Servers are found like on
+/// /thumb, and reports are cached by blob since the content behind a hash never changes.
+pub async fn handle_probe(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath(filename): AxPath<String>,
+    uri: Uri,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    let cfg = &state.app.cfg;
+    let (hash, ext) = filename
+        .rsplit_once('.')
+        .ok_or(SvcError::BadRequest("invalid filename format, expected <sha256>.<ext>"))?;
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(SvcError::BadRequest("invalid SHA256 hash"));
+    }
+    let (hash, ext) = (hash.to_ascii_lowercase(), ext.to_ascii_lowercase());
+    let video = is_video_extension(&ext);
+    if video && !cfg.video_support {
+        metrics::record_processing_error("video_disabled");
+        return Err(SvcError::UnsupportedMedia("video support is disabled"));
+    }
+    let query = ProbeQuery::from_uri(&uri)?;
+
+    let cache_key = format!("/probe/{}.{}", hash, ext);
+    let cache_path = probe_cache_path_for(cfg, &cache_key);
+    if let Some(resp) = try_serve_cache(&state.app, &cache_path, JSON_MIME, &req_headers).await? {
+        return Ok(resp);
+    }
+
+    let pipeline = journaled(
+        filename,
+        with_deadline(cfg.request_timeout, generate_probe(state.clone(), hash, ext, video, query, cache_path)),
+    );
+    Ok(state.inflight.run(namespaced_key(&cfg.cache_namespace, &cache_key), pipeline).await)
+}
+
+/// Probe the blob and cache the report after a cache miss
+async fn generate_probe(
+    state: CombinedState,
+    hash: String,
+    ext: String,
+    video: bool,
+    query: ProbeQuery,
+    cache_path: PathBuf,
+) -> Result<Response, SvcError> {
+    let (mut info, source_server) = if video {
+        probe_video(&state, &hash, &ext, &query).await?
+    } else {
+        probe_image(&state, &hash, &ext, &query).await?
+    };
+    info.source = Some(source_server.clone());
+    let body = serde_json::to_vec(&info).map_err(|e| SvcError::InternalError(format!("probe report: {}", e)))?;
+    let body = Bytes::from(body);
+    let modified = write_processed_cache(&state.app, &cache_path, &body, None).await?;
+    let etag = etag_for(&body);
+    let mut resp = build_image_response(body, JSON_MIME, "miss", None);
+    set_etag(&mut resp, &etag);
+    set_last_modified(&mut resp, modified);
+    set_source_server(&mut resp, &source_server);
+    Ok(resp)
+}
+
+/// Ask ffprobe on each server holding the blob until one answers
+async fn probe_video(
+    state: &CombinedState,
+    hash: &str,
+    ext: &str,
+    query: &ProbeQuery,
+) -> Result<(MediaInfo, String), SvcError> {
+    let servers = resolve_blossom_servers(state, &query.server_hints, query.author_pubkey.as_deref()).await;
+    let servers = state.app.blob_candidates(&servers, hash);
+    if servers.is_empty() {
+        return Err(SvcError::UpstreamError(404));
+    }
+    for server in &servers {
+        let url = format!("{}/{}.{}", server.trim_end_matches('/'), hash, ext);
+        if let Some(report) = probe_video_report(&state.app, &state.thumbnail, &url).await? {
+            let info = parse_ffprobe_report(&report)
+                .ok_or(SvcError::Unprocessable("ffprobe found no video stream"))?;
+            return Ok((info, server_origin(&url)));
+        }
+    }
+    metrics::record_processing_error("probe_failed");
+    Err(SvcError::Unprocessable("video could not be probed"))
+}
+
+/// Fetch the blob and read its image header
+async fn probe_image(
+    state: &CombinedState,
+    hash: &str,
+    ext: &str,
+    query: &ProbeQuery,
+) -> Result<(MediaInfo, String), SvcError> {
+    let servers = resolve_blossom_servers(state, &query.server_hints, query.author_pubkey.as_deref()).await;
+    let (bytes, source_server) = fetch_from_blossom_servers(&state.app, &servers, hash, ext).await?;
+    Ok((image_info(&bytes)?, source_server))
+}
+
+/// Format, dimensions and EXIF rotation from an image's header; pixels are not decoded
+fn image_info(bytes: &[u8]) -> Result<MediaInfo, SvcError> {
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| SvcError::Decode(ImageError::IoError(e)))?;
+    let format = reader
+        .format()
+        .ok_or(SvcError::UnsupportedMedia("unrecognized image format"))?;
+    let mut decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let orientation = decoder
+        .exif_metadata()
+        .ok()
+        .flatten()
+        .as_deref()
+        .and_then(Orientation::from_exif_chunk)
+        .unwrap_or(Orientation::NoTransforms);
+    Ok(MediaInfo {
+        kind: "image",
+        width: Some(width),
+        height: Some(height),
+        duration: None,
+        codec: Some(format!("{:?}", format).to_ascii_lowercase()),
+        rotation: match orientation {
+            Orientation::Rotate90 | Orientation::Rotate90FlipH => 90,
+            Orientation::Rotate180 | Orientation::FlipVertical => 180,
+            Orientation::Rotate270 | Orientation::Rotate270FlipH => 270,
+            Orientation::NoTransforms | Orientation::FlipHorizontal => 0,
+        },
+        size: Some(bytes.len() as u64),
+        source: None,
+    })
+}
+
+/// The parts of ffprobe's `-of json` output that /probe reads
+#[derive(Debug, Deserialize)]
+struct FfprobeReport {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    #[serde(default)]
+    format: FfprobeFormat,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeStream {
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    tags: FfprobeTags,
+    #[serde(default)]
+    side_data_list: Vec<FfprobeSideData>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeTags {
+    rotate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeSideData {
+    rotation: Option<f64>,
+}
+
+/// ffprobe prints numbers in the format section as strings
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeFormat {
+    duration: Option<String>,
+    size: Option<String>,
+}
+
+/// `None` when the report has no video stream
+fn parse_ffprobe_report(report: &[u8]) -> Option<MediaInfo> {
+    let report: FfprobeReport = serde_json::from_slice(report).ok()?;
+    let stream = report.streams.into_iter().next()?;
+    // A display matrix turns counter-clockwise; the older `rotate` tag clockwise
+    let rotation = stream
+        .side_data_list
+        .iter()
+        .find_map(|side_data| side_data.rotation)
+        .map(|degrees| -degrees)
+        .or_else(|| stream.tags.rotate.as_deref().and_then(|r| r.parse().ok()))
+        .map_or(0, |degrees: f64| ((degrees / 90.0).round() as i64 * 90).rem_euclid(360) as u16);
+    Some(MediaInfo {
+        kind: "video",
+        width: stream.width,
+        height: stream.height,
+        duration: report.format.duration.and_then(|d| d.parse().ok()),
+        codec: stream.codec_name,
+        rotation,
+        size: report.format.size.and_then(|s| s.parse().ok()),
+        source: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ffprobe_report() {
+        let report = br#"{
+            "programs": [],
+            "streams": [{
+                "codec_name": "h264", "width": 1920, "height": 1080,
+                "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]
+            }],
+            "format": {"duration": "12.500000", "size": "4096"}
+        }"#;
+        let info = parse_ffprobe_report(report).unwrap();
+        assert_eq!(info.codec.as_deref(), Some("h264"));
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+        assert_eq!(info.rotation, 90);
+        assert_eq!(info.duration, Some(12.5));
+        assert_eq!(info.size, Some(4096));
+
+        let tagged = br#"{"streams": [{"codec_name": "vp9", "tags": {"rotate": "270"}}], "format": {}}"#;
+        assert_eq!(parse_ffprobe_report(tagged).unwrap().rotation, 270);
+        assert!(parse_ffprobe_report(br#"{"streams": [], "format": {}}"#).is_none());
+    }
+
+    #[test]
+    fn test_image_info() {
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let info = image_info(&png).unwrap();
+        assert_eq!(info.kind, "image");
+        assert_eq!((info.width, info.height), (Some(3), Some(2)));
+        assert_eq!(info.codec.as_deref(), Some("png"));
+        assert_eq!(info.size, Some(png.len() as u64));
+        assert!(image_info(b"not an image").is_err());
+    }
+}
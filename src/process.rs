@@ -0,0 +1,323 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{FromRequest, Multipart, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    admin::bearer_token,
+    cache::{build_image_response, cache_path_for, original_cache_path_for, write_original_cache, write_processed_cache},
+    config::{AppCfg, TenantCfg},
+    error::SvcError,
+    jobs::{JobHandle, JobStage},
+    memory, metrics,
+    server::{
+        build_query_string, check_max_dimension, decode_source, negotiate_format, original_cache_key,
+        parse_thumb_params, render, set_format_fallback, set_format_substituted, set_processing_warnings,
+        set_vary_accept, substitute_cache_path, with_deadline, CombinedState, Rendered, ThumbQuery,
+    },
+    thumbnail::{extract_local_video_thumbnail, sniff_video, SNIFF_LEN},
+    transform::{parse_bool, Directives, OutFmt},
+};
+
+/// Options of `POST /process` beyond the /thumb processing parameters
+#[derive(Debug, Default, Deserialize)]
+struct ProcessOptions {
+    /// Also cache the upload and result so `/thumb/<sha256>.<ext>` serves them
+    store: Option<String>,
+    /// Extension for the stored blob (default: detected from image content; required for videos)
+    ext: Option<String>,
+    /// Process in the background and stream progress from `/jobs/{id}/events` (implies `store`)
+    #[serde(rename = "async")]
+    run_async: Option<String>,
+}
+
+/// POST /process - transform an uploaded image or video and return the result
+///
+/// The body is either the raw file or `multipart/form-data` with a `file` field. Processing
+/// parameters are the /thumb query parameters. With `store=1` the upload is cached as the
+/// original of `/thumb/<sha256>.<ext>` and the result as that request's processed entry.
+/// With `async=1` the upload is stored and processed in the background; the `202` response
+/// points at the job's progress stream.
+pub async fn handle_process(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    uri: Uri,
+    req_headers: HeaderMap,
+    req: Request,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    authorize_upload(&state.app.cfg, &req_headers)?;
+
+    let params = ThumbQuery::from_uri(&uri)?;
+    let Query(options) = Query::<ProcessOptions>::try_from_uri(&uri)
+        .map_err(|_| SvcError::BadRequest("invalid query"))?;
+    let store = options.store.as_deref().map(parse_bool).transpose()?.unwrap_or(false);
+    let run_async = options.run_async.as_deref().map(parse_bool).transpose()?.unwrap_or(false);
+    let store = store || run_async;
+    let mut dirs = parse_thumb_params(&params, &state.app.cfg.directive_defaults)?;
+    check_max_dimension(&state.app.cfg, &dirs)?;
+    let negotiated = negotiate_format(&mut dirs, &req_headers);
+
+    memory::check_soft_limit(&state.app.cfg)?;
+    let started = Instant::now();
+
+    let upload = read_upload(&state, req).await?;
+    let hash = hex::encode(Sha256::digest(&upload));
+    let deadline = state.app.cfg.request_timeout;
+    let job = Upload {
+        state,
+        upload,
+        hash: hash.clone(),
+        params,
+        dirs,
+        ext: options.ext,
+        store,
+        started,
+    };
+
+    if run_async {
+        let (job_id, progress) = job.state.jobs.create();
+        let events_url = format!("/jobs/{}/events", job_id);
+        let resp = (
+            StatusCode::ACCEPTED,
+            [(header::LOCATION, events_url.clone())],
+            Json(JobAccepted { job_id, events_url, sha256: hash }),
+        )
+            .into_response();
+        tokio::spawn(async move {
+            match job.process(Some(&progress)).await {
+                Ok(processed) if processed.fallback => progress.set(JobStage::Failed {
+                    error: format!("encoding failed; only a {} substitute could be made", processed.out_fmt.name()),
+                }),
+                Ok(processed) => progress.set(JobStage::Done { url: processed.thumb_path }),
+                Err(e) => {
+                    tracing::warn!("async upload processing failed: {}", e);
+                    progress.set(JobStage::Failed { error: e.to_string() });
+                }
+            }
+        });
+        return Ok(resp);
+    }
+
+    let Processed { encoded, out_fmt, output_dims, warnings, thumb_path, fallback, substituted } =
+        with_deadline(deadline, job.process(None)).await?;
+    let mut resp = build_image_response(encoded, out_fmt.mime_type(), "upload", Some(output_dims));
+    if fallback {
+        set_format_fallback(&mut resp, &out_fmt);
+    } else if substituted {
+        set_format_substituted(&mut resp, &out_fmt);
+    }
+    let headers = resp.headers_mut();
+    // The result belongs to the uploader; stored copies are served from Content-Location
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Ok(value) = HeaderValue::from_str(&hash) {
+        headers.insert("x-upload-sha256", value);
+    }
+    if store && !fallback {
+        if let Ok(value) = HeaderValue::from_str(&thumb_path) {
+            headers.insert(header::CONTENT_LOCATION, value);
+        }
+    }
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    set_processing_warnings(&mut resp, &warnings);
+    Ok(resp)
+}
+
+/// Body of the `202 Accepted` response to `async=1`
+#[derive(Serialize)]
+struct JobAccepted {
+    job_id: String,
+    /// Server-sent progress events; `done` carries the cached `/thumb` URL
+    events_url: String,
+    sha256: String,
+}
+
+/// An upload that has been read and validated, ready to process
+struct Upload {
+    state: CombinedState,
+    upload: Bytes,
+    /// Hex sha256 of the upload
+    hash: String,
+    params: ThumbQuery,
+    dirs: Directives,
+    ext: Option<String>,
+    store: bool,
+    started: Instant,
+}
+
+/// Result of processing an upload
+struct Processed {
+    encoded: Bytes,
+    /// Format actually encoded
+    out_fmt: OutFmt,
+    output_dims: (u32, u32),
+    warnings: Vec<String>,
+    /// `/thumb` URL the result is cached under when stored
+    thumb_path: String,
+    /// Whether the requested encoder failed; fallback output is never stored
+    fallback: bool,
+    /// Whether a transparent JPEG result was encoded as `JPEG_ALPHA_FORMAT`
+    substituted: bool,
+}
+
+impl Upload {
+    /// Thumbnail the upload and, with `store`, cache it and the result for /thumb
+    async fn process(self, progress: Option<&JobHandle>) -> Result<Processed, SvcError> {
+        let Upload { state, upload, hash, params, dirs, ext, store, started } = self;
+        let report = |stage: JobStage| {
+            if let Some(progress) = progress {
+                progress.set(stage);
+            }
+        };
+        let is_video = sniff_video(&upload[..upload.len().min(SNIFF_LEN)]);
+
+        // Videos are reduced to a frame first; the frame stands in for the original like on /insecure
+        let img_bytes = if is_video {
+            if !state.app.cfg.video_support {
+                metrics::record_processing_error("video_disabled");
+                return Err(SvcError::UnsupportedMedia("video support is disabled"));
+            }
+            report(JobStage::Extracting);
+            let file = tempfile::NamedTempFile::new()?;
+            tokio::fs::write(file.path(), &upload).await?;
+            let seek = dirs.video_seek.unwrap_or(state.app.cfg.video_thumb_second);
+            extract_local_video_thumbnail(file.path(), seek, &state.thumbnail, &state.app).await?
+        } else {
+            if upload.len() > state.app.cfg.max_image_bytes {
+                metrics::record_processing_error("image_too_large");
+                return Err(SvcError::PayloadTooLarge("image exceeds MAX_IMAGE_BYTES"));
+            }
+            upload.to_vec()
+        };
+
+        let ext = match ext {
+            Some(ext) => ext.trim_start_matches('.').to_ascii_lowercase(),
+            None if is_video => String::new(),
+            None => image::guess_format(&img_bytes)
+                .ok()
+                .and_then(|f| f.extensions_str().first())
+                .map(|ext| ext.to_string())
+                .unwrap_or_default(),
+        };
+        if store && (ext.is_empty() || !ext.chars().all(|c| c.is_ascii_alphanumeric())) {
+            return Err(SvcError::BadRequest("store needs a valid ext for this upload"));
+        }
+
+        report(JobStage::Encoding);
+        let permit = state.app.image_pool.acquire().await?;
+        let decoded = decode_source(&img_bytes, &dirs, &state.app.cfg)?;
+        let _decoded = memory::track_decoded(decoded.pixel_bytes());
+        let Rendered { encoded, output_dims, warnings, fallback, substituted } = render(decoded, &dirs)?;
+        drop(permit);
+        let is_fallback = fallback.is_some();
+        let is_substituted = substituted.is_some();
+        let out_fmt = fallback.or(substituted).unwrap_or_else(|| dirs.out_fmt.clone());
+
+        let out_fmt_str = out_fmt.name();
+        let tenant = state.app.cfg.metrics_label();
+        metrics::observe_processing_duration(tenant, "/process", out_fmt_str, started.elapsed().as_secs_f64());
+        if is_video {
+            metrics::record_video_processed(out_fmt_str);
+        } else {
+            metrics::record_image_processed(out_fmt_str);
+        }
+
+        let encoded = Bytes::from(encoded);
+        let thumb_path = format!("/thumb/{}.{}?{}", hash, ext, build_query_string(&params));
+        // A substitute in another format would poison the requested format's cache entry
+        if store && !is_fallback {
+            // Keyed like /thumb: a video's frame under the second it was taken at
+            let filename = format!("{}.{}", hash, ext);
+            let seek = dirs.video_seek.unwrap_or(state.app.cfg.video_thumb_second);
+            let original_key = original_cache_key(&dirs, &filename, None, seek);
+            let original_cache_path = original_cache_path_for(&state.app.cfg, &original_key);
+            write_original_cache(&state.app, &original_cache_path, &img_bytes).await?;
+            let mut cache_path = cache_path_for(&state.app.cfg, &thumb_path, &dirs.out_fmt);
+            if is_substituted {
+                cache_path = substitute_cache_path(&cache_path, &out_fmt);
+            }
+            write_processed_cache(&state.app, &cache_path, &encoded, Some(output_dims)).await?;
+        }
+        Ok(Processed {
+            encoded,
+            out_fmt,
+            output_dims,
+            warnings,
+            thumb_path,
+            fallback: is_fallback,
+            substituted: is_substituted,
+        })
+    }
+}
+
+/// Require the upload token (`Authorization: Bearer <UPLOAD_TOKEN>`)
+fn authorize_upload(cfg: &AppCfg, req_headers: &HeaderMap) -> Result<(), SvcError> {
+    let Some(ref token) = cfg.upload_token else {
+        return Err(SvcError::Forbidden("uploads are disabled"));
+    };
+    if bearer_token(req_headers) == Some(token.as_str()) {
+        Ok(())
+    } else {
+        Err(SvcError::Forbidden("invalid upload token"))
+    }
+}
+
+/// Read the uploaded file from a raw or `multipart/form-data` body
+async fn read_upload(state: &CombinedState, req: Request) -> Result<Bytes, SvcError> {
+    let is_multipart = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("multipart/form-data"));
+
+    let upload = if is_multipart {
+        let mut multipart = Multipart::from_request(req, state)
+            .await
+            .map_err(|_| SvcError::BadRequest("invalid multipart body"))?;
+        let mut file = None;
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| body_error(e.status(), "invalid multipart body"))?
+        {
+            // The `file` field, or else the first field carrying a file name
+            if field.name() == Some("file") || field.file_name().is_some() {
+                file = Some(
+                    field
+                        .bytes()
+                        .await
+                        .map_err(|e| body_error(e.status(), "invalid multipart body"))?,
+                );
+                break;
+            }
+        }
+        file.ok_or(SvcError::BadRequest("multipart body has no file field"))?
+    } else {
+        Bytes::from_request(req, state)
+            .await
+            .map_err(|e| body_error(e.status(), "could not read request body"))?
+    };
+
+    if upload.is_empty() {
+        return Err(SvcError::BadRequest("empty upload"));
+    }
+    Ok(upload)
+}
+
+/// Map a body read failure, keeping the 413 from the body size limit
+fn body_error(status: StatusCode, otherwise: &'static str) -> SvcError {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        SvcError::PayloadTooLarge("upload exceeds MAX_UPLOAD_BYTES")
+    } else {
+        SvcError::BadRequest(otherwise)
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path as AxPath, Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::Response,
+    Extension,
+};
+use serde::Deserialize;
+
+use crate::{
+    blossom::{BlossomState, Profile},
+    cache::cache_path_for,
+    config::{AppCfg, TenantCfg},
+    error::SvcError,
+    server::{
+        check_max_dimension, generate_insecure, journaled, negotiate_format, parse_thumb_params, set_vary_accept,
+        try_serve_processed, with_deadline, CombinedState, ServerHintsQuery, ThumbQuery,
+    },
+    singleflight::namespaced_key,
+    transform::{OutFmt, Resize, ResizeMode},
+};
+
+/// Edge lengths accepted for /avatar `size=` and `AVATAR_SIZE`
+pub const AVATAR_SIZES: RangeInclusive<u32> = 16..=1024;
+/// Widths accepted for /banner `size=` and `BANNER_WIDTH`
+pub const BANNER_WIDTHS: RangeInclusive<u32> = 64..=2048;
+/// Width-to-height ratio of /banner images
+const BANNER_ASPECT: u32 = 3;
+
+/// Query parameters for /avatar, /banner and /identicon
+#[derive(Debug, Deserialize)]
+pub struct ProfileImageQuery {
+    /// Avatar edge length or banner width in pixels (default `AVATAR_SIZE` / `BANNER_WIDTH`)
+    pub(crate) size: Option<u32>,
+    /// Output format (e.g., "webp", "png", "auto")
+    #[serde(rename = "f")]
+    pub(crate) format: Option<String>,
+}
+
+/// Which kind 0 image a profile endpoint serves, and how it is cut
+#[derive(Debug, Clone, Copy)]
+enum ProfileImage {
+    /// `picture`, square and round
+    Avatar,
+    /// `banner`, a wide 3:1 strip
+    Banner,
+}
+
+impl ProfileImage {
+    fn name(self) -> &'static str {
+        match self {
+            ProfileImage::Avatar => "avatar",
+            ProfileImage::Banner => "banner",
+        }
+    }
+
+    /// Output size for a `size=`, or an error when it is out of range
+    fn dimensions(self, size: u32) -> Result<(u32, u32), SvcError> {
+        match self {
+            ProfileImage::Avatar if AVATAR_SIZES.contains(&size) => Ok((size, size)),
+            ProfileImage::Avatar => Err(SvcError::BadRequest("size must be 16-1024")),
+            ProfileImage::Banner if BANNER_WIDTHS.contains(&size) => Ok((size, size / BANNER_ASPECT)),
+            ProfileImage::Banner => Err(SvcError::BadRequest("size must be 64-2048")),
+        }
+    }
+
+    fn url(self, profile: Profile) -> Option<String> {
+        match self {
+            ProfileImage::Avatar => profile.picture,
+            ProfileImage::Banner => profile.banner,
+        }
+    }
+}
+
+/// GET /avatar/{pubkey} - an author's kind 0 profile picture as a round, square thumbnail
+///
+/// The picture is filled to `size`x`size` and cut to a circle with transparent corners.
+pub async fn handle_avatar(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath(pubkey): AxPath<String>,
+    Query(query): Query<ProfileImageQuery>,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    serve_profile_image(state, ProfileImage::Avatar, &pubkey, query, req_headers).await
+}
+
+/// GET /banner/{pubkey} - an author's kind 0 banner, filled to a `size`-wide 3:1 strip
+pub async fn handle_banner(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath(pubkey): AxPath<String>,
+    Query(query): Query<ProfileImageQuery>,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    serve_profile_image(state, ProfileImage::Banner, &pubkey, query, req_headers).await
+}
+
+/// Shared pipeline of the profile endpoints
+///
+/// Results are cached per pubkey in `PROFILE_TTL_SECS` windows, so a changed profile image
+/// shows up within one window without purging anything.
+async fn serve_profile_image(
+    state: CombinedState,
+    kind: ProfileImage,
+    pubkey: &str,
+    query: ProfileImageQuery,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let cfg = &state.app.cfg;
+    let pubkey = BlossomState::parse_pubkey(pubkey).map_err(|_| SvcError::BadRequest("invalid pubkey"))?;
+    let size = query.size.unwrap_or(match kind {
+        ProfileImage::Avatar => cfg.avatar_size,
+        ProfileImage::Banner => cfg.banner_width,
+    });
+    let (w, h) = kind.dimensions(size)?;
+
+    // Fixed preset: fill around the center, /thumb defaults for the rest
+    let mut dirs = parse_thumb_params(&ThumbQuery::default(), &cfg.directive_defaults)?;
+    if let Some(ref fmt) = query.format {
+        dirs.out_fmt = OutFmt::from_name(fmt)?;
+    }
+    dirs.resize = Resize {
+        mode: ResizeMode::Fill,
+        w,
+        h,
+    };
+    dirs.round_mask = matches!(kind, ProfileImage::Avatar);
+    check_max_dimension(cfg, &dirs)?;
+    let negotiated = negotiate_format(&mut dirs, &req_headers);
+
+    // The TTL window is part of the key, so every cache layer moves on when it ends
+    let (window, secs_left) = ttl_window(cfg);
+    let cache_key = format!(
+        "/{}/{}?size={}&f={}&window={}",
+        kind.name(),
+        pubkey.to_hex(),
+        size,
+        dirs.out_fmt.name(),
+        window
+    );
+    let cache_path = cache_path_for(cfg, &cache_key, &dirs.out_fmt);
+
+    let cached = try_serve_processed(&state.app, &cache_path, &dirs, &req_headers).await?;
+    let mut resp = match cached {
+        Some(resp) => resp,
+        None => {
+            let profile = state
+                .blossom
+                .get_author_profile(&pubkey, cfg.profile_ttl)
+                .await
+                .map_err(|e| {
+                    tracing::warn!("profile lookup for {} failed: {}", pubkey, e);
+                    SvcError::UpstreamError(504)
+                })?;
+            let src_url = kind.url(profile).ok_or(match kind {
+                ProfileImage::Avatar => SvcError::NotFound("profile has no picture"),
+                ProfileImage::Banner => SvcError::NotFound("profile has no banner"),
+            })?;
+            let inflight_key = namespaced_key(&cfg.cache_namespace, &format!("{}#{}", cache_key, dirs.out_fmt.name()));
+            let pipeline = journaled(
+                src_url.clone(),
+                with_deadline(
+                    cfg.request_timeout,
+                    generate_insecure(state.clone(), src_url, dirs, ServerHintsQuery::default(), cache_path, false),
+                ),
+            );
+            state.inflight.run(inflight_key, pipeline).await
+        }
+    };
+
+    set_window_cache_control(&mut resp, secs_left);
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    Ok(resp)
+}
+
+/// Current `PROFILE_TTL_SECS` window: its index for cache keys and the seconds left in it
+pub(crate) fn ttl_window(cfg: &AppCfg) -> (u64, u64) {
+    let ttl = cfg.profile_ttl.as_secs().max(1);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    (now / ttl, ttl - now % ttl)
+}
+
+/// Let browsers and CDNs keep a successful result until the current window ends
+pub(crate) fn set_window_cache_control(resp: &mut Response, secs_left: u64) {
+    if resp.status().is_success() {
+        let cache_control = format!("public, max-age={}", secs_left);
+        resp.headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_str(&cache_control).unwrap());
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{admin::authorize_admin, error::SvcError, server::CombinedState};
+
+/// Longest capture accepted, so a typo can't pin the sampler for hours
+const MAX_PROFILE_SECS: u64 = 60;
+/// Sampling rate; a prime avoids lockstep with periodic work
+#[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+const SAMPLE_HZ: i32 = 99;
+
+/// Only one capture at a time: the sampler is process-wide
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Clears `PROFILING` when the capture ends
+struct ProfilingGuard;
+
+impl Drop for ProfilingGuard {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    /// Capture length in seconds (default 10, max 60)
+    seconds: Option<u64>,
+    /// `flamegraph` (SVG, default) or `pprof` (protobuf for `go tool pprof`)
+    format: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ProfileFormat {
+    Flamegraph,
+    Pprof,
+}
+
+/// GET /admin/profile - sample CPU stacks for N seconds and return a flamegraph or pprof profile
+pub async fn handle_profile(
+    State(state): State<CombinedState>,
+    req_headers: HeaderMap,
+    Query(query): Query<ProfileQuery>,
+) -> Result<Response, SvcError> {
+    authorize_admin(&state.app.cfg, &req_headers)?;
+
+    let seconds = query.seconds.unwrap_or(10);
+    if seconds == 0 || seconds > MAX_PROFILE_SECS {
+        return Err(SvcError::BadRequest("seconds must be 1-60"));
+    }
+    let format = match query.format.as_deref() {
+        None | Some("flamegraph") | Some("svg") => ProfileFormat::Flamegraph,
+        Some("pprof") | Some("proto") => ProfileFormat::Pprof,
+        Some(_) => return Err(SvcError::BadRequest("format must be flamegraph or pprof")),
+    };
+
+    if PROFILING.swap(true, Ordering::SeqCst) {
+        return Err(SvcError::Overloaded {
+            retry_after_secs: seconds,
+            queue_depth: 1,
+        });
+    }
+    // Released by the capture itself, which outlives the request if the client disconnects
+    let busy = ProfilingGuard;
+    tracing::info!("profiling CPU for {}s ({:?})", seconds, format);
+    let result = tokio::task::spawn_blocking(move || {
+        let _busy = busy;
+        capture(seconds, format)
+    })
+    .await;
+
+    let body = result.map_err(|e| SvcError::InternalError(format!("profiler task failed: {}", e)))??;
+    let content_type = match format {
+        ProfileFormat::Flamegraph => "image/svg+xml",
+        ProfileFormat::Pprof => "application/octet-stream",
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// Sample the whole process on a blocking thread; the profiler guard is not `Send`
+#[cfg(feature = "profiling")]
+fn capture(seconds: u64, format: ProfileFormat) -> Result<Vec<u8>, SvcError> {
+    use pprof::protos::Message;
+
+    let profiler_err = |e: pprof::Error| SvcError::InternalError(format!("profiler error: {}", e));
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_HZ)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(profiler_err)?;
+    std::thread::sleep(std::time::Duration::from_secs(seconds));
+    let report = guard.report().build().map_err(profiler_err)?;
+
+    let mut body = Vec::new();
+    match format {
+        ProfileFormat::Flamegraph => report.flamegraph(&mut body).map_err(profiler_err)?,
+        ProfileFormat::Pprof => {
+            let profile = report.pprof().map_err(profiler_err)?;
+            profile
+                .encode(&mut body)
+                .map_err(|e| SvcError::InternalError(format!("pprof encode error: {}", e)))?;
+        }
+    }
+    Ok(body)
+}
+
+#[cfg(not(feature = "profiling"))]
+fn capture(_seconds: u64, _format: ProfileFormat) -> Result<Vec<u8>, SvcError> {
+    Err(SvcError::BadRequest("profiling not enabled in this build"))
+}
@@ -0,0 +1,229 @@
+use std::{fmt::Write, ops::RangeInclusive, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Response,
+    Extension,
+};
+use bytes::Bytes;
+use image::{DynamicImage, GrayImage, Luma};
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+
+use crate::{
+    cache::{
+        build_image_response, cache_path_for, etag_for, set_etag, set_last_modified, try_serve_cache,
+        write_processed_cache,
+    },
+    config::TenantCfg,
+    error::SvcError,
+    server::{
+        check_max_dimension, generate_still, negotiate_format, parse_thumb_params, set_vary_accept,
+        try_serve_processed, with_deadline, CombinedState, ThumbQuery,
+    },
+    singleflight::namespaced_key,
+    transform::{Directives, OutFmt, Resize, ResizeMode},
+};
+
+/// Edge lengths accepted for `size=`
+pub const QR_SIZES: RangeInclusive<u32> = 64..=2048;
+const DEFAULT_SIZE: u32 = 512;
+/// Light modules around the code, as the QR spec asks for
+const QUIET_ZONE: usize = 4;
+/// Longest `data=` accepted, in bytes; a version 40 code holds at most 2953
+const MAX_DATA_BYTES: usize = 2953;
+const SVG_MIME: &str = "image/svg+xml";
+
+/// Query parameters for /qr
+#[derive(Debug, Deserialize)]
+pub struct QrQuery {
+    /// Encoded text (npub, nostr: URI, lightning invoice, URL, ...)
+    data: String,
+    /// Edge length in pixels (default 512)
+    size: Option<u32>,
+    /// Error correction: l, m (default), q or h
+    ec: Option<String>,
+    /// Output format: png (default), webp, jpeg, auto or svg
+    #[serde(rename = "f")]
+    format: Option<String>,
+}
+
+/// GET /qr?data=... - a square QR code for `data`, black on white
+///
+/// Raster formats go through the regular encoders and processed cache; `f=svg` returns a
+/// vector image whose modules scale without blurring.
+pub async fn handle_qr(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    Query(query): Query<QrQuery>,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    let cfg = &state.app.cfg;
+    if query.data.is_empty() {
+        return Err(SvcError::BadRequest("data must not be empty"));
+    }
+    if query.data.len() > MAX_DATA_BYTES {
+        return Err(SvcError::BadRequest("data is too long for a QR code"));
+    }
+    let size = query.size.unwrap_or(DEFAULT_SIZE);
+    if !QR_SIZES.contains(&size) {
+        return Err(SvcError::BadRequest("size must be 64-2048"));
+    }
+    let ec = parse_ec_level(query.ec.as_deref().unwrap_or("m"))?;
+    let svg = query.format.as_deref().is_some_and(|f| f.eq_ignore_ascii_case("svg"));
+
+    let mut dirs = parse_thumb_params(&ThumbQuery::default(), &cfg.directive_defaults)?;
+    dirs.out_fmt = match query.format {
+        Some(ref fmt) if !svg => OutFmt::from_name(fmt)?,
+        _ => OutFmt::Png,
+    };
+    dirs.resize = Resize {
+        mode: ResizeMode::Fit,
+        w: size,
+        h: size,
+    };
+    check_max_dimension(cfg, &dirs)?;
+    let negotiated = !svg && negotiate_format(&mut dirs, &req_headers);
+
+    // Debug formatting quotes the data, so no payload can imitate another parameter
+    let format_name = if svg { "svg" } else { dirs.out_fmt.name() };
+    let cache_key = format!("/qr#{:?}", (&query.data, size, ec_name(ec), format_name));
+    let mut cache_path = cache_path_for(cfg, &cache_key, &dirs.out_fmt);
+    if svg {
+        cache_path.set_extension("svg");
+        if let Some(resp) = try_serve_cache(&state.app, &cache_path, SVG_MIME, &req_headers).await? {
+            return Ok(resp);
+        }
+    } else if let Some(resp) = try_serve_processed(&state.app, &cache_path, &dirs, &req_headers).await? {
+        return Ok(finish(resp, negotiated));
+    }
+
+    let code = QrCode::with_error_correction_level(query.data.as_bytes(), ec)
+        .map_err(|_| SvcError::BadRequest("data is too long for a QR code"))?;
+    let inflight_key = namespaced_key(&cfg.cache_namespace, &format!("{}#{}", cache_key, format_name));
+    let pipeline = with_deadline(cfg.request_timeout, generate_qr(state.clone(), code, size, svg, dirs, cache_path));
+    let resp = state.inflight.run(inflight_key, pipeline).await;
+    Ok(finish(resp, negotiated))
+}
+
+fn finish(mut resp: Response, negotiated: bool) -> Response {
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    resp
+}
+
+/// Draw the code, then encode and cache it
+async fn generate_qr(
+    state: CombinedState,
+    code: QrCode,
+    size: u32,
+    svg: bool,
+    dirs: Directives,
+    cache_path: PathBuf,
+) -> Result<Response, SvcError> {
+    if !svg {
+        let img = DynamicImage::ImageLuma8(draw_modules(&code, size));
+        return generate_still(&state.app, img, &dirs, &cache_path).await;
+    }
+
+    let body = Bytes::from(svg_document(&code, size));
+    let modified = write_processed_cache(&state.app, &cache_path, &body, Some((size, size))).await?;
+    let etag = etag_for(&body);
+    let mut resp = build_image_response(body, SVG_MIME, "miss", Some((size, size)));
+    set_etag(&mut resp, &etag);
+    set_last_modified(&mut resp, modified);
+    Ok(resp)
+}
+
+fn parse_ec_level(name: &str) -> Result<EcLevel, SvcError> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "l" => EcLevel::L,
+        "m" => EcLevel::M,
+        "q" => EcLevel::Q,
+        "h" => EcLevel::H,
+        _ => return Err(SvcError::BadRequest("ec must be l, m, q or h")),
+    })
+}
+
+fn ec_name(ec: EcLevel) -> &'static str {
+    match ec {
+        EcLevel::L => "l",
+        EcLevel::M => "m",
+        EcLevel::Q => "q",
+        EcLevel::H => "h",
+    }
+}
+
+/// Modules plus quiet zone, as a `size`x`size` image
+///
+/// Each pixel takes the module under its center, so edges stay sharp when `size` isn't a
+/// multiple of the module count; modules then differ by at most one pixel.
+fn draw_modules(code: &QrCode, size: u32) -> GrayImage {
+    let colors = code.to_colors();
+    let width = code.width();
+    let total = (width + 2 * QUIET_ZONE) as u64;
+    let module_at = |px: u32| ((2 * px as u64 + 1) * total / (2 * size as u64)) as usize;
+    GrayImage::from_fn(size, size, |x, y| {
+        let (mx, my) = (module_at(x), module_at(y));
+        let dark = (QUIET_ZONE..QUIET_ZONE + width).contains(&mx)
+            && (QUIET_ZONE..QUIET_ZONE + width).contains(&my)
+            && colors[(my - QUIET_ZONE) * width + mx - QUIET_ZONE] == Color::Dark;
+        Luma([if dark { 0 } else { 255 }])
+    })
+}
+
+/// SVG with one unit per module and a single path for the dark modules
+fn svg_document(code: &QrCode, size: u32) -> String {
+    let width = code.width();
+    let total = width + 2 * QUIET_ZONE;
+    let mut path = String::new();
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color == Color::Dark {
+            let _ = write!(path, "M{},{}h1v1h-1z", i % width + QUIET_ZONE, i / width + QUIET_ZONE);
+        }
+    }
+    format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {total} {total}" shape-rendering="crispEdges">"#,
+            r##"<rect width="{total}" height="{total}" fill="#fff"/><path d="{path}" fill="#000"/></svg>"##
+        ),
+        size = size,
+        total = total,
+        path = path
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_modules() {
+        let code = QrCode::new(b"npub1test").unwrap();
+        let img = draw_modules(&code, 256);
+        assert_eq!(img.dimensions(), (256, 256));
+        // Quiet zone corner is light, the finder pattern's corner dark
+        assert_eq!(img.get_pixel(0, 0).0, [255]);
+        let total = (code.width() + 2 * QUIET_ZONE) as u32;
+        let finder = (2 * QUIET_ZONE as u32 + 1) * 256 / (2 * total);
+        assert_eq!(img.get_pixel(finder, finder).0, [0]);
+    }
+
+    #[test]
+    fn test_svg_document() {
+        let code = QrCode::new(b"https://example.com").unwrap();
+        let svg = svg_document(&code, 300);
+        assert!(svg.contains(r#"width="300""#));
+        assert!(svg.contains("M4,4h1v1h-1z"));
+    }
+
+    #[test]
+    fn test_parse_ec_level() {
+        assert!(matches!(parse_ec_level("H"), Ok(EcLevel::H)));
+        assert!(parse_ec_level("x").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{error::SvcError, server::CombinedState};
+
+/// Clients tracked before buckets that have refilled are dropped
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// An address range from `TRUSTED_PROXIES` (`10.0.0.0/8`, `::1`, ...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the top `prefix` of `bits` bits match
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || a >> shift == b >> shift
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("{} is not an IP address or CIDR range", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{}: prefix must be 0-{}", s, max))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client IP: `burst` requests at once, refilled at `rate` per second
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    trusted_proxies: Vec<IpNet>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32, trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            trusted_proxies,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for `ip`, or return the seconds until one is available
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&ip) {
+            // A full bucket is the same as no bucket, so only clients still limited are kept
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            if buckets.len() >= MAX_TRACKED_CLIENTS {
+                buckets.clear();
+            }
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+        }
+    }
+
+    /// Address the request is limited by
+    ///
+    /// `X-Forwarded-For` is only believed when the connection comes from a trusted proxy; then
+    /// the right-most hop that isn't a trusted proxy is the client, since anything left of it
+    /// could have been sent by the client itself.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let hops: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+        hops.into_iter().rev().find(|hop| !self.is_trusted(*hop)).unwrap_or(peer)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Answer `429` with `Retry-After` once a client runs out of tokens, when `RATE_LIMIT_RPS` is set
+///
+/// Requests without a peer address (no `ConnectInfo`, as in tests) are never limited.
+pub async fn limit_rate(
+    State(state): State<CombinedState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, SvcError> {
+    if let Some(ref limiter) = state.rate_limiter {
+        if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            let ip = limiter.client_ip(peer.ip(), req.headers());
+            if let Err(retry_after_secs) = limiter.check(ip) {
+                tracing::debug!(client = %ip, "rate limited");
+                return Err(SvcError::RateLimited { retry_after_secs });
+            }
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(ip("1.2.3.4")));
+        assert!("::1".parse::<IpNet>().unwrap().contains(ip("::1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = RateLimiter::new(2.0, 2, Vec::new());
+        let start = Instant::now();
+        let client = ip("1.2.3.4");
+        assert!(limiter.check_at(client, start).is_ok());
+        assert!(limiter.check_at(client, start).is_ok());
+        assert_eq!(limiter.check_at(client, start), Err(1));
+        assert!(limiter.check_at(ip("5.6.7.8"), start).is_ok());
+        assert!(limiter.check_at(client, start + Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let limiter = RateLimiter::new(1.0, 1, vec!["10.0.0.0/8".parse().unwrap()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.2"));
+
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("1.2.3.4"));
+        // Untrusted peers can't pick their own address
+        assert_eq!(limiter.client_ip(ip("9.9.9.9"), &headers), ip("9.9.9.9"));
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+    }
+}
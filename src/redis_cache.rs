@@ -0,0 +1,132 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use redis::{aio::ConnectionManager, AsyncCommands};
+use tracing::warn;
+
+/// Redis-backed store for small cache entries, shared by horizontally scaled instances
+///
+/// Entries are keyed by their path relative to `CACHE_DIR`, so the disk and Redis tiers
+/// agree on keys. Values are prefixed with the write time (8 bytes, big-endian unix seconds)
+/// for `Last-Modified`. Redis handles expiry. Errors are logged and treated as misses.
+#[derive(Clone)]
+pub struct RedisCache {
+    conn: ConnectionManager,
+    /// Entries larger than this still go to disk
+    max_item_bytes: usize,
+}
+
+/// Entry read back from Redis
+pub struct RedisEntry {
+    pub bytes: Vec<u8>,
+    pub modified: SystemTime,
+    pub stale: bool,
+}
+
+impl RedisCache {
+    pub async fn connect(url: &str, max_item_bytes: usize) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self {
+            conn,
+            max_item_bytes,
+        })
+    }
+
+    /// Whether an entry of `len` bytes belongs in Redis rather than on disk
+    pub fn accepts(&self, len: usize) -> bool {
+        len <= self.max_item_bytes
+    }
+
+    fn key_for(cache_dir: &Path, path: &Path) -> String {
+        let rel = path.strip_prefix(cache_dir).unwrap_or(path);
+        format!("imgproxy:{}", rel.to_string_lossy())
+    }
+
+    fn stale_key(key: &str) -> String {
+        format!("{}:stale", key)
+    }
+
+    pub async fn get(&self, cache_dir: &Path, path: &Path) -> Option<RedisEntry> {
+        let key = Self::key_for(cache_dir, path);
+        let mut conn = self.conn.clone();
+        let result: redis::RedisResult<(Option<Vec<u8>>, bool)> = redis::pipe()
+            .get(&key)
+            .exists(Self::stale_key(&key))
+            .query_async(&mut conn)
+            .await;
+
+        match result {
+            Ok((Some(value), stale)) if value.len() >= 8 => {
+                let (ts, bytes) = value.split_at(8);
+                let secs = u64::from_be_bytes(ts.try_into().unwrap_or_default());
+                Some(RedisEntry {
+                    bytes: bytes.to_vec(),
+                    modified: UNIX_EPOCH + Duration::from_secs(secs),
+                    stale,
+                })
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("redis get {} failed: {}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Store an entry; returns false if the write failed and the caller should use disk
+    pub async fn put(&self, cache_dir: &Path, path: &Path, bytes: &[u8], ttl: Duration) -> bool {
+        let key = Self::key_for(cache_dir, path);
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs();
+        let mut value = Vec::with_capacity(8 + bytes.len());
+        value.extend_from_slice(&secs.to_be_bytes());
+        value.extend_from_slice(bytes);
+
+        let mut conn = self.conn.clone();
+        // A fresh write supersedes any soft purge
+        let result: redis::RedisResult<()> = redis::pipe()
+            .set_ex(&key, value, ttl.as_secs().max(1))
+            .ignore()
+            .del(Self::stale_key(&key))
+            .ignore()
+            .query_async(&mut conn)
+            .await;
+        if let Err(e) = result {
+            warn!("redis set {} failed: {}", key, e);
+            return false;
+        }
+        true
+    }
+
+    /// Soft purge: flag an existing entry stale for as long as it lives
+    pub async fn mark_stale(&self, cache_dir: &Path, path: &Path) -> bool {
+        let key = Self::key_for(cache_dir, path);
+        let mut conn = self.conn.clone();
+        let ttl: i64 = conn.ttl(&key).await.unwrap_or(-2);
+        if ttl == -2 {
+            return false;
+        }
+        let stale_ttl = if ttl > 0 { ttl as u64 } else { 86400 };
+        conn.set_ex::<_, _, ()>(Self::stale_key(&key), 1u8, stale_ttl)
+            .await
+            .is_ok()
+    }
+
+    /// Hard purge: delete an entry and its stale flag
+    pub async fn remove(&self, cache_dir: &Path, path: &Path) -> bool {
+        let key = Self::key_for(cache_dir, path);
+        let mut conn = self.conn.clone();
+        conn.del::<_, usize>(&[Self::stale_key(&key), key.clone()])
+            .await
+            .map(|n| n > 0)
+            .unwrap_or_else(|e| {
+                warn!("redis del {} failed: {}", key, e);
+                false
+            })
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::info;
+
+lazy_static! {
+    /// Counters for the current report interval
+    static ref CURRENT: Mutex<IntervalStats> = Mutex::new(IntervalStats::default());
+    /// Most recently completed report, served by the admin API
+    static ref LAST_REPORT: Mutex<Option<CacheReport>> = Mutex::new(None);
+}
+
+#[derive(Default)]
+struct SourceStats {
+    hits: u64,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct IntervalStats {
+    sources: HashMap<String, SourceStats>,
+    evictions: HashMap<&'static str, u64>,
+    upstream_bytes: HashMap<String, u64>,
+}
+
+/// A source entry in the top-N lists
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceEntry {
+    pub source: String,
+    pub hits: u64,
+    pub bytes: u64,
+}
+
+/// Summary of one report interval
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheReport {
+    /// Unix timestamp (seconds) of the end of the interval
+    pub generated_at: u64,
+    pub interval_secs: u64,
+    pub top_by_hits: Vec<SourceEntry>,
+    pub top_by_bytes: Vec<SourceEntry>,
+    /// Files removed by the janitor, by cache tier
+    pub evictions: HashMap<&'static str, u64>,
+    /// Bytes downloaded from upstream, by source host
+    pub upstream_bytes_by_host: HashMap<String, u64>,
+}
+
+/// Record a response served for a source (cache hit or freshly processed)
+pub fn record_source_served(source: &str, bytes: usize) {
+    let mut stats = CURRENT.lock().unwrap();
+    let entry = stats.sources.entry(source.to_string()).or_default();
+    entry.hits += 1;
+    entry.bytes += bytes as u64;
+}
+
+/// Record a cache file removed by the janitor
+pub fn record_eviction(tier: &'static str) {
+    *CURRENT.lock().unwrap().evictions.entry(tier).or_default() += 1;
+}
+
+/// Record bytes downloaded from an upstream URL, attributed to its host
+pub fn record_upstream_bytes(url: &str, bytes: usize) {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string());
+    *CURRENT.lock().unwrap().upstream_bytes.entry(host).or_default() += bytes as u64;
+}
+
+/// Hit counters of the current interval, for the shutdown snapshot
+pub fn current_sources() -> Vec<SourceEntry> {
+    CURRENT
+        .lock()
+        .unwrap()
+        .sources
+        .iter()
+        .map(|(source, s)| SourceEntry {
+            source: source.clone(),
+            hits: s.hits,
+            bytes: s.bytes,
+        })
+        .collect()
+}
+
+/// Add the hit counters a previous run snapshotted to the current interval
+pub fn restore_sources(sources: Vec<SourceEntry>) {
+    let mut stats = CURRENT.lock().unwrap();
+    for entry in sources {
+        let s = stats.sources.entry(entry.source).or_default();
+        s.hits += entry.hits;
+        s.bytes += entry.bytes;
+    }
+}
+
+/// Most recently completed report, if any interval has finished yet
+pub fn last_report() -> Option<CacheReport> {
+    LAST_REPORT.lock().unwrap().clone()
+}
+
+/// Close the current interval and turn its counters into a report
+fn take_report(interval: Duration, top_n: usize) -> CacheReport {
+    let stats = std::mem::take(&mut *CURRENT.lock().unwrap());
+
+    let mut sources: Vec<SourceEntry> = stats
+        .sources
+        .into_iter()
+        .map(|(source, s)| SourceEntry {
+            source,
+            hits: s.hits,
+            bytes: s.bytes,
+        })
+        .collect();
+
+    sources.sort_by_key(|s| Reverse(s.hits));
+    let top_by_hits = sources.iter().take(top_n).cloned().collect();
+    sources.sort_by_key(|s| Reverse(s.bytes));
+    let top_by_bytes = sources.into_iter().take(top_n).collect();
+
+    CacheReport {
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs(),
+        interval_secs: interval.as_secs(),
+        top_by_hits,
+        top_by_bytes,
+        evictions: stats.evictions,
+        upstream_bytes_by_host: stats.upstream_bytes,
+    }
+}
+
+/// Background loop that periodically logs and publishes a cache report
+pub async fn report_loop(interval: Duration, top_n: usize) {
+    loop {
+        sleep(interval).await;
+
+        let report = take_report(interval, top_n);
+        info!(
+            top_by_hits = ?report.top_by_hits,
+            top_by_bytes = ?report.top_by_bytes,
+            evictions = ?report.evictions,
+            upstream_bytes_by_host = ?report.upstream_bytes_by_host,
+            "cache report for last {}s",
+            report.interval_secs
+        );
+        *LAST_REPORT.lock().unwrap() = Some(report);
+    }
+}
@@ -1,25 +1,61 @@
 use axum::{
-    body::Body,
-    extract::{Path as AxPath, Query, State},
-    http::{header, HeaderValue, StatusCode},
+    body::{Body, HttpBody},
+    extract::{DefaultBodyLimit, Path as AxPath, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware,
     response::Response,
-    routing::get,
-    Router,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use bytes::{Bytes, BytesMut};
+use image::{DynamicImage, Frame, GenericImageView};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use bytes::Bytes;
-use http::HeaderName;
-use serde::Deserialize;
-use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
 
 use crate::{
-    blossom::{combine_server_lists, BlossomState},
-    cache::{cache_path_for, original_cache_path_for, try_read_original_cache, try_serve_cache, write_cache_atomic},
-    config::AppState,
+    admin::{self, authorize_admin},
+    api_key,
+    blossom::{combine_server_lists, server_origin, BlossomState},
+    card,
+    cache::{
+        build_image_response, cache_path_for, etag_for, original_cache_path_for, pin_if_excluded,
+        set_etag, set_last_modified, try_read_original_cache, try_serve_cache,
+        write_original_cache, write_processed_cache,
+    },
+    config::{AppCfg, AppState, TenantCfg},
+    debug_trace::{self, run_traced, DebugQuery},
     error::SvcError,
-    metrics,
-    thumbnail::{extract_video_thumbnail, is_video_url, ThumbnailState},
-    transform::{apply_resize, encode_image, parse_rest, Directives, OutFmt, Resize, ResizeMode},
+    error_journal, identicon,
+    jobs::{self, Jobs},
+    memory, metrics, preview, probe, process, profile, profiling, qr,
+    rate_limit::{self, RateLimiter},
+    report, shadow,
+    singleflight::{namespaced_key, InFlight},
+    source_limit::SourceLimiter,
+    storyboard, svg,
+    tenant,
+    thumbnail::{
+        extract_video_thumbnail, has_media_extension, is_video_extension, is_video_url, sniff_source_kind,
+        ThumbnailState, DEFAULT_VIDEO_SEEK,
+    },
+    transform::{
+        apply_aspect_ratio, apply_background, apply_directives, apply_directives_to_frames, check_padded_size,
+        check_source_resolution,
+        decode_frames, decode_image, encode_animation, encode_image, has_transparency, is_animated, parse_aspect_ratio,
+        parse_background, parse_bool, parse_colors, parse_crop, parse_extend, parse_frames, parse_padding, parse_rest,
+        parse_rotation, parse_saturation, parse_text, parse_video_seek, validate_encoded, DirectiveDefaults, Directives,
+        Frames, Gravity, OutFmt, Resize, ResizeMode, SourceKind,
+    },
+    upstream_auth,
+    variants::{family_key, VariantIndex},
 };
 
 /// Combined state for image and video processing
@@ -28,6 +64,24 @@ pub struct CombinedState {
     pub app: AppState,
     pub thumbnail: Arc<ThumbnailState>,
     pub blossom: Arc<BlossomState>,
+    /// Processed-cache misses currently being generated, by cache key
+    pub inflight: Arc<InFlight>,
+    /// Per-source cap on concurrently processed variants
+    pub source_limiter: Arc<SourceLimiter>,
+    /// Progress of async `/process` jobs
+    pub jobs: Arc<Jobs>,
+    /// Per-client request budget (None when `RATE_LIMIT_RPS` is 0)
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl CombinedState {
+    /// State running with the selected tenant's configuration (the global one when none matched)
+    pub fn for_tenant(mut self, tenant: Option<Extension<Arc<TenantCfg>>>) -> Self {
+        if let Some(Extension(tenant)) = tenant {
+            self.app.cfg = tenant.cfg.clone();
+        }
+        self
+    }
 }
 
 /// Create the Axum router with all routes
@@ -36,10 +90,28 @@ pub fn create_router(
     thumbnail_state: Arc<ThumbnailState>,
     blossom_state: Arc<BlossomState>,
 ) -> Router {
+    let source_limiter = Arc::new(SourceLimiter::new(
+        state.cfg.max_concurrent_per_source,
+        state.cfg.max_queue_per_source,
+        thumbnail_state.retry_after_secs,
+    ));
+    let rate_limiter = (state.cfg.rate_limit_rps > 0.0).then(|| {
+        Arc::new(RateLimiter::new(
+            state.cfg.rate_limit_rps,
+            state.cfg.rate_limit_burst,
+            state.cfg.trusted_proxies.clone(),
+        ))
+    });
+    let tenants: tenant::Tenants = Arc::new(state.cfg.tenants.clone());
+    let max_upload_bytes = state.cfg.max_upload_bytes;
     let combined = CombinedState {
         app: state,
         thumbnail: thumbnail_state,
         blossom: blossom_state,
+        inflight: Arc::new(InFlight::default()),
+        source_limiter,
+        jobs: Arc::new(Jobs::default()),
+        rate_limiter,
     };
 
     // CORS layer - allow all origins
@@ -48,18 +120,58 @@ pub fn create_router(
         .allow_methods(Any)
         .allow_headers(Any);
 
-    Router::new()
+    // Processing endpoints, rate limited and behind API_KEYS when configured
+    let processing = Router::new()
         .route("/insecure/{*rest}", get(handle_insecure))
+        .route("/{signature}/{*rest}", get(handle_signed))
         .route("/thumb/{filename}", get(handle_thumb))
+        .route("/preview/{filename}", get(preview::handle_preview))
+        .route("/storyboard/{filename}", get(storyboard::handle_storyboard))
+        .route("/probe/{filename}", get(probe::handle_probe))
+        .route("/avatar/{pubkey}", get(profile::handle_avatar))
+        .route("/banner/{pubkey}", get(profile::handle_banner))
+        .route("/card", get(card::handle_card))
+        .route("/qr", get(qr::handle_qr))
+        .route("/identicon/{pubkey}", get(identicon::handle_identicon))
+        .route_layer(middleware::from_fn(shadow::mirror_sample))
+        .route_layer(middleware::from_fn_with_state(combined.clone(), api_key::require_api_key))
+        .route_layer(middleware::from_fn_with_state(combined.clone(), rate_limit::limit_rate));
+
+    let router = Router::new()
+        .merge(processing)
+        .route(
+            "/process",
+            post(process::handle_process)
+                .layer(DefaultBodyLimit::max(max_upload_bytes))
+                .layer(middleware::from_fn_with_state(combined.clone(), rate_limit::limit_rate)),
+        )
+        .route("/jobs/{id}/events", get(jobs::handle_job_events))
         .route("/health", get(health_check))
+        .route("/health/details", get(health_details))
+        .route("/version", get(handle_version))
         .route("/metrics", get(handle_metrics))
+        .route("/admin/cache-report", get(admin::handle_cache_report))
+        .route("/admin/errors", get(admin::handle_errors).delete(admin::handle_clear_errors))
+        .route("/admin/purge", post(admin::handle_purge))
+        .route("/admin/pin", post(admin::handle_pin))
+        .route("/admin/unpin", post(admin::handle_unpin))
+        .route("/admin/profile", get(profiling::handle_profile))
+        .route_layer(middleware::from_fn(metrics::track_http))
         .with_state(combined)
-        .layer(cors)
+        .layer(cors);
+
+    if tenants.is_empty() {
+        return router;
+    }
+    // Tenant selection may strip a path prefix, so it has to run before routing
+    Router::new()
+        .fallback_service(router)
+        .layer(middleware::from_fn_with_state(tenants, tenant::select_tenant))
 }
 
 /// Query parameters for /thumb endpoint
-#[derive(Debug, Deserialize)]
-struct ThumbQuery {
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ThumbQuery {
     /// Output format (e.g., "webp", "jpeg", "png", "avif")
     #[serde(rename = "f")]
     format: Option<String>,
@@ -72,13 +184,189 @@ struct ThumbQuery {
     #[serde(rename = "q")]
     quality: Option<u8>,
 
-    /// Server hints (can be multiple)
-    #[serde(rename = "xs", default)]
+    /// Server hints from `xs`, filled in by `ThumbQuery::from_uri` (repeated and/or comma-separated)
+    #[serde(skip)]
     server_hints: Vec<String>,
 
     /// Author pubkey for Nostr-based lookup
     #[serde(rename = "as")]
     author_pubkey: Option<String>,
+
+    /// Palette size for quantized PNG output (2-256)
+    colors: Option<String>,
+
+    /// Crop applied before resizing (e.g., "400:300" or "400:300:120:40")
+    #[serde(rename = "c")]
+    crop: Option<String>,
+
+    /// Gravity for fill crops (e.g., "no", "soea", "ce")
+    #[serde(rename = "g")]
+    gravity: Option<String>,
+
+    /// Saturation in percent (0-200)
+    #[serde(rename = "sat")]
+    saturation: Option<String>,
+
+    /// Shorthand for `sat=0`; any value (or none) enables it
+    grayscale: Option<String>,
+
+    /// Clockwise rotation in degrees (90, 180, 270)
+    #[serde(rename = "rot")]
+    rotation: Option<String>,
+
+    /// Keep EXIF Artist/Copyright in the output (1/0, t/f, true/false)
+    #[serde(rename = "km")]
+    keep_metadata: Option<String>,
+
+    /// Progressive JPEG output (1/0, t/f, true/false)
+    progressive: Option<String>,
+
+    /// Background color for transparent areas (RRGGBB)
+    #[serde(rename = "bg")]
+    background: Option<String>,
+
+    /// Extend to the exact `rs` canvas (e.g., "1" or "1:no")
+    #[serde(rename = "ex")]
+    extend: Option<String>,
+
+    /// Padding in pixels: top[:right[:bottom[:left]]]
+    #[serde(rename = "pd")]
+    padding: Option<String>,
+
+    /// Frames of an animated source to keep: "first" or "all"
+    frames: Option<String>,
+
+    /// Text overlay: base64url text[:size[:color[:gravity]]]
+    #[serde(rename = "txt")]
+    text: Option<String>,
+
+    /// Output aspect ratio as w:h (e.g., "16:9"), with one of the `rs` dimensions left at 0
+    #[serde(rename = "ar")]
+    aspect_ratio: Option<String>,
+
+    /// Second of an uploaded video its frame is taken at (`POST /process`)
+    #[serde(rename = "t")]
+    video_seek: Option<String>,
+}
+
+impl ThumbQuery {
+    /// Parse the query of a /thumb request
+    ///
+    /// List parameters are collected separately since client URL builders differ in
+    /// whether they repeat the key or join the values with commas.
+    pub(crate) fn from_uri(uri: &Uri) -> Result<Self, SvcError> {
+        let Query(mut params) = Query::<ThumbQuery>::try_from_uri(uri)
+            .map_err(|_| SvcError::BadRequest("invalid query"))?;
+        params.server_hints = parse_list_param(uri.query(), "xs");
+        Ok(params)
+    }
+}
+
+/// Blossom server hints accepted on /insecure, mirroring the `xs`/`as` parameters of /thumb
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ServerHintsQuery {
+    /// Server hints from `xs`, filled in by `ServerHintsQuery::from_uri`
+    #[serde(skip)]
+    server_hints: Vec<String>,
+
+    /// Author pubkey for Nostr-based lookup
+    #[serde(rename = "as")]
+    author_pubkey: Option<String>,
+
+    /// Expected sha256 of the source (the imeta `x` tag), lowercase hex
+    #[serde(rename = "x")]
+    expected_hash: Option<String>,
+}
+
+impl ServerHintsQuery {
+    fn from_uri(uri: &Uri) -> Result<Self, SvcError> {
+        let Query(mut params) = Query::<ServerHintsQuery>::try_from_uri(uri)
+            .map_err(|_| SvcError::BadRequest("invalid query"))?;
+        params.server_hints = parse_list_param(uri.query(), "xs");
+        if let Some(ref mut hash) = params.expected_hash {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(SvcError::BadRequest("x must be a sha256 hex digest"));
+            }
+            hash.make_ascii_lowercase();
+        }
+        Ok(params)
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.has_server_hints() && self.expected_hash.is_none()
+    }
+
+    fn has_server_hints(&self) -> bool {
+        !self.server_hints.is_empty() || self.author_pubkey.is_some()
+    }
+
+    /// Canonical query string for cache keys (empty without hints)
+    fn canonical(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.server_hints.is_empty() {
+            parts.push(format!("xs={}", self.server_hints.join(",")));
+        }
+        if let Some(ref as_) = self.author_pubkey {
+            parts.push(format!("as={}", as_));
+        }
+        if let Some(ref x) = self.expected_hash {
+            parts.push(format!("x={}", x));
+        }
+        parts.join("&")
+    }
+}
+
+/// Cache key for an /insecure request; server hints are part of the key like on /thumb
+fn insecure_cache_key(rest: &str, hints: &ServerHintsQuery) -> String {
+    if hints.is_empty() {
+        format!("/insecure/{}", rest)
+    } else {
+        format!("/insecure/{}?{}", rest, hints.canonical())
+    }
+}
+
+/// Resolve the ordered Blossom server list: xs (highest priority) -> as -> fallback
+pub(crate) async fn resolve_blossom_servers(
+    state: &CombinedState,
+    server_hints: &[String],
+    author_pubkey: Option<&str>,
+) -> Vec<String> {
+    // Get author servers if pubkey provided
+    let author_servers = if let Some(pubkey) = author_pubkey {
+        match state.blossom.get_author_servers(pubkey).await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                tracing::warn!("Failed to fetch author servers for pubkey {}: {}", pubkey, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    combine_server_lists(
+        if server_hints.is_empty() {
+            None
+        } else {
+            Some(server_hints)
+        },
+        author_servers.as_deref(),
+        &state.app.fallback_servers(),
+    )
+}
+
+/// Collect all values of a list query parameter, accepting both `k=a&k=b` and `k=a,b`
+pub(crate) fn parse_list_param(query: Option<&str>, key: &str) -> Vec<String> {
+    form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .filter(|(k, _)| k == key)
+        .flat_map(|(_, v)| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
 }
 
 /// Simple health check endpoint
@@ -86,6 +374,57 @@ async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Detailed health: memory usage, queue depths and load shedding state
+#[derive(Serialize)]
+struct HealthDetails {
+    status: &'static str,
+    memory: memory::MemoryStatus,
+    queues: QueueDepths,
+}
+
+/// Requests waiting for a processing permit, so load balancers can steer traffic away
+#[derive(Serialize)]
+struct QueueDepths {
+    image: usize,
+    ffmpeg: usize,
+}
+
+async fn health_details(State(state): State<CombinedState>) -> Json<HealthDetails> {
+    let memory = memory::status(&state.app.cfg, state.app.memory_cache.as_ref());
+    Json(HealthDetails {
+        status: if memory.shedding { "shedding" } else { "ok" },
+        memory,
+        queues: QueueDepths {
+            image: state.app.image_pool.queue_depth(),
+            ffmpeg: state.thumbnail.queue_depth(),
+        },
+    })
+}
+
+/// Build and capability information
+#[derive(Serialize)]
+struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+    /// Output formats compiled into this build
+    output_formats: Vec<&'static str>,
+    /// Whether AVIF sources can be decoded
+    avif_input: bool,
+    /// Whether video thumbnails are enabled at runtime
+    video: bool,
+}
+
+/// Version and runtime capability endpoint
+async fn handle_version(State(state): State<CombinedState>) -> Json<VersionInfo> {
+    Json(VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        output_formats: OutFmt::enabled_formats(),
+        avif_input: cfg!(feature = "avif"),
+        video: state.app.cfg.video_support,
+    })
+}
+
 /// Prometheus metrics endpoint
 async fn handle_metrics() -> Result<Response, SvcError> {
     let metrics_text = metrics::encode_metrics()
@@ -104,46 +443,265 @@ async fn handle_metrics() -> Result<Response, SvcError> {
 /// Main handler for /insecure/{*} requests (handles both images and videos)
 async fn handle_insecure(
     State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
     AxPath(rest): AxPath<String>,
+    Query(debug): Query<DebugQuery>,
+    uri: Uri,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    if state.app.cfg.url_signing.is_some() {
+        return Err(SvcError::Forbidden("unsigned URLs are disabled"));
+    }
+    let hints = ServerHintsQuery::from_uri(&uri)?;
+    serve_insecure(state, rest, hints, debug, req_headers).await
+}
+
+/// Handler for imgproxy-style signed URLs: /{signature}/{directives}/plain/{url}
+async fn handle_signed(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath((signature, rest)): AxPath<(String, String)>,
+    Query(debug): Query<DebugQuery>,
+    uri: Uri,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    // Without a configured key any signature is accepted, like imgproxy
+    if let Some(ref key) = state.app.cfg.url_signing {
+        // The signature covers the path exactly as the client encoded it
+        let raw_path = uri.path();
+        let signed_path = raw_path
+            .strip_prefix('/')
+            .and_then(|p| p.find('/').map(|i| &p[i..]))
+            .unwrap_or("");
+        key.verify(&signature, signed_path)?;
+    }
+
+    // Signed and unsigned URLs for the same request share one cache entry
+    let hints = ServerHintsQuery::from_uri(&uri)?;
+    serve_insecure(state, rest, hints, debug, req_headers).await
+}
+
+/// Shared entry point for /insecure and signed requests
+async fn serve_insecure(
+    state: CombinedState,
+    rest: String,
+    hints: ServerHintsQuery,
+    debug: DebugQuery,
+    req_headers: HeaderMap,
 ) -> Result<Response, SvcError> {
-    let start_time = std::time::Instant::now();
+    if debug.enabled() {
+        authorize_admin(&state.app.cfg, &req_headers)?;
+        return Ok(run_traced(process_insecure(state, rest, hints, req_headers, false)).await);
+    }
 
-    // full_url is the exact request path for cache keying
-    let full_request_url = format!("/insecure/{}", rest);
+    let accept_only = accept_headers(&req_headers);
+    let resp = process_insecure(state.clone(), rest.clone(), hints.clone(), req_headers, false).await?;
+    if is_stale(&resp) {
+        // Soft-purged entry was served; regenerate it in the background, once
+        let key = refresh_key(&state.app.cfg.cache_namespace, &insecure_cache_key(&rest, &hints), &accept_only);
+        let inflight = state.inflight.clone();
+        inflight.spawn_once(key, async move {
+            if let Err(e) = process_insecure(state, rest, hints, accept_only, true).await {
+                tracing::warn!("background refresh failed: {:?}", e);
+            }
+        });
+    }
+    Ok(resp)
+}
+
+/// Processing pipeline for /insecure requests
+///
+/// With `revalidate` set, both caches are bypassed and the source is fetched again.
+async fn process_insecure(
+    state: CombinedState,
+    rest: String,
+    hints: ServerHintsQuery,
+    req_headers: HeaderMap,
+    revalidate: bool,
+) -> Result<Response, SvcError> {
+    // full_url is the exact request path (plus any server hints) for cache keying
+    let full_request_url = insecure_cache_key(&rest, &hints);
 
     // Parse something like: f:webp/q:85/rs:fill:480:480/plain/<encoded>
-    let (dirs, src_url) = parse_rest(&rest)?;
+    let (mut dirs, src_url) = parse_rest(&rest, &state.app.cfg.directive_defaults)?;
+    check_max_dimension(&state.app.cfg, &dirs)?;
+    let negotiated = negotiate_format(&mut dirs, &req_headers);
+
+    // Image-only deployments never touch ffmpeg
+    if !state.app.cfg.video_support && is_video_source(&dirs, &src_url) {
+        metrics::record_processing_error("video_disabled");
+        return Err(SvcError::UnsupportedMedia("video support is disabled"));
+    }
 
     // Derive cache file path from hash(full_request_url)
     let cache_path = cache_path_for(&state.app.cfg, &full_request_url, &dirs.out_fmt);
-    let mime = dirs.out_fmt.mime_type();
+    debug_trace::event("parse", || format!("source={} directives={:?}", src_url, dirs));
+    debug_trace::event("cache", || format!("processed key={} path={}", full_request_url, cache_path.display()));
 
     // Serve from processed cache if present
-    if let Some(resp) = try_serve_cache(&cache_path, mime).await? {
-        metrics::record_cache_hit("processed");
-        let duration = start_time.elapsed().as_secs_f64();
-        metrics::observe_http_duration("/insecure", "GET", duration);
-        metrics::record_http_request("/insecure", "GET", 200);
+    let cached = if revalidate {
+        None
+    } else {
+        try_serve_processed(&state.app, &cache_path, &dirs, &req_headers).await?
+    };
+    let variants = state.app.variants.as_ref();
+    let family = variants.and_then(|_| family_key(&state.app.cfg.cache_namespace, &full_request_url, &dirs));
+    if let Some(mut resp) = cached {
+        debug_trace::event("cache", || "processed cache hit".to_string());
+        report::record_source_served(&src_url, resp.body().size_hint().exact().unwrap_or(0) as usize);
+        if let (Some(variants), Some(family)) = (variants, family.as_deref()) {
+            variants.record(family, &dirs, cache_path);
+        }
+        if negotiated {
+            set_vary_accept(&mut resp);
+        }
         return Ok(resp);
     }
 
-    metrics::record_cache_miss("processed");
+    // A cached variant close enough to the request spares processing another one
+    if let (Some(variants), Some(family), false) = (variants, family.as_deref(), revalidate) {
+        if let Some(mut resp) = try_serve_similar(&state.app, variants, family, &dirs, &req_headers).await? {
+            report::record_source_served(&src_url, resp.body().size_hint().exact().unwrap_or(0) as usize);
+            if negotiated {
+                set_vary_accept(&mut resp);
+            }
+            return Ok(resp);
+        }
+    }
+
+    // Identical concurrent misses share one pipeline; traced requests run their own
+    let inflight_key = namespaced_key(
+        &state.app.cfg.cache_namespace,
+        &format!("{}#{}", full_request_url, dirs.out_fmt.name()),
+    );
+    let deadline = state.app.cfg.request_timeout;
+    // Kept for the variant index; the pipeline takes the directives
+    let variant = family.map(|family| (family, dirs.clone(), cache_path.clone()));
+    let pipeline = journaled(
+        src_url.clone(),
+        with_deadline(deadline, generate_insecure(state.clone(), src_url, dirs, hints, cache_path, revalidate)),
+    );
+    let mut resp = if debug_trace::is_active() {
+        pipeline.await?
+    } else {
+        state.inflight.run(inflight_key, pipeline).await
+    };
+    if let (Some(variants), Some((family, dirs, cache_path))) = (state.app.variants.as_ref(), variant) {
+        if resp.status() == StatusCode::OK {
+            variants.record(&family, &dirs, cache_path);
+        }
+    }
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    Ok(resp)
+}
+
+/// Run a pipeline, recording a failure against its source in the error journal
+pub(crate) async fn journaled(
+    source: String,
+    pipeline: impl Future<Output = Result<Response, SvcError>>,
+) -> Result<Response, SvcError> {
+    let result = pipeline.await;
+    if let Err(ref e) = result {
+        error_journal::record_failure(&source, e);
+    }
+    result
+}
+
+/// Fail a pipeline with `504` once `REQUEST_TIMEOUT_SECS` pass (zero = no deadline)
+///
+/// The deadline is noticed whenever the pipeline awaits: a stuck fetch or ffmpeg run is
+/// dropped (ffmpeg is killed on drop), while a decode or encode already running finishes first.
+pub(crate) async fn with_deadline<T>(
+    timeout: Duration,
+    pipeline: impl Future<Output = Result<T, SvcError>>,
+) -> Result<T, SvcError> {
+    if timeout.is_zero() {
+        return pipeline.await;
+    }
+    match tokio::time::timeout(timeout, pipeline).await {
+        Ok(result) => result,
+        Err(_) => {
+            metrics::record_processing_error("timeout");
+            Err(SvcError::Timeout)
+        }
+    }
+}
+
+/// Fetch, transform, encode and cache an /insecure request after a processed-cache miss
+pub(crate) async fn generate_insecure(
+    state: CombinedState,
+    src_url: String,
+    mut dirs: Directives,
+    hints: ServerHintsQuery,
+    cache_path: PathBuf,
+    revalidate: bool,
+) -> Result<Response, SvcError> {
+    let mime = dirs.out_fmt.mime_type();
+
+    memory::check_soft_limit(&state.app.cfg)?;
+    let started = Instant::now();
+
+    // Bound the variants of one source processed at once
+    let _source_permit = state.source_limiter.acquire(&src_url).await?;
 
     // Try to get original image/video thumbnail from cache first
-    let original_cache_path = original_cache_path_for(&state.app.cfg, &src_url);
-    let img_bytes = if let Some(cached) = try_read_original_cache(&original_cache_path).await? {
+    let video_seek = dirs.video_seek.unwrap_or(state.app.cfg.video_thumb_second);
+    let original_cache_path = original_cache_path_for(
+        &state.app.cfg,
+        &original_cache_key(&dirs, &src_url, hints.expected_hash.as_deref(), video_seek),
+    );
+    debug_trace::event("cache", || format!("original path={}", original_cache_path.display()));
+    let cached_original = if revalidate {
+        None
+    } else {
+        try_read_original_cache(&state.app, &original_cache_path).await?
+    };
+    let (img_bytes, source_server) = if let Some(cached) = cached_original {
         metrics::record_cache_hit("original");
+        debug_trace::event("cache", || "original cache hit".to_string());
         // Cache hit - use cached original (could be image or previously extracted thumbnail)
-        cached
+        (cached, SOURCE_SERVER_CACHE.to_string())
     } else {
         metrics::record_cache_miss("original");
+
+        // Blossom sources fall back to hinted and author servers before the configured ones
+        let fallback_servers = if is_blossom_url(&src_url) && hints.has_server_hints() {
+            let servers = resolve_blossom_servers(
+                &state,
+                &hints.server_hints,
+                hints.author_pubkey.as_deref(),
+            )
+            .await;
+            debug_trace::event("servers", || format!("resolved {:?}", servers));
+            servers
+        } else {
+            state.app.fallback_servers()
+        };
+
+        // Extensionless sources (e.g. bare-hash Blossom blobs) are identified by their first bytes
+        if dirs.source_kind.is_none() && !has_media_extension(&src_url) {
+            let kind = sniff_source_kind(&state.app, &src_url).await;
+            debug_trace::event("sniff", || format!("extensionless source detected as {:?}", kind));
+            if kind == SourceKind::Video && !state.app.cfg.video_support {
+                metrics::record_processing_error("video_disabled");
+                return Err(SvcError::UnsupportedMedia("video support is disabled"));
+            }
+            dirs.source_kind = Some(kind);
+        }
+
         // Cache miss - check if source is a video or image
-        if is_video_url(&src_url) {
+        if is_video_source(&dirs, &src_url) {
             // It's a video - extract thumbnail using FFmpeg
-            let thumbnail_bytes = extract_video_thumbnail(
+            let (thumbnail_bytes, source_server) = extract_video_thumbnail(
                 &src_url,
-                &state.thumbnail.ffmpeg_semaphore,
-                &state.app.cfg.blossom_fallback_servers,
+                video_seek,
+                &state.thumbnail,
+                &state.app,
+                &fallback_servers,
             ).await?;
 
             // Ensure max size
@@ -152,82 +710,76 @@ async fn handle_insecure(
                 return Err(SvcError::BadRequest("thumbnail too large"));
             }
 
-            metrics::record_bytes_downloaded("video", thumbnail_bytes.len());
+            // ffmpeg streams the video itself; count the extracted frame under "video"
+            metrics::record_bytes_downloaded(state.app.cfg.metrics_label(), "video", thumbnail_bytes.len());
 
             // Cache the extracted thumbnail as "original"
-            write_cache_atomic(&original_cache_path, &thumbnail_bytes).await?;
-            thumbnail_bytes
+            write_original_cache(&state.app, &original_cache_path, &thumbnail_bytes).await?;
+            (thumbnail_bytes, source_server)
         } else {
-            // It's an image - fetch normally
-            let bytes = fetch_source(&state.app, &src_url).await?;
-
-            // Ensure max size
-            if bytes.len() > state.app.cfg.max_image_bytes {
-                metrics::record_processing_error("image_too_large");
-                return Err(SvcError::BadRequest("image too large"));
-            }
-
-            metrics::record_bytes_downloaded("image", bytes.len());
+            // It's an image - fetch normally, or checked against `x` when given
+            let (bytes, source_server) = match hints.expected_hash {
+                Some(ref hash) => fetch_verified_source(&state, &src_url, hash, &hints, &fallback_servers).await?,
+                None => fetch_source(&state.app, &src_url, &fallback_servers).await?,
+            };
 
             // Cache the original image
-            write_cache_atomic(&original_cache_path, &bytes).await?;
-            bytes.to_vec()
+            write_original_cache(&state.app, &original_cache_path, &bytes).await?;
+            (bytes.to_vec(), source_server)
         }
     };
+    debug_trace::event("source", || format!("supplied by {}", source_server));
 
-    // Decode - use ImageReader with content-based format detection
-    // Supports: JPEG, JFIF, PNG, WebP, AVIF, and other formats
-    // Works with or without file extensions (detects format from image data)
-    let img = {
-        use std::io::Cursor;
-        image::ImageReader::new(Cursor::new(&img_bytes))
-            .with_guessed_format()
-            .map_err(|e| SvcError::Decode(image::ImageError::IoError(e)))?
-            .decode()?
+    // Decode - content-based format detection, works with or without file extensions
+    let permit = state.app.image_pool.acquire().await?;
+    let decoded = match decode_source(&img_bytes, &dirs, &state.app.cfg) {
+        Ok(decoded) => decoded,
+        Err(e) => return passthrough_undecodable(&state.app.cfg, img_bytes, &source_server, e),
     };
-
-    // Transform
-    let img = apply_resize(img, &dirs.resize);
-
-    // Encode
-    let encoded = encode_image(&img, &dirs.out_fmt, dirs.quality)?;
+    let _decoded = memory::track_decoded(decoded.pixel_bytes());
+    let Rendered { encoded, output_dims, warnings, fallback, substituted } = render(decoded, &dirs)?;
+    drop(permit);
 
     // Record processing metrics
-    let out_fmt_str = match dirs.out_fmt {
-        OutFmt::Jpeg => "jpeg",
-        OutFmt::Png => "png",
-        OutFmt::Webp => "webp",
-        OutFmt::Avif => "avif",
-    };
+    let out_fmt_str = fallback.as_ref().or(substituted.as_ref()).unwrap_or(&dirs.out_fmt).name();
+    let tenant = state.app.cfg.metrics_label();
+    metrics::observe_processing_duration(tenant, "/insecure", out_fmt_str, started.elapsed().as_secs_f64());
 
-    if is_video_url(&src_url) {
+    if is_video_source(&dirs, &src_url) {
         metrics::record_video_processed(out_fmt_str);
     } else {
         metrics::record_image_processed(out_fmt_str);
     }
 
-    metrics::record_bytes_served(mime, encoded.len());
+    report::record_source_served(&src_url, encoded.len());
 
-    // Write to cache atomically
-    write_cache_atomic(&cache_path, &encoded).await?;
-
-    let mut resp = Response::new(Body::from(encoded));
-    *resp.status_mut() = StatusCode::OK;
-    let headers = resp.headers_mut();
-    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(mime).unwrap());
-    headers.insert(
-        header::CACHE_CONTROL,
-        HeaderValue::from_static("public, max-age=31536000, immutable"),
-    );
-    headers.insert(
-        HeaderName::from_static("x-cache"),
-        HeaderValue::from_static("miss"),
-    );
+    let encoded = Bytes::from(encoded);
+    if let Some(fmt) = fallback {
+        let mut resp = build_image_response(encoded, fmt.mime_type(), "miss", Some(output_dims));
+        set_format_fallback(&mut resp, &fmt);
+        set_source_server(&mut resp, &source_server);
+        set_processing_warnings(&mut resp, &warnings);
+        return Ok(resp);
+    }
+    // Transparent JPEG results are cached beside the JPEG entry, see `try_serve_processed`
+    let (cache_path, mime) = match substituted {
+        Some(ref fmt) => (substitute_cache_path(&cache_path, fmt), fmt.mime_type()),
+        None => (cache_path, mime),
+    };
 
-    // Record request metrics
-    let duration = start_time.elapsed().as_secs_f64();
-    metrics::observe_http_duration("/insecure", "GET", duration);
-    metrics::record_http_request("/insecure", "GET", 200);
+    // Write to cache atomically
+    let modified = write_processed_cache(&state.app, &cache_path, &encoded, Some(output_dims)).await?;
+    pin_if_excluded(&state.app.cfg, &src_url, &[&original_cache_path, &cache_path]).await;
+
+    let etag = etag_for(&encoded);
+    let mut resp = build_image_response(encoded, mime, "miss", Some(output_dims));
+    set_etag(&mut resp, &etag);
+    set_last_modified(&mut resp, modified);
+    set_source_server(&mut resp, &source_server);
+    set_processing_warnings(&mut resp, &warnings);
+    if let Some(ref fmt) = substituted {
+        set_format_substituted(&mut resp, fmt);
+    }
 
     Ok(resp)
 }
@@ -235,13 +787,48 @@ async fn handle_insecure(
 /// Handler for /thumb/<sha256>.<ext> endpoint (Blossom-specialized)
 async fn handle_thumb(
     State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
     AxPath(filename): AxPath<String>,
-    Query(params): Query<ThumbQuery>,
+    Query(debug): Query<DebugQuery>,
+    uri: Uri,
+    req_headers: HeaderMap,
 ) -> Result<Response, SvcError> {
-    let start_time = std::time::Instant::now();
+    let state = state.for_tenant(tenant);
+    let params = ThumbQuery::from_uri(&uri)?;
+
+    if debug.enabled() {
+        authorize_admin(&state.app.cfg, &req_headers)?;
+        return Ok(run_traced(process_thumb(state, filename, params, req_headers, false)).await);
+    }
+
+    let accept_only = accept_headers(&req_headers);
+    let resp = process_thumb(state.clone(), filename.clone(), params.clone(), req_headers, false).await?;
+    if is_stale(&resp) {
+        // Soft-purged entry was served; regenerate it in the background, once
+        let cache_key = format!("/thumb/{}?{}", filename, build_query_string(&params));
+        let key = refresh_key(&state.app.cfg.cache_namespace, &cache_key, &accept_only);
+        let inflight = state.inflight.clone();
+        inflight.spawn_once(key, async move {
+            if let Err(e) = process_thumb(state, filename, params, accept_only, true).await {
+                tracing::warn!("background refresh failed: {:?}", e);
+            }
+        });
+    }
+    Ok(resp)
+}
 
+/// Processing pipeline for /thumb requests
+///
+/// With `revalidate` set, both caches are bypassed and the blob is fetched again.
+async fn process_thumb(
+    state: CombinedState,
+    filename: String,
+    params: ThumbQuery,
+    req_headers: HeaderMap,
+    revalidate: bool,
+) -> Result<Response, SvcError> {
     // Validate filename format: <sha256>.<ext>
-    let (hash, ext) = filename
+    let (hash, _) = filename
         .rsplit_once('.')
         .ok_or(SvcError::BadRequest("invalid filename format, expected <sha256>.<ext>"))?;
 
@@ -251,181 +838,810 @@ async fn handle_thumb(
     }
 
     // Parse directives from query parameters
-    let dirs = parse_thumb_params(&params)?;
+    let mut dirs = parse_thumb_params(&params, &state.app.cfg.directive_defaults)?;
+    check_max_dimension(&state.app.cfg, &dirs)?;
+    let negotiated = negotiate_format(&mut dirs, &req_headers);
 
     // Build cache key from full request (path + query params)
     let cache_key = format!("/thumb/{}?{}", filename, build_query_string(&params));
     let cache_path = cache_path_for(&state.app.cfg, &cache_key, &dirs.out_fmt);
-    let mime = dirs.out_fmt.mime_type();
+    debug_trace::event("parse", || format!("blob={} directives={:?}", filename, dirs));
+    debug_trace::event("cache", || format!("processed key={} path={}", cache_key, cache_path.display()));
 
     // Serve from processed cache if present
-    if let Some(resp) = try_serve_cache(&cache_path, mime).await? {
-        metrics::record_cache_hit("processed");
-        let duration = start_time.elapsed().as_secs_f64();
-        metrics::observe_http_duration("/thumb", "GET", duration);
-        metrics::record_http_request("/thumb", "GET", 200);
+    let cached = if revalidate {
+        None
+    } else {
+        try_serve_processed(&state.app, &cache_path, &dirs, &req_headers).await?
+    };
+    if let Some(mut resp) = cached {
+        debug_trace::event("cache", || "processed cache hit".to_string());
+        report::record_source_served(&filename, resp.body().size_hint().exact().unwrap_or(0) as usize);
+        if negotiated {
+            set_vary_accept(&mut resp);
+        }
         return Ok(resp);
     }
 
-    metrics::record_cache_miss("processed");
-
-    // Get author servers if pubkey provided
-    let author_servers = if let Some(ref pubkey) = params.author_pubkey {
-        match state.blossom.get_author_servers(pubkey).await {
-            Ok(s) => Some(s),
-            Err(e) => {
-                tracing::warn!("Failed to fetch author servers for pubkey {}: {}", pubkey, e);
-                None
-            }
-        }
+    // Identical concurrent misses share one pipeline; traced requests run their own
+    let inflight_key =
+        namespaced_key(&state.app.cfg.cache_namespace, &format!("{}#{}", cache_key, dirs.out_fmt.name()));
+    let deadline = state.app.cfg.request_timeout;
+    let pipeline = journaled(
+        filename.clone(),
+        with_deadline(deadline, generate_thumb(state.clone(), filename, params, dirs, cache_path, revalidate)),
+    );
+    let mut resp = if debug_trace::is_active() {
+        pipeline.await?
     } else {
-        None
+        state.inflight.run(inflight_key, pipeline).await
     };
+    if negotiated {
+        set_vary_accept(&mut resp);
+    }
+    Ok(resp)
+}
 
-    // Combine servers: xs (highest priority) -> as -> fallback
-    let servers = combine_server_lists(
-        if params.server_hints.is_empty() {
-            None
-        } else {
-            Some(&params.server_hints)
-        },
-        author_servers.as_deref(),
-        &state.app.cfg.blossom_fallback_servers,
-    );
+/// Fetch, transform, encode and cache a /thumb request after a processed-cache miss
+async fn generate_thumb(
+    state: CombinedState,
+    filename: String,
+    params: ThumbQuery,
+    dirs: Directives,
+    cache_path: PathBuf,
+    revalidate: bool,
+) -> Result<Response, SvcError> {
+    // Filename was validated by process_thumb
+    let (hash, ext) = filename
+        .rsplit_once('.')
+        .ok_or(SvcError::BadRequest("invalid filename format, expected <sha256>.<ext>"))?;
+    let mime = dirs.out_fmt.mime_type();
+
+    memory::check_soft_limit(&state.app.cfg)?;
+    let started = Instant::now();
+
+    // Bound the variants of one blob processed at once
+    let _source_permit = state.source_limiter.acquire(hash).await?;
+
+    let servers = resolve_blossom_servers(
+        &state,
+        &params.server_hints,
+        params.author_pubkey.as_deref(),
+    )
+    .await;
 
     tracing::debug!("Resolved {} servers for {}.{}: {:?}", servers.len(), hash, ext, servers);
+    debug_trace::event("servers", || format!("resolved {:?}", servers));
 
-    // Try to fetch from servers in order
-    let original_cache_key = format!("{}.{}", hash, ext);
+    // Video blobs keep the frame as their original, keyed by the second it was taken at
+    let video_seek = dirs.video_seek.unwrap_or(state.app.cfg.video_thumb_second);
+    let original_cache_key = original_cache_key(&dirs, &filename, None, video_seek);
     let original_cache_path = original_cache_path_for(&state.app.cfg, &original_cache_key);
+    debug_trace::event("cache", || format!("original path={}", original_cache_path.display()));
 
     // Check original cache first
-    let img_bytes = if let Some(cached) = try_read_original_cache(&original_cache_path).await? {
+    let cached_original = if revalidate {
+        None
+    } else {
+        try_read_original_cache(&state.app, &original_cache_path).await?
+    };
+    let (img_bytes, source_server) = if let Some(cached) = cached_original {
         metrics::record_cache_hit("original");
+        debug_trace::event("cache", || "original cache hit".to_string());
         tracing::debug!("Original cache hit for {}.{}", hash, ext);
-        cached
+        (cached, SOURCE_SERVER_CACHE.to_string())
     } else {
         metrics::record_cache_miss("original");
-        // Fetch from Blossom servers
-        let bytes = fetch_from_blossom_servers(&state.app, &servers, hash, ext).await?;
-
-        // Validate size
-        if bytes.len() > state.app.cfg.max_image_bytes {
-            metrics::record_processing_error("image_too_large");
-            return Err(SvcError::BadRequest("image too large"));
-        }
+        let candidates = state.app.blob_candidates(&servers, hash);
+        let blob_url = |server: &String| format!("{}/{}.{}", server.trim_end_matches('/'), hash, ext);
+        // Extensions that name no known format (`.bin`, `.dat`, ...) are identified by the blob's first bytes
+        let kind = if is_video_extension(ext) {
+            SourceKind::Video
+        } else if has_media_extension(&filename) {
+            SourceKind::Image
+        } else {
+            match candidates.first() {
+                Some(server) => sniff_source_kind(&state.app, &blob_url(server)).await,
+                None => SourceKind::Image,
+            }
+        };
+        debug_trace::event("sniff", || format!("blob treated as {:?}", kind));
 
-        metrics::record_bytes_downloaded("blossom", bytes.len());
+        if kind == SourceKind::Video {
+            if !state.app.cfg.video_support {
+                metrics::record_processing_error("video_disabled");
+                return Err(SvcError::UnsupportedMedia("video support is disabled"));
+            }
+            let Some((primary, fallbacks)) = candidates.split_first() else {
+                return Err(SvcError::UpstreamError(404));
+            };
+            let (frame, source_server) =
+                extract_video_thumbnail(&blob_url(primary), video_seek, &state.thumbnail, &state.app, fallbacks)
+                    .await?;
+            if frame.len() > state.app.cfg.max_image_bytes {
+                metrics::record_processing_error("thumbnail_too_large");
+                return Err(SvcError::BadRequest("thumbnail too large"));
+            }
+            metrics::record_bytes_downloaded(state.app.cfg.metrics_label(), "video", frame.len());
+            write_original_cache(&state.app, &original_cache_path, &frame).await?;
+            (frame, source_server)
+        } else {
+            // Fetch from Blossom servers
+            let (bytes, source_server) = fetch_from_blossom_servers(&state.app, &servers, hash, ext).await?;
 
-        // Cache the original
-        write_cache_atomic(&original_cache_path, &bytes).await?;
-        bytes.to_vec()
+            // Cache the original
+            write_original_cache(&state.app, &original_cache_path, &bytes).await?;
+            (bytes.to_vec(), source_server)
+        }
     };
+    debug_trace::event("source", || format!("supplied by {}", source_server));
 
     // Decode image
-    let img = {
-        use std::io::Cursor;
-        image::ImageReader::new(Cursor::new(&img_bytes))
-            .with_guessed_format()
-            .map_err(|e| SvcError::Decode(image::ImageError::IoError(e)))?
-            .decode()?
+    let permit = state.app.image_pool.acquire().await?;
+    let decoded = match decode_source(&img_bytes, &dirs, &state.app.cfg) {
+        Ok(decoded) => decoded,
+        Err(e) => return passthrough_undecodable(&state.app.cfg, img_bytes, &source_server, e),
     };
-
-    // Transform
-    let img = apply_resize(img, &dirs.resize);
-
-    // Encode
-    let encoded = encode_image(&img, &dirs.out_fmt, dirs.quality)?;
+    let _decoded = memory::track_decoded(decoded.pixel_bytes());
+    let Rendered { encoded, output_dims, warnings, fallback, substituted } = render(decoded, &dirs)?;
+    drop(permit);
 
     // Record processing metrics
-    let out_fmt_str = match dirs.out_fmt {
-        OutFmt::Jpeg => "jpeg",
-        OutFmt::Png => "png",
-        OutFmt::Webp => "webp",
-        OutFmt::Avif => "avif",
+    let out_fmt_str = fallback.as_ref().or(substituted.as_ref()).unwrap_or(&dirs.out_fmt).name();
+    let tenant = state.app.cfg.metrics_label();
+    metrics::observe_processing_duration(tenant, "/thumb", out_fmt_str, started.elapsed().as_secs_f64());
+    if is_video_url(&filename) {
+        metrics::record_video_processed(out_fmt_str);
+    } else {
+        metrics::record_image_processed(out_fmt_str);
+    }
+    report::record_source_served(&filename, encoded.len());
+
+    let encoded = Bytes::from(encoded);
+    if let Some(fmt) = fallback {
+        let mut resp = build_image_response(encoded, fmt.mime_type(), "miss", Some(output_dims));
+        set_format_fallback(&mut resp, &fmt);
+        set_source_server(&mut resp, &source_server);
+        set_processing_warnings(&mut resp, &warnings);
+        return Ok(resp);
+    }
+    // Transparent JPEG results are cached beside the JPEG entry, see `try_serve_processed`
+    let (cache_path, mime) = match substituted {
+        Some(ref fmt) => (substitute_cache_path(&cache_path, fmt), fmt.mime_type()),
+        None => (cache_path, mime),
     };
-    metrics::record_image_processed(out_fmt_str);
-    metrics::record_bytes_served(mime, encoded.len());
 
     // Write to processed cache
-    write_cache_atomic(&cache_path, &encoded).await?;
+    let modified = write_processed_cache(&state.app, &cache_path, &encoded, Some(output_dims)).await?;
+    pin_if_excluded(&state.app.cfg, &filename, &[&original_cache_path, &cache_path]).await;
 
     // Build response
-    let mut resp = Response::new(Body::from(encoded));
-    *resp.status_mut() = StatusCode::OK;
-    let headers = resp.headers_mut();
-    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(mime).unwrap());
-    headers.insert(
-        header::CACHE_CONTROL,
-        HeaderValue::from_static("public, max-age=31536000, immutable"),
-    );
-    headers.insert(
-        HeaderName::from_static("x-cache"),
-        HeaderValue::from_static("miss"),
+    let etag = etag_for(&encoded);
+    let mut resp = build_image_response(encoded, mime, "miss", Some(output_dims));
+    set_etag(&mut resp, &etag);
+    set_last_modified(&mut resp, modified);
+    set_source_server(&mut resp, &source_server);
+    set_processing_warnings(&mut resp, &warnings);
+    if let Some(ref fmt) = substituted {
+        set_format_substituted(&mut resp, fmt);
+    }
+
+    Ok(resp)
+}
+
+/// Serve the original bytes unchanged when decoding failed but they are a recognizable image
+///
+/// Only with `PASSTHROUGH_UNDECODABLE` enabled; otherwise the decode error is returned.
+/// The result is not written to the processed cache, its format differs from the request.
+fn passthrough_undecodable(
+    cfg: &AppCfg,
+    img_bytes: Vec<u8>,
+    source_server: &str,
+    err: SvcError,
+) -> Result<Response, SvcError> {
+    if !cfg.passthrough_undecodable || !matches!(err, SvcError::Decode(_)) {
+        return Err(err);
+    }
+    let Ok(format) = image::guess_format(&img_bytes) else {
+        return Err(err);
+    };
+
+    tracing::warn!("decode failed ({:?}), passing through original {:?}", err, format);
+    debug_trace::event("decode", || format!("failed, passing through {:?} original", format));
+    metrics::record_processing_error("decode_passthrough");
+
+    let mut resp = build_image_response(img_bytes, format.to_mime_type(), "passthrough", None);
+    // The source may still change, so it is not immutable; expire like a processed entry
+    let cache_control = format!("public, max-age={}", cfg.processed_cache_ttl.as_secs());
+    resp.headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_str(&cache_control).unwrap());
+    set_source_server(&mut resp, source_server);
+    set_processing_warnings(
+        &mut resp,
+        &[format!("decode failed: original {:?} served unchanged", format).to_ascii_lowercase()],
     );
+    Ok(resp)
+}
+
+/// Tell clients why the output differs from the request (`X-Processing-Warnings`)
+pub(crate) fn set_processing_warnings(resp: &mut Response, warnings: &[String]) {
+    if warnings.is_empty() {
+        return;
+    }
+    let joined = warnings.join("; ");
+    tracing::info!("processing warnings: {}", joined);
+    debug_trace::event("warnings", || joined.clone());
+    if let Ok(value) = HeaderValue::from_str(&joined) {
+        resp.headers_mut().insert("x-processing-warnings", value);
+    }
+}
+
+/// Cache-Control of responses encoded in a fallback format
+const FALLBACK_CACHE_CONTROL: &str = "public, max-age=3600";
+
+/// `X-Source-Server` value when the original came from the local original cache
+const SOURCE_SERVER_CACHE: &str = "cache";
+
+/// Report which server supplied the original (origin URL, or `cache`)
+pub(crate) fn set_source_server(resp: &mut Response, source_server: &str) {
+    if let Ok(value) = HeaderValue::from_str(source_server) {
+        resp.headers_mut().insert("x-source-server", value);
+    }
+}
+
+/// Singleflight key of the background refresh of a request's soft-purged entry
+///
+/// `Accept` is part of it, since `f:auto` requests refresh the variant it negotiates.
+fn refresh_key(namespace: &str, cache_key: &str, accept_only: &HeaderMap) -> String {
+    let accept = accept_only.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+    namespaced_key(namespace, &format!("refresh#{}#{}", cache_key, accept))
+}
 
-    // Record request metrics
-    let duration = start_time.elapsed().as_secs_f64();
-    metrics::observe_http_duration("/thumb", "GET", duration);
-    metrics::record_http_request("/thumb", "GET", 200);
+/// Whether a response was served from a soft-purged cache entry
+fn is_stale(resp: &Response) -> bool {
+    resp.headers()
+        .get("x-cache")
+        .is_some_and(|v| v.as_bytes() == b"stale")
+}
+
+/// Resolve a request path (as clients send it) to its processed and original cache files
+///
+/// Accepts `/insecure/<directives>/plain/<url>`, `/<signature>/<directives>/plain/<url>` and
+/// `/thumb/<sha256>.<ext>?<query>` paths. `f:auto` requests resolve to every format variant.
+pub(crate) fn resolve_cache_paths(cfg: &AppCfg, path: &str) -> Result<(Vec<PathBuf>, PathBuf), SvcError> {
+    // Signed paths (/{signature}/...) share the /insecure cache entry
+    let insecure_rest = path.strip_prefix("/insecure/").or_else(|| {
+        path.strip_prefix('/')
+            .and_then(|p| p.split_once('/'))
+            .map(|(_, rest)| rest)
+            .filter(|rest| rest.contains("/plain/") || rest.starts_with("plain/"))
+    });
+    if let Some(rest) = insecure_rest {
+        let (rest, hints) = match rest.split_once('?') {
+            Some((rest, query)) => {
+                let uri: Uri = format!("/?{}", query)
+                    .parse()
+                    .map_err(|_| SvcError::BadRequest("invalid query"))?;
+                (rest, ServerHintsQuery::from_uri(&uri)?)
+            }
+            None => (rest, ServerHintsQuery::default()),
+        };
+        // Handlers key on the path as decoded by the router, so decode the same way
+        let rest = percent_decode_str(rest)
+            .decode_utf8()
+            .map_err(|_| SvcError::BadRequest("bad encoded path"))?;
+        let (dirs, src_url) = parse_rest(&rest, &cfg.directive_defaults)?;
+        let processed = processed_variants(cfg, &insecure_cache_key(&rest, &hints), &dirs.out_fmt);
+        let video_seek = dirs.video_seek.unwrap_or(cfg.video_thumb_second);
+        let original_key = original_cache_key(&dirs, &src_url, hints.expected_hash.as_deref(), video_seek);
+        return Ok((processed, original_cache_path_for(cfg, &original_key)));
+    }
+
+    if let Some(thumb) = path.strip_prefix("/thumb/") {
+        let uri: Uri = path.parse().map_err(|_| SvcError::BadRequest("invalid path"))?;
+        let params = ThumbQuery::from_uri(&uri)?;
+        let filename = thumb.split('?').next().unwrap_or(thumb);
+        let dirs = parse_thumb_params(&params, &cfg.directive_defaults)?;
+        let cache_key = format!("/thumb/{}?{}", filename, build_query_string(&params));
+        let processed = processed_variants(cfg, &cache_key, &dirs.out_fmt);
+        let video_seek = dirs.video_seek.unwrap_or(cfg.video_thumb_second);
+        let original_key = original_cache_key(&dirs, filename, None, video_seek);
+        return Ok((processed, original_cache_path_for(cfg, &original_key)));
+    }
+
+    Err(SvcError::BadRequest("path must start with /insecure/ or /thumb/"))
+}
+
+/// A decoded source: one still image, or every frame of an animation kept by `frames:all`
+enum Source {
+    Still { img: DynamicImage, exif: Option<Vec<u8>> },
+    Animation(Vec<Frame>),
+}
 
+/// Decoded source plus warnings about frames that were dropped
+pub(crate) struct Decoded {
+    source: Source,
+    warnings: Vec<String>,
+}
+
+impl Decoded {
+    /// A generated still image, e.g. the plain background of a `/card`
+    pub(crate) fn still(img: DynamicImage) -> Self {
+        Decoded {
+            source: Source::Still { img, exif: None },
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Pixel bytes held while the pipeline runs
+    pub(crate) fn pixel_bytes(&self) -> usize {
+        match self.source {
+            Source::Still { ref img, .. } => img.as_bytes().len(),
+            Source::Animation(ref frames) => frames.iter().map(|f| f.buffer().len()).sum(),
+        }
+    }
+}
+
+/// Encoded output of a pipeline
+pub(crate) struct Rendered {
+    pub(crate) encoded: Vec<u8>,
+    /// Final dimensions after no-upscale and fill-down cropping decisions
+    pub(crate) output_dims: (u32, u32),
+    pub(crate) warnings: Vec<String>,
+    /// Format actually encoded when the requested encoder failed; the output is not cacheable
+    pub(crate) fallback: Option<OutFmt>,
+    /// Format encoded instead of JPEG because the result has transparency (`JPEG_ALPHA_FORMAT`);
+    /// cached under that format's extension, see `substitute_cache_path`
+    pub(crate) substituted: Option<OutFmt>,
+}
+
+/// Decode a source, keeping every frame of an animated GIF/WebP when `frames:all` can be honored
+///
+/// Animations longer than `MAX_ANIMATION_FRAMES` fall back to their first frame with a warning.
+/// SVG sources are rasterized at the resize target's size. Raster sources over
+/// `MAX_SRC_RESOLUTION` are refused from their header, before anything is decoded.
+pub(crate) fn decode_source(bytes: &[u8], dirs: &Directives, cfg: &AppCfg) -> Result<Decoded, SvcError> {
+    let max_frames = cfg.max_animation_frames;
+    let mut warnings = Vec::new();
+    if svg::is_svg(bytes) {
+        let img = svg::rasterize(bytes, (dirs.resize.w, dirs.resize.h), cfg.svg_max_elements)?;
+        debug_trace::event("decode", || format!("rasterized SVG at {}x{}", img.width(), img.height()));
+        return Ok(Decoded { source: Source::Still { img, exif: None }, warnings });
+    }
+    if let Err(e) = check_source_resolution(bytes, cfg.max_src_resolution) {
+        metrics::record_processing_error("resolution_too_large");
+        return Err(e);
+    }
+    if dirs.frames == Frames::All && is_animated(bytes) {
+        if !dirs.out_fmt.supports_animation() {
+            warnings.push(format!("animation not kept: {} output is a single frame", dirs.out_fmt.name()));
+        } else if let Some(frames) = decode_frames(bytes, max_frames)? {
+            debug_trace::event("decode", || {
+                let (w, h) = frames[0].buffer().dimensions();
+                format!("decoded {} frames of {}x{} from {} bytes", frames.len(), w, h, bytes.len())
+            });
+            return Ok(Decoded { source: Source::Animation(frames), warnings });
+        } else {
+            warnings.push(format!("animation has more than {} frames, only the first is kept", max_frames));
+        }
+    }
+
+    let (img, exif) = decode_image(bytes, dirs.keep_metadata)?;
+    debug_trace::event("decode", || format!("decoded {}x{} from {} bytes", img.width(), img.height(), bytes.len()));
+    Ok(Decoded { source: Source::Still { img, exif }, warnings })
+}
+
+/// Transform and encode a decoded source
+///
+/// Every output is checked with `validate_encoded`. When a codec-library encoder fails or
+/// produces invalid output, the output is retried in the format from
+/// `OutFmt::encode_fallback` rather than failing content that could still be served.
+pub(crate) fn render(decoded: Decoded, dirs: &Directives) -> Result<Rendered, SvcError> {
+    let mut warnings = decoded.warnings;
+    let mut fallback = None;
+    let mut substituted = None;
+    let (encoded, output_dims) = match decoded.source {
+        Source::Still { img, exif } => {
+            // Transparency is judged on the result, as crops, padding and `ex:` change it
+            let substitute = dirs.alpha_substitute().map(|out_fmt| Directives { out_fmt, ..dirs.clone() });
+            let (mut img, transform_warnings) = apply_directives(img, substitute.as_ref().unwrap_or(dirs))?;
+            warnings.extend(transform_warnings);
+            let dirs = match substitute {
+                Some(ref substitute) if has_transparency(&img) => {
+                    substituted = Some(substitute.out_fmt.clone());
+                    substitute
+                }
+                Some(_) => {
+                    // Opaque after all: flatten like any JPEG (there is no `bg:` here)
+                    img = apply_background(img, [255, 255, 255]);
+                    dirs
+                }
+                None => dirs,
+            };
+            let dims = img.dimensions();
+            if matches!(dirs.out_fmt, OutFmt::Raw) && dirs.exact_size() != Some(dims) {
+                return Err(SvcError::Unprocessable(
+                    "f:raw needs an exact size, e.g. rs:fill or rs:force with both dimensions",
+                ));
+            }
+            let encoded = match encode_image(&img, dirs, exif.clone()).and_then(|out| validated(out, dirs, dims)) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    let fallback_dirs = fallback_directives(dirs, img.color().has_alpha(), false, e)?;
+                    let encoded = validated(encode_image(&img, &fallback_dirs, exif)?, &fallback_dirs, dims)?;
+                    fallback = Some(fallback_dirs.out_fmt);
+                    encoded
+                }
+            };
+            (encoded, dims)
+        }
+        Source::Animation(frames) => {
+            let (frames, transform_warnings) = apply_directives_to_frames(frames, dirs)?;
+            warnings.extend(transform_warnings);
+            let output_dims = frames.first().map(|f| f.buffer().dimensions()).unwrap_or_default();
+            let encoded = match encode_animation(&frames, dirs).and_then(|out| validated(out, dirs, output_dims)) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    let fallback_dirs = fallback_directives(dirs, true, true, e)?;
+                    let encoded = validated(encode_animation(&frames, &fallback_dirs)?, &fallback_dirs, output_dims)?;
+                    fallback = Some(fallback_dirs.out_fmt);
+                    encoded
+                }
+            };
+            (encoded, output_dims)
+        }
+    };
+    if let Some(ref fmt) = fallback {
+        warnings.push(format!("{} encoding failed, served as {}", dirs.out_fmt.name(), fmt.name()));
+    }
+    if let Some(ref fmt) = substituted {
+        debug_trace::event("encode", || format!("result has transparency, encoded as {} instead of jpeg", fmt.name()));
+    }
+    debug_trace::event("resize", || format!("{:?} -> {}x{}", dirs.resize, output_dims.0, output_dims.1));
+    debug_trace::event("encode", || {
+        format!(
+            "format={:?} quality={} colors={:?} -> {} bytes",
+            dirs.out_fmt, dirs.quality, dirs.colors, encoded.len()
+        )
+    });
+    Ok(Rendered { encoded, output_dims, warnings, fallback, substituted })
+}
+
+/// Encode and cache an image generated in-process (cards, QR codes) after a processed-cache miss
+///
+/// Generated images are opaque, so JPEG alpha substitution never applies.
+pub(crate) async fn generate_still(
+    app: &AppState,
+    img: DynamicImage,
+    dirs: &Directives,
+    cache_path: &Path,
+) -> Result<Response, SvcError> {
+    let Rendered { encoded, output_dims, warnings, fallback, .. } = {
+        let _permit = app.image_pool.acquire().await?;
+        render(Decoded::still(img), dirs)?
+    };
+
+    let encoded = Bytes::from(encoded);
+    if let Some(fmt) = fallback {
+        let mut resp = build_image_response(encoded, fmt.mime_type(), "miss", Some(output_dims));
+        set_format_fallback(&mut resp, &fmt);
+        set_processing_warnings(&mut resp, &warnings);
+        return Ok(resp);
+    }
+
+    let modified = write_processed_cache(app, cache_path, &encoded, Some(output_dims)).await?;
+    let etag = etag_for(&encoded);
+    let mut resp = build_image_response(encoded, dirs.out_fmt.mime_type(), "miss", Some(output_dims));
+    set_etag(&mut resp, &etag);
+    set_last_modified(&mut resp, modified);
+    set_processing_warnings(&mut resp, &warnings);
+    Ok(resp)
+}
+
+/// Encoder output that passed `validate_encoded`, so a broken encoder can't poison the cache
+fn validated(encoded: Vec<u8>, dirs: &Directives, dims: (u32, u32)) -> Result<Vec<u8>, SvcError> {
+    if let Err(e) = validate_encoded(&encoded, &dirs.out_fmt, dims) {
+        metrics::record_processing_error("invalid_encode");
+        return Err(e);
+    }
+    Ok(encoded)
+}
+
+/// Directives for re-encoding after `err` from the requested encoder, or `err` if there is no fallback
+fn fallback_directives(dirs: &Directives, has_alpha: bool, animated: bool, err: SvcError) -> Result<Directives, SvcError> {
+    // BadRequest means the request itself can't be encoded (e.g. codec compiled out)
+    let fallback = match dirs.out_fmt.encode_fallback(has_alpha, animated) {
+        Some(fmt) if !matches!(err, SvcError::BadRequest(_)) => fmt,
+        _ => return Err(err),
+    };
+    tracing::warn!("{} encoding failed ({}), retrying as {}", dirs.out_fmt.name(), err, fallback.name());
+    debug_trace::event("encode", || format!("{} failed: {}; retrying as {}", dirs.out_fmt.name(), err, fallback.name()));
+    metrics::record_processing_error("encode_fallback");
+    Ok(Directives { out_fmt: fallback, ..dirs.clone() })
+}
+
+/// Processed cache path of a JPEG request's alpha substitute: same key, the substitute's extension
+pub(crate) fn substitute_cache_path(cache_path: &Path, fmt: &OutFmt) -> PathBuf {
+    cache_path.with_extension(fmt.extension())
+}
+
+/// Serve a processed cache entry, or the cached alpha substitute of a JPEG request
+pub(crate) async fn try_serve_processed(
+    app: &AppState,
+    cache_path: &Path,
+    dirs: &Directives,
+    req_headers: &HeaderMap,
+) -> Result<Option<Response>, SvcError> {
+    if let Some(mut resp) = try_serve_cache(app, cache_path, dirs.out_fmt.mime_type(), req_headers).await? {
+        // Raw pixels have no header to read the size from; only exact sizes are ever cached
+        if let (OutFmt::Raw, Some((w, h))) = (&dirs.out_fmt, dirs.exact_size()) {
+            let headers = resp.headers_mut();
+            headers.insert("x-width", HeaderValue::from(w));
+            headers.insert("x-height", HeaderValue::from(h));
+        }
+        return Ok(Some(resp));
+    }
+    let Some(fmt) = dirs.alpha_substitute() else {
+        return Ok(None);
+    };
+    let path = substitute_cache_path(cache_path, &fmt);
+    let mut resp = try_serve_cache(app, &path, fmt.mime_type(), req_headers).await?;
+    if let Some(ref mut resp) = resp {
+        set_format_substituted(resp, &fmt);
+    }
     Ok(resp)
 }
 
+/// Serve the closest cached variant within `VARIANT_*_TOLERANCE` of the request, if any
+///
+/// Soft-purged variants are passed over, since serving one would refresh it anyway.
+async fn try_serve_similar(
+    app: &AppState,
+    variants: &VariantIndex,
+    family: &str,
+    dirs: &Directives,
+    req_headers: &HeaderMap,
+) -> Result<Option<Response>, SvcError> {
+    for variant in variants.similar(family, dirs) {
+        let Some(mut resp) = try_serve_processed(app, &variant.path, dirs, req_headers).await? else {
+            continue;
+        };
+        if is_stale(&resp) {
+            continue;
+        }
+        debug_trace::event("cache", || format!("similar variant {}x{} q{}", variant.w, variant.h, variant.quality));
+        metrics::VARIANTS_REUSED_TOTAL.inc();
+        let similar = format!("{}x{} q{}", variant.w, variant.h, variant.quality);
+        if let Ok(value) = HeaderValue::from_str(&similar) {
+            resp.headers_mut().insert("x-similar-variant", value);
+        }
+        return Ok(Some(resp));
+    }
+    Ok(None)
+}
+
+/// Mark a JPEG request served in another format because the image has transparency
+pub(crate) fn set_format_substituted(resp: &mut Response, fmt: &OutFmt) {
+    resp.headers_mut()
+        .insert("x-format-substituted", HeaderValue::from_static(fmt.name()));
+}
+
+/// Mark a response whose encoder fell back to another format (`X-Format-Fallback`)
+///
+/// The substitute is kept out of the processed cache and expires quickly, so the requested
+/// format gets another try once the encoder can handle the image.
+pub(crate) fn set_format_fallback(resp: &mut Response, fmt: &OutFmt) {
+    let headers = resp.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(FALLBACK_CACHE_CONTROL));
+    headers.insert("x-format-fallback", HeaderValue::from_static(fmt.name()));
+}
+
+/// Reject requested sizes (resize target plus padding) above `MAX_DIMENSION`, and `ex:` canvases
+/// over `MAX_SRC_RESOLUTION` megapixels
+pub(crate) fn check_max_dimension(cfg: &AppCfg, dirs: &Directives) -> Result<(), SvcError> {
+    let (mut w, mut h) = (dirs.resize.w, dirs.resize.h);
+    if let Some(ref padding) = dirs.padding {
+        w = w.saturating_add(padding.left + padding.right);
+        h = h.saturating_add(padding.top + padding.bottom);
+    }
+    let max = cfg.max_dimension;
+    if max > 0 && (w > max || h > max) {
+        return Err(SvcError::BadRequest("requested size exceeds MAX_DIMENSION"));
+    }
+    // `ex:` makes the requested size the output size, however small the source
+    let canvas = dirs.extend.is_some() && dirs.resize.w > 0 && dirs.resize.h > 0;
+    if canvas && cfg.max_src_resolution > 0.0 && w as f64 * h as f64 > cfg.max_src_resolution * 1_000_000.0 {
+        return Err(SvcError::BadRequest("extended size exceeds MAX_SRC_RESOLUTION"));
+    }
+    Ok(())
+}
+
+/// Whether the source goes through ffmpeg; `ext:` overrides the URL extension
+fn is_video_source(dirs: &Directives, src_url: &str) -> bool {
+    match dirs.source_kind {
+        Some(kind) => kind == SourceKind::Video,
+        None => is_video_url(src_url),
+    }
+}
+
+/// Original-cache key for a source
+///
+/// Videos cache the extracted frame and images the raw bytes, so an `ext:` that
+/// contradicts the URL extension gets its own entry. Frames taken anywhere but the default
+/// second are keyed by it; extensionless sources may turn out to be videos, so they are too.
+pub(crate) fn original_cache_key(
+    dirs: &Directives,
+    src_url: &str,
+    expected_hash: Option<&str>,
+    video_seek: f32,
+) -> String {
+    let mut key = match dirs.source_kind {
+        Some(SourceKind::Video) if !is_video_url(src_url) => format!("{}#ext=video", src_url),
+        Some(SourceKind::Image) if is_video_url(src_url) => format!("{}#ext=image", src_url),
+        _ => src_url.to_string(),
+    };
+    let may_be_video =
+        is_video_source(dirs, src_url) || (dirs.source_kind.is_none() && !has_media_extension(src_url));
+    if may_be_video && video_seek != DEFAULT_VIDEO_SEEK {
+        key = format!("{}#t={}", key, video_seek);
+    }
+    // Verified sources may come from a Blossom server instead of the URL itself
+    match expected_hash {
+        Some(hash) => format!("{}#x={}", key, hash),
+        None => key,
+    }
+}
+
+/// Processed cache files a request may have produced
+fn processed_variants(cfg: &AppCfg, cache_key: &str, fmt: &OutFmt) -> Vec<PathBuf> {
+    match fmt {
+        OutFmt::Auto => OutFmt::auto_candidates()
+            .iter()
+            .map(|f| cache_path_for(cfg, cache_key, f))
+            .collect(),
+        OutFmt::Jpeg => {
+            let path = cache_path_for(cfg, cache_key, fmt);
+            let substitute = cfg.directive_defaults.jpeg_alpha_format.as_ref();
+            let substitute = substitute.map(|alpha| substitute_cache_path(&path, alpha));
+            std::iter::once(path).chain(substitute).collect()
+        }
+        _ => vec![cache_path_for(cfg, cache_key, fmt)],
+    }
+}
+
+/// Resolve `f:auto` against the request's `Accept` header
+///
+/// Returns whether the format was negotiated, in which case responses need `Vary: Accept`.
+pub(crate) fn negotiate_format(dirs: &mut Directives, req_headers: &HeaderMap) -> bool {
+    let negotiated = matches!(dirs.out_fmt, OutFmt::Auto);
+    let accept = req_headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    dirs.out_fmt = dirs.out_fmt.clone().negotiate(accept);
+    negotiated
+}
+
+/// Headers a background refresh needs to regenerate the same variant
+fn accept_headers(req_headers: &HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(accept) = req_headers.get(header::ACCEPT) {
+        headers.insert(header::ACCEPT, accept.clone());
+    }
+    headers
+}
+
+pub(crate) fn set_vary_accept(resp: &mut Response) {
+    resp.headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+}
+
 /// Parse thumb query parameters into Directives
-fn parse_thumb_params(params: &ThumbQuery) -> Result<Directives, SvcError> {
+pub(crate) fn parse_thumb_params(params: &ThumbQuery, defaults: &DirectiveDefaults) -> Result<Directives, SvcError> {
     // Parse output format
     let out_fmt = if let Some(ref fmt) = params.format {
-        match fmt.to_ascii_lowercase().as_str() {
-            "jpeg" | "jpg" => OutFmt::Jpeg,
-            "png" => OutFmt::Png,
-            "webp" => OutFmt::Webp,
-            "avif" => OutFmt::Avif,
-            _ => return Err(SvcError::BadRequest("unsupported format")),
-        }
+        OutFmt::from_name(fmt)?
     } else {
-        OutFmt::Webp // Default to WebP for Blossom thumbs
+        defaults.thumb_format.clone()
     };
 
     // Parse quality
-    let quality = params.quality.unwrap_or(82);
+    let quality = params.quality.unwrap_or(defaults.quality);
     if quality > 100 {
         return Err(SvcError::BadRequest("quality must be 0-100"));
     }
 
     // Parse resize directive
-    let resize = if let Some(ref rs) = params.resize {
-        parse_resize_from_query(rs)?
+    let mut resize = if let Some(ref rs) = params.resize {
+        parse_resize_from_query(rs, &defaults.resize_mode)?
     } else {
-        // Default: fit 480x480
         Resize {
-            mode: ResizeMode::Fit,
-            w: 480,
-            h: 480,
+            mode: defaults.resize_mode.clone(),
+            w: defaults.thumb_width,
+            h: defaults.thumb_height,
         }
     };
 
+    // Parse palette size
+    let colors = params.colors.as_deref().map(parse_colors).transpose()?;
+
+    let crop = params.crop.as_deref().map(parse_crop).transpose()?;
+
+    let gravity = params
+        .gravity
+        .as_deref()
+        .map(Gravity::from_name)
+        .transpose()?
+        .unwrap_or_default();
+
+    let saturation = if params.grayscale.is_some() {
+        Some(0)
+    } else {
+        params.saturation.as_deref().map(parse_saturation).transpose()?
+    };
+
+    let rotation = params.rotation.as_deref().map(parse_rotation).transpose()?.unwrap_or(0);
+
+    let keep_metadata = params
+        .keep_metadata
+        .as_deref()
+        .map(parse_bool)
+        .transpose()?
+        .unwrap_or(defaults.keep_metadata);
+    let progressive = params
+        .progressive
+        .as_deref()
+        .map(parse_bool)
+        .transpose()?
+        .unwrap_or(defaults.progressive);
+
+    let background = params.background.as_deref().map(parse_background).transpose()?;
+    let mut extend = params.extend.as_deref().map(parse_extend).transpose()?.flatten();
+    if let Some(ref ratio) = params.aspect_ratio {
+        apply_aspect_ratio(&mut resize, &mut extend, parse_aspect_ratio(ratio)?)?;
+    }
+    let padding = params.padding.as_deref().map(parse_padding).transpose()?;
+    if let Some(ref padding) = padding {
+        check_padded_size(&resize, padding)?;
+    }
+    let frames = params.frames.as_deref().map(parse_frames).transpose()?.unwrap_or_default();
+    let text = params.text.as_deref().map(parse_text).transpose()?;
+    let video_seek = params.video_seek.as_deref().map(parse_video_seek).transpose()?;
+
     Ok(Directives {
         out_fmt,
         quality,
         resize,
+        colors,
+        crop,
+        gravity,
+        source_kind: None,
+        video_seek,
+        saturation,
+        rotation,
+        keep_metadata,
+        background,
+        extend,
+        padding,
+        frames,
+        text,
+        embed_srgb_profile: defaults.embed_srgb_profile,
+        deterministic: defaults.deterministic,
+        progressive,
+        jpeg_alpha_format: defaults.jpeg_alpha_format.clone(),
+        round_mask: false,
+        card: None,
     })
 }
 
-/// Parse resize directive from query param (e.g., "fit:480:480")
-fn parse_resize_from_query(rs: &str) -> Result<Resize, SvcError> {
+/// Parse resize directive from query param (e.g., "fit:480:480"; an empty mode uses the default)
+fn parse_resize_from_query(rs: &str, default_mode: &ResizeMode) -> Result<Resize, SvcError> {
     let parts: Vec<&str> = rs.split(':').collect();
     if parts.len() != 3 {
         return Err(SvcError::BadRequest("invalid resize format, expected mode:width:height"));
     }
 
-    let mode = match parts[0].to_ascii_lowercase().as_str() {
-        "fit" => ResizeMode::Fit,
-        "fill" => ResizeMode::Fill,
-        "fill-down" => ResizeMode::FillDown,
-        "force" => ResizeMode::Force,
-        "auto" => ResizeMode::Auto,
-        _ => return Err(SvcError::BadRequest("unsupported resize mode")),
+    let mode = if parts[0].is_empty() {
+        default_mode.clone()
+    } else {
+        ResizeMode::from_name(parts[0])?
     };
 
     let w = parts[1].parse().unwrap_or(0);
@@ -435,7 +1651,7 @@ fn parse_resize_from_query(rs: &str) -> Result<Resize, SvcError> {
 }
 
 /// Build query string for cache key
-fn build_query_string(params: &ThumbQuery) -> String {
+pub(crate) fn build_query_string(params: &ThumbQuery) -> String {
     let mut parts = Vec::new();
 
     if let Some(ref f) = params.format {
@@ -447,39 +1663,191 @@ fn build_query_string(params: &ThumbQuery) -> String {
     if let Some(q) = params.quality {
         parts.push(format!("q={}", q));
     }
-    for xs in &params.server_hints {
-        parts.push(format!("xs={}", xs));
+    // Repeated and comma-separated forms share one canonical key
+    if !params.server_hints.is_empty() {
+        parts.push(format!("xs={}", params.server_hints.join(",")));
     }
     if let Some(ref as_) = params.author_pubkey {
         parts.push(format!("as={}", as_));
     }
+    if let Some(ref colors) = params.colors {
+        parts.push(format!("colors={}", colors));
+    }
+    if let Some(ref crop) = params.crop {
+        parts.push(format!("c={}", crop));
+    }
+    if let Some(ref gravity) = params.gravity {
+        parts.push(format!("g={}", gravity));
+    }
+    if let Some(ref sat) = params.saturation {
+        parts.push(format!("sat={}", sat));
+    }
+    if params.grayscale.is_some() {
+        parts.push("grayscale".to_string());
+    }
+    if let Some(ref rot) = params.rotation {
+        parts.push(format!("rot={}", rot));
+    }
+    if let Some(ref km) = params.keep_metadata {
+        parts.push(format!("km={}", km));
+    }
+    if let Some(ref progressive) = params.progressive {
+        parts.push(format!("progressive={}", progressive));
+    }
+    if let Some(ref bg) = params.background {
+        parts.push(format!("bg={}", bg));
+    }
+    if let Some(ref ex) = params.extend {
+        parts.push(format!("ex={}", ex));
+    }
+    if let Some(ref pd) = params.padding {
+        parts.push(format!("pd={}", pd));
+    }
+    if let Some(ref frames) = params.frames {
+        parts.push(format!("frames={}", frames));
+    }
+    if let Some(ref txt) = params.text {
+        parts.push(format!("txt={}", txt));
+    }
+    if let Some(ref ar) = params.aspect_ratio {
+        parts.push(format!("ar={}", ar));
+    }
+    if let Some(ref t) = params.video_seek {
+        parts.push(format!("t={}", t));
+    }
 
     parts.join("&")
 }
 
+/// Read a source body, giving up as soon as it exceeds `max_bytes`
+///
+/// A `Content-Length` over the limit is refused before reading anything; otherwise bytes are
+/// counted as they arrive, and dropping the response aborts the download.
+async fn read_body_limited(mut resp: reqwest::Response, max_bytes: usize) -> Result<Bytes, SvcError> {
+    let too_large = || {
+        metrics::record_processing_error("image_too_large");
+        SvcError::BadRequest("image too large")
+    };
+    let expected = resp.content_length().unwrap_or(0);
+    if expected > max_bytes as u64 {
+        return Err(too_large());
+    }
+    let mut body = BytesMut::with_capacity(expected as usize);
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+/// BUD-01 `HEAD` of a blob before downloading it, when `BLOSSOM_HEAD_CHECK` is on
+///
+/// Ok means the server confirmed the blob with a size within `MAX_IMAGE_BYTES` (or no size;
+/// the download is still capped). Servers that lack the blob, fail or answer anything but 2xx
+/// give the error to record for them; `BadRequest` means the blob is too large on every server.
+async fn check_blob_head(state: &AppState, url: &str, hash: &str) -> Result<(), SvcError> {
+    if !state.cfg.blossom_head_check {
+        return Ok(());
+    }
+    let attempt_start = Instant::now();
+    let resp = match upstream_auth::sign(state.http.head(url), url).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            state.server_stats.record_error(url);
+            debug_trace::server_attempt(url, || format!("HEAD error: {}", e), attempt_start);
+            metrics::record_blossom_head_check("failed");
+            return Err(SvcError::UpstreamError(500));
+        }
+    };
+    let status = resp.status();
+    metrics::record_upstream_response(resp.version());
+    state.server_stats.record_response(url, status, attempt_start.elapsed());
+    state.record_blob_status(url, hash, status);
+    debug_trace::server_attempt(url, || format!("HEAD status {}", status), attempt_start);
+    if !status.is_success() {
+        metrics::record_blossom_head_check(if status == StatusCode::NOT_FOUND { "missing" } else { "failed" });
+        return Err(SvcError::UpstreamError(status.as_u16()));
+    }
+    // reqwest sizes a HEAD body as empty, so read the header itself
+    let advertised = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if advertised.is_some_and(|len| len > state.cfg.max_image_bytes as u64) {
+        metrics::record_blossom_head_check("too_large");
+        metrics::record_processing_error("image_too_large");
+        return Err(SvcError::BadRequest("image too large"));
+    }
+    metrics::record_blossom_head_check("confirmed");
+    Ok(())
+}
+
+/// Whether `bytes` are the blob `hash` names
+///
+/// A server serving other content counts as not having the blob, so later requests skip it.
+fn verify_blob(state: &AppState, url: &str, hash: &str, bytes: &[u8]) -> bool {
+    if hex::encode(Sha256::digest(bytes)).eq_ignore_ascii_case(hash) {
+        return true;
+    }
+    metrics::record_processing_error("hash_mismatch");
+    state.record_blob_status(url, hash, StatusCode::NOT_FOUND);
+    debug_trace::event("verify", || format!("content from {} does not match its hash", url));
+    tracing::warn!("{} does not hash to {}, rejecting it", url, hash);
+    false
+}
+
 /// Fetch image from Blossom servers (try each in order)
-async fn fetch_from_blossom_servers(
+pub(crate) async fn fetch_from_blossom_servers(
     state: &AppState,
     servers: &[String],
     hash: &str,
     ext: &str,
-) -> Result<Bytes, SvcError> {
+) -> Result<(Bytes, String), SvcError> {
     if servers.is_empty() {
         return Err(SvcError::BadRequest("no servers available to fetch from"));
     }
 
+    let servers = &state.blob_candidates(servers, hash);
+    if servers.is_empty() {
+        tracing::debug!("all servers recently returned 404 for {}.{}", hash, ext);
+        return Err(SvcError::UpstreamError(404));
+    }
+
     let mut last_error = None;
 
     for (idx, server) in servers.iter().enumerate() {
         let url = format!("{}/{}.{}", server.trim_end_matches('/'), hash, ext);
         tracing::debug!("Attempting server {}/{}: {}", idx + 1, servers.len(), url);
+        match check_blob_head(state, &url, hash).await {
+            Ok(()) => {}
+            Err(e @ SvcError::BadRequest(_)) => return Err(e),
+            Err(e) => {
+                tracing::debug!("✗ Server {}/{} did not confirm the blob: {:?}", idx + 1, servers.len(), e);
+                last_error = Some(e);
+                continue;
+            }
+        }
+        let attempt_start = Instant::now();
 
-        match state.http.get(&url).send().await {
+        match state.get_with_retry(&url).await {
             Ok(resp) => {
                 let status = resp.status();
+                metrics::record_upstream_response(resp.version());
+                state.server_stats.record_response(&url, status, attempt_start.elapsed());
+                state.record_blob_status(&url, hash, status);
+                debug_trace::server_attempt(&url, || format!("status {}", status), attempt_start);
                 if status.is_success() {
-                    match resp.bytes().await {
+                    match read_body_limited(resp, state.cfg.max_image_bytes).await {
                         Ok(bytes) => {
+                            metrics::record_bytes_downloaded(state.cfg.metrics_label(), "blossom", bytes.len());
+                            report::record_upstream_bytes(&url, bytes.len());
+                            if !verify_blob(state, &url, hash, &bytes) {
+                                last_error = Some(SvcError::HashMismatch);
+                                continue;
+                            }
                             tracing::info!(
                                 "✓ Server {}/{} succeeded: {} ({} bytes)",
                                 idx + 1,
@@ -487,8 +1855,10 @@ async fn fetch_from_blossom_servers(
                                 server,
                                 bytes.len()
                             );
-                            return Ok(bytes);
+                            return Ok((bytes, server_origin(&url)));
                         }
+                        // Every server holds the same blob, so it's too large everywhere
+                        Err(e @ SvcError::BadRequest(_)) => return Err(e),
                         Err(e) => {
                             tracing::debug!("✗ Server {}/{} failed to read bytes: {:?}", idx + 1, servers.len(), e);
                             last_error = Some(SvcError::UpstreamError(500));
@@ -506,6 +1876,8 @@ async fn fetch_from_blossom_servers(
                 }
             }
             Err(e) => {
+                state.server_stats.record_error(&url);
+                debug_trace::server_attempt(&url, || format!("error: {}", e), attempt_start);
                 tracing::debug!("✗ Server {}/{} request failed: {:?}", idx + 1, servers.len(), e);
                 last_error = Some(SvcError::UpstreamError(500));
             }
@@ -518,6 +1890,58 @@ async fn fetch_from_blossom_servers(
     Err(last_error.unwrap_or(SvcError::UpstreamError(404)))
 }
 
+/// Fetch an /insecure source that must hash to `expected` (`x=`)
+///
+/// The URL is tried first; when it fails or serves other content (an edited or replaced
+/// file), the blob is fetched by hash from the hinted, author and fallback Blossom servers.
+async fn fetch_verified_source(
+    state: &CombinedState,
+    src_url: &str,
+    expected: &str,
+    hints: &ServerHintsQuery,
+    fallback_servers: &[String],
+) -> Result<(Bytes, String), SvcError> {
+    let direct_error = match fetch_source(&state.app, src_url, fallback_servers).await {
+        Ok((bytes, server)) if hex::encode(Sha256::digest(&bytes)) == expected => return Ok((bytes, server)),
+        Ok((_, server)) => {
+            metrics::record_processing_error("hash_mismatch");
+            tracing::info!("{} from {} does not match x={}, trying Blossom servers", src_url, server, expected);
+            debug_trace::event("verify", || format!("content from {} does not match x", server));
+            SvcError::HashMismatch
+        }
+        Err(e) => e,
+    };
+
+    let servers = resolve_blossom_servers(state, &hints.server_hints, hints.author_pubkey.as_deref()).await;
+    let ext = extract_blossom_hash(src_url)
+        .map(|(_, ext)| ext)
+        .or_else(|| url_extension(src_url))
+        .unwrap_or("bin");
+    // Blobs from Blossom servers are verified as they're fetched
+    fetch_from_blossom_servers(&state.app, &servers, expected, ext)
+        .await
+        .map_err(|e| {
+            debug_trace::event("verify", || format!("no Blossom server has the blob: {:?}", e));
+            direct_error
+        })
+}
+
+/// Extension of a URL's last path segment, if it looks like one
+fn url_extension(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let (_, ext) = path.rsplit('/').next()?.rsplit_once('.')?;
+    (!ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric())).then_some(ext)
+}
+
+/// Refuse sources whose host `ALLOWED_SOURCE_HOSTS` / `DENIED_SOURCE_HOSTS` exclude
+pub(crate) fn check_source_host(cfg: &AppCfg, url: &str) -> Result<(), SvcError> {
+    if cfg.source_host_allowed(url) {
+        return Ok(());
+    }
+    metrics::record_processing_error("host_denied");
+    Err(SvcError::Forbidden("source host not allowed"))
+}
+
 /// Check if a URL is a Blossom CDN URL (has <sha256>.<ext> format)
 fn is_blossom_url(url: &str) -> bool {
     if let Some(filename) = url.rsplit('/').next() {
@@ -543,63 +1967,112 @@ fn extract_blossom_hash(url: &str) -> Option<(&str, &str)> {
     None
 }
 
-/// Fetch source image from URL with Blossom fallback support
-async fn fetch_source(state: &AppState, src_url: &str) -> Result<Bytes, SvcError> {
+/// Fetch a source image, returning its bytes and the origin of the server that supplied them
+async fn fetch_source(
+    state: &AppState,
+    src_url: &str,
+    fallback_servers: &[String],
+) -> Result<(Bytes, String), SvcError> {
     // Basic allowlist: only http/https
     if !(src_url.starts_with("http://") || src_url.starts_with("https://")) {
         return Err(SvcError::BadRequest("unsupported source scheme"));
     }
+    check_source_host(&state.cfg, src_url)?;
 
     // Try original URL first
+    let attempt_start = Instant::now();
     let result = async {
-        let resp = state.http.get(src_url).send().await?;
+        let resp = state.get_with_retry(src_url).await.inspect_err(|_| {
+            state.server_stats.record_error(src_url);
+        })?;
         let status = resp.status();
+        metrics::record_upstream_response(resp.version());
+        state.server_stats.record_response(src_url, status, attempt_start.elapsed());
+        if let Some((hash, _)) = extract_blossom_hash(src_url) {
+            state.record_blob_status(src_url, hash, status);
+        }
+        debug_trace::server_attempt(src_url, || format!("status {}", status), attempt_start);
         if status.is_success() {
-            resp.bytes().await.map_err(Into::into)
+            read_body_limited(resp, state.cfg.max_image_bytes).await
         } else {
             tracing::debug!("primary server returned non-success status for image {}: {}", src_url, status);
             Err(SvcError::UpstreamError(status.as_u16()))
         }
     }.await;
+    // A Blossom URL names its content; anything else is rejected like a failed fetch
+    let result = match (result, extract_blossom_hash(src_url)) {
+        (Ok(bytes), Some((hash, _))) if !verify_blob(state, src_url, hash, &bytes) => Err(SvcError::HashMismatch),
+        (result, _) => result,
+    };
 
     // If successful, return immediately
     if let Ok(bytes) = &result {
+        let source_type = if is_blossom_url(src_url) { "blossom" } else { "direct" };
+        metrics::record_bytes_downloaded(state.cfg.metrics_label(), source_type, bytes.len());
+        report::record_upstream_bytes(src_url, bytes.len());
         tracing::debug!("primary server succeeded for image {}, received {} bytes", src_url, bytes.len());
-        return Ok(bytes.clone());
+        return Ok((bytes.clone(), server_origin(src_url)));
+    }
+
+    // An oversized blob is the same size on every fallback server
+    if let Err(SvcError::BadRequest(msg)) = result {
+        return Err(SvcError::BadRequest(msg));
     }
 
     // Log primary failure
     tracing::debug!("primary server failed for image {}: {:?}", src_url, result);
+    debug_trace::event("fetch", || format!("primary failed: {:?}", result));
 
     // If failed and it's a Blossom URL, try fallback servers
     if is_blossom_url(src_url) {
-        tracing::debug!("url is blossom format, attempting {} fallback servers", state.cfg.blossom_fallback_servers.len());
+        tracing::debug!("url is blossom format, attempting {} fallback servers", fallback_servers.len());
 
         if let Some((hash, ext)) = extract_blossom_hash(src_url) {
+            let fallback_servers = &state.blob_candidates(fallback_servers, hash);
+
             // Try each fallback server
-            for (idx, fallback_server) in state.cfg.blossom_fallback_servers.iter().enumerate() {
+            for (idx, fallback_server) in fallback_servers.iter().enumerate() {
                 let fallback_url = format!("{}/{}.{}", fallback_server.trim_end_matches('/'), hash, ext);
                 tracing::debug!(
                     "attempting fallback server {}/{} for image: {}",
                     idx + 1,
-                    state.cfg.blossom_fallback_servers.len(),
+                    fallback_servers.len(),
                     fallback_url
                 );
+                match check_blob_head(state, &fallback_url, hash).await {
+                    Ok(()) => {}
+                    Err(e @ SvcError::BadRequest(_)) => return Err(e),
+                    Err(e) => {
+                        tracing::debug!("✗ fallback server {} did not confirm the blob: {:?}", idx + 1, e);
+                        continue;
+                    }
+                }
 
-                match state.http.get(&fallback_url).send().await {
+                let attempt_start = Instant::now();
+                match state.get_with_retry(&fallback_url).await {
                     Ok(fallback_resp) => {
                         let status = fallback_resp.status();
+                        metrics::record_upstream_response(fallback_resp.version());
+                        state.server_stats.record_response(&fallback_url, status, attempt_start.elapsed());
+                        state.record_blob_status(&fallback_url, hash, status);
+                        debug_trace::server_attempt(&fallback_url, || format!("status {}", status), attempt_start);
                         if status.is_success() {
-                            match fallback_resp.bytes().await {
+                            match read_body_limited(fallback_resp, state.cfg.max_image_bytes).await {
                                 Ok(bytes) => {
+                                    metrics::record_bytes_downloaded(state.cfg.metrics_label(), "blossom", bytes.len());
+                                    report::record_upstream_bytes(&fallback_url, bytes.len());
+                                    if !verify_blob(state, &fallback_url, hash, &bytes) {
+                                        continue;
+                                    }
                                     tracing::info!(
                                         "✓ fallback server {} succeeded for image, received {} bytes from {}",
                                         idx + 1,
                                         bytes.len(),
                                         fallback_server
                                     );
-                                    return Ok(bytes);
+                                    return Ok((bytes, server_origin(&fallback_url)));
                                 }
+                                Err(e @ SvcError::BadRequest(_)) => return Err(e),
                                 Err(e) => {
                                     tracing::debug!(
                                         "✗ fallback server {} failed to read response bytes: {:?}",
@@ -618,6 +2091,8 @@ async fn fetch_source(state: &AppState, src_url: &str) -> Result<Bytes, SvcError
                         }
                     }
                     Err(e) => {
+                        state.server_stats.record_error(&fallback_url);
+                        debug_trace::server_attempt(&fallback_url, || format!("error: {}", e), attempt_start);
                         tracing::debug!(
                             "✗ fallback server {} request failed for {}: {:?}",
                             idx + 1,
@@ -630,7 +2105,7 @@ async fn fetch_source(state: &AppState, src_url: &str) -> Result<Bytes, SvcError
 
             tracing::warn!(
                 "all {} fallback servers exhausted for image {}, returning original error",
-                state.cfg.blossom_fallback_servers.len(),
+                fallback_servers.len(),
                 src_url
             );
         }
@@ -639,6 +2114,6 @@ async fn fetch_source(state: &AppState, src_url: &str) -> Result<Bytes, SvcError
     }
 
     // All attempts failed - return original error
-    result
+    result.map(|bytes| (bytes, server_origin(src_url)))
 }
 
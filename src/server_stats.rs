@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::blossom::server_origin;
+
+/// Weight of the newest sample in the moving latency average
+const EWMA_ALPHA: f64 = 0.3;
+/// Consecutive failures after which a server is moved to the back of the list
+const UNHEALTHY_AFTER: u32 = 3;
+/// How long an unhealthy server stays at the back before it is given another chance
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Response-time tracking for upstream servers, used to try the fastest fallback first
+///
+/// Servers are keyed by origin. Any HTTP response except 5xx counts as a latency sample
+/// (a Blossom 404 only means the blob is missing); connection errors and 5xx count as failures.
+#[derive(Default)]
+pub struct ServerStats {
+    servers: Mutex<HashMap<String, ServerStat>>,
+}
+
+struct ServerStat {
+    /// Exponentially weighted time to response headers, in milliseconds
+    latency_ms: f64,
+    consecutive_failures: u32,
+    last_failure: Option<Instant>,
+}
+
+/// A server's stats as kept across restarts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerHealth {
+    pub latency_ms: f64,
+    pub consecutive_failures: u32,
+    /// Seconds from the last failure to the export
+    pub last_failure_secs_ago: Option<u64>,
+}
+
+impl ServerStat {
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures < UNHEALTHY_AFTER
+            || self
+                .last_failure
+                .is_some_and(|t| t.elapsed() >= UNHEALTHY_COOLDOWN)
+    }
+}
+
+impl ServerStats {
+    /// Record an upstream response for `url`, `elapsed` being the time to response headers
+    pub fn record_response(&self, url: &str, status: StatusCode, elapsed: Duration) {
+        if status.is_server_error() {
+            self.record_error(url);
+            return;
+        }
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        let mut servers = self.servers.lock().unwrap();
+        servers
+            .entry(server_origin(url))
+            .and_modify(|s| {
+                s.latency_ms = EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * s.latency_ms;
+                s.consecutive_failures = 0;
+            })
+            .or_insert(ServerStat {
+                latency_ms: sample,
+                consecutive_failures: 0,
+                last_failure: None,
+            });
+    }
+
+    /// Record a failed request (connection error, timeout or 5xx) for `url`
+    pub fn record_error(&self, url: &str) {
+        let mut servers = self.servers.lock().unwrap();
+        let stat = servers.entry(server_origin(url)).or_insert(ServerStat {
+            latency_ms: 0.0,
+            consecutive_failures: 0,
+            last_failure: None,
+        });
+        stat.consecutive_failures += 1;
+        stat.last_failure = Some(Instant::now());
+    }
+
+    /// Order servers fastest healthy first
+    ///
+    /// Servers without samples keep their configured position ahead of measured ones so
+    /// they get measured; unhealthy servers go last. The sort is stable, so ties keep
+    /// the configured order.
+    pub fn order(&self, servers: &[String]) -> Vec<String> {
+        let stats = self.servers.lock().unwrap();
+        let mut ranked: Vec<(u8, f64, &String)> = servers
+            .iter()
+            .map(|server| match stats.get(&server_origin(server)) {
+                None => (0, 0.0, server),
+                Some(s) if s.is_healthy() => (1, s.latency_ms, server),
+                Some(s) => (2, s.latency_ms, server),
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        ranked.into_iter().map(|(_, _, s)| s.clone()).collect()
+    }
+
+    /// Every server's stats, by origin
+    pub fn export(&self) -> HashMap<String, ServerHealth> {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .iter()
+            .map(|(origin, s)| {
+                let health = ServerHealth {
+                    latency_ms: s.latency_ms,
+                    consecutive_failures: s.consecutive_failures,
+                    last_failure_secs_ago: s.last_failure.map(|t| t.elapsed().as_secs()),
+                };
+                (origin.clone(), health)
+            })
+            .collect()
+    }
+
+    /// Take over stats exported `age` ago; servers measured since keep their own
+    pub fn import(&self, health: HashMap<String, ServerHealth>, age: Duration) {
+        let mut servers = self.servers.lock().unwrap();
+        for (origin, h) in health {
+            // Failures older than this process count as just now, which only delays a retry
+            let last_failure = h
+                .last_failure_secs_ago
+                .map(|ago| Instant::now().checked_sub(age + Duration::from_secs(ago)).unwrap_or_else(Instant::now));
+            servers.entry(origin).or_insert(ServerStat {
+                latency_ms: h.latency_ms,
+                consecutive_failures: h.consecutive_failures,
+                last_failure,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers() -> Vec<String> {
+        vec![
+            "https://a.example".to_string(),
+            "https://b.example".to_string(),
+            "https://c.example".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_orders_by_latency() {
+        let stats = ServerStats::default();
+        stats.record_response("https://a.example/x.jpg", StatusCode::OK, Duration::from_millis(300));
+        stats.record_response("https://b.example/x.jpg", StatusCode::NOT_FOUND, Duration::from_millis(50));
+        // c is unmeasured and is tried first
+        assert_eq!(
+            stats.order(&servers()),
+            vec!["https://c.example", "https://b.example", "https://a.example"]
+        );
+    }
+
+    #[test]
+    fn test_unhealthy_servers_go_last() {
+        let stats = ServerStats::default();
+        for s in servers() {
+            stats.record_response(&s, StatusCode::OK, Duration::from_millis(100));
+        }
+        stats.record_response("https://a.example", StatusCode::OK, Duration::from_millis(10));
+        for _ in 0..UNHEALTHY_AFTER {
+            stats.record_error("https://a.example/x.jpg");
+        }
+        assert_eq!(stats.order(&servers()).last().unwrap(), "https://a.example");
+
+        // One good response restores it
+        stats.record_response("https://a.example", StatusCode::OK, Duration::from_millis(10));
+        assert_eq!(stats.order(&servers())[0], "https://a.example");
+    }
+
+    #[test]
+    fn test_export_import() {
+        let stats = ServerStats::default();
+        stats.record_response("https://b.example/x.jpg", StatusCode::OK, Duration::from_millis(50));
+        for _ in 0..UNHEALTHY_AFTER {
+            stats.record_error("https://a.example/x.jpg");
+        }
+        let exported = stats.export();
+        assert_eq!(exported["https://a.example"].consecutive_failures, UNHEALTHY_AFTER);
+
+        let restored = ServerStats::default();
+        restored.import(exported, Duration::from_secs(5));
+        assert_eq!(restored.order(&servers()), stats.order(&servers()));
+        assert_eq!(restored.order(&servers()).last().unwrap(), "https://a.example");
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{OriginalUri, Request},
+    http::{header, HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use reqwest::{redirect, Client};
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+use crate::{config::AppCfg, error::SvcError, metrics};
+
+/// Marks mirrored requests, so an instance never mirrors them again
+const SHADOW_HEADER: &str = "x-shadow";
+/// Mirrored requests outstanding at once; samples beyond it are skipped
+const MAX_IN_FLIGHT: usize = 32;
+/// Output sizes further apart than this fraction of the larger count as a divergence
+const SIZE_TOLERANCE: f64 = 0.25;
+
+/// Mirroring set up at startup; unset means no request is mirrored
+static SHADOW: OnceLock<Shadow> = OnceLock::new();
+
+struct Shadow {
+    /// `SHADOW_URL` without a trailing slash
+    base: String,
+    sample_rate: f64,
+    http: Client,
+    permits: Arc<Semaphore>,
+}
+
+/// Start mirroring to `SHADOW_URL`, when set with a non-zero sample rate; call once at startup
+pub fn init(cfg: &AppCfg) {
+    let Some(ref url) = cfg.shadow_url else {
+        return;
+    };
+    if cfg.shadow_sample_rate <= 0.0 {
+        return;
+    }
+    let http = Client::builder()
+        .timeout(cfg.shadow_timeout)
+        .user_agent("rust-imgproxy/0.1")
+        .redirect(redirect::Policy::none())
+        .build()
+        .expect("reqwest client");
+    info!("mirroring {:.1}% of processing requests to {}", cfg.shadow_sample_rate * 100.0, url);
+    let _ = SHADOW.set(Shadow {
+        base: url.trim_end_matches('/').to_string(),
+        sample_rate: cfg.shadow_sample_rate,
+        http,
+        permits: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+    });
+}
+
+/// Mirror a sample of processing GETs to `SHADOW_URL` and compare the answers in the background
+///
+/// The client always gets this instance's response, unchanged and without waiting on the
+/// shadow. The mirrored request carries the original path (tenant prefix included) and query,
+/// plus the `Accept` and `Authorization` headers, so the shadow should run with the same keys
+/// and tenants. Status, content type, dimensions and size are compared; a divergence is logged
+/// as a warning and counted in `imgproxy_shadow_comparisons_total`.
+pub async fn mirror_sample(req: Request, next: Next) -> Response {
+    let Some(shadow) = SHADOW.get() else {
+        return next.run(req).await;
+    };
+    if req.method() != Method::GET || req.headers().contains_key(SHADOW_HEADER) || !sampled(shadow.sample_rate) {
+        return next.run(req).await;
+    }
+    // A slow shadow must not pile up work here
+    let Ok(permit) = shadow.permits.clone().try_acquire_owned() else {
+        return next.run(req).await;
+    };
+    let uri = req.extensions().get::<OriginalUri>().map_or(req.uri(), |original| &original.0);
+    let path = uri.path_and_query().map_or("/", |pq| pq.as_str()).to_string();
+    let mut headers = HeaderMap::new();
+    for name in [header::ACCEPT, header::AUTHORIZATION] {
+        if let Some(value) = req.headers().get(&name) {
+            headers.insert(name, value.clone());
+        }
+    }
+
+    let started = Instant::now();
+    let resp = next.run(req).await;
+    let primary_secs = started.elapsed().as_secs_f64();
+
+    // Processing responses are already in memory, so buffering copies nothing
+    let (parts, body) = resp.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => return SvcError::InternalError(format!("buffering response: {}", e)).into_response(),
+    };
+    let (status, resp_headers, primary_body) = (parts.status.as_u16(), parts.headers.clone(), body.clone());
+    tokio::spawn(async move {
+        let _permit = permit;
+        let primary = Answer::new(status, &resp_headers, &primary_body);
+        compare(shadow, path, headers, primary, primary_secs).await;
+    });
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// True for roughly `rate` of calls
+fn sampled(rate: f64) -> bool {
+    rate >= 1.0 || (RandomState::new().hash_one(Instant::now()) as f64 / u64::MAX as f64) < rate
+}
+
+/// Fetch the mirrored request from the shadow and log how its answer differs
+async fn compare(shadow: &Shadow, path: String, headers: HeaderMap, primary: Answer, primary_secs: f64) {
+    let started = Instant::now();
+    let answer = async {
+        let resp = shadow
+            .http
+            .get(format!("{}{}", shadow.base, path))
+            .headers(headers)
+            .header(SHADOW_HEADER, "1")
+            .send()
+            .await?;
+        let status = resp.status().as_u16();
+        let resp_headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+        Ok::<_, reqwest::Error>(Answer::new(status, &resp_headers, &body))
+    }
+    .await;
+    let shadow_secs = started.elapsed().as_secs_f64();
+    let (primary_ms, shadow_ms) = ((primary_secs * 1000.0) as u64, (shadow_secs * 1000.0) as u64);
+
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(e) => {
+            warn!(primary_ms, "shadow request for {} failed: {}", path, e);
+            metrics::record_shadow_comparison("error", primary_secs, None);
+            return;
+        }
+    };
+    let divergences = divergences(&primary, &answer);
+    if divergences.is_empty() {
+        let identical = primary.digest == answer.digest;
+        debug!(primary_ms, shadow_ms, identical, "shadow matched for {}", path);
+        metrics::record_shadow_comparison("match", primary_secs, Some(shadow_secs));
+    } else {
+        warn!(primary_ms, shadow_ms, "shadow diverged for {}: {}", path, divergences.join("; "));
+        metrics::record_shadow_comparison("divergent", primary_secs, Some(shadow_secs));
+    }
+}
+
+/// The parts of a response that are compared
+#[derive(Debug, Clone, PartialEq)]
+struct Answer {
+    status: u16,
+    content_type: Option<String>,
+    /// From `X-Width`/`X-Height`, when the endpoint sets them
+    dimensions: Option<(u32, u32)>,
+    len: usize,
+    digest: Vec<u8>,
+}
+
+impl Answer {
+    fn new(status: u16, headers: &HeaderMap, body: &Bytes) -> Self {
+        let header_str = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let dimension = |name: &str| header_str(name).and_then(|v| v.parse::<u32>().ok());
+        Self {
+            status,
+            content_type: header_str(header::CONTENT_TYPE.as_str()).map(str::to_string),
+            dimensions: dimension("x-width").zip(dimension("x-height")),
+            len: body.len(),
+            digest: Sha256::digest(body).to_vec(),
+        }
+    }
+}
+
+/// Differences worth a warning; byte-level differences alone are expected from lossy encoders
+fn divergences(primary: &Answer, shadow: &Answer) -> Vec<String> {
+    let mut found = Vec::new();
+    if primary.status != shadow.status {
+        found.push(format!("status {} vs {}", primary.status, shadow.status));
+    }
+    if primary.content_type != shadow.content_type {
+        found.push(format!("content type {:?} vs {:?}", primary.content_type, shadow.content_type));
+    }
+    if primary.dimensions != shadow.dimensions {
+        found.push(format!("dimensions {:?} vs {:?}", primary.dimensions, shadow.dimensions));
+    }
+    let larger = primary.len.max(shadow.len);
+    if larger > 0 && primary.len.abs_diff(shadow.len) as f64 > larger as f64 * SIZE_TOLERANCE {
+        found.push(format!("size {} vs {} bytes", primary.len, shadow.len));
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(status: u16, content_type: &str, dimensions: Option<(u32, u32)>, body: &'static [u8]) -> Answer {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        if let Some((w, h)) = dimensions {
+            headers.insert("x-width", w.into());
+            headers.insert("x-height", h.into());
+        }
+        Answer::new(status, &headers, &Bytes::from_static(body))
+    }
+
+    #[test]
+    fn test_answer_reads_headers() {
+        let a = answer(200, "image/webp", Some((320, 240)), b"abc");
+        assert_eq!(a.content_type.as_deref(), Some("image/webp"));
+        assert_eq!(a.dimensions, Some((320, 240)));
+        assert_eq!(a.len, 3);
+    }
+
+    #[test]
+    fn test_divergences() {
+        let primary = answer(200, "image/webp", Some((320, 240)), b"0123456789");
+        // Same shape, bytes a little different: not a divergence
+        assert!(divergences(&primary, &answer(200, "image/webp", Some((320, 240)), b"012345678")).is_empty());
+
+        let other = answer(502, "text/plain", None, b"err");
+        let found = divergences(&primary, &other);
+        assert_eq!(found.len(), 4, "{:?}", found);
+        assert!(found[0].starts_with("status 200 vs 502"));
+    }
+
+    #[test]
+    fn test_sampled_bounds() {
+        assert!(sampled(1.0));
+        assert!(!sampled(0.0));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use tokio::sync::watch;
+
+use crate::error::SvcError;
+
+/// Buffered response shared with every waiter of an in-flight request
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    async fn capture(result: Result<Response, SvcError>) -> Self {
+        let (parts, body) = result.into_response().into_parts();
+        // Image and error bodies are already fully buffered
+        let body = to_bytes(body, usize::MAX).await.unwrap_or_default();
+        Self {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        }
+    }
+
+    fn to_response(&self) -> Response {
+        let mut resp = Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        *resp.headers_mut() = self.headers.clone();
+        resp
+    }
+}
+
+type Slot = watch::Receiver<Option<SharedResponse>>;
+
+/// Coalesces identical in-flight requests so only one pipeline runs per cache key
+#[derive(Default)]
+pub struct InFlight {
+    calls: Mutex<HashMap<String, Slot>>,
+}
+
+/// Removes the in-flight entry even if the pipeline panics
+struct CallGuard {
+    inflight: Arc<InFlight>,
+    key: String,
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.inflight.calls.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl InFlight {
+    /// Run `pipeline` for `key`, or wait for the identical pipeline already running
+    ///
+    /// The pipeline runs on its own task so a disconnecting first client does not cancel it
+    /// for everyone else. Followers get the leader's response with `X-Cache: coalesced`.
+    pub async fn run<F>(self: &Arc<Self>, key: String, pipeline: F) -> Response
+    where
+        F: Future<Output = Result<Response, SvcError>> + Send + 'static,
+    {
+        let (mut rx, leader_tx) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(rx) => (rx.clone(), None),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    calls.insert(key.clone(), rx.clone());
+                    (rx, Some(tx))
+                }
+            }
+        };
+
+        let leader = leader_tx.is_some();
+        if let Some(tx) = leader_tx {
+            let guard = CallGuard {
+                inflight: self.clone(),
+                key,
+            };
+            tokio::spawn(async move {
+                let shared = SharedResponse::capture(pipeline.await).await;
+                // Publish before unregistering so late joiners still see the result
+                let _ = tx.send(Some(shared));
+                drop(guard);
+            });
+        }
+
+        let shared = match rx.wait_for(Option::is_some).await {
+            Ok(shared) => shared.clone(),
+            Err(_) => None,
+        };
+        let Some(shared) = shared else {
+            // The pipeline task panicked before producing a response
+            return SvcError::InternalError("request processing aborted".to_string()).into_response();
+        };
+
+        let mut resp = shared.to_response();
+        if !leader && resp.headers().contains_key("x-cache") {
+            resp.headers_mut()
+                .insert("x-cache", HeaderValue::from_static("coalesced"));
+        }
+        resp
+    }
+
+    /// Start `task` on its own task, unless the one last started for `key` is still running
+    ///
+    /// For background work nobody waits on, like refreshing a soft-purged entry: every stale
+    /// hit asks for a refresh, only the first one until it finishes starts it.
+    pub fn spawn_once<F>(self: &Arc<Self>, key: String, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        {
+            let mut calls = self.calls.lock().unwrap();
+            if calls.contains_key(&key) {
+                return;
+            }
+            // Nothing is ever published; the entry only marks the task as running
+            calls.insert(key.clone(), watch::channel(None).1);
+        }
+        let guard = CallGuard {
+            inflight: self.clone(),
+            key,
+        };
+        tokio::spawn(async move {
+            task.await;
+            drop(guard);
+        });
+    }
+}
+
+/// Key of a call within a tenant's cache namespace
+///
+/// Tenants with separate caches serve different results for the same path, so they never share a call.
+pub fn namespaced_key(namespace: &str, key: &str) -> String {
+    format!("{}|{}", namespace, key)
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cache::warm_memory_cache,
+    config::AppState,
+    report::{self, SourceEntry},
+    server_stats::ServerHealth,
+};
+
+/// Snapshot file, relative to `CACHE_DIR`
+const SNAPSHOT_FILE: &str = "state-snapshot.json";
+
+/// In-memory state kept across a graceful restart
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    /// Unix timestamp (seconds) of the shutdown
+    saved_at: u64,
+    /// Entries of the memory tier, as paths relative to `CACHE_DIR`
+    hot_paths: Vec<PathBuf>,
+    /// Hit counters of the unfinished cache report interval
+    sources: Vec<SourceEntry>,
+    /// Upstream health, by origin
+    servers: HashMap<String, ServerHealth>,
+}
+
+fn snapshot_path(app: &AppState) -> PathBuf {
+    app.cfg.cache_dir.join(SNAPSHOT_FILE)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// Write the snapshot; call once the server has stopped taking requests
+pub async fn save(app: &AppState) {
+    if app.cfg.state_snapshot_max_age.is_zero() {
+        return;
+    }
+    let cache_dir = &app.cfg.cache_dir;
+    let hot_paths = app
+        .memory_cache
+        .as_ref()
+        .map(|memory| memory.paths())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|path| path.strip_prefix(cache_dir).ok().map(Path::to_path_buf))
+        .collect();
+    let snapshot = Snapshot {
+        saved_at: unix_now(),
+        hot_paths,
+        sources: report::current_sources(),
+        servers: app.server_stats.export(),
+    };
+
+    let path = snapshot_path(app);
+    let result = match serde_json::to_vec(&snapshot) {
+        Ok(bytes) => write_atomic(&path, &bytes).await,
+        Err(e) => Err(std::io::Error::other(e)),
+    };
+    match result {
+        Ok(()) => info!(
+            hot_entries = snapshot.hot_paths.len(),
+            sources = snapshot.sources.len(),
+            servers = snapshot.servers.len(),
+            "saved state snapshot"
+        ),
+        Err(e) => warn!(path = %path.display(), "failed to save state snapshot: {}", e),
+    }
+}
+
+/// Load the snapshot of the previous run, if recent enough, and remove it
+///
+/// Server health and hit counters are restored right away; the memory tier is refilled from the
+/// disk cache in the background, so startup doesn't wait on reading every hot entry.
+pub async fn restore(app: &AppState) {
+    let max_age = app.cfg.state_snapshot_max_age;
+    if max_age.is_zero() {
+        return;
+    }
+    let path = snapshot_path(app);
+    let bytes = match tokio::fs::read(&path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!(path = %path.display(), "failed to read state snapshot: {}", e);
+            return;
+        }
+    };
+    // Used once: a later crash must not bring back this run's start
+    let _ = tokio::fs::remove_file(&path).await;
+    let snapshot: Snapshot = match serde_json::from_slice(&bytes) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!(path = %path.display(), "ignoring unreadable state snapshot: {}", e);
+            return;
+        }
+    };
+    let age = Duration::from_secs(unix_now().saturating_sub(snapshot.saved_at));
+    if age > max_age {
+        info!(age_secs = age.as_secs(), "ignoring outdated state snapshot");
+        return;
+    }
+
+    info!(
+        hot_entries = snapshot.hot_paths.len(),
+        sources = snapshot.sources.len(),
+        servers = snapshot.servers.len(),
+        "restoring state snapshot"
+    );
+    app.server_stats.import(snapshot.servers, age);
+    report::restore_sources(snapshot.sources);
+
+    let hot_paths: Vec<PathBuf> = snapshot
+        .hot_paths
+        .iter()
+        .filter_map(|path| within(&app.cfg.cache_dir, path))
+        .collect();
+    if hot_paths.is_empty() || app.memory_cache.is_none() {
+        return;
+    }
+    let app = app.clone();
+    tokio::spawn(async move {
+        let loaded = warm_memory_cache(&app, &hot_paths).await;
+        info!(loaded, of = hot_paths.len(), "memory cache warmed from snapshot");
+    });
+}
+
+/// `relative` joined to `dir`, unless it could point outside of it
+fn within(dir: &Path, relative: &Path) -> Option<PathBuf> {
+    relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| dir.join(relative))
+}
+
+async fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, bytes).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_within() {
+        let dir = Path::new("/cache");
+        assert_eq!(
+            within(dir, Path::new("processed/abc.webp")),
+            Some(PathBuf::from("/cache/processed/abc.webp"))
+        );
+        assert_eq!(within(dir, Path::new("../etc/passwd")), None);
+        assert_eq!(within(dir, Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let snapshot = Snapshot {
+            saved_at: 1_700_000_000,
+            hot_paths: vec![PathBuf::from("processed/abc.webp")],
+            sources: vec![SourceEntry {
+                source: "https://example.com/a.jpg".to_string(),
+                hits: 3,
+                bytes: 300,
+            }],
+            servers: HashMap::new(),
+        };
+        let bytes = serde_json::to_vec(&snapshot).unwrap();
+        let loaded: Snapshot = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(loaded.hot_paths, snapshot.hot_paths);
+        assert_eq!(loaded.sources[0].hits, 3);
+    }
+}
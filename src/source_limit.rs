@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{error::SvcError, metrics};
+
+/// Caps concurrent pipelines per source so one viral image or video can't take every slot
+///
+/// Coalescing already merges identical requests; this bounds the distinct variants
+/// (sizes, formats) requested for the same source at once. Sources are tracked only
+/// while they have pipelines running or queued.
+pub struct SourceLimiter {
+    /// Pipelines allowed per source at once (0 = unlimited)
+    max_concurrent: usize,
+    /// Reject with 503 once this many requests wait on one source (0 = unbounded)
+    max_queue: usize,
+    retry_after_secs: u64,
+    sources: Mutex<HashMap<String, Arc<SourceSlot>>>,
+}
+
+struct SourceSlot {
+    semaphore: Arc<Semaphore>,
+    waiters: AtomicUsize,
+}
+
+/// Held while a pipeline for the source runs; frees the slot on drop
+pub struct SourcePermit {
+    limiter: Arc<SourceLimiter>,
+    key: String,
+    slot: Arc<SourceSlot>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for SourcePermit {
+    fn drop(&mut self) {
+        self.permit.take();
+        self.limiter.release_idle(&self.key, &self.slot);
+    }
+}
+
+/// Counts a queued request; decrements even if the request is cancelled
+struct WaiterGuard<'a>(&'a SourceSlot);
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl SourceLimiter {
+    pub fn new(max_concurrent: usize, max_queue: usize, retry_after_secs: u64) -> Self {
+        Self {
+            max_concurrent,
+            max_queue,
+            retry_after_secs,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for a slot for `source`; `None` when the limit is disabled
+    pub async fn acquire(self: &Arc<Self>, source: &str) -> Result<Option<SourcePermit>, SvcError> {
+        if self.max_concurrent == 0 {
+            return Ok(None);
+        }
+
+        let slot = {
+            let mut sources = self.sources.lock().unwrap();
+            sources
+                .entry(source.to_string())
+                .or_insert_with(|| {
+                    Arc::new(SourceSlot {
+                        semaphore: Arc::new(Semaphore::new(self.max_concurrent)),
+                        waiters: AtomicUsize::new(0),
+                    })
+                })
+                .clone()
+        };
+        let semaphore = slot.semaphore.clone();
+        // From here on the permit owns the only extra reference, so dropping it
+        // (on success, rejection or cancellation) can release the source
+        let mut permit = SourcePermit {
+            limiter: self.clone(),
+            key: source.to_string(),
+            slot,
+            permit: None,
+        };
+
+        if let Ok(p) = semaphore.clone().try_acquire_owned() {
+            permit.permit = Some(p);
+            return Ok(Some(permit));
+        }
+
+        let queue_depth = permit.slot.waiters.load(Ordering::Relaxed);
+        if self.max_queue > 0 && queue_depth >= self.max_queue {
+            tracing::warn!("source queue full for {} ({} waiting), rejecting request", source, queue_depth);
+            metrics::record_processing_error("source_queue_full");
+            return Err(SvcError::Overloaded {
+                retry_after_secs: self.retry_after_secs,
+                queue_depth,
+            });
+        }
+
+        permit.slot.waiters.fetch_add(1, Ordering::Relaxed);
+        let waiter = WaiterGuard(&permit.slot);
+        let acquired = semaphore.acquire_owned().await;
+        drop(waiter);
+        let p = acquired.map_err(|_| SvcError::InternalError("source semaphore closed".to_string()))?;
+        permit.permit = Some(p);
+        Ok(Some(permit))
+    }
+
+    /// Forget a source once nothing holds or waits for its slot
+    fn release_idle(&self, key: &str, slot: &Arc<SourceSlot>) {
+        let mut sources = self.sources.lock().unwrap();
+        // Only the map and the dropping permit still reference the slot
+        if Arc::strong_count(slot) == 2 {
+            sources.remove(key);
+        }
+    }
+
+    #[cfg(test)]
+    fn tracked_sources(&self) -> usize {
+        self.sources.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_disabled_limit() {
+        let limiter = Arc::new(SourceLimiter::new(0, 0, 5));
+        assert!(limiter.acquire("a").await.unwrap().is_none());
+        assert_eq!(limiter.tracked_sources(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_when_queue_full() {
+        let limiter = Arc::new(SourceLimiter::new(1, 1, 5));
+        let first = limiter.acquire("a").await.unwrap();
+
+        // Other sources are unaffected
+        let other = limiter.acquire("b").await.unwrap();
+        assert!(other.is_some());
+
+        let queued = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire("a").await.map(|p| p.is_some()) })
+        };
+        while limiter.sources.lock().unwrap()["a"].waiters.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            limiter.acquire("a").await,
+            Err(SvcError::Overloaded { queue_depth: 1, .. })
+        ));
+
+        drop(first);
+        assert!(queued.await.unwrap().unwrap());
+        drop(other);
+        assert_eq!(limiter.tracked_sources(), 0);
+    }
+}
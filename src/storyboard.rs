@@ -0,0 +1,291 @@
+use std::{fmt::Write, ops::RangeInclusive, path::PathBuf, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{Path as AxPath, Query, State},
+    http::{HeaderMap, Uri},
+    response::Response,
+    Extension,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::{
+    cache::{
+        build_image_response, etag_for, set_etag, set_last_modified, storyboard_cache_path_for, try_serve_cache,
+        write_processed_cache,
+    },
+    config::TenantCfg,
+    error::SvcError,
+    metrics,
+    preview::parse_filename,
+    report,
+    server::{journaled, parse_list_param, resolve_blossom_servers, set_source_server, with_deadline, CombinedState},
+    singleflight::namespaced_key,
+    thumbnail::{extract_video_storyboard, probe_video_duration, StoryboardGrid},
+    transform::image_dimensions,
+};
+
+/// Tiles per row or column accepted for `cols=` and `rows=`
+pub const STORYBOARD_GRID: RangeInclusive<u32> = 1..=10;
+/// Tile widths accepted for `w=`
+pub const STORYBOARD_WIDTHS: RangeInclusive<u32> = 64..=320;
+const DEFAULT_GRID: u32 = 5;
+const DEFAULT_WIDTH: u32 = 160;
+const JPEG_MIME: &str = "image/jpeg";
+const VTT_MIME: &str = "text/vtt; charset=utf-8";
+
+/// Query parameters for /storyboard
+#[derive(Debug, Default, Deserialize)]
+pub struct StoryboardQuery {
+    /// Server hints from `xs`, filled in by `StoryboardQuery::from_uri`
+    #[serde(skip)]
+    server_hints: Vec<String>,
+    /// Author pubkey for Nostr-based lookup
+    #[serde(rename = "as")]
+    author_pubkey: Option<String>,
+    /// Tiles per row (default 5)
+    cols: Option<u32>,
+    /// Tiles per column (default 5)
+    rows: Option<u32>,
+    /// Tile width in pixels (default 160)
+    w: Option<u32>,
+}
+
+impl StoryboardQuery {
+    fn from_uri(uri: &Uri) -> Result<Self, SvcError> {
+        let Query(mut params) =
+            Query::<StoryboardQuery>::try_from_uri(uri).map_err(|_| SvcError::BadRequest("invalid query"))?;
+        params.server_hints = parse_list_param(uri.query(), "xs");
+        Ok(params)
+    }
+}
+
+/// Which artifact of a storyboard was asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Artifact {
+    Sprite,
+    Vtt,
+}
+
+/// GET /storyboard/<sha256>.<ext> - a JPEG sprite of evenly spaced frames of a Blossom video;
+/// GET /storyboard/<sha256>.<ext>.vtt - WebVTT cues pointing into that sprite
+///
+/// Players load the `.vtt` as a thumbnails track for seek-bar previews. Both artifacts come
+/// from one ffmpeg run and are cached under the hash and grid (`cols`, `rows`, `w`); the cues
+/// reference the sprite relatively with the same grid, so the two always match.
+pub async fn handle_storyboard(
+    State(state): State<CombinedState>,
+    tenant: Option<Extension<Arc<TenantCfg>>>,
+    AxPath(filename): AxPath<String>,
+    uri: Uri,
+    req_headers: HeaderMap,
+) -> Result<Response, SvcError> {
+    let state = state.for_tenant(tenant);
+    let cfg = &state.app.cfg;
+    let (video_name, artifact) = match filename.strip_suffix(".vtt") {
+        Some(video_name) => (video_name, Artifact::Vtt),
+        None => (filename.as_str(), Artifact::Sprite),
+    };
+    let (hash, ext) = parse_filename(video_name)?;
+    let (hash, ext) = (hash.to_ascii_lowercase(), ext.to_ascii_lowercase());
+    if !cfg.video_support {
+        metrics::record_processing_error("video_disabled");
+        return Err(SvcError::UnsupportedMedia("video support is disabled"));
+    }
+    let query = StoryboardQuery::from_uri(&uri)?;
+    let (cols, rows) = (query.cols.unwrap_or(DEFAULT_GRID), query.rows.unwrap_or(DEFAULT_GRID));
+    if !STORYBOARD_GRID.contains(&cols) || !STORYBOARD_GRID.contains(&rows) {
+        return Err(SvcError::BadRequest("cols and rows must be 1-10"));
+    }
+    let width = query.w.unwrap_or(DEFAULT_WIDTH);
+    if !STORYBOARD_WIDTHS.contains(&width) {
+        return Err(SvcError::BadRequest("w must be 64-320"));
+    }
+
+    let sprite_name = format!("{}.{}?cols={}&rows={}&w={}", hash, ext, cols, rows, width);
+    let cache_key = format!("/storyboard/{}", sprite_name);
+    let paths = StoryboardPaths {
+        sprite: storyboard_cache_path_for(cfg, &cache_key, "jpg"),
+        vtt: storyboard_cache_path_for(cfg, &cache_key, "vtt"),
+    };
+    let (cached_path, mime, inflight_suffix) = match artifact {
+        Artifact::Sprite => (&paths.sprite, JPEG_MIME, "sprite"),
+        Artifact::Vtt => (&paths.vtt, VTT_MIME, "vtt"),
+    };
+    if let Some(resp) = try_serve_cache(&state.app, cached_path, mime, &req_headers).await? {
+        return Ok(resp);
+    }
+
+    let job = StoryboardJob {
+        hash,
+        ext,
+        query,
+        cols,
+        rows,
+        width,
+        sprite_name,
+        paths,
+        artifact,
+    };
+    let inflight_key = namespaced_key(&cfg.cache_namespace, &format!("{}#{}", cache_key, inflight_suffix));
+    let pipeline = journaled(filename, with_deadline(cfg.request_timeout, generate_storyboard(state.clone(), job)));
+    Ok(state.inflight.run(inflight_key, pipeline).await)
+}
+
+struct StoryboardPaths {
+    sprite: PathBuf,
+    vtt: PathBuf,
+}
+
+/// Everything `generate_storyboard` needs after a cache miss
+struct StoryboardJob {
+    hash: String,
+    ext: String,
+    query: StoryboardQuery,
+    cols: u32,
+    rows: u32,
+    width: u32,
+    /// Sprite URL relative to the `.vtt`, as written into the cues
+    sprite_name: String,
+    paths: StoryboardPaths,
+    artifact: Artifact,
+}
+
+/// Render the sprite, derive the cues from its size and cache both
+async fn generate_storyboard(state: CombinedState, job: StoryboardJob) -> Result<Response, SvcError> {
+    let cfg = &state.app.cfg;
+    let started = Instant::now();
+
+    // Bound the variants of one blob processed at once
+    let _source_permit = state.source_limiter.acquire(&job.hash).await?;
+
+    let servers = resolve_blossom_servers(&state, &job.query.server_hints, job.query.author_pubkey.as_deref()).await;
+    let servers = state.app.blob_candidates(&servers, &job.hash);
+    let Some((primary, fallbacks)) = servers.split_first() else {
+        return Err(SvcError::UpstreamError(404));
+    };
+    let video_url = format!("{}/{}.{}", primary.trim_end_matches('/'), job.hash, job.ext);
+
+    // Spacing the frames needs the length; any server holding the blob can tell
+    let mut duration = None;
+    for server in &servers {
+        let url = format!("{}/{}.{}", server.trim_end_matches('/'), job.hash, job.ext);
+        duration = probe_video_duration(&state.app, &state.thumbnail, &url).await?;
+        if duration.is_some() {
+            break;
+        }
+    }
+    let duration = duration
+        .filter(|d| d.is_finite() && *d > 0.0)
+        .ok_or(SvcError::Unprocessable("video length could not be determined"))?;
+
+    let grid = StoryboardGrid {
+        cols: job.cols,
+        rows: job.rows,
+        width: job.width,
+        interval: duration / (job.cols * job.rows) as f64,
+    };
+    let (sprite, source_server) =
+        extract_video_storyboard(&video_url, grid, &state.thumbnail, &state.app, fallbacks).await?;
+    let (sprite_w, sprite_h) = image_dimensions(&sprite)
+        .ok_or_else(|| SvcError::InternalError("ffmpeg wrote an unreadable storyboard".to_string()))?;
+    let tile = (sprite_w / grid.cols, sprite_h / grid.rows);
+    let vtt = storyboard_vtt(&job.sprite_name, grid, tile, duration);
+
+    metrics::observe_processing_duration(cfg.metrics_label(), "/storyboard", "jpeg", started.elapsed().as_secs_f64());
+    metrics::record_video_processed("jpeg");
+    report::record_source_served(&format!("{}.{}", job.hash, job.ext), sprite.len());
+
+    let sprite = Bytes::from(sprite);
+    let vtt = Bytes::from(vtt);
+    let sprite_modified =
+        write_processed_cache(&state.app, &job.paths.sprite, &sprite, Some((sprite_w, sprite_h))).await?;
+    let vtt_modified = write_processed_cache(&state.app, &job.paths.vtt, &vtt, None).await?;
+
+    let mut resp = match job.artifact {
+        Artifact::Sprite => {
+            let etag = etag_for(&sprite);
+            let mut resp = build_image_response(sprite, JPEG_MIME, "miss", Some((sprite_w, sprite_h)));
+            set_etag(&mut resp, &etag);
+            set_last_modified(&mut resp, sprite_modified);
+            resp
+        }
+        Artifact::Vtt => {
+            let etag = etag_for(&vtt);
+            let mut resp = build_image_response(vtt, VTT_MIME, "miss", None);
+            set_etag(&mut resp, &etag);
+            set_last_modified(&mut resp, vtt_modified);
+            resp
+        }
+    };
+    set_source_server(&mut resp, &source_server);
+    Ok(resp)
+}
+
+/// One cue per tile, in reading order, each pointing at its region of the sprite
+///
+/// Cues never run past `duration`; tiles left over by rounding get none.
+fn storyboard_vtt(sprite: &str, grid: StoryboardGrid, (tile_w, tile_h): (u32, u32), duration: f64) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for i in 0..grid.cols * grid.rows {
+        let start = grid.interval * i as f64;
+        if start >= duration {
+            break;
+        }
+        let end = (start + grid.interval).min(duration);
+        let (x, y) = ((i % grid.cols) * tile_w, (i / grid.cols) * tile_h);
+        let _ = write!(
+            vtt,
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end),
+            sprite,
+            x,
+            y,
+            tile_w,
+            tile_h
+        );
+    }
+    vtt
+}
+
+/// `HH:MM:SS.mmm`
+fn vtt_timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vtt_timestamp() {
+        assert_eq!(vtt_timestamp(0.0), "00:00:00.000");
+        assert_eq!(vtt_timestamp(61.5), "00:01:01.500");
+        assert_eq!(vtt_timestamp(3725.0125), "01:02:05.013");
+    }
+
+    #[test]
+    fn test_storyboard_vtt() {
+        let grid = StoryboardGrid {
+            cols: 2,
+            rows: 2,
+            width: 160,
+            interval: 2.5,
+        };
+        let vtt = storyboard_vtt("abc.mp4?cols=2&rows=2&w=160", grid, (160, 90), 10.0);
+        assert!(vtt.starts_with("WEBVTT\n"));
+        assert_eq!(vtt.matches("#xywh=").count(), 4);
+        assert!(vtt.contains("00:00:07.500 --> 00:00:10.000\nabc.mp4?cols=2&rows=2&w=160#xywh=160,90,160,90\n"));
+        // Cues stop at the end of the video
+        let vtt = storyboard_vtt("abc.mp4", grid, (160, 90), 4.0);
+        assert_eq!(vtt.matches("#xywh=").count(), 2);
+    }
+}
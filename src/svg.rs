@@ -0,0 +1,203 @@
+use image::DynamicImage;
+
+use crate::error::SvcError;
+
+/// Longest side of a rasterized SVG, whatever size the request asks for
+#[cfg_attr(not(feature = "svg"), allow(dead_code))]
+const MAX_RASTER_SIDE: u32 = 4096;
+/// Deepest element nesting accepted, counting `<use>` expansion
+#[cfg_attr(not(feature = "svg"), allow(dead_code))]
+const MAX_DEPTH: usize = 256;
+
+/// Whether the bytes look like an SVG document (an `<svg` root within the first KiB)
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// Rasterize an SVG large enough for a `target` resize (0 = unconstrained side)
+///
+/// Parsing is sandboxed: DTDs are rejected, external files and URLs referenced by `<image>`
+/// are never loaded (embedded `data:` images are), and documents with more than
+/// `max_elements` elements after `<use>` expansion are refused. Text is only drawn when the
+/// SVG converted it to paths, as no fonts are loaded.
+#[cfg(feature = "svg")]
+pub fn rasterize(bytes: &[u8], target: (u32, u32), max_elements: usize) -> Result<DynamicImage, SvcError> {
+    use resvg::{tiny_skia, usvg};
+
+    if max_elements == 0 {
+        return Err(SvcError::UnsupportedMedia("SVG sources are disabled"));
+    }
+    let text = std::str::from_utf8(bytes).map_err(|_| svg_error("not UTF-8"))?;
+    let parse_opts = roxmltree::ParsingOptions {
+        allow_dtd: false,
+        nodes_limit: u32::try_from(max_elements.saturating_mul(4)).unwrap_or(u32::MAX),
+    };
+    let doc = roxmltree::Document::parse_with_options(text, parse_opts).map_err(|e| svg_error(&e.to_string()))?;
+    if expanded_elements(&doc, max_elements) > max_elements {
+        return Err(SvcError::UnsupportedMedia("SVG has too many elements"));
+    }
+
+    let opts = usvg::Options {
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_data: usvg::ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_xmltree(&doc, &opts).map_err(|e| svg_error(&e.to_string()))?;
+
+    let size = tree.size();
+    let scale = raster_scale((size.width(), size.height()), target);
+    let w = ((size.width() * scale).round() as u32).max(1);
+    let h = ((size.height() * scale).round() as u32).max(1);
+    let mut pixmap = tiny_skia::Pixmap::new(w, h).ok_or_else(|| svg_error("invalid size"))?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skia renders premultiplied alpha
+    let rgba: Vec<u8> = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    let img = image::RgbaImage::from_raw(w, h, rgba).ok_or_else(|| svg_error("raster size mismatch"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn rasterize(_bytes: &[u8], _target: (u32, u32), _max_elements: usize) -> Result<DynamicImage, SvcError> {
+    Err(SvcError::UnsupportedMedia("SVG input not enabled in this build"))
+}
+
+/// Undecodable SVG, reported like any other decode failure (422)
+#[cfg(feature = "svg")]
+fn svg_error(msg: &str) -> SvcError {
+    SvcError::Decode(image::ImageError::Decoding(image::error::DecodingError::new(
+        image::error::ImageFormatHint::Name("SVG".to_string()),
+        msg.to_string(),
+    )))
+}
+
+/// Scale from the SVG's intrinsic size so the raster covers the resize target
+///
+/// The resize step then only scales down, keeping edges sharp.
+#[cfg_attr(not(feature = "svg"), allow(dead_code))]
+fn raster_scale((w, h): (f32, f32), (target_w, target_h): (u32, u32)) -> f32 {
+    if w <= 0.0 || h <= 0.0 {
+        return 1.0;
+    }
+    let scale = match (target_w, target_h) {
+        (0, 0) => 1.0,
+        (tw, 0) => tw as f32 / w,
+        (0, th) => th as f32 / h,
+        (tw, th) => (tw as f32 / w).max(th as f32 / h),
+    };
+    scale.min(MAX_RASTER_SIDE as f32 / w.max(h))
+}
+
+/// Element count once every `<use>` is replaced by what it references, capped at `limit + 1`
+///
+/// Nested `<use>` chains grow exponentially, so this runs before usvg expands them.
+/// Reference cycles and nesting deeper than `MAX_DEPTH` count as over the limit.
+#[cfg(feature = "svg")]
+fn expanded_elements(doc: &roxmltree::Document, limit: usize) -> usize {
+    use std::collections::{HashMap, HashSet};
+
+    struct Walk<'a, 'input> {
+        ids: HashMap<&'a str, roxmltree::Node<'a, 'input>>,
+        sizes: HashMap<roxmltree::NodeId, usize>,
+        visiting: HashSet<roxmltree::NodeId>,
+        limit: usize,
+    }
+
+    impl<'a, 'input> Walk<'a, 'input> {
+        fn size(&mut self, node: roxmltree::Node<'a, 'input>, depth: usize) -> usize {
+            if let Some(&size) = self.sizes.get(&node.id()) {
+                return size;
+            }
+            if depth > MAX_DEPTH || !self.visiting.insert(node.id()) {
+                return self.limit + 1;
+            }
+            let mut size = 1usize;
+            for child in node.children().filter(|c| c.is_element()) {
+                size = size.saturating_add(self.size(child, depth + 1));
+                if size > self.limit {
+                    break;
+                }
+            }
+            if size <= self.limit && node.tag_name().name() == "use" {
+                let href = node
+                    .attribute("href")
+                    .or_else(|| node.attribute(("http://www.w3.org/1999/xlink", "href")));
+                let target = href.and_then(|h| h.strip_prefix('#')).and_then(|id| self.ids.get(id)).copied();
+                if let Some(target) = target {
+                    size = size.saturating_add(self.size(target, depth + 1));
+                }
+            }
+            self.visiting.remove(&node.id());
+            let size = size.min(self.limit + 1);
+            self.sizes.insert(node.id(), size);
+            size
+        }
+    }
+
+    let ids = doc
+        .descendants()
+        .filter_map(|n| n.attribute("id").map(|id| (id, n)))
+        .collect();
+    let mut walk = Walk {
+        ids,
+        sizes: HashMap::new(),
+        visiting: HashSet::new(),
+        limit,
+    };
+    walk.size(doc.root_element(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"));
+        assert!(is_svg(b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<!-- logo -->\n<svg></svg>"));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n"));
+        assert!(!is_svg(b"<html><body>no</body></html>"));
+    }
+
+    #[test]
+    fn test_raster_scale_covers_target() {
+        assert_eq!(raster_scale((100.0, 50.0), (0, 0)), 1.0);
+        assert_eq!(raster_scale((100.0, 50.0), (400, 0)), 4.0);
+        assert_eq!(raster_scale((100.0, 50.0), (400, 400)), 8.0);
+        // Capped at MAX_RASTER_SIDE
+        assert_eq!(raster_scale((100.0, 50.0), (100_000, 0)), 40.96);
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn test_expanded_elements_counts_use_chains() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg">
+            <g id="a"><rect/><rect/></g>
+            <g id="b"><use href="#a"/><use href="#a"/></g>
+            <use href="#b"/>
+        </svg>"##;
+        let doc = roxmltree::Document::parse(svg).unwrap();
+        // svg + a(3) + b(1 + 2 * 4) + use(1 + 9)
+        assert_eq!(expanded_elements(&doc, 100), 23);
+        assert_eq!(expanded_elements(&doc, 10), 11);
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn test_expanded_elements_rejects_cycles() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg"><g id="a"><use href="#a"/></g></svg>"##;
+        let doc = roxmltree::Document::parse(svg).unwrap();
+        assert_eq!(expanded_elements(&doc, 100), 101);
+    }
+}
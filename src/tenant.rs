@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, uri::PathAndQuery, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::config::TenantCfg;
+
+/// Configured tenants, in `TENANTS` order
+pub type Tenants = Arc<Vec<Arc<TenantCfg>>>;
+
+/// Select the request's tenant by Host header, then by path prefix
+///
+/// Runs before routing: a matched prefix is stripped, so `/app/insecure/...` routes (and is
+/// signed) like `/insecure/...`. The tenant is attached as an `Arc<TenantCfg>` extension;
+/// requests matching no tenant run with the global configuration.
+pub async fn select_tenant(State(tenants): State<Tenants>, mut req: Request, next: Next) -> Response {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(host_without_port);
+    let by_host = host.and_then(|host| {
+        tenants
+            .iter()
+            .find(|t| t.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+    });
+
+    let tenant = match by_host {
+        Some(tenant) => Some(tenant.clone()),
+        None => {
+            let by_prefix = tenants.iter().find_map(|t| {
+                let rest = strip_path_prefix(req.uri().path(), t.path_prefix.as_deref()?)?;
+                Some((t.clone(), rewrite_path(req.uri(), rest)?))
+            });
+            by_prefix.map(|(tenant, uri)| {
+                *req.uri_mut() = uri;
+                tenant
+            })
+        }
+    };
+
+    if let Some(tenant) = tenant {
+        tracing::debug!(tenant = %tenant.name, "tenant selected");
+        req.extensions_mut().insert(tenant);
+    }
+    next.run(req).await
+}
+
+/// Host header value without its port
+fn host_without_port(host: &str) -> &str {
+    // Bracketed IPv6 literals contain colons of their own
+    if let Some(end) = host.find(']') {
+        return &host[..=end];
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Path below `prefix`, only at a segment boundary ("/app" matches "/app/x", not "/apple")
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    if rest.starts_with('/') {
+        Some(rest)
+    } else if rest.is_empty() {
+        Some("/")
+    } else {
+        None
+    }
+}
+
+/// `uri` with its path replaced, keeping the query
+fn rewrite_path(uri: &Uri, path: &str) -> Option<Uri> {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_without_port() {
+        assert_eq!(host_without_port("img.example.com:8080"), "img.example.com");
+        assert_eq!(host_without_port("img.example.com"), "img.example.com");
+        assert_eq!(host_without_port("[::1]:8080"), "[::1]");
+    }
+
+    #[test]
+    fn test_strip_path_prefix() {
+        assert_eq!(strip_path_prefix("/app/insecure/x", "/app"), Some("/insecure/x"));
+        assert_eq!(strip_path_prefix("/app", "/app"), Some("/"));
+        assert_eq!(strip_path_prefix("/apple/insecure/x", "/app"), None);
+        assert_eq!(strip_path_prefix("/other/x", "/app"), None);
+    }
+
+    #[test]
+    fn test_rewrite_path_keeps_query() {
+        let uri: Uri = "/app/thumb/abc.jpg?rs=fit:100:100".parse().unwrap();
+        let rewritten = rewrite_path(&uri, "/thumb/abc.jpg").unwrap();
+        assert_eq!(rewritten.to_string(), "/thumb/abc.jpg?rs=fit:100:100");
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use ab_glyph::{point, Font, FontVec, GlyphId, GlyphImageFormat, PxScale, ScaleFont};
+use image::{imageops, DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+use crate::{
+    metrics,
+    transform::{Gravity, TextOverlay},
+};
+
+/// Fonts from `TEXT_FONTS`, in fallback order; replaced as a whole when the files change
+static FONTS: RwLock<Option<Arc<Vec<FontVec>>>> = RwLock::new(None);
+
+/// Characters without a glyph of their own (joiners, emoji variation selectors, CR)
+///
+/// Without a shaper they would each count as a missing glyph, so they are skipped; an emoji
+/// ZWJ sequence is drawn as its component emoji side by side.
+const ZERO_WIDTH: &[char] = &['\u{200C}', '\u{200D}', '\u{FE0E}', '\u{FE0F}', '\r'];
+
+/// Read and parse one font file
+pub fn read_font(path: &Path) -> Result<FontVec, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    FontVec::try_from_vec(data).map_err(|e| e.to_string())
+}
+
+/// Load the `TEXT_FONTS` fallback chain
+///
+/// Either every font loads and replaces the chain, or the chain in use is kept.
+pub fn load_fonts<P: AsRef<Path>>(paths: &[P]) -> Result<(), String> {
+    let fonts = paths
+        .iter()
+        .map(|path| read_font(path.as_ref()).map_err(|e| format!("{}: {}", path.as_ref().display(), e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    *FONTS.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(fonts));
+    Ok(())
+}
+
+/// The loaded chain, if it has any font
+fn fonts() -> Option<Arc<Vec<FontVec>>> {
+    let fonts = FONTS.read().unwrap_or_else(|e| e.into_inner());
+    fonts.as_ref().filter(|fonts| !fonts.is_empty()).cloned()
+}
+
+/// Whether any font is configured, i.e. `txt:` can be honored
+pub fn has_fonts() -> bool {
+    fonts().is_some()
+}
+
+/// Modification time and length of each font file, None where it can't be read
+fn font_stamps(paths: &[String]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|path| {
+            let meta = std::fs::metadata(path).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+/// Reload `TEXT_FONTS` whenever one of the files changes, checking every `interval`
+///
+/// Files are replaced in place or renamed over; a chain that fails to load (say, a file
+/// caught half-written) keeps the previous fonts and is retried on the next change.
+pub async fn watch_loop(paths: Vec<String>, interval: Duration) {
+    let mut stamps = font_stamps(&paths);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let current = font_stamps(&paths);
+        if current == stamps {
+            continue;
+        }
+        stamps = current;
+        let reload_paths = paths.clone();
+        match tokio::task::spawn_blocking(move || load_fonts(&reload_paths)).await {
+            Ok(Ok(())) => {
+                tracing::info!(fonts = paths.len(), "reloaded TEXT_FONTS");
+                metrics::record_asset_reload("ok");
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("reloading TEXT_FONTS failed, keeping the previous fonts: {}", e);
+                metrics::record_asset_reload("failed");
+            }
+            Err(e) => tracing::warn!("reloading TEXT_FONTS panicked: {}", e),
+        }
+    }
+}
+
+/// A glyph placed on the text block, from the font that covers its character
+struct Placed {
+    font: usize,
+    id: GlyphId,
+    x: f32,
+}
+
+/// Draw `overlay` onto the image; returns how many characters no font could render
+///
+/// Each character uses the first font in the chain that has a glyph for it, so a Latin
+/// primary font can be followed by CJK, Arabic or emoji fonts. Outline glyphs are filled
+/// with the overlay color; color bitmap glyphs (CBDT/sbix emoji fonts) are drawn as-is.
+pub fn draw_text(img: DynamicImage, overlay: &TextOverlay) -> (DynamicImage, usize) {
+    let margin = (overlay.size / 2.0).round() as u32;
+    draw_text_at(img, overlay, margin)
+}
+
+/// `draw_text` keeping the text block `margin` pixels away from the edges it is anchored to
+pub fn draw_text_at(img: DynamicImage, overlay: &TextOverlay, margin: u32) -> (DynamicImage, usize) {
+    let Some(fonts) = fonts() else {
+        return (img, overlay.text.chars().count());
+    };
+    let scale = PxScale::from(overlay.size);
+    let primary = fonts[0].as_scaled(scale);
+    let line_height = primary.height() + primary.line_gap();
+
+    // Lay out each line left-aligned from x = 0
+    let mut missing = 0;
+    let lines: Vec<(Vec<Placed>, f32)> = overlay
+        .text
+        .split('\n')
+        .map(|line| {
+            let mut placed = Vec::new();
+            let mut x = 0.0;
+            let mut prev: Option<(usize, GlyphId)> = None;
+            for c in line.chars().filter(|c| !ZERO_WIDTH.contains(c)) {
+                let Some(font) = fonts.iter().position(|f| f.glyph_id(c).0 != 0) else {
+                    missing += 1;
+                    continue;
+                };
+                let scaled = fonts[font].as_scaled(scale);
+                let id = scaled.glyph_id(c);
+                // Kerning only applies between glyphs of the same font
+                if let Some((_, prev_id)) = prev.filter(|(prev_font, _)| *prev_font == font) {
+                    x += scaled.kern(prev_id, id);
+                }
+                placed.push(Placed { font, id, x });
+                x += scaled.h_advance(id);
+                prev = Some((font, id));
+            }
+            (placed, x)
+        })
+        .collect();
+
+    let block_w = lines.iter().map(|(_, w)| w.ceil() as u32).max().unwrap_or(0);
+    let block_h = (line_height * lines.len() as f32).ceil() as u32;
+    if block_w == 0 || block_h == 0 {
+        return (img, missing);
+    }
+
+    let mut block = RgbaImage::new(block_w, block_h);
+    let [r, g, b, a] = overlay.color;
+    for (i, (placed, line_w)) in lines.iter().enumerate() {
+        // Align lines toward the side the block is anchored to
+        let indent = match overlay.gravity {
+            Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0.0,
+            Gravity::East | Gravity::NorthEast | Gravity::SouthEast => block_w as f32 - line_w,
+            _ => (block_w as f32 - line_w) / 2.0,
+        };
+        let baseline = line_height * i as f32 + primary.ascent();
+        for glyph in placed {
+            let font = &fonts[glyph.font];
+            let position = point(indent + glyph.x, baseline);
+            if let Some(outlined) = font.outline_glyph(glyph.id.with_scale_and_position(scale, position)) {
+                let bounds = outlined.px_bounds();
+                outlined.draw(|gx, gy, coverage| {
+                    let (x, y) = (bounds.min.x as i32 + gx as i32, bounds.min.y as i32 + gy as i32);
+                    if x < 0 || y < 0 || x >= block_w as i32 || y >= block_h as i32 {
+                        return;
+                    }
+                    let alpha = (coverage * a as f32).round() as u8;
+                    let px = block.get_pixel_mut(x as u32, y as u32);
+                    if alpha > px.0[3] {
+                        *px = Rgba([r, g, b, alpha]);
+                    }
+                });
+            } else if let Some(bitmap) = color_glyph(font, glyph.id, overlay.size) {
+                // Sit color glyphs on the primary font's ascent, like the outline glyphs
+                let top = baseline - primary.ascent();
+                imageops::overlay(&mut block, &bitmap, (indent + glyph.x) as i64, top as i64);
+            }
+        }
+    }
+
+    let mut canvas = img.to_rgba8();
+    let (w, h) = canvas.dimensions();
+    let free_w = w.saturating_sub(block_w + 2 * margin);
+    let free_h = h.saturating_sub(block_h + 2 * margin);
+    let (x, y) = overlay.gravity.anchor(free_w, free_h);
+    imageops::overlay(&mut canvas, &block, (x + margin) as i64, (y + margin) as i64);
+    (DynamicImage::ImageRgba8(canvas), missing)
+}
+
+/// Break `text` into lines at most `max_width` pixels wide at `size`, keeping `max_lines`
+///
+/// Lines break between words, and a word wider than a line gets a line of its own. When text
+/// is cut, the last kept line ends with an ellipsis. Without fonts the text is returned as-is.
+pub fn wrap_text(text: &str, size: f32, max_width: f32, max_lines: usize) -> String {
+    let Some(fonts) = fonts() else {
+        return text.to_string();
+    };
+    let scale = PxScale::from(size);
+    // Advance widths only; kerning changes a line's width by a few pixels at most
+    let width = |line: &str| -> f32 {
+        line.chars()
+            .filter(|c| !ZERO_WIDTH.contains(c))
+            .filter_map(|c| fonts.iter().find(|f| f.glyph_id(c).0 != 0).map(|f| (f, c)))
+            .map(|(font, c)| {
+                let scaled = font.as_scaled(scale);
+                scaled.h_advance(scaled.glyph_id(c))
+            })
+            .sum()
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if width(&format!("{} {}", line, word)) <= max_width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            // Drop words until the ellipsis fits
+            while width(&format!("{}\u{2026}", last)) > max_width {
+                match last.rfind(' ') {
+                    Some(i) => last.truncate(i),
+                    None => break,
+                }
+            }
+            last.push('\u{2026}');
+        }
+    }
+    lines.join("\n")
+}
+
+/// Embedded PNG of a color bitmap glyph, scaled to the line height
+fn color_glyph(font: &FontVec, id: GlyphId, size: f32) -> Option<RgbaImage> {
+    let raster = font.glyph_raster_image2(id, size.round() as u16)?;
+    if !matches!(raster.format, GlyphImageFormat::Png) {
+        return None;
+    }
+    let png = image::load_from_memory_with_format(raster.data, image::ImageFormat::Png).ok()?;
+    let factor = size / raster.pixels_per_em as f32;
+    let (w, h) = png.dimensions();
+    let (w, h) = (((w as f32 * factor).round() as u32).max(1), ((h as f32 * factor).round() as u32).max(1));
+    Some(png.resize_exact(w, h, imageops::FilterType::Triangle).to_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smallest font ab_glyph accepts: `head`, `hhea` and `maxp` with a single empty glyph,
+    /// followed by `padding` zero bytes so rewrites change the file length
+    fn tiny_font(padding: usize) -> Vec<u8> {
+        let mut head = vec![0u8; 54];
+        head[18..20].copy_from_slice(&1000u16.to_be_bytes()); // units per em
+        let hhea = vec![0u8; 36];
+        let mut maxp = 0x0000_5000u32.to_be_bytes().to_vec();
+        maxp.extend_from_slice(&1u16.to_be_bytes()); // glyph count
+
+        let tables: [(&[u8; 4], Vec<u8>); 3] = [(b"head", head), (b"hhea", hhea), (b"maxp", maxp)];
+        let mut font = 0x0001_0000u32.to_be_bytes().to_vec();
+        font.extend_from_slice(&[0, 3, 0, 32, 0, 1, 0, 16]); // table count, search hints
+        let mut offset = 12 + 16 * tables.len() as u32;
+        for (tag, data) in &tables {
+            font.extend_from_slice(*tag);
+            font.extend_from_slice(&0u32.to_be_bytes()); // checksum, not verified
+            font.extend_from_slice(&offset.to_be_bytes());
+            font.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            offset += data.len() as u32;
+        }
+        for (_, data) in &tables {
+            font.extend_from_slice(data);
+        }
+        font.resize(font.len() + padding, 0);
+        font
+    }
+
+    /// Poll `check` until it yields a value, failing the test after a few seconds
+    async fn wait_for<T>(mut check: impl FnMut() -> Option<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(value) = check() {
+                    return value;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("condition not reached")
+    }
+
+    #[tokio::test]
+    async fn test_watch_loop_reloads_changed_fonts_and_keeps_them_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("font.ttf");
+        std::fs::write(&path, tiny_font(0)).unwrap();
+        let paths = vec![path.to_string_lossy().into_owned()];
+        load_fonts(&paths).unwrap();
+        let initial = fonts().unwrap();
+
+        tokio::spawn(watch_loop(paths, Duration::from_millis(10)));
+        // Let the loop record the current files before they change
+        tokio::task::yield_now().await;
+
+        std::fs::write(&path, tiny_font(4)).unwrap();
+        let reloaded = wait_for(|| fonts().filter(|fonts| !Arc::ptr_eq(fonts, &initial))).await;
+
+        let failed = || metrics::ASSET_RELOADS_TOTAL.with_label_values(&["failed"]).get();
+        let failed_before = failed();
+        std::fs::write(&path, b"not a font").unwrap();
+        wait_for(|| (failed() > failed_before).then_some(())).await;
+        assert!(Arc::ptr_eq(&fonts().unwrap(), &reloaded));
+    }
+}
@@ -1,41 +1,209 @@
-use std::sync::Arc;
-use tokio::sync::Semaphore;
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use tracing::{error, info};
 
-use crate::{error::SvcError, metrics};
+use crate::{
+    blossom::server_origin,
+    config::{AppCfg, AppState},
+    debug_trace,
+    error::{SvcError, VideoError},
+    ffmpeg_error::{stderr_detail, FfmpegFailure},
+    metrics,
+    server::check_source_host,
+    transform::SourceKind,
+    upstream_auth,
+    video_range::{self, LocalCopy},
+    work_pool::WorkPool,
+};
 
 #[derive(Clone)]
 pub struct ThumbnailState {
-    pub ffmpeg_semaphore: Arc<Semaphore>,
+    /// ffmpeg runs, with 503 once `MAX_FFMPEG_QUEUE` requests wait
+    pub ffmpeg: Arc<WorkPool>,
+    /// Retry-After hint sent with 503 responses when a queue is full
+    pub retry_after_secs: u64,
+    /// Separate pool for cheap ffprobe metadata queries so they don't queue behind extractions
+    pub ffprobe_semaphore: Arc<Semaphore>,
+    pub ffprobe_timeout: Duration,
 }
 
 impl ThumbnailState {
-    pub fn new(max_concurrent: usize) -> Self {
+    pub fn new(
+        max_concurrent: usize,
+        max_queue: usize,
+        retry_after_secs: u64,
+        max_probe_concurrent: usize,
+        probe_timeout: Duration,
+    ) -> Self {
         Self {
-            ffmpeg_semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            ffmpeg: Arc::new(WorkPool::new(
+                "ffmpeg",
+                max_concurrent,
+                max_queue,
+                retry_after_secs,
+                metrics::update_ffmpeg_semaphore_metrics,
+            )),
+            retry_after_secs,
+            ffprobe_semaphore: Arc::new(Semaphore::new(max_probe_concurrent)),
+            ffprobe_timeout: probe_timeout,
         }
     }
+
+    /// Current number of requests waiting for an ffmpeg permit
+    pub fn queue_depth(&self) -> usize {
+        self.ffmpeg.queue_depth()
+    }
+
+    /// Acquire an ffmpeg permit, shedding load when the wait queue is full
+    async fn acquire_ffmpeg_permit(&self) -> Result<SemaphorePermit<'_>, SvcError> {
+        self.ffmpeg.acquire().await
+    }
+}
+
+/// Second frames were always taken at before `t:`; keys of frames taken there carry no `#t=`
+pub const DEFAULT_VIDEO_SEEK: f32 = 0.5;
+/// Output options for `DETERMINISTIC_OUTPUT`
+const BITEXACT_ARGS: &[&str] = &[
+    "-threads", "1",                // Single-threaded encoders
+    "-flags", "+bitexact",          // No encoder version tags
+    "-fflags", "+bitexact",         // No muxer version tags
+    "-map_metadata", "-1",          // Nothing copied from the source container
+];
+/// Clips an animated preview is assembled from, spread evenly over the video
+const PREVIEW_CLIPS: u32 = 6;
+/// Frame rate of animated previews
+const PREVIEW_FPS: u32 = 10;
+
+/// Fields of `probe_video_report`
+const PROBE_REPORT_ENTRIES: &str =
+    "stream=codec_name,width,height:stream_tags=rotate:stream_side_data=rotation:format=duration,size";
+
+/// What an ffmpeg run makes of a video
+#[derive(Debug, Clone, Copy)]
+enum FfmpegJob {
+    /// One WebP frame at this second
+    Frame { seek: f32 },
+    /// Animated WebP of `PREVIEW_SECS`, at most `width` pixels wide
+    Preview { width: u32 },
+    /// One JPEG sprite sheet of evenly spaced frames
+    Storyboard(StoryboardGrid),
+}
+
+/// Layout of a storyboard sprite sheet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoryboardGrid {
+    pub cols: u32,
+    pub rows: u32,
+    /// Width of one tile in pixels; the height follows the video's aspect ratio
+    pub width: u32,
+    /// Seconds of video each tile stands for
+    pub interval: f64,
+}
+
+/// File extensions handled by the ffmpeg thumbnail pipeline
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "mov", "avi", "webm", "mkv", "flv", "wmv", "m4v", "mpg", "mpeg", "3gp", "ogv", "m3u8",
+];
+
+/// Protocols ffmpeg may open for a remote input
+///
+/// HLS playlists name their segments and AES keys by URL, so without this a playlist could
+/// make ffmpeg read local files (`file:`), other processes' output (`pipe:`) and the like.
+const REMOTE_PROTOCOLS: &str = "http,https,tcp,tls,crypto";
+/// Protocols ffmpeg may open for a local copy of an HLS playlist, whose URIs are files beside it
+const HLS_COPY_PROTOCOLS: &str = "file,crypto";
+
+/// Check if a file extension (without the dot) is a known video extension
+pub fn is_video_extension(ext: &str) -> bool {
+    VIDEO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
 }
 
 /// Check if a URL is likely a video based on file extension
 ///
-/// Returns true only for known video extensions.
+/// Returns true only for known video extensions, including `.m3u8` HLS playlists; the query
+/// and fragment are ignored, since playlist URLs often carry access tokens.
 /// All other URLs (including .jfif, .jpg, .jpeg, .png, .webp, .avif, and URLs without extensions)
 /// are treated as images and processed with content-based format detection.
+/// Requests can override this with the `ext:` directive.
 pub fn is_video_url(url: &str) -> bool {
-    let url_lower = url.to_lowercase();
-    url_lower.ends_with(".mp4")
-        || url_lower.ends_with(".mov")
-        || url_lower.ends_with(".avi")
-        || url_lower.ends_with(".webm")
-        || url_lower.ends_with(".mkv")
-        || url_lower.ends_with(".flv")
-        || url_lower.ends_with(".wmv")
-        || url_lower.ends_with(".m4v")
-        || url_lower.ends_with(".mpg")
-        || url_lower.ends_with(".mpeg")
-        || url_lower.ends_with(".3gp")
-        || url_lower.ends_with(".ogv")
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    path.rsplit_once('.')
+        .is_some_and(|(_, ext)| is_video_extension(ext))
+}
+
+/// Whether the URL's last path segment ends in a known image or video extension
+pub fn has_media_extension(url: &str) -> bool {
+    let segment = url.rsplit('/').next().unwrap_or(url);
+    segment.rsplit_once('.').is_some_and(|(_, ext)| {
+        is_video_extension(ext) || ext.eq_ignore_ascii_case("svg") || image::ImageFormat::from_extension(ext).is_some()
+    })
+}
+
+/// Bytes requested when sniffing a source; enough for three MPEG-TS packets
+pub const SNIFF_LEN: usize = 512;
+/// MPEG transport stream packet size; every packet starts with the 0x47 sync byte
+const TS_PACKET_LEN: usize = 188;
+
+/// Detect a video container from a file's first bytes
+///
+/// Recognizes ISO BMFF (`ftyp`, except HEIF/AVIF image brands), Matroska/WebM (EBML),
+/// AVI (RIFF), FLV, Ogg, MPEG program and transport streams, and ASF/WMV.
+pub fn sniff_video(head: &[u8]) -> bool {
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        // AVIF and HEIC images use the same container
+        return !matches!(
+            &head[8..12],
+            b"avif" | b"avis" | b"heic" | b"heix" | b"heim" | b"heis" | b"mif1" | b"msf1"
+        );
+    }
+    head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3])
+        || (head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"AVI ")
+        || head.starts_with(b"FLV")
+        || head.starts_with(b"OggS")
+        || head.starts_with(&[0x00, 0x00, 0x01, 0xBA])
+        || head.starts_with(&[0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11])
+        // A lone 0x47 is too common; require the sync byte on three consecutive packets
+        || (head.len() > 2 * TS_PACKET_LEN && (0..3).all(|i| head[i * TS_PACKET_LEN] == 0x47))
+}
+
+/// Fetch the first bytes of a source to decide between the video and image pipelines
+///
+/// Anything that can't be probed is treated as an image, as before.
+pub async fn sniff_source_kind(app: &AppState, url: &str) -> SourceKind {
+    // Excluded hosts aren't contacted; fetch_source reports them
+    if !(url.starts_with("http://") || url.starts_with("https://")) || !app.cfg.source_host_allowed(url) {
+        return SourceKind::Image;
+    }
+    let request = app
+        .http
+        .get(url)
+        .header(reqwest::header::RANGE, format!("bytes=0-{}", SNIFF_LEN - 1));
+    let resp = upstream_auth::sign(request, url).send().await;
+    let mut resp = match resp {
+        Ok(resp) if resp.status().is_success() => resp,
+        _ => return SourceKind::Image,
+    };
+
+    // Servers that ignore Range stream the whole body; stop after the first bytes
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    while head.len() < SNIFF_LEN {
+        match resp.chunk().await {
+            Ok(Some(chunk)) => head.extend_from_slice(&chunk),
+            _ => break,
+        }
+    }
+
+    // HLS playlists only count here: uploads are read as local files, where a playlist could
+    // point ffmpeg at anything on disk
+    if sniff_video(&head) || head.starts_with(b"#EXTM3U") {
+        SourceKind::Video
+    } else {
+        SourceKind::Image
+    }
 }
 
 /// Check if a URL is a Blossom CDN URL (has <sha256>.<ext> format)
@@ -64,29 +232,94 @@ fn extract_blossom_hash(url: &str) -> Option<(&str, &str)> {
 }
 
 /// Extract a video thumbnail and return the image bytes (to be cached as "original")
+/// together with the origin of the server that supplied the video
+///
+/// `blossom_fallback_servers` are tried in order when `video_url` is a Blossom URL and fails.
 pub async fn extract_video_thumbnail(
     video_url: &str,
-    semaphore: &Arc<Semaphore>,
+    seek: f32,
+    thumbnail: &ThumbnailState,
+    app: &AppState,
     blossom_fallback_servers: &[String],
-) -> Result<Vec<u8>, SvcError> {
+) -> Result<(Vec<u8>, String), SvcError> {
     info!("extracting thumbnail from video: {}", video_url);
+    run_with_fallbacks(video_url, FfmpegJob::Frame { seek }, thumbnail, app, blossom_fallback_servers).await
+}
+
+/// Render a short animated WebP sampled across a video, falling back like `extract_video_thumbnail`
+pub async fn extract_video_preview(
+    video_url: &str,
+    width: u32,
+    thumbnail: &ThumbnailState,
+    app: &AppState,
+    blossom_fallback_servers: &[String],
+) -> Result<(Vec<u8>, String), SvcError> {
+    info!("rendering preview of video: {}", video_url);
+    run_with_fallbacks(video_url, FfmpegJob::Preview { width }, thumbnail, app, blossom_fallback_servers).await
+}
+
+/// Tile frames spaced `grid.interval` apart into one JPEG, falling back like `extract_video_thumbnail`
+pub async fn extract_video_storyboard(
+    video_url: &str,
+    grid: StoryboardGrid,
+    thumbnail: &ThumbnailState,
+    app: &AppState,
+    blossom_fallback_servers: &[String],
+) -> Result<(Vec<u8>, String), SvcError> {
+    info!("rendering storyboard of video: {}", video_url);
+    run_with_fallbacks(video_url, FfmpegJob::Storyboard(grid), thumbnail, app, blossom_fallback_servers).await
+}
+
+/// Container duration of `video_url` in seconds, `None` when ffprobe can't tell
+pub async fn probe_video_duration(
+    app: &AppState,
+    thumbnail: &ThumbnailState,
+    video_url: &str,
+) -> Result<Option<f64>, SvcError> {
+    check_source_host(&app.cfg, video_url)?;
+    let copy = local_copy(app, video_url).await?;
+    Ok(probe_duration_secs(thumbnail, input_path(video_url, copy.as_ref().map(LocalCopy::path))?).await)
+}
+
+/// Run `job` on `video_url`, then on the same blob at each fallback server
+async fn run_with_fallbacks(
+    video_url: &str,
+    job: FfmpegJob,
+    thumbnail: &ThumbnailState,
+    app: &AppState,
+    blossom_fallback_servers: &[String],
+) -> Result<(Vec<u8>, String), SvcError> {
+    check_source_host(&app.cfg, video_url)?;
+
+    // A copy that can't be fetched is left to the fallback servers, like a failed ffmpeg run
+    let copy = match local_copy(app, video_url).await {
+        Err(e) if repeats_on_every_server(&e) => return Err(e),
+        copy => copy,
+    };
+    // Probe limits before taking an ffmpeg permit; probing has its own pool
+    if let Ok(copy) = &copy {
+        check_video_limits(app, thumbnail, video_url, copy.as_ref()).await?;
+    }
 
     // Acquire semaphore permit to limit concurrent ffmpeg processes
-    // This will block (async-wait) if MAX_FFMPEG_CONCURRENT limit is reached
+    // This will block (async-wait) if MAX_FFMPEG_CONCURRENT limit is reached,
+    // or fail with 503 if MAX_FFMPEG_QUEUE requests are already waiting
     // Automatically releases permit when _permit is dropped (when function returns)
-    let _permit = semaphore
-        .acquire()
-        .await
-        .map_err(|_| SvcError::Io(std::io::Error::new(std::io::ErrorKind::Other, "semaphore error")))?;
+    let _permit = thumbnail.acquire_ffmpeg_permit().await?;
 
     // Try original URL first
-    let result = extract_thumbnail_with_ffmpeg(video_url).await;
+    let attempt_start = Instant::now();
+    let result = match copy {
+        Ok(copy) => run_job(app, thumbnail, video_url, copy.as_ref(), job).await,
+        Err(e) => Err(e),
+    };
+    debug_trace::server_attempt(video_url, || format!("ffmpeg: {:?}", result.as_ref().map(|b| b.len())), attempt_start);
 
     // Log success or failure of primary attempt
     match &result {
         Ok(bytes) => {
             tracing::debug!("primary server succeeded for video {}, extracted {} bytes", video_url, bytes.len());
-            return Ok(bytes.clone());
+            return Ok((bytes.clone(), server_origin(video_url)));
         }
         Err(e) => {
             tracing::debug!("primary server failed for video {}: {:?}", video_url, e);
@@ -107,7 +340,14 @@ pub async fn extract_video_thumbnail(
                     fallback_url
                 );
 
-                match extract_thumbnail_with_ffmpeg(&fallback_url).await {
+                let attempt_start = Instant::now();
+                let attempt = extract_thumbnail_checked(app, thumbnail, &fallback_url, job).await;
+                debug_trace::server_attempt(
+                    &fallback_url,
+                    || format!("ffmpeg: {:?}", attempt.as_ref().map(|b| b.len())),
+                    attempt_start,
+                );
+                match attempt {
                     Ok(thumbnail_bytes) => {
                         tracing::info!(
                             "✓ fallback server {} succeeded for video, extracted {} bytes from {}",
@@ -115,8 +355,9 @@ pub async fn extract_video_thumbnail(
                             thumbnail_bytes.len(),
                             fallback_server
                         );
-                        return Ok(thumbnail_bytes);
+                        return Ok((thumbnail_bytes, server_origin(&fallback_url)));
                     }
+                    Err(e) if repeats_on_every_server(&e) => return Err(e),
                     Err(e) => {
                         tracing::debug!(
                             "✗ fallback server {} extraction failed for {}: {:?}",
@@ -138,71 +379,435 @@ pub async fn extract_video_thumbnail(
         tracing::debug!("url is not blossom format, skipping fallback servers");
     }
 
-    result
+    result.map(|bytes| (bytes, server_origin(video_url)))
+}
+
+/// Extract a thumbnail from an uploaded video saved to a local file
+///
+/// The upload size was already bounded by `MAX_UPLOAD_BYTES`, so only the ffmpeg pool applies.
+pub async fn extract_local_video_thumbnail(
+    path: &Path,
+    seek: f32,
+    thumbnail: &ThumbnailState,
+    app: &AppState,
+) -> Result<Vec<u8>, SvcError> {
+    let input = path
+        .to_str()
+        .ok_or(SvcError::InternalError("temporary file path is not UTF-8".to_string()))?;
+    let _permit = thumbnail.acquire_ffmpeg_permit().await?;
+    run_ffmpeg(input, FfmpegJob::Frame { seek }, thumbnail, &app.cfg).await
+}
+
+/// Enforce the configured video limits, then extract a thumbnail from a single URL
+async fn extract_thumbnail_checked(
+    app: &AppState,
+    thumbnail: &ThumbnailState,
+    video_url: &str,
+    job: FfmpegJob,
+) -> Result<Vec<u8>, SvcError> {
+    check_source_host(&app.cfg, video_url)?;
+    let copy = local_copy(app, video_url).await?;
+    // Limit violations are properties of the video itself, fallbacks won't help
+    check_video_limits(app, thumbnail, video_url, copy.as_ref()).await?;
+    run_job(app, thumbnail, video_url, copy.as_ref(), job).await
+}
+
+/// Local copy for ffmpeg and ffprobe to read instead of `video_url`, when they mustn't open it
+///
+/// ffmpeg sends `-headers` with every request of an input, redirects and playlist segments on
+/// other hosts included, so it never gets credentials: sources on hosts in `UPSTREAM_AUTH` are
+/// downloaded through the signing client instead. ffmpeg also follows redirects to any host,
+/// so with source host lists set every source is downloaded by the policy-bound client. HLS
+/// playlists come with their segments, every URI checked against the lists.
+async fn local_copy(app: &AppState, video_url: &str) -> Result<Option<LocalCopy>, SvcError> {
+    let remote = video_url.starts_with("http://") || video_url.starts_with("https://");
+    if !remote || !(app.cfg.restricts_source_hosts() || upstream_auth::signs(video_url)) {
+        return Ok(None);
+    }
+    video_range::fetch_whole(app, video_url).await.map(Some)
+}
+
+/// What ffmpeg and ffprobe open: the path of a local copy, or `video_url` without one
+fn input_path<'a>(video_url: &'a str, copy: Option<&'a Path>) -> Result<&'a str, SvcError> {
+    match copy {
+        Some(path) => path
+            .to_str()
+            .ok_or(SvcError::InternalError("temporary file path is not UTF-8".to_string())),
+        None => Ok(video_url),
+    }
+}
+
+/// Run `job` on `video_url`, or on its whole `copy` when there is one, taking frames of MP4s
+/// from a partial download when `VIDEO_PARTIAL_FETCH_BYTES` is set
+///
+/// The partial copy only holds the start of the video and its index, so ffmpeg runs strictly
+/// on it; when the frame needs more, the URL is read as usual.
+async fn run_job(
+    app: &AppState,
+    thumbnail: &ThumbnailState,
+    video_url: &str,
+    copy: Option<&LocalCopy>,
+    job: FfmpegJob,
+) -> Result<Vec<u8>, SvcError> {
+    let limit = app.cfg.video_partial_fetch_bytes;
+    if let FfmpegJob::Frame { seek } = job {
+        let remote = video_url.starts_with("http://") || video_url.starts_with("https://");
+        if limit > 0 && remote && copy.is_none() {
+            if let Some(partial) = video_range::fetch_partial(app, video_url, limit).await {
+                let input = input_path(video_url, Some(partial.path()))?;
+                // Stop at the first damaged packet instead of concealing it
+                let mut args: Vec<String> = ["-xerror", "-err_detect", "explode"].map(String::from).to_vec();
+                args.extend(frame_args(input, seek, app.cfg.video_thumb_max_side));
+                match spawn_ffmpeg(video_url, &args, &app.cfg).await {
+                    Ok(frame) => return Ok(frame),
+                    Err(e) => {
+                        tracing::debug!("partial copy of {} was not enough ({:?}), reading the URL", video_url, e);
+                        metrics::record_processing_error("video_partial_fallback");
+                    }
+                }
+            }
+        }
+    }
+    run_ffmpeg(input_path(video_url, copy.map(LocalCopy::path))?, job, thumbnail, &app.cfg).await
+}
+
+/// Refuse videos whose advertised size or probed duration exceed the configured caps
+///
+/// Probes that fail (no Content-Length, HEAD unsupported, ffprobe error) are not treated
+/// as violations; ffmpeg itself will report unreachable sources. A local `copy` was already
+/// held to `MAX_VIDEO_BYTES` while downloading, and is what ffprobe reads.
+async fn check_video_limits(
+    app: &AppState,
+    thumbnail: &ThumbnailState,
+    video_url: &str,
+    copy: Option<&LocalCopy>,
+) -> Result<(), SvcError> {
+    let cfg = &app.cfg;
+
+    if cfg.max_video_bytes > 0 && copy.is_none() {
+        if let Some(len) = probe_content_length(app, video_url).await {
+            if len > cfg.max_video_bytes {
+                tracing::info!(
+                    "refusing video {}: {} bytes exceeds limit of {} bytes",
+                    video_url,
+                    len,
+                    cfg.max_video_bytes
+                );
+                metrics::record_processing_error("video_too_large");
+                return Err(VideoError::TooLarge("video too large").into());
+            }
+        }
+    }
+
+    if cfg.max_video_duration_secs > 0 {
+        let input = input_path(video_url, copy.map(LocalCopy::path))?;
+        if let Some(duration) = probe_duration_secs(thumbnail, input).await {
+            if duration > cfg.max_video_duration_secs as f64 {
+                tracing::info!(
+                    "refusing video {}: duration {:.1}s exceeds limit of {}s",
+                    video_url,
+                    duration,
+                    cfg.max_video_duration_secs
+                );
+                metrics::record_processing_error("video_too_long");
+                return Err(VideoError::TooLarge("video too long").into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the Content-Length advertised by a HEAD request
+async fn probe_content_length(app: &AppState, video_url: &str) -> Option<u64> {
+    let resp = upstream_auth::sign(app.http.head(video_url), video_url).send().await.ok()?;
+    metrics::record_upstream_response(resp.version());
+    if !resp.status().is_success() {
+        return None;
+    }
+    resp.headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Read the container duration in seconds using ffprobe
+async fn probe_duration_secs(thumbnail: &ThumbnailState, video_url: &str) -> Option<f64> {
+    let stdout = run_ffprobe(
+        thumbnail,
+        video_url,
+        &["-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"],
+    )
+    .await?;
+    String::from_utf8_lossy(&stdout).trim().parse().ok()
+}
+
+/// ffprobe's JSON report on the first video stream and the container, `None` when it fails
+///
+/// Covers codec, dimensions, rotation (as a `rotate` tag or display matrix), duration and size.
+pub async fn probe_video_report(
+    app: &AppState,
+    thumbnail: &ThumbnailState,
+    video_url: &str,
+) -> Result<Option<Vec<u8>>, SvcError> {
+    check_source_host(&app.cfg, video_url)?;
+    let copy = local_copy(app, video_url).await?;
+    Ok(run_ffprobe(
+        thumbnail,
+        input_path(video_url, copy.as_ref().map(LocalCopy::path))?,
+        &[
+            "-select_streams", "v:0",
+            "-show_entries", PROBE_REPORT_ENTRIES,
+            "-of", "json",
+        ],
+    )
+    .await)
 }
 
-/// Extract a thumbnail from a video using ffmpeg CLI
-async fn extract_thumbnail_with_ffmpeg(video_url: &str) -> Result<Vec<u8>, SvcError> {
+/// Run ffprobe with `args` on `video_url` and return its stdout
+///
+/// Runs under the dedicated ffprobe semaphore and is killed after `FFPROBE_TIMEOUT_SECS`.
+async fn run_ffprobe(thumbnail: &ThumbnailState, video_url: &str, args: &[&str]) -> Option<Vec<u8>> {
     use tokio::process::Command;
-    
-    // Create a temporary file for the output
-    let temp_file = tempfile::NamedTempFile::new()
-        .map_err(|e| SvcError::Io(e))?;
-    let output_path = temp_file.path();
 
-    // Run ffmpeg to extract thumbnail
-    // Equivalent to:
-    // ffmpeg -ss 0.5 -i <video_url> -vframes 1 -vf "scale=-1:'min(720,ih)'" -q:v 80 -c:v libwebp -f image2 output.webp
-    tracing::debug!("spawning ffmpeg for video: {}", video_url);
+    let _permit = thumbnail.ffprobe_semaphore.acquire().await.ok()?;
+
+    let mut cmd = Command::new("ffprobe");
+    cmd.args(["-v", "error"])
+        .args(args)
+        .args(input_args(video_url))
+        .kill_on_drop(true);
+
+    let output = match tokio::time::timeout(thumbnail.ffprobe_timeout, cmd.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            tracing::debug!("failed to spawn ffprobe for {}: {}", video_url, e);
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!("ffprobe timed out for {}", video_url);
+            metrics::record_processing_error("ffprobe_timeout");
+            return None;
+        }
+    };
+
+    if !output.status.success() {
+        tracing::debug!("ffprobe failed for {}", video_url);
+        return None;
+    }
+
+    Some(output.stdout)
+}
+
+/// Failures another server can't fix, since every server holds the same blob
+fn repeats_on_every_server(err: &SvcError) -> bool {
+    matches!(
+        err,
+        SvcError::BadRequest(_)
+            | SvcError::UnsupportedMedia(_)
+            | SvcError::Video(VideoError::TooLarge(_) | VideoError::UnsupportedCodec(_))
+    )
+}
 
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-ss", "0.5",               // Seek to 0.5 seconds
-            "-i", video_url,            // Input URL
+/// ffmpeg arguments for a single WebP frame at `seek`
+///
+/// Equivalent to (with the default VIDEO_THUMB_MAX_SIDE of 720):
+/// ffmpeg -ss <seek, default 0.5> -i <video_url> -vframes 1 \
+///   -vf "scale='if(gt(iw,ih),-2,min(720,iw))':'if(gt(iw,ih),min(720,ih),-2)'" \
+///   -q:v 80 -c:v libwebp -f image2 output.webp
+fn frame_args(video_url: &str, seek: f32, max_side: u32) -> Vec<String> {
+    let scale_filter = thumbnail_scale_filter(max_side);
+    // Seek to the requested second (input seeking, fast)
+    let mut args = vec!["-ss".to_string(), seek.to_string()];
+    args.extend(input_args(video_url));
+    args.extend(
+        [
             "-vframes", "1",            // Extract 1 frame
-            "-vf", "scale=-1:'min(720,ih)'",  // Scale to max height 720, keep aspect ratio
+            "-vf", scale_filter.as_str(), // Cap the shorter side, keep aspect ratio
             "-q:v", "80",               // Quality 80
             "-c:v", "libwebp",          // WebP codec
             "-f", "image2",             // Image format
             "-y",                       // Overwrite output file
-        ])
-        .arg(output_path)
-        .output()
-        .await
-        .map_err(|e| {
+        ]
+        .map(String::from),
+    );
+    args
+}
+
+/// `-i <video_url>`, limited to `REMOTE_PROTOCOLS` when the input is a URL
+///
+/// Applies to everything the input opens, such as the segments of an HLS playlist. Copied
+/// playlists (`hls::copy`) may only open their own files, whatever their extension. Local
+/// uploads and other copies are plain files and keep ffmpeg's defaults.
+fn input_args(video_url: &str) -> Vec<String> {
+    let mut args = Vec::with_capacity(6);
+    if video_url.starts_with("http://") || video_url.starts_with("https://") {
+        args.extend(["-protocol_whitelist".to_string(), REMOTE_PROTOCOLS.to_string()]);
+    } else if video_url.ends_with(".m3u8") {
+        args.extend(["-protocol_whitelist", HLS_COPY_PROTOCOLS, "-allowed_extensions", "ALL"].map(String::from));
+    }
+    args.extend(["-i".to_string(), video_url.to_string()]);
+    args
+}
+
+/// ffmpeg arguments for an animated WebP preview lasting `secs`
+///
+/// Videos at least twice that long are sampled as `PREVIEW_CLIPS` clips spread over their
+/// length, each its own seeking input so only those parts are downloaded and decoded. Shorter
+/// videos, and those whose length couldn't be probed, contribute their first `secs` seconds.
+fn preview_args(video_url: &str, width: u32, duration: Option<f64>, secs: f32) -> Vec<String> {
+    let secs = secs as f64;
+    let filter = format!("fps={},scale='min({},iw)':-2", PREVIEW_FPS, width);
+    let mut args: Vec<String> = Vec::new();
+    match duration.filter(|duration| *duration >= secs * 2.0) {
+        Some(duration) => {
+            let clip = secs / PREVIEW_CLIPS as f64;
+            for i in 0..PREVIEW_CLIPS {
+                let start = (duration * (i as f64 + 0.5) / PREVIEW_CLIPS as f64 - clip / 2.0).max(0.0);
+                args.extend(["-ss".into(), format!("{:.3}", start), "-t".into(), format!("{:.3}", clip)]);
+                args.extend(input_args(video_url));
+            }
+            let mut graph: String = (0..PREVIEW_CLIPS)
+                .map(|i| format!("[{}:v:0]{},setpts=PTS-STARTPTS[v{}];", i, filter, i))
+                .collect();
+            graph.extend((0..PREVIEW_CLIPS).map(|i| format!("[v{}]", i)));
+            graph.push_str(&format!("concat=n={}:v=1:a=0[out]", PREVIEW_CLIPS));
+            args.extend(["-filter_complex".into(), graph, "-map".into(), "[out]".into()]);
+        }
+        None => {
+            args.extend(["-t".into(), format!("{:.3}", secs)]);
+            args.extend(input_args(video_url));
+            args.extend(["-vf".into(), filter]);
+        }
+    }
+    args.extend(
+        [
+            "-an",                      // No audio track
+            "-c:v", "libwebp",          // WebP codec, animated by the webp muxer
+            "-q:v", "60",               // Quality 60; previews are small and short-lived
+            "-loop", "0",               // Loop forever
+            "-f", "webp",               // Animated WebP container
+            "-y",                       // Overwrite output file
+        ]
+        .map(String::from),
+    );
+    args
+}
+
+/// ffmpeg arguments for a JPEG sprite sheet of `grid.cols` x `grid.rows` frames
+///
+/// Frames are taken once per `grid.interval`, starting half an interval in, so each tile shows
+/// the middle of the span it stands for. The whole video is decoded in one pass.
+fn storyboard_args(video_url: &str, grid: StoryboardGrid) -> Vec<String> {
+    let filter = format!(
+        "fps=1/{:.3},scale={}:-2,tile={}x{}",
+        grid.interval, grid.width, grid.cols, grid.rows
+    );
+    // Skip to the middle of the first span
+    let mut args = vec!["-ss".to_string(), format!("{:.3}", grid.interval / 2.0)];
+    args.extend(input_args(video_url));
+    args.extend(
+        [
+            "-vf", filter.as_str(),     // Sample, scale and tile
+            "-frames:v", "1",           // One sheet
+            "-an",                      // No audio track
+            "-c:v", "mjpeg",            // JPEG codec
+            "-q:v", "4",                // JPEG quality scale (2-31, lower is better)
+            "-f", "image2",             // Image format
+            "-y",                       // Overwrite output file
+        ]
+        .map(String::from),
+    );
+    args
+}
+
+/// Build the ffmpeg scale filter capping the *shorter* side at `max_side`
+///
+/// Capping the height alone (`scale=-1:min(720,ih)`) shrinks portrait videos far more than
+/// landscape ones; bounding the short side treats both orientations alike.
+fn thumbnail_scale_filter(max_side: u32) -> String {
+    if max_side == 0 {
+        // Keep source resolution
+        return "scale=iw:ih".to_string();
+    }
+    format!(
+        "scale='if(gt(iw,ih),-2,min({max},iw))':'if(gt(iw,ih),min({max},ih),-2)'",
+        max = max_side
+    )
+}
+
+/// Run ffmpeg for `job` and return the file it wrote
+async fn run_ffmpeg(
+    video_url: &str,
+    job: FfmpegJob,
+    thumbnail: &ThumbnailState,
+    cfg: &AppCfg,
+) -> Result<Vec<u8>, SvcError> {
+    let args = match job {
+        FfmpegJob::Frame { seek } => frame_args(video_url, seek, cfg.video_thumb_max_side),
+        FfmpegJob::Preview { width } => {
+            // Sampling across the video needs its length
+            let duration = probe_duration_secs(thumbnail, video_url).await;
+            preview_args(video_url, width, duration, cfg.preview_secs)
+        }
+        FfmpegJob::Storyboard(grid) => storyboard_args(video_url, grid),
+    };
+    let output = spawn_ffmpeg(video_url, &args, cfg).await?;
+    tracing::debug!("ffmpeg successfully ran {:?} for: {}", job, video_url);
+    Ok(output)
+}
+
+/// Run ffmpeg with `args` plus an output path and return the file it wrote
+///
+/// Failures are classified from stderr; `video_url` names the source in logs. Runs past
+/// `FFMPEG_TIMEOUT_SECS` are killed. `DETERMINISTIC_OUTPUT` encodes on one thread and leaves
+/// out the version tags ffmpeg writes into some outputs.
+async fn spawn_ffmpeg(video_url: &str, args: &[String], cfg: &AppCfg) -> Result<Vec<u8>, SvcError> {
+    use tokio::process::Command;
+
+    // Create a temporary file for the output
+    let temp_file = tempfile::NamedTempFile::new().map_err(SvcError::Io)?;
+    let output_path = temp_file.path();
+
+    tracing::debug!("spawning ffmpeg for video: {}", video_url);
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args([
+        "-hide_banner",             // Keep stderr to the diagnostics
+        "-loglevel", "error",       // Only errors, which are classified on failure
+    ])
+    .args(args)
+    .args(if cfg.directive_defaults.deterministic { BITEXACT_ARGS } else { &[] })
+    .arg(output_path)
+    // Stops the extraction when the timeout below or REQUEST_TIMEOUT_SECS drops the pipeline
+    .kill_on_drop(true);
+
+    let output = match tokio::time::timeout(cfg.ffmpeg_timeout, cmd.output()).await {
+        Ok(output) => output.map_err(|e| {
             error!("failed to spawn ffmpeg for {}: {}", video_url, e);
             SvcError::Io(e)
-        })?;
+        })?,
+        Err(_) => {
+            // The dropped child was killed; its semaphore permit goes back as this returns
+            tracing::warn!("ffmpeg still running after {:?} for {}, killed", cfg.ffmpeg_timeout, video_url);
+            metrics::record_ffmpeg_extraction(false);
+            metrics::record_processing_error("ffmpeg_killed");
+            return Err(VideoError::Killed(cfg.ffmpeg_timeout.as_secs()).into());
+        }
+    };
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        let _stdout = String::from_utf8_lossy(&output.stdout);
-
-        // Check for common error patterns
-        let is_timeout = stderr.contains("timed out") || stderr.contains("Connection timed out");
-        let is_network_error = stderr.contains("Connection refused") || stderr.contains("Could not resolve host");
-        let is_404 = stderr.contains("404") || stderr.contains("Not Found");
-
-        if is_timeout {
-            tracing::debug!("ffmpeg timeout for {}: connection timed out", video_url);
-        } else if is_network_error {
-            tracing::debug!("ffmpeg network error for {}: {}", video_url, stderr.lines().next().unwrap_or("unknown"));
-        } else if is_404 {
-            tracing::debug!("ffmpeg 404 error for {}: resource not found", video_url);
-        } else {
-            tracing::debug!("ffmpeg failed for {}: {}", video_url, stderr.lines().take(3).collect::<Vec<_>>().join(" | "));
-        }
+        let failure = FfmpegFailure::classify(&stderr);
+        let detail = stderr_detail(&stderr);
+        tracing::debug!(failure = %failure, "ffmpeg failed for {}: {}", video_url, detail);
 
         metrics::record_ffmpeg_extraction(false);
-
-        return Err(SvcError::Io(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("ffmpeg failed: {}", stderr),
-        )));
+        metrics::record_processing_error(&format!("ffmpeg_{}", failure.label()));
+        return Err(failure.into_error(detail));
     }
 
-    tracing::debug!("ffmpeg successfully extracted thumbnail for: {}", video_url);
-
     metrics::record_ffmpeg_extraction(true);
 
     // Read the generated thumbnail
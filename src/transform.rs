@@ -1,41 +1,491 @@
-use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use std::io::Cursor;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        webp::WebPDecoder,
+    },
+    imageops::FilterType,
+    metadata::Orientation,
+    AnimationDecoder, DynamicImage, Frame, GenericImageView, ImageDecoder, ImageEncoder, ImageError,
+    ImageFormat, ImageReader, ImageResult,
+};
 use percent_encoding::percent_decode_str;
 
-use crate::error::SvcError;
+use crate::{card, error::SvcError, icc, metadata, text, thumbnail::is_video_extension};
 
 #[derive(Debug, Clone)]
 pub struct Directives {
     pub out_fmt: OutFmt,
     pub quality: u8,
     pub resize: Resize,
+    /// Palette size for quantized PNG output (None = full color)
+    pub colors: Option<u16>,
+    /// Region cut from the source before resizing
+    pub crop: Option<Crop>,
+    /// Which part of the image to keep when fill modes or a centered crop cut it
+    pub gravity: Gravity,
+    /// Source media type from `ext:` (None = guess from the URL extension)
+    pub source_kind: Option<SourceKind>,
+    /// Second of a video source its frame is taken at (`t:`; None = `VIDEO_THUMB_SECOND`)
+    pub video_seek: Option<f32>,
+    /// Color saturation in percent, 0 = grayscale (None = unchanged)
+    pub saturation: Option<u16>,
+    /// Clockwise rotation in degrees (0, 90, 180 or 270), applied after EXIF orientation
+    pub rotation: u16,
+    /// Copy the source's EXIF Artist/Copyright fields into the output (all other metadata is stripped)
+    pub keep_metadata: bool,
+    /// Color transparent areas are flattened onto (None = keep alpha, white for JPEG)
+    pub background: Option<[u8; 3]>,
+    /// Extend a smaller result to the requested canvas, placed by this gravity (`ex:1[:<gravity>]`)
+    pub extend: Option<Gravity>,
+    /// Space added around the result (`pd:`), filled like extended areas
+    pub padding: Option<Padding>,
+    /// Which frames of an animated GIF/WebP source are kept (`frames:`)
+    pub frames: Frames,
+    /// Text drawn over the result (`txt:`)
+    pub text: Option<TextOverlay>,
+    /// Tag JPEG and PNG outputs with an sRGB ICC profile (`EMBED_SRGB_PROFILE`)
+    pub embed_srgb_profile: bool,
+    /// Pin encoder settings that vary by machine, for byte-identical output (`DETERMINISTIC_OUTPUT`)
+    pub deterministic: bool,
+    /// Progressive instead of baseline JPEG (`progressive:`)
+    pub progressive: bool,
+    /// Format a transparent JPEG result switches to (`JPEG_ALPHA_FORMAT`)
+    pub jpeg_alpha_format: Option<OutFmt>,
+    /// Cut the result to its inscribed circle (`/avatar` preset only)
+    pub round_mask: bool,
+    /// Shade and text of an Open Graph card (`/card` only)
+    pub card: Option<Card>,
+}
+
+/// Text of an Open Graph preview card
+#[derive(Debug, Clone, Default)]
+pub struct Card {
+    pub title: Option<String>,
+    pub author: Option<String>,
 }
 
+/// Text overlay from `txt:<base64 text>[:<size>[:<color>[:<gravity>]]]`
 #[derive(Debug, Clone)]
+pub struct TextOverlay {
+    pub text: String,
+    /// Font size (line height) in pixels
+    pub size: f32,
+    /// RGBA fill color for outline glyphs
+    pub color: [u8; 4],
+    /// Corner or edge of the image the text block is placed at
+    pub gravity: Gravity,
+}
+
+/// Frames of an animated source that are processed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Frames {
+    /// Only the first frame, as a still image
+    #[default]
+    First,
+    /// Every frame, re-encoded as an animation when the output format supports it
+    All,
+}
+
+/// Padding in pixels, in CSS order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Padding {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+}
+
+/// Largest padding accepted per side
+const MAX_PADDING: u32 = 1024;
+/// Most pixels `ex:` and `pd:` may grow a result to, whatever `MAX_DIMENSION` allows
+const MAX_CANVAS_PIXELS: u64 = 100_000_000;
+/// Longest `txt:` overlay, in characters and in lines
+const MAX_TEXT_CHARS: usize = 256;
+const MAX_TEXT_LINES: usize = 16;
+/// Most lopsided `ar:` accepted, as width over height (and its inverse)
+const MIN_ASPECT_RATIO: f32 = 0.01;
+/// Largest side `ar:` may compute, JPEG's limit; `MAX_DIMENSION` is checked after it
+const MAX_ASPECT_SIDE: f32 = 65_535.0;
+/// Latest `t:` accepted, one day in
+pub const MAX_VIDEO_SEEK_SECS: f32 = 86_400.0;
+
+impl Directives {
+    /// Background to composite onto before encoding, if any
+    ///
+    /// JPEG and the grayscale tensors have no alpha channel, so they are always flattened, onto
+    /// white unless `bg:` says otherwise.
+    pub fn flatten_background(&self) -> Option<[u8; 3]> {
+        match self.out_fmt {
+            OutFmt::Jpeg | OutFmt::Raw | OutFmt::Json | OutFmt::Auto => Some(self.background.unwrap_or([255, 255, 255])),
+            _ => self.background,
+        }
+    }
+
+    /// Output size fixed by the request itself: both resize dimensions plus padding
+    ///
+    /// `f:raw` has no header, so its output must have this size for clients to know the shape.
+    /// None as well when the padded size doesn't fit in a `u32`.
+    pub fn exact_size(&self) -> Option<(u32, u32)> {
+        let (w, h) = (self.resize.w, self.resize.h);
+        if w == 0 || h == 0 {
+            return None;
+        }
+        match self.padding {
+            Some(ref p) => Some((
+                w.checked_add(p.left)?.checked_add(p.right)?,
+                h.checked_add(p.top)?.checked_add(p.bottom)?,
+            )),
+            None => Some((w, h)),
+        }
+    }
+
+    /// Format to encode instead of JPEG if the result turns out to have transparency
+    ///
+    /// Only when `JPEG_ALPHA_FORMAT` is set and the request didn't ask for a `bg:` to flatten onto.
+    pub fn alpha_substitute(&self) -> Option<OutFmt> {
+        match self.out_fmt {
+            OutFmt::Jpeg if self.background.is_none() => self.jpeg_alpha_format.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Source media type, forced with `ext:<type>` for URLs with a missing or wrong extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Image,
+    Video,
+}
+
+impl SourceKind {
+    /// Accepts `image`, `video`, or a file extension such as `mp4` or `jpg`
+    pub fn from_ext(ext: &str) -> Result<SourceKind, SvcError> {
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        match ext.as_str() {
+            "image" | "svg" => Ok(SourceKind::Image),
+            "video" => Ok(SourceKind::Video),
+            _ if is_video_extension(&ext) => Ok(SourceKind::Video),
+            _ if image::ImageFormat::from_extension(&ext).is_some() => Ok(SourceKind::Image),
+            _ => Err(SvcError::BadRequest("unsupported ext")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum OutFmt {
     Jpeg,
     Png,
     Webp,
     Avif,
+    Gif,
+    Jxl,
+    /// 8-bit grayscale pixels, row by row with no header, for ML and moderation pipelines
+    Raw,
+    /// The same grayscale pixels as a JSON matrix with its shape
+    Json,
+    /// Chosen per request from the `Accept` header, see `OutFmt::negotiate`
+    Auto,
 }
 
 impl OutFmt {
     pub fn mime_type(&self) -> &'static str {
         match self {
-            OutFmt::Jpeg => "image/jpeg",
+            // Auto is resolved before encoding; JPEG is its fallback
+            OutFmt::Jpeg | OutFmt::Auto => "image/jpeg",
             OutFmt::Png => "image/png",
             OutFmt::Webp => "image/webp",
             OutFmt::Avif => "image/avif",
+            OutFmt::Jxl => "image/jxl",
+            OutFmt::Gif => "image/gif",
+            OutFmt::Raw => "application/octet-stream",
+            OutFmt::Json => "application/json",
+        }
+    }
+
+    /// Parse a format name as used in `f:<format>` and `?f=<format>`
+    ///
+    /// Formats whose codec was compiled out are rejected here, before any fetching.
+    pub fn from_name(name: &str) -> Result<OutFmt, SvcError> {
+        let fmt = match name.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => OutFmt::Jpeg,
+            "png" => OutFmt::Png,
+            "webp" => OutFmt::Webp,
+            "avif" => OutFmt::Avif,
+            "gif" => OutFmt::Gif,
+            "jxl" => OutFmt::Jxl,
+            "raw" => OutFmt::Raw,
+            "json" => OutFmt::Json,
+            "auto" => OutFmt::Auto,
+            _ => return Err(SvcError::BadRequest("unsupported format")),
+        };
+        if !fmt.is_enabled() {
+            return Err(SvcError::BadRequest("output format not enabled in this build"));
         }
+        Ok(fmt)
+    }
+
+    /// Canonical format name (used for metrics labels and capability reporting)
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutFmt::Jpeg => "jpeg",
+            OutFmt::Png => "png",
+            OutFmt::Webp => "webp",
+            OutFmt::Avif => "avif",
+            OutFmt::Gif => "gif",
+            OutFmt::Jxl => "jxl",
+            OutFmt::Raw => "raw",
+            OutFmt::Json => "json",
+            OutFmt::Auto => "auto",
+        }
+    }
+
+    /// Whether the encoder for this format was compiled in (cargo features)
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            OutFmt::Jpeg | OutFmt::Png | OutFmt::Gif | OutFmt::Raw | OutFmt::Json | OutFmt::Auto => true,
+            OutFmt::Webp => cfg!(feature = "webp"),
+            OutFmt::Avif => cfg!(feature = "avif"),
+            OutFmt::Jxl => cfg!(feature = "jxl"),
+        }
+    }
+
+    /// All output formats available in this build
+    pub fn enabled_formats() -> Vec<&'static str> {
+        [OutFmt::Jpeg, OutFmt::Png, OutFmt::Webp, OutFmt::Avif, OutFmt::Gif, OutFmt::Jxl, OutFmt::Raw, OutFmt::Json]
+            .iter()
+            .filter(|f| f.is_enabled())
+            .map(|f| f.name())
+            .collect()
     }
 
     pub fn extension(&self) -> &'static str {
         match self {
-            OutFmt::Jpeg => "jpg",
+            OutFmt::Jpeg | OutFmt::Auto => "jpg",
             OutFmt::Png => "png",
             OutFmt::Webp => "webp",
             OutFmt::Avif => "avif",
+            OutFmt::Gif => "gif",
+            OutFmt::Jxl => "jxl",
+            OutFmt::Raw => "raw",
+            OutFmt::Json => "json",
+        }
+    }
+
+    /// Whether `frames:all` output in this format keeps the animation
+    pub fn supports_animation(&self) -> bool {
+        matches!(self, OutFmt::Gif | OutFmt::Webp) && self.is_enabled()
+    }
+
+    /// Format to retry with when this format's encoder fails on an image
+    ///
+    /// The codec-library formats fall back to JPEG, or PNG to keep an alpha channel;
+    /// animated WebP falls back to GIF. The built-in encoders have no fallback.
+    pub fn encode_fallback(&self, has_alpha: bool, animated: bool) -> Option<OutFmt> {
+        match self {
+            OutFmt::Webp if animated => Some(OutFmt::Gif),
+            OutFmt::Webp | OutFmt::Avif | OutFmt::Jxl if has_alpha => Some(OutFmt::Png),
+            OutFmt::Webp | OutFmt::Avif | OutFmt::Jxl => Some(OutFmt::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// Concrete formats `Auto` can resolve to, in order of preference
+    pub fn auto_candidates() -> Vec<OutFmt> {
+        [OutFmt::Avif, OutFmt::Webp, OutFmt::Jpeg]
+            .into_iter()
+            .filter(|f| f.is_enabled())
+            .collect()
+    }
+
+    /// Resolve `Auto` against the client's `Accept` header: AVIF > WebP > JPEG
+    ///
+    /// Concrete formats are returned unchanged.
+    pub fn negotiate(self, accept: Option<&str>) -> OutFmt {
+        if !matches!(self, OutFmt::Auto) {
+            return self;
+        }
+        let accept = accept.unwrap_or("");
+        OutFmt::auto_candidates()
+            .into_iter()
+            .find(|f| matches!(f, OutFmt::Jpeg) || accepts_mime(accept, f.mime_type()))
+            .unwrap_or(OutFmt::Jpeg)
+    }
+}
+
+/// Whether an Accept header explicitly lists `mime` with a non-zero q-value
+fn accepts_mime(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        parts.next() == Some(mime)
+            && !parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            })
+    })
+}
+
+/// Anchor for cropping, as in `g:<type>` (imgproxy gravity names)
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+    /// Window with the most detail (edge energy), for subjects that aren't centered
+    Smart,
+    /// Point given relative to the image (0-1 from the top-left), as in `fp:<x>:<y>`; crops
+    /// center on it as far as the image edges allow
+    FocusPoint(f32, f32),
+}
+
+impl Gravity {
+    pub fn from_name(name: &str) -> Result<Gravity, SvcError> {
+        let name = name.to_ascii_lowercase();
+        if let Some(point) = name.strip_prefix("fp:") {
+            return parse_focus_point(point);
+        }
+        Ok(match name.as_str() {
+            "ce" => Gravity::Center,
+            "no" => Gravity::North,
+            "so" => Gravity::South,
+            "ea" => Gravity::East,
+            "we" => Gravity::West,
+            "noea" => Gravity::NorthEast,
+            "nowe" => Gravity::NorthWest,
+            "soea" => Gravity::SouthEast,
+            "sowe" => Gravity::SouthWest,
+            "sm" => Gravity::Smart,
+            _ => return Err(SvcError::BadRequest("unsupported gravity")),
+        })
+    }
+
+    /// Top-left corner of a `crop_w`x`crop_h` window inside `img`
+    fn offset(self, img: &DynamicImage, crop_w: u32, crop_h: u32) -> (u32, u32) {
+        let (w, h) = img.dimensions();
+        match self {
+            Gravity::Smart => return smart_offset(img, crop_w, crop_h),
+            Gravity::FocusPoint(x, y) => return (focus_start(x, w, crop_w), focus_start(y, h, crop_h)),
+            _ => {}
         }
+        self.anchor(w.saturating_sub(crop_w), h.saturating_sub(crop_h))
     }
+
+    /// Split `free_w`x`free_h` of spare room by compass direction (smart = center)
+    pub(crate) fn anchor(self, free_w: u32, free_h: u32) -> (u32, u32) {
+        if let Gravity::FocusPoint(x, y) = self {
+            return ((free_w as f32 * x).round() as u32, (free_h as f32 * y).round() as u32);
+        }
+        let x = match self {
+            Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+            Gravity::East | Gravity::NorthEast | Gravity::SouthEast => free_w,
+            Gravity::Center | Gravity::North | Gravity::South | Gravity::Smart | Gravity::FocusPoint(..) => {
+                free_w / 2
+            }
+        };
+        let y = match self {
+            Gravity::North | Gravity::NorthEast | Gravity::NorthWest => 0,
+            Gravity::South | Gravity::SouthEast | Gravity::SouthWest => free_h,
+            Gravity::Center | Gravity::East | Gravity::West | Gravity::Smart | Gravity::FocusPoint(..) => {
+                free_h / 2
+            }
+        };
+        (x, y)
+    }
+}
+
+/// Parse the `<x>:<y>` of `fp:<x>:<y>`, both fractions 0-1
+fn parse_focus_point(arg: &str) -> Result<Gravity, SvcError> {
+    let coord = |v: &str| v.parse::<f32>().ok().filter(|v| (0.0..=1.0).contains(v));
+    arg.split_once(':')
+        .and_then(|(x, y)| Some(Gravity::FocusPoint(coord(x)?, coord(y)?)))
+        .ok_or(SvcError::BadRequest("focus point must be fp:<x>:<y> with x and y from 0 to 1"))
+}
+
+/// Start of a `crop`-long window centered on `focus` (0-1) along a `full`-long side, kept inside it
+fn focus_start(focus: f32, full: u32, crop: u32) -> u32 {
+    let start = (focus * full as f32 - crop as f32 / 2.0).round().max(0.0) as u32;
+    start.min(full.saturating_sub(crop))
+}
+
+/// Longest side of the grayscale copy analyzed by smart gravity
+const SMART_ANALYSIS_SIDE: u32 = 256;
+
+/// Place a crop window over the region with the most edge energy
+///
+/// Works on a small grayscale copy: each column and row gets the sum of its gradient
+/// magnitudes, and the window is slid along each axis with room to keep the densest span.
+fn smart_offset(img: &DynamicImage, crop_w: u32, crop_h: u32) -> (u32, u32) {
+    let (w, h) = img.dimensions();
+    if (crop_w >= w && crop_h >= h) || w < 3 || h < 3 {
+        return (0, 0);
+    }
+
+    let scale = f32::min(1.0, SMART_ANALYSIS_SIDE as f32 / w.max(h) as f32);
+    let small_w = ((w as f32 * scale).round() as u32).max(3);
+    let small_h = ((h as f32 * scale).round() as u32).max(3);
+    let luma = img.resize_exact(small_w, small_h, FilterType::Triangle).to_luma8();
+
+    let mut col_energy = vec![0u64; small_w as usize];
+    let mut row_energy = vec![0u64; small_h as usize];
+    for y in 1..small_h - 1 {
+        for x in 1..small_w - 1 {
+            let px = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as i32;
+            let gx = (px(x + 1, y) - px(x - 1, y)).unsigned_abs();
+            let gy = (px(x, y + 1) - px(x, y - 1)).unsigned_abs();
+            let e = (gx + gy) as u64;
+            col_energy[x as usize] += e;
+            row_energy[y as usize] += e;
+        }
+    }
+
+    let x = densest_window(&col_energy, crop_w as f32 * scale, w, crop_w);
+    let y = densest_window(&row_energy, crop_h as f32 * scale, h, crop_h);
+    (x, y)
+}
+
+/// Start (in full-size pixels) of the window of `window` analysis cells with the highest energy
+fn densest_window(energy: &[u64], window: f32, full: u32, crop: u32) -> u32 {
+    let free = full.saturating_sub(crop);
+    let window = (window.round() as usize).clamp(1, energy.len());
+    if free == 0 || window >= energy.len() {
+        return free / 2;
+    }
+
+    let mut sum: u64 = energy[..window].iter().sum();
+    let mut best = (sum, 0);
+    for start in 1..=energy.len() - window {
+        sum = sum + energy[start + window - 1] - energy[start - 1];
+        if sum > best.0 {
+            best = (sum, start);
+        }
+    }
+    if best.0 == 0 {
+        // Flat image: nothing to aim for
+        return free / 2;
+    }
+
+    let step = full as f32 / energy.len() as f32;
+    ((best.1 as f32 * step).round() as u32).min(free)
+}
+
+/// Crop region, as in `c:<w>:<h>[:<x>:<y>]`
+#[derive(Debug, Clone)]
+pub struct Crop {
+    /// Size in pixels; values below 1 are fractions of the source, 0 keeps the full side
+    pub w: f32,
+    pub h: f32,
+    /// Top-left corner in pixels (None = placed by gravity)
+    pub offset: Option<(u32, u32)>,
 }
 
 #[derive(Debug, Clone)]
@@ -45,7 +495,7 @@ pub struct Resize {
     pub h: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ResizeMode {
     Fit,
     Fill,
@@ -54,8 +504,80 @@ pub enum ResizeMode {
     Auto,
 }
 
+impl ResizeMode {
+    pub fn from_name(name: &str) -> Result<ResizeMode, SvcError> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "fit" => ResizeMode::Fit,
+            "fill" => ResizeMode::Fill,
+            "fill-down" => ResizeMode::FillDown,
+            "force" => ResizeMode::Force,
+            "auto" => ResizeMode::Auto,
+            _ => return Err(SvcError::BadRequest("unsupported resize mode")),
+        })
+    }
+}
+
+/// Values used for directives a request leaves out, configurable by operators
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectiveDefaults {
+    /// Output format for /insecure and signed URLs without `f:`
+    pub format: OutFmt,
+    /// Output format for /thumb requests without `f=`
+    pub thumb_format: OutFmt,
+    pub quality: u8,
+    /// Mode for resize directives that leave it empty (`rs::800:600`) and for /thumb without `rs=`
+    pub resize_mode: ResizeMode,
+    /// Bounding box for /thumb requests without `rs=`
+    pub thumb_width: u32,
+    pub thumb_height: u32,
+    /// Preserve copyright fields unless a request sets `km:0`
+    pub keep_metadata: bool,
+    /// Tag JPEG and PNG outputs with an sRGB ICC profile
+    pub embed_srgb_profile: bool,
+    /// Same input and directives always encode to the same bytes, on any machine
+    pub deterministic: bool,
+    /// Write progressive JPEGs unless a request sets `progressive:0`
+    pub progressive: bool,
+    /// Format for JPEG results with transparency and no `bg:` (None = flatten onto white)
+    pub jpeg_alpha_format: Option<OutFmt>,
+}
+
+impl Default for DirectiveDefaults {
+    fn default() -> Self {
+        Self {
+            format: OutFmt::Jpeg,
+            // Builds without libwebp fall back to JPEG rather than failing every /thumb
+            thumb_format: if OutFmt::Webp.is_enabled() {
+                OutFmt::Webp
+            } else {
+                OutFmt::Jpeg
+            },
+            quality: 82, // sensible default similar to imgproxy defaults
+            resize_mode: ResizeMode::Fit,
+            thumb_width: 480,
+            thumb_height: 480,
+            keep_metadata: false,
+            embed_srgb_profile: false,
+            deterministic: false,
+            progressive: false,
+            jpeg_alpha_format: None,
+        }
+    }
+}
+
+impl DirectiveDefaults {
+    /// Part of processed cache keys standing for these defaults; None for the built-in ones
+    ///
+    /// Requests that leave a directive out render with its default, so a cached result is only
+    /// valid under the defaults it was made with. The built-in defaults add nothing, which keeps
+    /// existing entries valid.
+    pub fn cache_tag(&self) -> Option<String> {
+        (*self != Self::default()).then(|| format!("{:?}", self))
+    }
+}
+
 /// Parse URL path segments into directives and source URL
-pub fn parse_rest(rest: &str) -> Result<(Directives, String), SvcError> {
+pub fn parse_rest(rest: &str, defaults: &DirectiveDefaults) -> Result<(Directives, String), SvcError> {
     // Split at "/plain/"
     let (before_plain, after_plain) = rest
         .split_once("/plain/")
@@ -69,23 +591,32 @@ pub fn parse_rest(rest: &str) -> Result<(Directives, String), SvcError> {
         .collect();
 
     // Defaults
-    let mut out_fmt = OutFmt::Jpeg;
-    let mut quality: u8 = 82; // sensible default similar to imgproxy defaults
+    let mut out_fmt = defaults.format.clone();
+    let mut quality = defaults.quality;
     let mut resize = Resize {
-        mode: ResizeMode::Fit,
+        mode: defaults.resize_mode.clone(),
         w: 0,
         h: 0,
     };
+    let mut colors = None;
+    let mut crop = None;
+    let mut gravity = Gravity::default();
+    let mut source_kind = None;
+    let mut video_seek = None;
+    let mut aspect_ratio = None;
+    let mut saturation = None;
+    let mut rotation = 0;
+    let mut keep_metadata = defaults.keep_metadata;
+    let mut progressive = defaults.progressive;
+    let mut background = None;
+    let mut extend = None;
+    let mut padding = None;
+    let mut frames = Frames::default();
+    let mut text = None;
 
     for seg in segments {
         if let Some(arg) = seg.strip_prefix("f:") {
-            out_fmt = match arg.to_ascii_lowercase().as_str() {
-                "jpeg" | "jpg" => OutFmt::Jpeg,
-                "png" => OutFmt::Png,
-                "webp" => OutFmt::Webp,
-                "avif" => OutFmt::Avif,
-                _ => return Err(SvcError::BadRequest("unsupported format")),
-            };
+            out_fmt = OutFmt::from_name(arg)?;
         } else if let Some(arg) = seg.strip_prefix("q:") {
             quality = arg
                 .parse()
@@ -94,10 +625,42 @@ pub fn parse_rest(rest: &str) -> Result<(Directives, String), SvcError> {
                 .ok_or(SvcError::BadRequest("bad quality"))?;
         } else if let Some(arg) = seg.strip_prefix("rs:") {
             // Parse rs:<mode>:<w>:<h> or rt:<mode>:<w>:<h>
-            resize = parse_resize_directive(arg)?;
+            resize = parse_resize_directive(arg, &defaults.resize_mode)?;
         } else if let Some(arg) = seg.strip_prefix("rt:") {
             // Alternative syntax: rt:<mode>:<w>:<h>
-            resize = parse_resize_directive(arg)?;
+            resize = parse_resize_directive(arg, &defaults.resize_mode)?;
+        } else if let Some(arg) = seg.strip_prefix("colors:") {
+            colors = Some(parse_colors(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("c:").or_else(|| seg.strip_prefix("crop:")) {
+            crop = Some(parse_crop(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("g:").or_else(|| seg.strip_prefix("gravity:")) {
+            gravity = Gravity::from_name(arg)?;
+        } else if let Some(arg) = seg.strip_prefix("ext:") {
+            source_kind = Some(SourceKind::from_ext(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("ar:").or_else(|| seg.strip_prefix("aspect_ratio:")) {
+            aspect_ratio = Some(parse_aspect_ratio(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("t:") {
+            video_seek = Some(parse_video_seek(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("sat:").or_else(|| seg.strip_prefix("saturation:")) {
+            saturation = Some(parse_saturation(arg)?);
+        } else if seg == "grayscale" {
+            saturation = Some(0);
+        } else if let Some(arg) = seg.strip_prefix("rot:").or_else(|| seg.strip_prefix("rotate:")) {
+            rotation = parse_rotation(arg)?;
+        } else if let Some(arg) = seg.strip_prefix("km:").or_else(|| seg.strip_prefix("keep_metadata:")) {
+            keep_metadata = parse_bool(arg)?;
+        } else if let Some(arg) = seg.strip_prefix("progressive:") {
+            progressive = parse_bool(arg)?;
+        } else if let Some(arg) = seg.strip_prefix("bg:").or_else(|| seg.strip_prefix("background:")) {
+            background = Some(parse_background(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("ex:").or_else(|| seg.strip_prefix("extend:")) {
+            extend = parse_extend(arg)?;
+        } else if let Some(arg) = seg.strip_prefix("pd:").or_else(|| seg.strip_prefix("padding:")) {
+            padding = Some(parse_padding(arg)?);
+        } else if let Some(arg) = seg.strip_prefix("frames:") {
+            frames = parse_frames(arg)?;
+        } else if let Some(arg) = seg.strip_prefix("txt:").or_else(|| seg.strip_prefix("text:")) {
+            text = Some(parse_text(arg)?);
         }
     }
 
@@ -105,6 +668,12 @@ pub fn parse_rest(rest: &str) -> Result<(Directives, String), SvcError> {
     if resize.w == 0 && resize.h == 0 {
         return Err(SvcError::BadRequest("at least one dimension required"));
     }
+    if let Some(ratio) = aspect_ratio {
+        apply_aspect_ratio(&mut resize, &mut extend, ratio)?;
+    }
+    if let Some(ref padding) = padding {
+        check_padded_size(&resize, padding)?;
+    }
 
     // Decode percent-encoded source URL
     let src_url = percent_decode_str(after_plain)
@@ -117,25 +686,112 @@ pub fn parse_rest(rest: &str) -> Result<(Directives, String), SvcError> {
             out_fmt,
             quality,
             resize,
+            colors,
+            crop,
+            gravity,
+            source_kind,
+            video_seek,
+            saturation,
+            rotation,
+            keep_metadata,
+            background,
+            extend,
+            padding,
+            frames,
+            text,
+            embed_srgb_profile: defaults.embed_srgb_profile,
+            deterministic: defaults.deterministic,
+            progressive,
+            jpeg_alpha_format: defaults.jpeg_alpha_format.clone(),
+            round_mask: false,
+            card: None,
         },
         src_url,
     ))
 }
 
+/// Parse `ar:<w>:<h>` into a width-to-height ratio (`ar:16:9`, `ar:1.91:1`)
+pub fn parse_aspect_ratio(arg: &str) -> Result<f32, SvcError> {
+    let side = |v: &str| v.parse::<f32>().ok().filter(|v| v.is_finite() && *v > 0.0);
+    arg.split_once(':')
+        .and_then(|(w, h)| Some(side(w)? / side(h)?))
+        .filter(|ratio| (MIN_ASPECT_RATIO..=1.0 / MIN_ASPECT_RATIO).contains(ratio))
+        .ok_or(SvcError::BadRequest("ar must be <w>:<h>, both positive and at most 100 times the other"))
+}
+
+/// Fill in the dimension `resize` leaves out so the output has exactly `ratio`
+///
+/// `fit` would leave the result smaller on one side, so it is extended (padded) to the full
+/// canvas unless `ex:` already says how; `auto` becomes `fill`, which crops. Other modes reach
+/// the computed size on their own. Computed sides over `MAX_ASPECT_SIDE` are refused.
+pub fn apply_aspect_ratio(resize: &mut Resize, extend: &mut Option<Gravity>, ratio: f32) -> Result<(), SvcError> {
+    let side = |len: f32| {
+        let len = len.round();
+        (len <= MAX_ASPECT_SIDE)
+            .then_some((len as u32).max(1))
+            .ok_or(SvcError::BadRequest("ar gives a side over 65535 pixels"))
+    };
+    match (resize.w, resize.h) {
+        (0, 0) => return Err(SvcError::BadRequest("ar needs a width or a height")),
+        (w, 0) => resize.h = side(w as f32 / ratio)?,
+        (0, h) => resize.w = side(h as f32 * ratio)?,
+        _ => return Err(SvcError::BadRequest("ar takes exactly one of width and height")),
+    }
+    match resize.mode {
+        ResizeMode::Fit => *extend = extend.or(Some(Gravity::Center)),
+        ResizeMode::Auto => resize.mode = ResizeMode::Fill,
+        ResizeMode::Fill | ResizeMode::FillDown | ResizeMode::Force => {}
+    }
+    Ok(())
+}
+
+/// Parse `t:<seconds>`, where in a video its frame is taken
+pub fn parse_video_seek(arg: &str) -> Result<f32, SvcError> {
+    arg.parse()
+        .ok()
+        .filter(|secs: &f32| (0.0..=MAX_VIDEO_SEEK_SECS).contains(secs))
+        .ok_or(SvcError::BadRequest("t must be 0-86400 seconds"))
+}
+
+/// Parse an imgproxy-style boolean argument (`1`/`t`/`true` or `0`/`f`/`false`)
+pub fn parse_bool(arg: &str) -> Result<bool, SvcError> {
+    match arg.to_ascii_lowercase().as_str() {
+        "1" | "t" | "true" => Ok(true),
+        "0" | "f" | "false" => Ok(false),
+        _ => Err(SvcError::BadRequest("boolean must be 1/0, t/f or true/false")),
+    }
+}
+
+/// Parse `frames:first|all`
+pub fn parse_frames(arg: &str) -> Result<Frames, SvcError> {
+    match arg.to_ascii_lowercase().as_str() {
+        "first" => Ok(Frames::First),
+        "all" => Ok(Frames::All),
+        _ => Err(SvcError::BadRequest("frames must be first or all")),
+    }
+}
+
+/// Parse a palette size for `colors:<n>` (2-256)
+pub fn parse_colors(arg: &str) -> Result<u16, SvcError> {
+    arg.parse()
+        .ok()
+        .filter(|n: &u16| (2..=256).contains(n))
+        .ok_or(SvcError::BadRequest("colors must be 2-256"))
+}
+
 /// Parse a resize directive like "fill:480:480", "fit:800:600", "fit::600", or "fit:800:"
-fn parse_resize_directive(arg: &str) -> Result<Resize, SvcError> {
+///
+/// An empty mode (":800:600") uses the operator's default resize mode.
+fn parse_resize_directive(arg: &str, default_mode: &ResizeMode) -> Result<Resize, SvcError> {
     let parts: Vec<&str> = arg.split(':').collect();
     if parts.len() != 3 {
         return Err(SvcError::BadRequest("invalid resize format"));
     }
 
-    let mode = match parts[0].to_ascii_lowercase().as_str() {
-        "fit" => ResizeMode::Fit,
-        "fill" => ResizeMode::Fill,
-        "fill-down" => ResizeMode::FillDown,
-        "force" => ResizeMode::Force,
-        "auto" => ResizeMode::Auto,
-        _ => return Err(SvcError::BadRequest("unsupported resize mode")),
+    let mode = if parts[0].is_empty() {
+        default_mode.clone()
+    } else {
+        ResizeMode::from_name(parts[0])?
     };
 
     // Parse width and height, allowing empty strings (0 means "calculate from aspect ratio")
@@ -158,8 +814,398 @@ fn parse_resize_directive(arg: &str) -> Result<Resize, SvcError> {
     Ok(Resize { mode, w, h })
 }
 
+/// Decode an image, convert it to sRGB and apply its EXIF orientation
+///
+/// Wide-gamut sources (e.g. Display P3 phone photos) are converted using their embedded
+/// ICC profile, so colors survive outputs that carry no profile. With `keep_metadata` the
+/// source's copyright fields are returned for the encoder; all other metadata is dropped here.
+pub fn decode_image(bytes: &[u8], keep_metadata: bool) -> Result<(DynamicImage, Option<Vec<u8>>), SvcError> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| SvcError::Decode(ImageError::IoError(e)))?
+        .into_decoder()?;
+    // Missing or unreadable metadata just means no transform
+    let exif = decoder.exif_metadata().ok().flatten();
+    let orientation = exif
+        .as_deref()
+        .and_then(Orientation::from_exif_chunk)
+        .unwrap_or(Orientation::NoTransforms);
+    let kept = exif
+        .filter(|_| keep_metadata)
+        .and_then(|exif| metadata::copyright_exif(&exif));
+    let profile = decoder.icc_profile().ok().flatten();
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    if let Some(profile) = profile {
+        img = icc::to_srgb(img, &profile);
+    }
+    img.apply_orientation(orientation);
+    Ok((img, kept))
+}
+
+/// Run the pixel pipeline on a decoded image: rotate, crop, resize, adjust, pad, flatten
+///
+/// Also returns the warnings for the request (judged on the cropped source size). Fails only when
+/// `ex:` or `pd:` would grow the result past `MAX_CANVAS_PIXELS`.
+pub fn apply_directives(img: DynamicImage, dirs: &Directives) -> Result<(DynamicImage, Vec<String>), SvcError> {
+    let img = apply_rotation(img, dirs.rotation);
+    let img = match dirs.crop {
+        Some(ref crop) => apply_crop(img, crop, dirs.gravity),
+        None => img,
+    };
+    let mut warnings = processing_warnings(dirs, img.dimensions());
+    let img = apply_resize(img, &dirs.resize, dirs.gravity);
+    let img = match dirs.saturation {
+        Some(percent) => apply_saturation(img, percent),
+        None => img,
+    };
+    let img = if dirs.round_mask { apply_round_mask(img) } else { img };
+    let img = match dirs.card {
+        Some(ref content) => {
+            let (img, missing) = card::draw_card(img, content);
+            if missing > 0 {
+                warnings.push(format!("{} characters in the card text have no glyph in TEXT_FONTS", missing));
+            }
+            img
+        }
+        None => img,
+    };
+    let img = apply_canvas(img, dirs)?;
+    let img = match dirs.text {
+        Some(ref overlay) => {
+            let (img, missing) = text::draw_text(img, overlay);
+            if missing > 0 {
+                warnings.push(format!("{} characters in txt have no glyph in TEXT_FONTS", missing));
+            }
+            img
+        }
+        None => img,
+    };
+    let img = match dirs.flatten_background() {
+        Some(rgb) => apply_background(img, rgb),
+        None => img,
+    };
+    Ok((img, warnings))
+}
+
+/// `apply_directives` for every frame of an animation, keeping frame timing
+pub fn apply_directives_to_frames(frames: Vec<Frame>, dirs: &Directives) -> Result<(Vec<Frame>, Vec<String>), SvcError> {
+    let mut warnings = Vec::new();
+    let frames = frames
+        .into_iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let delay = frame.delay();
+            let (img, frame_warnings) = apply_directives(DynamicImage::ImageRgba8(frame.into_buffer()), dirs)?;
+            if i == 0 {
+                warnings = frame_warnings;
+            }
+            Ok(Frame::from_parts(img.to_rgba8(), 0, 0, delay))
+        })
+        .collect::<Result<_, SvcError>>()?;
+    Ok((frames, warnings))
+}
+
+/// Parse a clockwise rotation for `rot:<deg>` (0, 90, 180 or 270)
+pub fn parse_rotation(arg: &str) -> Result<u16, SvcError> {
+    match arg.parse() {
+        Ok(deg @ (0 | 90 | 180 | 270)) => Ok(deg),
+        _ => Err(SvcError::BadRequest("rotation must be 0, 90, 180 or 270")),
+    }
+}
+
+/// Rotate clockwise by a multiple of 90 degrees
+pub fn apply_rotation(img: DynamicImage, degrees: u16) -> DynamicImage {
+    match degrees {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Parse a saturation percentage for `sat:<n>` (0-200, 100 = unchanged)
+pub fn parse_saturation(arg: &str) -> Result<u16, SvcError> {
+    arg.parse()
+        .ok()
+        .filter(|n: &u16| *n <= 200)
+        .ok_or(SvcError::BadRequest("saturation must be 0-200"))
+}
+
+/// Parse a background color for `bg:RRGGBB` (hex, optional leading `#`)
+pub fn parse_background(arg: &str) -> Result<[u8; 3], SvcError> {
+    let hex = arg.trim_start_matches('#');
+    let mut rgb = [0u8; 3];
+    if hex.len() != 6 || hex::decode_to_slice(hex, &mut rgb).is_err() {
+        return Err(SvcError::BadRequest("background must be a RRGGBB hex color"));
+    }
+    Ok(rgb)
+}
+
+/// Parse `txt:<base64url text>[:<size>[:<RRGGBB[AA]>[:<gravity>]]]`
+///
+/// Defaults: 24px, opaque white, bottom-right (`soea`). Lines are separated by `\n`.
+pub fn parse_text(arg: &str) -> Result<TextOverlay, SvcError> {
+    if !text::has_fonts() {
+        return Err(SvcError::BadRequest("txt needs TEXT_FONTS to be configured"));
+    }
+    let mut parts = arg.split(':');
+    let encoded = parts.next().unwrap_or_default().trim_end_matches('=');
+    let text = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .ok()
+        .and_then(|raw| String::from_utf8(raw).ok())
+        .ok_or(SvcError::BadRequest("txt text must be base64url-encoded UTF-8"))?;
+    if text.trim().is_empty() || text.chars().count() > MAX_TEXT_CHARS || text.lines().count() > MAX_TEXT_LINES {
+        return Err(SvcError::BadRequest("txt text must be 1-256 characters on at most 16 lines"));
+    }
+    let size = match parts.next() {
+        Some(size) => size
+            .parse()
+            .ok()
+            .filter(|px: &f32| (4.0..=512.0).contains(px))
+            .ok_or(SvcError::BadRequest("txt size must be 4-512"))?,
+        None => 24.0,
+    };
+    let color = match parts.next() {
+        Some(color) => parse_text_color(color)?,
+        None => [255, 255, 255, 255],
+    };
+    let gravity = match parts.next() {
+        Some(gravity) => Gravity::from_name(gravity)?,
+        None => Gravity::SouthEast,
+    };
+    if parts.next().is_some() {
+        return Err(SvcError::BadRequest("txt takes at most text, size, color and gravity"));
+    }
+    Ok(TextOverlay { text, size, color, gravity })
+}
+
+/// `RRGGBB` or `RRGGBBAA` hex, optional leading `#`
+fn parse_text_color(arg: &str) -> Result<[u8; 4], SvcError> {
+    let hex = arg.trim_start_matches('#');
+    let mut rgba = [255u8; 4];
+    let ok = match hex.len() {
+        6 => hex::decode_to_slice(hex, &mut rgba[..3]).is_ok(),
+        8 => hex::decode_to_slice(hex, &mut rgba).is_ok(),
+        _ => false,
+    };
+    if !ok {
+        return Err(SvcError::BadRequest("txt color must be a RRGGBB or RRGGBBAA hex color"));
+    }
+    Ok(rgba)
+}
+
+/// Parse `ex:<bool>[:<gravity>]`; Some(gravity) when extending is on
+pub fn parse_extend(arg: &str) -> Result<Option<Gravity>, SvcError> {
+    let (enabled, gravity) = match arg.split_once(':') {
+        Some((enabled, gravity)) => (enabled, Gravity::from_name(gravity)?),
+        None => (arg, Gravity::Center),
+    };
+    Ok(parse_bool(enabled)?.then_some(gravity))
+}
+
+/// Parse `pd:<top>[:<right>[:<bottom>[:<left>]]]`, omitted sides following CSS shorthand
+pub fn parse_padding(arg: &str) -> Result<Padding, SvcError> {
+    let sides = arg
+        .split(':')
+        .map(|v| v.parse().ok().filter(|px: &u32| *px <= MAX_PADDING))
+        .collect::<Option<Vec<u32>>>()
+        .ok_or(SvcError::BadRequest("padding must be 1-4 values of 0-1024"))?;
+    let (top, right, bottom, left) = match sides[..] {
+        [all] => (all, all, all, all),
+        [vertical, horizontal] => (vertical, horizontal, vertical, horizontal),
+        [top, horizontal, bottom] => (top, horizontal, bottom, horizontal),
+        [top, right, bottom, left] => (top, right, bottom, left),
+        _ => return Err(SvcError::BadRequest("padding must be 1-4 values of 0-1024")),
+    };
+    Ok(Padding { top, right, bottom, left })
+}
+
+/// Refuse padding whose sum with the requested size doesn't fit in a `u32`
+pub fn check_padded_size(resize: &Resize, padding: &Padding) -> Result<(), SvcError> {
+    let w = resize.w.checked_add(padding.left).and_then(|w| w.checked_add(padding.right));
+    let h = resize.h.checked_add(padding.top).and_then(|h| h.checked_add(padding.bottom));
+    match (w, h) {
+        (Some(_), Some(_)) => Ok(()),
+        _ => Err(SvcError::BadRequest("requested size plus padding is too large")),
+    }
+}
+
+/// Extend to the requested canvas (`ex:`) and add padding (`pd:`)
+///
+/// New areas are filled with the `bg:` color, or left transparent (white once flattened for JPEG).
+/// Canvases over `MAX_CANVAS_PIXELS` are refused: `ex:` sizes them from the request, not the source.
+pub fn apply_canvas(img: DynamicImage, dirs: &Directives) -> Result<DynamicImage, SvcError> {
+    let (w, h) = img.dimensions();
+    let (canvas_w, canvas_h, x, y) = match dirs.extend {
+        Some(gravity) => {
+            let (canvas_w, canvas_h) = (w.max(dirs.resize.w), h.max(dirs.resize.h));
+            let (x, y) = gravity.anchor(canvas_w - w, canvas_h - h);
+            (canvas_w, canvas_h, x, y)
+        }
+        None => (w, h, 0, 0),
+    };
+    let pad = dirs.padding.unwrap_or_default();
+    let total_w = canvas_w.checked_add(pad.left).and_then(|w| w.checked_add(pad.right));
+    let total_h = canvas_h.checked_add(pad.top).and_then(|h| h.checked_add(pad.bottom));
+    let (total_w, total_h) = total_w
+        .zip(total_h)
+        .filter(|&(w, h)| w as u64 * h as u64 <= MAX_CANVAS_PIXELS)
+        .ok_or(SvcError::BadRequest("extended or padded size is too large"))?;
+    if (total_w, total_h) == (w, h) {
+        return Ok(img);
+    }
+
+    let fill = match dirs.background {
+        Some([r, g, b]) => image::Rgba([r, g, b, 255]),
+        None => image::Rgba([0, 0, 0, 0]),
+    };
+    let mut canvas = image::RgbaImage::from_pixel(total_w, total_h, fill);
+    image::imageops::overlay(
+        &mut canvas,
+        &img.to_rgba8(),
+        (pad.left + x) as i64,
+        (pad.top + y) as i64,
+    );
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Composite the image over a solid color, dropping the alpha channel; opaque images pass through
+pub fn apply_background(img: DynamicImage, rgb: [u8; 3]) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let mut out = image::RgbImage::new(w, h);
+    for (src, dst) in rgba.pixels().zip(out.pixels_mut()) {
+        let alpha = src[3] as u32;
+        for c in 0..3 {
+            let blended = src[c] as u32 * alpha + rgb[c] as u32 * (255 - alpha);
+            dst[c] = ((blended + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(out)
+}
+
+/// Whether enough of the image is see-through that flattening would visibly change it
+///
+/// Stray translucent pixels (e.g. anti-aliasing noise in an otherwise opaque PNG) don't
+/// count: at least 0.1% of the pixels must have an alpha below 250.
+pub fn has_transparency(img: &DynamicImage) -> bool {
+    if !img.color().has_alpha() {
+        return false;
+    }
+    let (w, h) = img.dimensions();
+    let needed = (w as usize * h as usize / 1000).max(1);
+    img.pixels().filter(|(_, _, px)| px.0[3] < 250).take(needed).count() == needed
+}
+
+/// Make everything outside the inscribed circle transparent, with an antialiased edge
+pub fn apply_round_mask(img: DynamicImage) -> DynamicImage {
+    let mut rgba = img.into_rgba8();
+    let (w, h) = rgba.dimensions();
+    let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+    let radius = cx.min(cy);
+    for (x, y, px) in rgba.enumerate_pixels_mut() {
+        // Distance of the pixel center from the image center
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        let coverage = (radius - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0);
+        px.0[3] = (px.0[3] as f32 * coverage).round() as u8;
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Scale color saturation around each pixel's luma; alpha and grayscale sources are untouched
+pub fn apply_saturation(img: DynamicImage, percent: u16) -> DynamicImage {
+    if percent == 100 {
+        return img;
+    }
+    let factor = percent as f32 / 100.0;
+    let adjust = |px: &mut [u8]| {
+        let (r, g, b) = (px[0] as f32, px[1] as f32, px[2] as f32);
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        for c in &mut px[..3] {
+            *c = (luma + (*c as f32 - luma) * factor).round().clamp(0.0, 255.0) as u8;
+        }
+    };
+
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => img,
+        // Keep the alpha channel only where there is one; JPEG can't encode RGBA
+        img if img.color().has_alpha() => {
+            let mut rgba = img.to_rgba8();
+            rgba.pixels_mut().for_each(|p| adjust(&mut p.0));
+            DynamicImage::ImageRgba8(rgba)
+        }
+        img => {
+            let mut rgb = img.to_rgb8();
+            rgb.pixels_mut().for_each(|p| adjust(&mut p.0));
+            DynamicImage::ImageRgb8(rgb)
+        }
+    }
+}
+
+/// Parse a crop directive like "400:300" (centered) or "400:300:120:40" (offset from top-left)
+pub fn parse_crop(arg: &str) -> Result<Crop, SvcError> {
+    let parts: Vec<&str> = arg.split(':').collect();
+    if parts.len() != 2 && parts.len() != 4 {
+        return Err(SvcError::BadRequest("invalid crop format, expected w:h or w:h:x:y"));
+    }
+
+    let size = |s: &str| -> Result<f32, SvcError> {
+        if s.is_empty() {
+            return Ok(0.0);
+        }
+        s.parse()
+            .ok()
+            .filter(|v: &f32| v.is_finite() && *v >= 0.0)
+            .ok_or(SvcError::BadRequest("bad crop size"))
+    };
+    let w = size(parts[0])?;
+    let h = size(parts[1])?;
+
+    let offset = if parts.len() == 4 {
+        let x = parts[2].parse().map_err(|_| SvcError::BadRequest("bad crop offset"))?;
+        let y = parts[3].parse().map_err(|_| SvcError::BadRequest("bad crop offset"))?;
+        Some((x, y))
+    } else {
+        None
+    };
+
+    Ok(Crop { w, h, offset })
+}
+
+/// Cut the crop region out of the source, clamped to the image bounds
+pub fn apply_crop(img: DynamicImage, crop: &Crop, gravity: Gravity) -> DynamicImage {
+    let (src_w, src_h) = img.dimensions();
+
+    let side = |v: f32, src: u32| -> u32 {
+        let px = if v <= 0.0 {
+            src
+        } else if v < 1.0 {
+            (v * src as f32).round() as u32
+        } else {
+            v as u32
+        };
+        px.clamp(1, src.max(1))
+    };
+    let w = side(crop.w, src_w);
+    let h = side(crop.h, src_h);
+
+    let (x, y) = match crop.offset {
+        Some((x, y)) => (x.min(src_w.saturating_sub(w)), y.min(src_h.saturating_sub(h))),
+        None => gravity.offset(&img, w, h),
+    };
+
+    if (x, y, w, h) == (0, 0, src_w, src_h) {
+        return img;
+    }
+    img.crop_imm(x, y, w, h)
+}
+
 /// Apply resize transformation based on the resize mode
-pub fn apply_resize(img: DynamicImage, resize: &Resize) -> DynamicImage {
+pub fn apply_resize(img: DynamicImage, resize: &Resize, gravity: Gravity) -> DynamicImage {
     let (src_w, src_h) = img.dimensions();
     
     // Calculate missing dimension based on aspect ratio
@@ -181,13 +1227,48 @@ pub fn apply_resize(img: DynamicImage, resize: &Resize) -> DynamicImage {
 
     match mode {
         ResizeMode::Fit => apply_resize_fit(img, target_w, target_h),
-        ResizeMode::Fill => apply_resize_fill(img, target_w, target_h),
-        ResizeMode::FillDown => apply_resize_fill_down(img, target_w, target_h),
+        ResizeMode::Fill => apply_resize_fill(img, target_w, target_h, gravity),
+        ResizeMode::FillDown => apply_resize_fill_down(img, target_w, target_h, gravity),
         ResizeMode::Force => apply_resize_force(img, target_w, target_h),
         ResizeMode::Auto => unreachable!(), // Already resolved above
     }
 }
 
+/// Notes on where the output will differ from what was requested, for `X-Processing-Warnings`
+///
+/// `src_dims` are the dimensions going into the resize (after any crop).
+pub fn processing_warnings(dirs: &Directives, src_dims: (u32, u32)) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let (src_w, src_h) = src_dims;
+    let (target_w, target_h) = calculate_dimensions(src_w, src_h, dirs.resize.w, dirs.resize.h);
+
+    // Fit and fill-down never upscale, so small sources come out smaller than requested
+    let scale_w = target_w as f32 / src_w.max(1) as f32;
+    let scale_h = target_h as f32 / src_h.max(1) as f32;
+    let clamped = match dirs.resize.mode {
+        ResizeMode::Fit => f32::min(scale_w, scale_h) > 1.0,
+        ResizeMode::FillDown => f32::max(scale_w, scale_h) > 1.0,
+        ResizeMode::Auto => (src_h > src_w) != (target_h > target_w) && f32::min(scale_w, scale_h) > 1.0,
+        ResizeMode::Fill | ResizeMode::Force => false,
+    };
+    if clamped {
+        warnings.push(format!(
+            "upscaling skipped: source is {}x{}, smaller than requested {}x{}",
+            src_w, src_h, target_w, target_h
+        ));
+    }
+
+    if dirs.colors.is_some() && !matches!(dirs.out_fmt, OutFmt::Png) {
+        warnings.push(format!("colors ignored: palettes only apply to png, not {}", dirs.out_fmt.name()));
+    }
+
+    if dirs.keep_metadata && !matches!(dirs.out_fmt, OutFmt::Jpeg | OutFmt::Png | OutFmt::Auto) {
+        warnings.push(format!("metadata not kept: only jpeg and png carry it, not {}", dirs.out_fmt.name()));
+    }
+
+    warnings
+}
+
 /// Calculate target dimensions, filling in missing dimension based on aspect ratio
 fn calculate_dimensions(src_w: u32, src_h: u32, target_w: u32, target_h: u32) -> (u32, u32) {
     match (target_w, target_h) {
@@ -224,8 +1305,8 @@ fn apply_resize_fit(img: DynamicImage, target_w: u32, target_h: u32) -> DynamicI
     img.resize_exact(new_w, new_h, FilterType::Lanczos3)
 }
 
-/// Fill: Resize while keeping aspect ratio to fill the given size, with center crop
-fn apply_resize_fill(img: DynamicImage, target_w: u32, target_h: u32) -> DynamicImage {
+/// Fill: Resize while keeping aspect ratio to fill the given size, cropping towards the gravity
+fn apply_resize_fill(img: DynamicImage, target_w: u32, target_h: u32, gravity: Gravity) -> DynamicImage {
     let (w, h) = img.dimensions();
 
     // Scale to fill the box
@@ -235,14 +1316,12 @@ fn apply_resize_fill(img: DynamicImage, target_w: u32, target_h: u32) -> Dynamic
 
     let resized = img.resize_exact(new_w, new_h, FilterType::Lanczos3);
 
-    // Center crop
-    let x = (new_w.saturating_sub(target_w)) / 2;
-    let y = (new_h.saturating_sub(target_h)) / 2;
+    let (x, y) = gravity.offset(&resized, target_w, target_h);
     resized.crop_imm(x, y, target_w, target_h)
 }
 
 /// Fill-Down: Like fill, but if result is smaller, crop to maintain aspect ratio
-fn apply_resize_fill_down(img: DynamicImage, target_w: u32, target_h: u32) -> DynamicImage {
+fn apply_resize_fill_down(img: DynamicImage, target_w: u32, target_h: u32, gravity: Gravity) -> DynamicImage {
     let (w, h) = img.dimensions();
 
     // Scale to fill the box
@@ -260,9 +1339,7 @@ fn apply_resize_fill_down(img: DynamicImage, target_w: u32, target_h: u32) -> Dy
     let crop_w = new_w.min(target_w);
     let crop_h = new_h.min(target_h);
     
-    // Center crop
-    let x = (new_w.saturating_sub(crop_w)) / 2;
-    let y = (new_h.saturating_sub(crop_h)) / 2;
+    let (x, y) = gravity.offset(&resized, crop_w, crop_h);
     resized.crop_imm(x, y, crop_w, crop_h)
 }
 
@@ -271,54 +1348,629 @@ fn apply_resize_force(img: DynamicImage, target_w: u32, target_h: u32) -> Dynami
     img.resize_exact(target_w, target_h, FilterType::Lanczos3)
 }
 
-/// Encode image to the specified format with quality settings
-pub fn encode_image(img: &DynamicImage, fmt: &OutFmt, quality: u8) -> Result<Vec<u8>, SvcError> {
+/// Read image dimensions from the encoded header without decoding pixel data
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Reject sources whose header declares more than `max_megapixels` (0 = unlimited)
+///
+/// Reads only the header, so a small file that would expand to gigabytes of pixels (a
+/// "decompression bomb") is refused before any pixel buffer is allocated. Sources whose
+/// dimensions can't be read are left to the decoder.
+pub fn check_source_resolution(bytes: &[u8], max_megapixels: f64) -> Result<(), SvcError> {
+    if max_megapixels <= 0.0 {
+        return Ok(());
+    }
+    match image_dimensions(bytes) {
+        Some((w, h)) if w as f64 * h as f64 > max_megapixels * 1_000_000.0 => {
+            Err(SvcError::Unprocessable("source resolution exceeds MAX_SRC_RESOLUTION"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Encode image to the output format with the quality and palette settings from the directives
+///
+/// `exif` (from `decode_image` with `keep_metadata`) is embedded in JPEG and PNG output;
+/// nothing else from the source is ever written.
+pub fn encode_image(img: &DynamicImage, dirs: &Directives, exif: Option<Vec<u8>>) -> Result<Vec<u8>, SvcError> {
+    let quality = dirs.quality;
     let mut out = Vec::new();
-    match fmt {
-        OutFmt::Jpeg => {
+    match dirs.out_fmt {
+        OutFmt::Jpeg | OutFmt::Auto if dirs.progressive => return encode_jpeg_progressive(img, dirs, exif),
+        OutFmt::Jpeg | OutFmt::Auto => {
             let mut enc = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality);
+            if let Some(exif) = exif {
+                enc.set_exif_metadata(exif).map_err(ImageError::Unsupported)?;
+            }
+            if let Some(profile) = dirs.embed_srgb_profile.then(icc::srgb_profile).flatten() {
+                enc.set_icc_profile(profile.to_vec()).map_err(ImageError::Unsupported)?;
+            }
             enc.encode_image(img)?;
         }
         OutFmt::Png => {
-            let enc = image::codecs::png::PngEncoder::new(&mut out);
+            if let Some(colors) = dirs.colors {
+                return encode_png_quantized(img, colors, exif);
+            }
+            let mut enc = image::codecs::png::PngEncoder::new(&mut out);
+            if let Some(exif) = exif {
+                enc.set_exif_metadata(exif).map_err(ImageError::Unsupported)?;
+            }
+            if let Some(profile) = dirs.embed_srgb_profile.then(icc::srgb_profile).flatten() {
+                enc.set_icc_profile(profile.to_vec()).map_err(ImageError::Unsupported)?;
+            }
             img.write_with_encoder(enc)?;
         }
-        OutFmt::Webp => {
-            // Use lossy WebP encoding with quality control
-            let webp_data = webp::Encoder::from_image(img)
-                .map_err(|e| SvcError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?
-                .encode(quality as f32);
-            out.extend_from_slice(&webp_data);
-        }
-        OutFmt::Avif => {
-            // Use ravif for AVIF encoding with quality control
-            let rgba = img.to_rgba8();
-            let (w, h) = (rgba.width(), rgba.height());
-
-            // Convert to rgb::RGBA format
-            let pixels: Vec<rgb::RGBA<u8>> = rgba
-                .pixels()
-                .map(|p| rgb::RGBA {
-                    r: p[0],
-                    g: p[1],
-                    b: p[2],
-                    a: p[3],
-                })
-                .collect();
-
-            let avif_img = ravif::Img::new(&pixels[..], w as usize, h as usize);
-            let encoder = ravif::Encoder::new()
-                .with_quality(quality as f32)
-                .with_speed(6);
-            let encoded = encoder.encode_rgba(avif_img).map_err(|e| {
-                SvcError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("AVIF encode error: {}", e),
-                ))
-            })?;
-            out.extend_from_slice(&encoded.avif_file);
+        OutFmt::Webp => return encode_webp(img, quality),
+        OutFmt::Avif => return encode_avif(img, quality, dirs.deterministic),
+        OutFmt::Jxl => return encode_jxl(img, quality),
+        OutFmt::Gif => {
+            let mut enc = GifEncoder::new(&mut out);
+            enc.encode_frame(Frame::new(img.to_rgba8()))?;
+        }
+        OutFmt::Raw => return Ok(img.to_luma8().into_raw()),
+        OutFmt::Json => return encode_gray_json(img),
+    }
+    Ok(out)
+}
+
+/// `{"width":W,"height":H,"channels":1,"data":[[row 0], [row 1], ...]}` of 8-bit luma values
+fn encode_gray_json(img: &DynamicImage) -> Result<Vec<u8>, SvcError> {
+    let gray = img.to_luma8();
+    let (width, height) = gray.dimensions();
+    let rows: Vec<&[u8]> = gray.as_raw().chunks(width.max(1) as usize).collect();
+    let tensor = serde_json::json!({ "width": width, "height": height, "channels": 1, "data": rows });
+    serde_json::to_vec(&tensor).map_err(|e| SvcError::InternalError(format!("JSON encode error: {}", e)))
+}
+
+/// Progressive JPEG (`progressive:1`): a blurry full-size preview shows after the first scan
+///
+/// image's encoder only writes baseline JPEGs, so this goes through jpeg-encoder.
+fn encode_jpeg_progressive(img: &DynamicImage, dirs: &Directives, exif: Option<Vec<u8>>) -> Result<Vec<u8>, SvcError> {
+    let jpeg_err = |e: jpeg_encoder::EncodingError| SvcError::InternalError(format!("JPEG encode error: {}", e));
+    let (w, h) = img.dimensions();
+    let (w, h) = match (u16::try_from(w), u16::try_from(h)) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err(SvcError::BadRequest("JPEG output is limited to 65535x65535")),
+    };
+
+    let mut out = Vec::new();
+    let mut enc = jpeg_encoder::Encoder::new(&mut out, dirs.quality.max(1));
+    enc.set_progressive(true);
+    if let Some(exif) = exif {
+        let segment = [b"Exif\0\0".as_slice(), &exif].concat();
+        enc.add_app_segment(1, &segment).map_err(jpeg_err)?;
+    }
+    if let Some(profile) = dirs.embed_srgb_profile.then(icc::srgb_profile).flatten() {
+        enc.add_icc_profile(profile).map_err(jpeg_err)?;
+    }
+    enc.encode(&img.to_rgb8(), w, h, jpeg_encoder::ColorType::Rgb).map_err(jpeg_err)?;
+    Ok(out)
+}
+
+/// Check encoder output before it is cached or served
+///
+/// Verifies the signature matches `fmt`, the header declares `dims`, and the file is not
+/// truncated (JPEG EOI, PNG IEND, GIF trailer, WebP RIFF size). Cheap enough for every
+/// encode, unlike a full decode; JPEG XL only gets the signature check.
+pub fn validate_encoded(bytes: &[u8], fmt: &OutFmt, dims: (u32, u32)) -> Result<(), SvcError> {
+    let invalid = |what: &str| SvcError::InternalError(format!("{} encoder produced invalid output ({})", fmt.name(), what));
+
+    let expected = match fmt {
+        OutFmt::Jpeg | OutFmt::Auto => ImageFormat::Jpeg,
+        OutFmt::Png => ImageFormat::Png,
+        OutFmt::Webp => ImageFormat::WebP,
+        OutFmt::Avif => ImageFormat::Avif,
+        OutFmt::Gif => ImageFormat::Gif,
+        OutFmt::Jxl => {
+            let naked = bytes.starts_with(&[0xFF, 0x0A]);
+            let container = bytes.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n");
+            return if naked || container { Ok(()) } else { Err(invalid("signature")) };
+        }
+        OutFmt::Raw => {
+            let expected_len = dims.0 as usize * dims.1 as usize;
+            return if bytes.len() == expected_len { Ok(()) } else { Err(invalid("length")) };
+        }
+        // Built by serde_json from the pixels
+        OutFmt::Json => return Ok(()),
+    };
+    if image::guess_format(bytes).ok() != Some(expected) {
+        return Err(invalid("signature"));
+    }
+
+    let complete = match fmt {
+        OutFmt::Jpeg | OutFmt::Auto => bytes.ends_with(&[0xFF, 0xD9]),
+        OutFmt::Png => bytes.ends_with(b"IEND\xAE\x42\x60\x82"),
+        OutFmt::Gif => bytes.ends_with(&[0x3B]),
+        OutFmt::Webp => bytes
+            .get(4..8)
+            .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize + 8 == bytes.len())
+            .unwrap_or(false),
+        OutFmt::Avif | OutFmt::Jxl | OutFmt::Raw | OutFmt::Json => true,
+    };
+    if !complete {
+        return Err(invalid("truncated"));
+    }
+
+    match image_dimensions(bytes) {
+        Some(found) if found == dims => Ok(()),
+        _ => Err(invalid("dimensions")),
+    }
+}
+
+/// Whether the source is a GIF or WebP with more than one frame
+pub fn is_animated(bytes: &[u8]) -> bool {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))
+            .map(|d| d.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        Ok(ImageFormat::WebP) => WebPDecoder::new(Cursor::new(bytes))
+            .map(|d| d.has_animation())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Decode every frame of an animated GIF or WebP, composited onto the full canvas and
+/// converted to sRGB like `decode_image`
+///
+/// Returns None when the animation has more than `max_frames` frames; decoding stops there,
+/// so oversized animations cost at most `max_frames + 1` frames of memory.
+pub fn decode_frames(bytes: &[u8], max_frames: usize) -> Result<Option<Vec<Frame>>, SvcError> {
+    let (frames, profile) = match image::guess_format(bytes)? {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(Cursor::new(bytes))?;
+            let profile = decoder.icc_profile().ok().flatten();
+            (decoder.into_frames(), profile)
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            let profile = decoder.icc_profile().ok().flatten();
+            (decoder.into_frames(), profile)
+        }
+        _ => return Err(SvcError::UnsupportedMedia("source is not an animated image")),
+    };
+    let mut frames = frames.take(max_frames + 1).collect::<ImageResult<Vec<_>>>()?;
+    if frames.len() > max_frames {
+        return Ok(None);
+    }
+    if let Some(profile) = profile {
+        frames = frames
+            .into_iter()
+            .map(|frame| {
+                let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+                let img = icc::to_srgb(DynamicImage::ImageRgba8(frame.into_buffer()), &profile);
+                Frame::from_parts(img.into_rgba8(), left, top, delay)
+            })
+            .collect();
+    }
+    Ok(Some(frames))
+}
+
+/// Encode transformed frames as an animated GIF or WebP, looping forever
+pub fn encode_animation(frames: &[Frame], dirs: &Directives) -> Result<Vec<u8>, SvcError> {
+    match dirs.out_fmt {
+        OutFmt::Gif => {
+            let mut out = Vec::new();
+            let mut enc = GifEncoder::new(&mut out);
+            enc.set_repeat(Repeat::Infinite)?;
+            for frame in frames {
+                enc.encode_frame(frame.clone())?;
+            }
+            drop(enc);
+            Ok(out)
+        }
+        OutFmt::Webp => encode_webp_animation(frames, dirs.quality),
+        _ => Err(SvcError::BadRequest("output format does not support animation")),
+    }
+}
+
+/// Animated lossy WebP via libwebp's animation encoder
+#[cfg(feature = "webp")]
+fn encode_webp_animation(frames: &[Frame], quality: u8) -> Result<Vec<u8>, SvcError> {
+    let Some(first) = frames.first() else {
+        return Err(SvcError::BadRequest("animation has no frames"));
+    };
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| SvcError::InternalError("webp config init failed".to_string()))?;
+    config.quality = quality as f32;
+
+    let (w, h) = first.buffer().dimensions();
+    let mut encoder = webp::AnimEncoder::new(w, h, &config);
+    encoder.set_loop_count(0);
+    // libwebp takes each frame's start time rather than its duration
+    let mut timestamp_ms = 0i32;
+    for frame in frames {
+        let buffer = frame.buffer();
+        encoder.add_frame(webp::AnimFrame::from_rgba(buffer.as_raw(), buffer.width(), buffer.height(), timestamp_ms));
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        timestamp_ms += (numer / denom.max(1)) as i32;
+    }
+    let webp_data = encoder
+        .try_encode()
+        .map_err(|e| SvcError::InternalError(format!("animated WebP encode error: {:?}", e)))?;
+    Ok(webp_data.to_vec())
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp_animation(_frames: &[Frame], _quality: u8) -> Result<Vec<u8>, SvcError> {
+    Err(SvcError::BadRequest("output format not enabled in this build"))
+}
+
+
+/// Lossy WebP encoding with quality control
+#[cfg(feature = "webp")]
+fn encode_webp(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, SvcError> {
+    // `encode` panics on libwebp errors (e.g. sides over 16383px), `encode_simple` reports them
+    let webp_data = webp::Encoder::from_image(img)
+        .map_err(|e| SvcError::Io(std::io::Error::other(e)))?
+        .encode_simple(false, quality as f32)
+        .map_err(|e| SvcError::InternalError(format!("WebP encode error: {:?}", e)))?;
+    Ok(webp_data.to_vec())
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_img: &DynamicImage, _quality: u8) -> Result<Vec<u8>, SvcError> {
+    Err(SvcError::BadRequest("output format not enabled in this build"))
+}
+
+/// AVIF encoding via ravif with quality control
+///
+/// ravif splits images into as many tiles as it has threads, so `deterministic` encodes on
+/// one thread to get the same file on every machine.
+#[cfg(feature = "avif")]
+fn encode_avif(img: &DynamicImage, quality: u8, deterministic: bool) -> Result<Vec<u8>, SvcError> {
+    let rgba = img.to_rgba8();
+    let (w, h) = (rgba.width(), rgba.height());
+
+    // Convert to rgb::RGBA format
+    let pixels: Vec<rgb::RGBA<u8>> = rgba
+        .pixels()
+        .map(|p| rgb::RGBA {
+            r: p[0],
+            g: p[1],
+            b: p[2],
+            a: p[3],
+        })
+        .collect();
+
+    let avif_img = ravif::Img::new(&pixels[..], w as usize, h as usize);
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_speed(6)
+        .with_num_threads(deterministic.then_some(1));
+    let encoded = encoder
+        .encode_rgba(avif_img)
+        .map_err(|e| SvcError::Io(std::io::Error::other(format!("AVIF encode error: {}", e))))?;
+    Ok(encoded.avif_file)
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_img: &DynamicImage, _quality: u8, _deterministic: bool) -> Result<Vec<u8>, SvcError> {
+    Err(SvcError::BadRequest("output format not enabled in this build"))
+}
+
+/// JPEG XL encoding via libjxl
+#[cfg(feature = "jxl")]
+fn encode_jxl(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, SvcError> {
+    use jpegxl_rs::encode::{EncoderResult, EncoderSpeed};
+
+    let jxl_err = |e: jpegxl_rs::encode::EncodeError| SvcError::InternalError(format!("JXL encode error: {}", e));
+    let rgba = img.to_rgba8();
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .has_alpha(true)
+        .quality(jxl_distance(quality))
+        .speed(EncoderSpeed::Squirrel)
+        .build()
+        .map_err(jxl_err)?;
+    let encoded: EncoderResult<u8> = encoder
+        .encode::<u8, u8>(rgba.as_raw(), rgba.width(), rgba.height())
+        .map_err(jxl_err)?;
+    Ok(encoded.data)
+}
+
+#[cfg(not(feature = "jxl"))]
+fn encode_jxl(_img: &DynamicImage, _quality: u8) -> Result<Vec<u8>, SvcError> {
+    Err(SvcError::BadRequest("output format not enabled in this build"))
+}
+
+/// Map a 1-100 quality to libjxl's Butteraugli distance, the way `cjxl -q` does
+///
+/// 100 is lossless-grade (distance 0), 90 is about 1.0 ("visually lossless").
+#[cfg_attr(not(feature = "jxl"), allow(dead_code))]
+fn jxl_distance(quality: u8) -> f32 {
+    let q = quality as f32;
+    if q >= 100.0 {
+        0.0
+    } else if q >= 30.0 {
+        0.1 + (100.0 - q) * 0.09
+    } else {
+        53.0 / 3000.0 * q * q - 23.0 / 20.0 * q + 25.0
+    }
+}
+
+/// Encode an indexed PNG with at most `colors` palette entries (NeuQuant quantization)
+fn encode_png_quantized(img: &DynamicImage, colors: u16, exif: Option<Vec<u8>>) -> Result<Vec<u8>, SvcError> {
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+
+    let quant = color_quant::NeuQuant::new(10, colors as usize, rgba.as_raw());
+    let indices: Vec<u8> = rgba
+        .as_raw()
+        .chunks_exact(4)
+        .map(|p| quant.index_of(p) as u8)
+        .collect();
+
+    // Split the RGBA color map into PLTE (RGB) and tRNS (alpha) chunks
+    let color_map = quant.color_map_rgba();
+    let mut palette = Vec::with_capacity(color_map.len() / 4 * 3);
+    let mut trns = Vec::with_capacity(color_map.len() / 4);
+    for entry in color_map.chunks_exact(4) {
+        palette.extend_from_slice(&entry[..3]);
+        trns.push(entry[3]);
+    }
+
+    let png_err = |e: png::EncodingError| SvcError::Io(std::io::Error::other(format!("PNG encode error: {}", e)));
+
+    let mut out = Vec::new();
+    {
+        let mut enc = png::Encoder::new(&mut out, w, h);
+        enc.set_color(png::ColorType::Indexed);
+        enc.set_depth(png::BitDepth::Eight);
+        enc.set_palette(palette);
+        enc.set_trns(trns);
+        let mut writer = enc.write_header().map_err(png_err)?;
+        if let Some(exif) = exif {
+            writer.write_chunk(png::chunk::eXIf, &exif).map_err(png_err)?;
         }
+        writer.write_image_data(&indices).map_err(png_err)?;
+        writer.finish().map_err(png_err)?;
     }
     Ok(out)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directives of an `/insecure/<path>/plain/...` request with the built-in defaults
+    fn directives(path: &str) -> Directives {
+        let rest = format!("{}/plain/https%3A%2F%2Fexample.com%2Fa.jpg", path);
+        parse_rest(&rest, &DirectiveDefaults::default()).unwrap().0
+    }
+
+    fn solid(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(w, h, image::Rgba([255, 0, 0, 255])))
+    }
+
+    #[test]
+    fn test_parse_padding() {
+        assert_eq!(parse_padding("10").unwrap(), Padding { top: 10, right: 10, bottom: 10, left: 10 });
+        assert_eq!(parse_padding("1:2").unwrap(), Padding { top: 1, right: 2, bottom: 1, left: 2 });
+        assert_eq!(parse_padding("1:2:3").unwrap(), Padding { top: 1, right: 2, bottom: 3, left: 2 });
+        assert_eq!(parse_padding("1:2:3:4").unwrap(), Padding { top: 1, right: 2, bottom: 3, left: 4 });
+        assert!(parse_padding("1025").is_err());
+        assert!(parse_padding("1:2:3:4:5").is_err());
+        assert!(parse_padding("-1").is_err());
+        assert!(parse_padding("").is_err());
+    }
+
+    #[test]
+    fn test_padded_size_overflow() {
+        let rest = format!("rs:fit:{}:10/pd:1024/plain/https%3A%2F%2Fexample.com%2Fa.jpg", u32::MAX - 100);
+        assert!(parse_rest(&rest, &DirectiveDefaults::default()).is_err());
+        assert!(parse_rest("rs:fit:100:10/pd:1024/plain/a.jpg", &DirectiveDefaults::default()).is_ok());
+    }
+
+    #[test]
+    fn test_apply_canvas_extend_and_pad() {
+        let dirs = directives("rs:fit:30:20/ex:1:nowe/pd:1:2:3:4/bg:00ff00");
+        let img = apply_canvas(solid(10, 10), &dirs).unwrap();
+        assert_eq!(img.dimensions(), (30 + 2 + 4, 20 + 1 + 3));
+        let img = img.to_rgba8();
+        // The source sits in the top-left corner of the canvas, inside the padding
+        assert_eq!(img.get_pixel(4, 1).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(14, 1).0, [0, 255, 0, 255]);
+        assert_eq!(img.get_pixel(0, 0).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_apply_canvas_untouched() {
+        let dirs = directives("rs:fit:30:20");
+        let img = apply_canvas(solid(10, 10), &dirs).unwrap();
+        assert_eq!(img.dimensions(), (10, 10));
+        // A result as large as the canvas isn't extended
+        let dirs = directives("rs:fit:10:10/ex:1");
+        assert_eq!(apply_canvas(solid(10, 10), &dirs).unwrap().dimensions(), (10, 10));
+    }
+
+    #[test]
+    fn test_apply_canvas_refuses_huge_canvas() {
+        let dirs = directives("rs:fit:30000:30000/ex:1");
+        assert!(matches!(apply_canvas(solid(10, 10), &dirs), Err(SvcError::BadRequest(_))));
+        let mut dirs = directives("rs:fit:10:10/ex:1");
+        dirs.resize.w = u32::MAX;
+        dirs.padding = Some(Padding { left: 1024, ..Default::default() });
+        assert!(matches!(apply_canvas(solid(10, 10), &dirs), Err(SvcError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_apply_aspect_ratio() {
+        let dirs = directives("rs:fit:300:0/ar:3:2");
+        assert_eq!((dirs.resize.w, dirs.resize.h), (300, 200));
+        // fit is extended to the full canvas
+        assert!(dirs.extend.is_some());
+        let dirs = directives("rs:auto:0:100/ar:16:9");
+        assert_eq!((dirs.resize.w, dirs.resize.h), (178, 100));
+        assert!(matches!(dirs.resize.mode, ResizeMode::Fill));
+        // The computed side is at least one pixel
+        let dirs = directives("rs:fill:1:0/ar:100:1");
+        assert_eq!((dirs.resize.w, dirs.resize.h), (1, 1));
+    }
+
+    #[test]
+    fn test_apply_aspect_ratio_refuses_huge_sides() {
+        let mut resize = Resize { mode: ResizeMode::Fit, w: 0, h: 1_000_000 };
+        assert!(apply_aspect_ratio(&mut resize, &mut None, 100.0).is_err());
+        let mut resize = Resize { mode: ResizeMode::Fit, w: u32::MAX, h: 0 };
+        assert!(apply_aspect_ratio(&mut resize, &mut None, 0.01).is_err());
+        let mut resize = Resize { mode: ResizeMode::Fit, w: 655, h: 0 };
+        assert!(apply_aspect_ratio(&mut resize, &mut None, 0.01).is_ok());
+        assert_eq!(resize.h, 65_500);
+        let mut resize = Resize { mode: ResizeMode::Fit, w: 100, h: 100 };
+        assert!(apply_aspect_ratio(&mut resize, &mut None, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_exact_size() {
+        assert_eq!(directives("rs:fill:30:20/pd:1:2:3:4").exact_size(), Some((36, 24)));
+        assert_eq!(directives("rs:fit:30:0").exact_size(), None);
+        let mut dirs = directives("rs:fill:30:20/pd:1024");
+        dirs.resize.w = u32::MAX - 1024;
+        assert_eq!(dirs.exact_size(), None);
+    }
+
+    #[test]
+    fn test_parse_crop() {
+        let crop = parse_crop("400:300").unwrap();
+        assert_eq!((crop.w, crop.h, crop.offset), (400.0, 300.0, None));
+        let crop = parse_crop("0.5::10:20").unwrap();
+        assert_eq!((crop.w, crop.h, crop.offset), (0.5, 0.0, Some((10, 20))));
+        assert!(parse_crop("400").is_err());
+        assert!(parse_crop("400:300:10").is_err());
+        assert!(parse_crop("-1:300").is_err());
+        assert!(parse_crop("NaN:300").is_err());
+        assert!(parse_crop("1e40:300").is_err());
+        assert!(parse_crop("400:300:-1:0").is_err());
+        assert!(parse_crop("400:300:4294967296:0").is_err());
+        let dirs = directives("rs:fit:100:0/c:100:50/g:sm");
+        assert!(dirs.crop.is_some() && matches!(dirs.gravity, Gravity::Smart));
+    }
+
+    #[test]
+    fn test_apply_crop() {
+        let crop = |w, h, offset| Crop { w, h, offset };
+        assert_eq!(apply_crop(solid(100, 50), &crop(40.0, 20.0, None), Gravity::Center).dimensions(), (40, 20));
+        // Fractions of the source, and 0 keeps the full side
+        assert_eq!(apply_crop(solid(100, 50), &crop(0.5, 0.0, None), Gravity::Center).dimensions(), (50, 50));
+        // Sizes past the source are clamped to it
+        let img = apply_crop(solid(100, 50), &crop(500.0, 1e30, None), Gravity::Center);
+        assert_eq!(img.dimensions(), (100, 50));
+
+        // Offsets past the edges keep the window inside the image
+        let mut src = image::RgbaImage::from_pixel(100, 50, image::Rgba([255, 0, 0, 255]));
+        src.put_pixel(99, 49, image::Rgba([0, 0, 255, 255]));
+        let img = apply_crop(DynamicImage::ImageRgba8(src), &crop(20.0, 20.0, Some((90, 45))), Gravity::Center);
+        assert_eq!(img.dimensions(), (20, 20));
+        assert_eq!(img.to_rgba8().get_pixel(19, 19).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_gravity() {
+        assert!(matches!(Gravity::from_name("NOEA").unwrap(), Gravity::NorthEast));
+        assert!(matches!(Gravity::from_name("sm").unwrap(), Gravity::Smart));
+        assert!(Gravity::from_name("up").is_err());
+        assert_eq!(Gravity::Center.anchor(10, 20), (5, 10));
+        assert_eq!(Gravity::NorthEast.anchor(10, 20), (10, 0));
+        assert_eq!(Gravity::SouthWest.anchor(10, 20), (0, 20));
+        assert_eq!(Gravity::Smart.anchor(10, 20), (5, 10));
+    }
+
+    #[test]
+    fn test_smart_gravity() {
+        // Flat gray except for a checkerboard of 4px squares at x 70-89
+        let src = image::RgbImage::from_fn(100, 20, |x, y| {
+            let v = if (70..90).contains(&x) && (x / 4 + y / 4) % 2 == 0 { 255 } else { 128 };
+            image::Rgb([v, v, v])
+        });
+        let src = DynamicImage::ImageRgb8(src);
+        let (x, y) = Gravity::Smart.offset(&src, 20, 20);
+        assert!((65..=75).contains(&x), "x = {}", x);
+        assert_eq!(y, 0);
+        // Nothing to aim for: centered
+        assert_eq!(Gravity::Smart.offset(&solid(100, 20), 20, 20), (40, 0));
+    }
+
+    #[test]
+    fn test_focus_point() {
+        let point = Gravity::from_name("fp:0.25:1").unwrap();
+        assert!(matches!(point, Gravity::FocusPoint(x, y) if x == 0.25 && y == 1.0));
+        assert!(Gravity::from_name("fp:1.5:0.5").is_err());
+        assert!(Gravity::from_name("fp:-0.1:0.5").is_err());
+        assert!(Gravity::from_name("fp:NaN:0.5").is_err());
+        assert!(Gravity::from_name("fp:0.5").is_err());
+        assert!(parse_rest("g:fp:0.5:2/plain/a.jpg", &DirectiveDefaults::default()).is_err());
+
+        let img = solid(100, 100);
+        assert_eq!(Gravity::FocusPoint(0.5, 0.5).offset(&img, 20, 20), (40, 40));
+        // Windows centered near an edge stay inside the image
+        assert_eq!(Gravity::FocusPoint(1.0, 0.0).offset(&img, 20, 20), (80, 0));
+        assert_eq!(Gravity::FocusPoint(0.25, 1.0).anchor(100, 40), (25, 40));
+    }
+
+    #[test]
+    fn test_saturation() {
+        assert_eq!(parse_saturation("0").unwrap(), 0);
+        assert_eq!(parse_saturation("200").unwrap(), 200);
+        assert!(parse_saturation("201").is_err());
+        assert!(parse_saturation("-1").is_err());
+        assert_eq!(directives("rs:fit:100:0/grayscale").saturation, Some(0));
+
+        let gray = apply_saturation(solid(2, 2), 0);
+        assert!(gray.color().has_alpha());
+        assert_eq!(gray.to_rgba8().get_pixel(0, 0).0, [76, 76, 76, 255]);
+        let vivid = apply_saturation(solid(2, 2), 200);
+        assert_eq!(vivid.to_rgba8().get_pixel(0, 0).0, [255, 0, 0, 255]);
+        let luma = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(2, 2, image::Luma([90])));
+        assert!(matches!(apply_saturation(luma, 0), DynamicImage::ImageLuma8(_)));
+    }
+
+    #[test]
+    fn test_rotation() {
+        assert_eq!(parse_rotation("270").unwrap(), 270);
+        assert!(parse_rotation("45").is_err());
+        assert!(parse_rotation("360").is_err());
+        assert!(parse_rotation("-90").is_err());
+
+        let mut src = image::RgbaImage::from_pixel(2, 1, image::Rgba([0, 0, 255, 255]));
+        src.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        // Clockwise: the left end of the top row ends up on top
+        let img = apply_rotation(DynamicImage::ImageRgba8(src), 90).to_rgba8();
+        assert_eq!(img.dimensions(), (1, 2));
+        assert_eq!(img.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(apply_rotation(solid(10, 20), 180).dimensions(), (10, 20));
+    }
+
+    #[test]
+    fn test_parse_background() {
+        assert_eq!(parse_background("ff8000").unwrap(), [255, 128, 0]);
+        assert_eq!(parse_background("#00FF00").unwrap(), [0, 255, 0]);
+        assert!(parse_background("fff").is_err());
+        assert!(parse_background("gggggg").is_err());
+        assert!(parse_background("ff80001").is_err());
+    }
+
+    #[test]
+    fn test_parse_colors() {
+        assert_eq!(parse_colors("2").unwrap(), 2);
+        assert_eq!(parse_colors("256").unwrap(), 256);
+        assert!(parse_colors("1").is_err());
+        assert!(parse_colors("257").is_err());
+        assert!(parse_colors("").is_err());
+        assert_eq!(directives("rs:fit:100:0/colors:16").colors, Some(16));
+    }
+
+    #[test]
+    fn test_directive_defaults_cache_tag() {
+        assert_eq!(DirectiveDefaults::default().cache_tag(), None);
+        let quality = DirectiveDefaults { quality: 90, ..Default::default() };
+        let progressive = DirectiveDefaults { progressive: true, ..Default::default() };
+        assert!(quality.cache_tag().is_some());
+        assert_ne!(quality.cache_tag(), progressive.cache_tag());
+    }
+}
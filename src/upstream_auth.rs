@@ -0,0 +1,217 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::OnceLock};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use http::HeaderValue;
+use nostr_sdk::{EventBuilder, JsonUtil, Keys, Kind, Tag, TagKind, Timestamp};
+use reqwest::{header, RequestBuilder, Url};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Blossom authorization event kind (BUD-01)
+const BLOSSOM_AUTH_KIND: u16 = 24242;
+/// How long a signature stays valid; leaves room for clock skew between us and the mirror
+const SIGNATURE_TTL_SECS: u64 = 300;
+
+/// Credentials set at startup; unset means no request is signed
+static AUTH: OnceLock<UpstreamAuth> = OnceLock::new();
+
+/// How requests to one host are signed
+#[derive(Clone)]
+enum Signer {
+    /// `Authorization: HMAC <timestamp>:<hex HMAC-SHA256 of "<timestamp>\n<path>">`
+    Hmac(Vec<u8>),
+    /// `Authorization: Nostr <base64 event>`, a kind 24242 `get` authorization (BUD-01)
+    Nostr(Keys),
+}
+
+/// Request signing for private mirrors, from `UPSTREAM_AUTH`
+/// (`blobs.internal=hmac:<hex key>,mirror.example.com=nostr:<nsec or hex key>`)
+///
+/// Hosts are matched exactly, without port; hosts without credentials get unsigned requests.
+#[derive(Clone, Default)]
+pub struct UpstreamAuth {
+    hosts: HashMap<String, Signer>,
+}
+
+impl fmt::Debug for UpstreamAuth {
+    /// Hosts only; the keys stay out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.hosts.keys()).finish()
+    }
+}
+
+impl UpstreamAuth {
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// `Authorization` value for a read of `url`, when its host has credentials
+    fn authorization(&self, url: &str) -> Option<HeaderValue> {
+        let url = Url::parse(url).ok()?;
+        let signer = self.hosts.get(&url.host_str()?.to_ascii_lowercase())?;
+        let now = Timestamp::now().as_u64();
+        let value = match signer {
+            Signer::Hmac(key) => {
+                let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(format!("{}\n{}", now, url.path()).as_bytes());
+                format!("HMAC {}:{}", now, hex::encode(mac.finalize().into_bytes()))
+            }
+            Signer::Nostr(keys) => {
+                // Without an `x` tag the event would authorize reads of any blob; such URLs go unsigned
+                let Some(hash) = blob_hash(url.path()) else {
+                    tracing::debug!("not signing {}: the path names no blob", url);
+                    return None;
+                };
+                let tags = [
+                    Tag::hashtag("get"),
+                    Tag::expiration(Timestamp::from(now + SIGNATURE_TTL_SECS)),
+                    Tag::custom(TagKind::custom("x"), [hash]),
+                ];
+                let event = EventBuilder::new(Kind::Custom(BLOSSOM_AUTH_KIND), "Get blob")
+                    .tags(tags)
+                    .sign_with_keys(keys)
+                    .inspect_err(|e| tracing::warn!("signing authorization for {} failed: {}", url, e))
+                    .ok()?;
+                format!("Nostr {}", STANDARD.encode(event.as_json()))
+            }
+        };
+        let mut value = HeaderValue::from_str(&value).ok()?;
+        value.set_sensitive(true);
+        Some(value)
+    }
+}
+
+impl FromStr for UpstreamAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut auth = UpstreamAuth::default();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (host, credential) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("{}: must look like host=hmac:<key> or host=nostr:<key>", host_of(rule)))?;
+            let host = host.trim().to_ascii_lowercase();
+            if host.is_empty() || host.contains(['/', ':']) {
+                return Err(format!("{}: expected a bare host name", host));
+            }
+            let signer = match credential.trim().split_once(':') {
+                Some(("hmac", key)) => match hex::decode(key.trim()) {
+                    Ok(key) if !key.is_empty() => Signer::Hmac(key),
+                    _ => return Err(format!("{}: hmac key must be non-empty hex", host)),
+                },
+                Some(("nostr", key)) => Keys::parse(key.trim())
+                    .map(Signer::Nostr)
+                    .map_err(|_| format!("{}: nostr key must be an nsec or hex secret key", host))?,
+                _ => return Err(format!("{}: scheme must be hmac or nostr", host)),
+            };
+            if auth.hosts.insert(host.clone(), signer).is_some() {
+                return Err(format!("{}: host has credentials twice", host));
+            }
+        }
+        Ok(auth)
+    }
+}
+
+/// The part of a rule that names its host, so errors never echo a key
+fn host_of(rule: &str) -> &str {
+    rule.split(['=', ':']).next().unwrap_or_default().trim()
+}
+
+/// The SHA-256 in a Blossom path (`/<sha256>.<ext>` or `/<sha256>`)
+fn blob_hash(path: &str) -> Option<&str> {
+    let name = path.rsplit('/').next()?;
+    let hash = name.split_once('.').map_or(name, |(hash, _)| hash);
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some(hash)
+}
+
+/// Install `UPSTREAM_AUTH`; call once at startup
+pub fn init(auth: UpstreamAuth) {
+    if !auth.is_empty() {
+        tracing::info!("signing requests to {:?}", auth);
+        let _ = AUTH.set(auth);
+    }
+}
+
+/// `request` with the `Authorization` header for `url`'s host, if it has credentials
+pub fn sign(request: RequestBuilder, url: &str) -> RequestBuilder {
+    match AUTH.get().and_then(|auth| auth.authorization(url)) {
+        Some(value) => request.header(header::AUTHORIZATION, value),
+        None => request,
+    }
+}
+
+/// Whether `url`'s host has credentials, so reads of it must go through `sign`
+pub fn signs(url: &str) -> bool {
+    let Some(auth) = AUTH.get() else {
+        return false;
+    };
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        .is_some_and(|host| auth.hosts.contains_key(&host))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::Event;
+
+    use super::*;
+
+    const HASH: &str = "b1674191a88ec5cdd733e4240a81803105dc412d6c6708d53ab94fc248f4f553";
+
+    #[test]
+    fn test_parse() {
+        let key = Keys::generate().secret_key().to_secret_hex();
+        let auth: UpstreamAuth = format!("Blobs.Internal=hmac:00ff, mirror.example.com=nostr:{}", key)
+            .parse()
+            .unwrap();
+        assert_eq!(auth.hosts.len(), 2);
+        assert!(matches!(auth.hosts["blobs.internal"], Signer::Hmac(_)));
+        assert!(!format!("{:?}", auth).contains(&key));
+
+        assert!("blobs.internal".parse::<UpstreamAuth>().is_err());
+        assert!("blobs.internal=basic:abc".parse::<UpstreamAuth>().is_err());
+        assert!("blobs.internal=hmac:zz".parse::<UpstreamAuth>().is_err());
+        assert!("https://blobs.internal=hmac:00".parse::<UpstreamAuth>().is_err());
+        assert!("a.example=hmac:00,a.example=hmac:01".parse::<UpstreamAuth>().is_err());
+    }
+
+    #[test]
+    fn test_hmac_authorization() {
+        let auth: UpstreamAuth = "blobs.internal=hmac:00ff".parse().unwrap();
+        let url = format!("https://blobs.internal/{}.jpg", HASH);
+        let value = auth.authorization(&url).unwrap();
+        let (timestamp, signature) = value.to_str().unwrap().strip_prefix("HMAC ").unwrap().split_once(':').unwrap();
+
+        let mut mac = HmacSha256::new_from_slice(&[0x00, 0xff]).unwrap();
+        mac.update(format!("{}\n/{}.jpg", timestamp, HASH).as_bytes());
+        assert_eq!(signature, hex::encode(mac.finalize().into_bytes()));
+        assert!(auth.authorization("https://other.example/a.jpg").is_none());
+    }
+
+    #[test]
+    fn test_nostr_authorization() {
+        let keys = Keys::generate();
+        let auth: UpstreamAuth = format!("mirror.example.com=nostr:{}", keys.secret_key().to_secret_hex())
+            .parse()
+            .unwrap();
+        let value = auth.authorization(&format!("https://mirror.example.com/{}.mp4", HASH)).unwrap();
+        let encoded = value.to_str().unwrap().strip_prefix("Nostr ").unwrap();
+        let event = Event::from_json(STANDARD.decode(encoded).unwrap()).unwrap();
+        assert!(event.verify().is_ok());
+        assert_eq!(event.pubkey, keys.public_key());
+        assert_eq!(event.kind, Kind::Custom(BLOSSOM_AUTH_KIND));
+        assert!(event.tags.iter().any(|t| t.as_slice() == ["x", HASH]));
+        // An event without `x` would open every blob to whoever holds it
+        assert!(auth.authorization("https://mirror.example.com/avatar.jpg").is_none());
+    }
+
+    #[test]
+    fn test_blob_hash() {
+        assert_eq!(blob_hash(&format!("/{}.jpg", HASH)), Some(HASH));
+        assert_eq!(blob_hash(&format!("/media/{}", HASH)), Some(HASH));
+        assert_eq!(blob_hash("/avatar.jpg"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::HashMap, str::FromStr, sync::OnceLock};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+/// Policy set at startup; unset means upstream statuses pass through unchanged
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+struct Policy {
+    map: UpstreamStatusMap,
+    retry_after_secs: u64,
+}
+
+/// Statuses answered in place of upstream ones, from `UPSTREAM_STATUS_MAP` (`429:503,401:404,5xx:502`)
+///
+/// An exact code wins over its class; codes matching no rule pass through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamStatusMap {
+    exact: HashMap<u16, u16>,
+    /// Keyed by the hundreds digit, so `5xx` is 5
+    class: HashMap<u16, u16>,
+}
+
+impl UpstreamStatusMap {
+    /// Status to answer with when the upstream answered `code`
+    pub fn map(&self, code: u16) -> u16 {
+        self.exact
+            .get(&code)
+            .or_else(|| self.class.get(&(code / 100)))
+            .copied()
+            .unwrap_or(code)
+    }
+}
+
+impl FromStr for UpstreamStatusMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = UpstreamStatusMap::default();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (from, to) = rule
+                .split_once(':')
+                .ok_or_else(|| format!("{} must look like 429:503", rule))?;
+            let to = to
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|to| (400..600).contains(to))
+                .ok_or_else(|| format!("{}: status must be 400-599", rule))?;
+            let from = from.trim().to_ascii_lowercase();
+            let target = match from.strip_suffix("xx") {
+                Some("4") => map.class.insert(4, to),
+                Some("5") => map.class.insert(5, to),
+                _ => match from.parse::<u16>().ok().filter(|from| (400..600).contains(from)) {
+                    Some(from) => map.exact.insert(from, to),
+                    None => return Err(format!("{}: upstream status must be 400-599, 4xx or 5xx", rule)),
+                },
+            };
+            if target.is_some() {
+                return Err(format!("{}: {} is mapped twice", rule, from));
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Install `UPSTREAM_STATUS_MAP`; call once at startup
+///
+/// `retry_after_secs` is sent with mapped `429` and `503` answers, so clients back off the same
+/// way as when this server is saturated.
+pub fn init(map: UpstreamStatusMap, retry_after_secs: u64) {
+    let _ = POLICY.set(Policy { map, retry_after_secs });
+}
+
+/// Client response for a failed upstream fetch
+pub fn upstream_response(code: u16) -> Response {
+    let (mapped, retry_after_secs) = match POLICY.get() {
+        Some(policy) => (policy.map.map(code), policy.retry_after_secs),
+        None => (code, 0),
+    };
+    let status = StatusCode::from_u16(mapped).unwrap_or(StatusCode::BAD_GATEWAY);
+    let message = match mapped {
+        404 => "Source image not found".to_string(),
+        403 => "Source image forbidden".to_string(),
+        429 | 503 if mapped != code => "Source temporarily unavailable, retry later".to_string(),
+        _ => format!("Upstream server returned status {}", code),
+    };
+    if mapped != code && matches!(mapped, 429 | 503) {
+        return (status, [(header::RETRY_AFTER, retry_after_secs.to_string())], message).into_response();
+    }
+    (status, message).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_map() {
+        let map: UpstreamStatusMap = "429:503, 401:404,403:404,5xx:502,4XX:404".parse().unwrap();
+        assert_eq!(map.map(429), 503);
+        assert_eq!(map.map(401), 404);
+        assert_eq!(map.map(410), 404);
+        assert_eq!(map.map(504), 502);
+        assert_eq!(UpstreamStatusMap::default().map(418), 418);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for bad in ["429", "429:200", "200:404", "6xx:502", "429:503,429:502", "abc:404"] {
+            assert!(bad.parse::<UpstreamStatusMap>().is_err(), "{}", bad);
+        }
+        assert_eq!("".parse::<UpstreamStatusMap>().unwrap(), UpstreamStatusMap::default());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{path::PathBuf, time::Duration};
+
+use moka::sync::Cache;
+
+use crate::transform::{Directives, OutFmt};
+
+/// Upper bound on remembered request families
+const MAX_FAMILIES: u64 = 100_000;
+/// Variants remembered per family; the oldest is forgotten first
+const MAX_VARIANTS_PER_FAMILY: usize = 16;
+
+/// A processed variant in the cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// Requested width and height (0 = derived from the other)
+    pub w: u32,
+    pub h: u32,
+    pub quality: u8,
+    pub path: PathBuf,
+}
+
+/// Cached variants of each request family, so a near match can be served instead of a new one
+///
+/// A family is a request with its size and quality left out; everything else (source, mode,
+/// format, crop, effects, server hints) must match exactly. Entries expire with the processed
+/// cache; a remembered variant whose file is gone is simply not served.
+#[derive(Clone)]
+pub struct VariantIndex {
+    families: Cache<String, Vec<Variant>>,
+    /// Largest relative size difference, e.g. 0.02 for 318px vs 320px
+    size_tolerance: f32,
+    /// Largest quality difference, in quality points
+    quality_tolerance: u8,
+}
+
+impl VariantIndex {
+    pub fn new(size_tolerance: f32, quality_tolerance: u8, ttl: Duration) -> Self {
+        let families = Cache::builder().max_capacity(MAX_FAMILIES).time_to_live(ttl).build();
+        Self {
+            families,
+            size_tolerance,
+            quality_tolerance,
+        }
+    }
+
+    /// Remember the variant cached at `path` for a request
+    pub fn record(&self, family: &str, dirs: &Directives, path: PathBuf) {
+        let variant = Variant {
+            w: dirs.resize.w,
+            h: dirs.resize.h,
+            quality: dirs.quality,
+            path,
+        };
+        let mut variants = self.families.get(family).unwrap_or_default();
+        if variants.contains(&variant) {
+            return;
+        }
+        variants.retain(|v| v.path != variant.path);
+        if variants.len() >= MAX_VARIANTS_PER_FAMILY {
+            variants.remove(0);
+        }
+        variants.push(variant);
+        self.families.insert(family.to_string(), variants);
+    }
+
+    /// Cached variants close enough to a request, closest first
+    pub fn similar(&self, family: &str, dirs: &Directives) -> Vec<Variant> {
+        let Some(variants) = self.families.get(family) else {
+            return Vec::new();
+        };
+        let mut similar: Vec<(f32, Variant)> = variants
+            .into_iter()
+            .filter(|v| v.quality.abs_diff(dirs.quality) <= self.quality_tolerance)
+            .filter_map(|v| {
+                let distance = size_distance((dirs.resize.w, dirs.resize.h), (v.w, v.h))?;
+                (distance <= self.size_tolerance).then_some((distance, v))
+            })
+            .collect();
+        // Closest size first; on a tie the larger variant, which the client scales down
+        similar.sort_by(|a, b| a.0.total_cmp(&b.0).then((b.1.w + b.1.h).cmp(&(a.1.w + a.1.h))));
+        similar.into_iter().map(|(_, v)| v).collect()
+    }
+}
+
+/// Family of a request from its processed cache key, or None for requests never reused
+///
+/// `q:` and the dimensions of `rs:`/`rt:` are left out; the resize mode and the negotiated
+/// format are added, since defaults and `Accept` decide them outside the path.
+pub fn family_key(namespace: &str, cache_key: &str, dirs: &Directives) -> Option<String> {
+    // Raw pixels and metadata are consumed as is, not scaled by the client
+    if matches!(dirs.out_fmt, OutFmt::Raw | OutFmt::Json) {
+        return None;
+    }
+    let (before_plain, after_plain) = cache_key.split_once("/plain/")?;
+    let segments: Vec<&str> = before_plain
+        .split('/')
+        .filter(|seg| !(seg.starts_with("q:") || seg.starts_with("rs:") || seg.starts_with("rt:")))
+        .collect();
+    Some(format!(
+        "{}|{:?}|{}|{}/plain/{}",
+        namespace,
+        dirs.resize.mode,
+        dirs.out_fmt.name(),
+        segments.join("/"),
+        after_plain
+    ))
+}
+
+/// Largest relative difference between the requested and a cached size, per side
+///
+/// None when one side is derived (0) in one and fixed in the other, since the results can
+/// differ in shape.
+fn size_distance(requested: (u32, u32), cached: (u32, u32)) -> Option<f32> {
+    let side = |r: u32, c: u32| match (r, c) {
+        (0, 0) => Some(0.0),
+        (0, _) | (_, 0) => None,
+        (r, c) => Some(r.abs_diff(c) as f32 / r as f32),
+    };
+    Some(side(requested.0, cached.0)?.max(side(requested.1, cached.1)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::{parse_rest, DirectiveDefaults};
+
+    fn dirs(rest: &str) -> Directives {
+        parse_rest(rest, &DirectiveDefaults::default()).unwrap().0
+    }
+
+    #[test]
+    fn test_family_key() {
+        let a = dirs("rs:fill:320:320/q:80/plain/https://example.com/a.jpg");
+        let b = dirs("q:75/rs:fill:318:318/plain/https://example.com/a.jpg");
+        let key_a = family_key("", "/insecure/rs:fill:320:320/q:80/plain/https://example.com/a.jpg", &a);
+        let key_b = family_key("", "/insecure/q:75/rs:fill:318:318/plain/https://example.com/a.jpg", &b);
+        assert!(key_a.is_some());
+        assert_eq!(key_a, key_b);
+
+        let fit = dirs("rs:fit:320:320/plain/https://example.com/a.jpg");
+        let key_fit = family_key("", "/insecure/rs:fit:320:320/plain/https://example.com/a.jpg", &fit);
+        assert_ne!(key_a, key_fit);
+        let key_tenant = family_key("t1", "/insecure/rs:fill:320:320/q:80/plain/https://example.com/a.jpg", &a);
+        assert_ne!(key_a, key_tenant);
+    }
+
+    #[test]
+    fn test_size_distance() {
+        assert_eq!(size_distance((320, 0), (320, 0)), Some(0.0));
+        assert_eq!(size_distance((320, 0), (320, 240)), None);
+        assert!((size_distance((320, 320), (318, 320)).unwrap() - 0.00625).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_similar() {
+        let index = VariantIndex::new(0.02, 5, Duration::from_secs(60));
+        let family = "f";
+        index.record(family, &dirs("rs:fill:320:320/q:80/plain/x"), PathBuf::from("a"));
+        index.record(family, &dirs("rs:fill:400:400/q:80/plain/x"), PathBuf::from("b"));
+        index.record(family, &dirs("rs:fill:318:318/q:60/plain/x"), PathBuf::from("c"));
+
+        let found = index.similar(family, &dirs("rs:fill:318:318/q:78/plain/x"));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, PathBuf::from("a"));
+        assert!(index.similar(family, &dirs("rs:fill:360:360/q:80/plain/x")).is_empty());
+        assert!(index.similar("other", &dirs("rs:fill:320:320/q:80/plain/x")).is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use reqwest::{header, StatusCode};
+use tempfile::NamedTempFile;
+
+use crate::{
+    config::AppState,
+    error::{SvcError, VideoError},
+    hls::{self, HlsCopy},
+    metrics,
+    thumbnail::{sniff_video, SNIFF_LEN},
+    upstream_auth,
+};
+
+/// Smallest `VIDEO_PARTIAL_FETCH_BYTES`; less rarely holds even the first keyframe
+pub const MIN_PARTIAL_FETCH_BYTES: u64 = 64 * 1024;
+/// Largest `moov` box fetched on its own; bigger indexes go back to ffmpeg reading the URL
+const MAX_MOOV_BYTES: u64 = 32 * 1024 * 1024;
+/// Box headers fetched one by one past the head before giving up on finding `moov`
+const MAX_HEADER_FETCHES: usize = 8;
+/// Longest box header: size, type and a 64-bit size
+const MAX_BOX_HEADER: u64 = 16;
+
+/// Where a box sits in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BoxSpan {
+    offset: u64,
+    size: u64,
+}
+
+/// Outcome of walking the box headers in the bytes at hand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoovSearch {
+    Found(BoxSpan),
+    /// The next box header starts at this offset, past the bytes at hand
+    Beyond(u64),
+}
+
+/// Copy of an MP4 holding only what a frame near its start needs, or None to read the URL
+///
+/// Fetches the first `limit` bytes with a range request and, when the `moov` index comes after
+/// them (no faststart), the box headers past them one by one and then that box. Both land at their original offsets in a
+/// sparse file of the video's full length, so sample offsets in the index stay valid. Frames
+/// whose data lies beyond the first `limit` bytes read as zeros there; callers make ffmpeg fail
+/// on damaged packets and fall back to the URL then.
+pub async fn fetch_partial(app: &AppState, url: &str, limit: u64) -> Option<NamedTempFile> {
+    let (head, total) = fetch_range(app, url, 0, limit).await?;
+    // Only MP4s are copied; anything else (a playlist, say) must not become a local input
+    let mut search = locate_moov(&head, 0, total)?;
+    let mut header_fetches = 0;
+    let moov = loop {
+        match search {
+            MoovSearch::Found(moov) => break moov,
+            MoovSearch::Beyond(offset) => {
+                header_fetches += 1;
+                if header_fetches > MAX_HEADER_FETCHES {
+                    tracing::debug!("no moov within {} boxes of {}, reading the URL instead", header_fetches, url);
+                    return None;
+                }
+                let (header, _) = fetch_range(app, url, offset, MAX_BOX_HEADER.min(total - offset)).await?;
+                search = locate_moov(&header, offset, total)?;
+            }
+        }
+    };
+    let fetched = head.len() as u64;
+    let mut chunks = vec![(0, head)];
+    if moov.offset + moov.size > fetched {
+        if moov.size > MAX_MOOV_BYTES {
+            tracing::debug!("moov of {} is {} bytes, reading the URL instead", url, moov.size);
+            return None;
+        }
+        let start = moov.offset.max(fetched);
+        let (tail, _) = fetch_range(app, url, start, moov.offset + moov.size - start).await?;
+        chunks.push((start, tail));
+    }
+
+    let downloaded: usize = chunks.iter().map(|(_, bytes)| bytes.len()).sum();
+    metrics::record_bytes_downloaded(app.cfg.metrics_label(), "video_range", downloaded);
+    tracing::debug!("fetched {} of {} bytes of {} by range", downloaded, total, url);
+
+    let write = || -> std::io::Result<NamedTempFile> {
+        let mut file = NamedTempFile::new()?;
+        file.as_file().set_len(total)?;
+        for (offset, bytes) in &chunks {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(bytes)?;
+        }
+        file.flush()?;
+        Ok(file)
+    };
+    write()
+        .inspect_err(|e| tracing::debug!("writing partial copy of {} failed: {}", url, e))
+        .ok()
+}
+
+/// Video downloaded for ffmpeg to read instead of its URL; the files go away on drop
+pub enum LocalCopy {
+    File(NamedTempFile),
+    Hls(HlsCopy),
+}
+
+impl LocalCopy {
+    /// What ffmpeg opens: the file, or the rewritten playlist
+    pub fn path(&self) -> &Path {
+        match self {
+            LocalCopy::File(file) => file.path(),
+            LocalCopy::Hls(copy) => copy.playlist(),
+        }
+    }
+}
+
+/// Whole copy of a video for ffmpeg to read instead of its URL
+///
+/// Fetched with the app client, so requests are signed and redirects obey the source host
+/// lists, within `FFMPEG_TIMEOUT_SECS` and at most `MAX_VIDEO_BYTES`. HLS playlists are copied
+/// with their segments (`hls::copy`); otherwise only recognized video containers are kept, since
+/// anything else read as a local file could point ffmpeg at anything on disk.
+pub async fn fetch_whole(app: &AppState, url: &str) -> Result<LocalCopy, SvcError> {
+    tokio::time::timeout(app.cfg.ffmpeg_timeout, copy_source(app, url))
+        .await
+        .map_err(|_| {
+            tracing::debug!("copying {} took over {:?}", url, app.cfg.ffmpeg_timeout);
+            SvcError::from(VideoError::Timeout)
+        })?
+}
+
+/// `fetch_whole` without its deadline
+async fn copy_source(app: &AppState, url: &str) -> Result<LocalCopy, SvcError> {
+    let mut resp = upstream_auth::sign(app.http.get(url), url).timeout(app.cfg.ffmpeg_timeout).send().await?;
+    metrics::record_upstream_response(resp.version());
+    if !resp.status().is_success() {
+        return Err(SvcError::UpstreamError(resp.status().as_u16()));
+    }
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    while head.len() < SNIFF_LEN {
+        match resp.chunk().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    if head.starts_with(b"#EXTM3U") {
+        let base = resp.url().clone();
+        while let Some(chunk) = resp.chunk().await? {
+            head.extend_from_slice(&chunk);
+            if head.len() > hls::MAX_PLAYLIST_BYTES {
+                return Err(SvcError::Unprocessable("playlist too large"));
+            }
+        }
+        let playlist = String::from_utf8(head).map_err(|_| SvcError::Unprocessable("playlist is not UTF-8"))?;
+        return hls::copy(app, &base, &playlist).await.map(LocalCopy::Hls);
+    }
+    if !sniff_video(&head) {
+        tracing::debug!("{} is not a video container, not reading it as a local file", url);
+        return Err(SvcError::UnsupportedMedia("source is not a video container"));
+    }
+
+    let mut file = NamedTempFile::new()?;
+    let mut downloaded = 0u64;
+    let mut chunk: Option<bytes::Bytes> = Some(head.into());
+    while let Some(bytes) = chunk {
+        downloaded += bytes.len() as u64;
+        if app.cfg.max_video_bytes > 0 && downloaded > app.cfg.max_video_bytes {
+            tracing::info!("refusing video {}: more than {} bytes", url, app.cfg.max_video_bytes);
+            metrics::record_processing_error("video_too_large");
+            return Err(VideoError::TooLarge("video too large").into());
+        }
+        file.write_all(&bytes)?;
+        chunk = resp.chunk().await?;
+    }
+    file.flush()?;
+    metrics::record_bytes_downloaded(app.cfg.metrics_label(), "video_copy", downloaded as usize);
+    tracing::debug!("copied {} bytes of {}", downloaded, url);
+    Ok(LocalCopy::File(file))
+}
+
+/// `len` bytes from `start` and the full length of the file, if the server honors the range
+async fn fetch_range(app: &AppState, url: &str, start: u64, len: u64) -> Option<(Vec<u8>, u64)> {
+    let range = format!("bytes={}-{}", start, start + len - 1);
+    let request = app.http.get(url).header(header::RANGE, range);
+    let mut resp = upstream_auth::sign(request, url).send().await.ok()?;
+    metrics::record_upstream_response(resp.version());
+    // A 200 would be the whole video; leave that to ffmpeg
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        tracing::debug!("range request to {} answered {}", url, resp.status());
+        return None;
+    }
+    let total = resp
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(content_range_total)?;
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > len {
+            return None;
+        }
+    }
+    Some((body, total))
+}
+
+/// Full length from `Content-Range: bytes 0-99/1234`; None when the server doesn't know it
+fn content_range_total(value: &str) -> Option<u64> {
+    let (_, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    total.trim().parse().ok()
+}
+
+/// The top-level `moov` box, found by walking box headers of an MP4
+///
+/// `bytes` start at file offset `start`, where a box header begins (0 for the head of the file).
+/// The walk goes on until `moov` or a header past `bytes`, whose offset is returned to fetch it.
+/// None when the file doesn't start with `ftyp`, a header is malformed, or there is no `moov`.
+fn locate_moov(bytes: &[u8], start: u64, total: u64) -> Option<MoovSearch> {
+    let mut offset = start;
+    while offset < total {
+        let at = usize::try_from(offset - start).ok()?;
+        let Some(header) = bytes.get(at..at + 8) else {
+            return Some(MoovSearch::Beyond(offset));
+        };
+        let kind = &header[4..8];
+        if offset == 0 && kind != b"ftyp" {
+            return None;
+        }
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().ok()?) {
+            // 64-bit size follows the type
+            1 => match bytes.get(at + 8..at + 16) {
+                Some(large) => (u64::from_be_bytes(large.try_into().ok()?), 16),
+                None => return Some(MoovSearch::Beyond(offset)),
+            },
+            // Box runs to the end of the file
+            0 => (total - offset, 8),
+            size => (size as u64, 8),
+        };
+        if size < header_len || offset + size > total {
+            return None;
+        }
+        if kind == b"moov" {
+            return Some(MoovSearch::Found(BoxSpan { offset, size }));
+        }
+        offset += size;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], payload_len: usize) -> Vec<u8> {
+        let mut b = ((payload_len + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.resize(payload_len + 8, 0);
+        b
+    }
+
+    #[test]
+    fn test_content_range_total() {
+        assert_eq!(content_range_total("bytes 0-99/1234"), Some(1234));
+        assert_eq!(content_range_total("bytes 0-99/*"), None);
+        assert_eq!(content_range_total("items 0-99/1234"), None);
+    }
+
+    #[test]
+    fn test_locate_moov_faststart() {
+        let mut file = mp4_box(b"ftyp", 16);
+        file.extend(mp4_box(b"moov", 100));
+        file.extend(mp4_box(b"mdat", 1000));
+        let moov = locate_moov(&file, 0, file.len() as u64);
+        assert_eq!(moov, Some(MoovSearch::Found(BoxSpan { offset: 24, size: 108 })));
+    }
+
+    #[test]
+    fn test_locate_moov_after_mdat() {
+        let mut file = mp4_box(b"ftyp", 16);
+        file.extend(mp4_box(b"mdat", 5000));
+        file.extend(mp4_box(b"moov", 100));
+        let total = file.len() as u64;
+        // Only the first kilobyte was fetched, but the mdat header gives the next box's offset
+        assert_eq!(locate_moov(&file[..1024], 0, total), Some(MoovSearch::Beyond(5032)));
+        // Its header, fetched on its own, is moov's
+        let moov = locate_moov(&file[5032..5048], 5032, total);
+        assert_eq!(moov, Some(MoovSearch::Found(BoxSpan { offset: 5032, size: 108 })));
+    }
+
+    #[test]
+    fn test_locate_moov_rejects() {
+        // Not an MP4
+        assert_eq!(locate_moov(&mp4_box(b"RIFF", 100), 0, 108), None);
+        // No moov before the end
+        let file = [mp4_box(b"ftyp", 16), mp4_box(b"mdat", 10)].concat();
+        assert_eq!(locate_moov(&file, 0, file.len() as u64), None);
+        // Box claiming more than the file
+        let file = mp4_box(b"ftyp", 16);
+        assert_eq!(locate_moov(&file, 0, 10), None);
+    }
+}
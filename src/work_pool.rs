@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{error::SvcError, metrics};
+
+/// Bounded pool of concurrent jobs whose wait queue sheds load once full
+///
+/// Requests beyond `max_queue` waiting ones get 503 with `Retry-After` and `X-Queue-Depth`
+/// instead of queueing without bound.
+pub struct WorkPool {
+    /// Names the pool in logs and in its `<name>_queue_full` processing error
+    name: &'static str,
+    semaphore: Semaphore,
+    /// Requests currently waiting for a permit
+    waiters: AtomicUsize,
+    /// Reject once this many requests are queued (0 = unbounded)
+    max_queue: usize,
+    /// Retry-After hint sent with 503 responses when the queue is full
+    retry_after_secs: u64,
+    /// Publishes available permits and waiters after every change
+    report: fn(usize, usize),
+}
+
+impl WorkPool {
+    pub fn new(
+        name: &'static str,
+        max_concurrent: usize,
+        max_queue: usize,
+        retry_after_secs: u64,
+        report: fn(usize, usize),
+    ) -> Self {
+        report(max_concurrent, 0);
+        Self {
+            name,
+            semaphore: Semaphore::new(max_concurrent),
+            waiters: AtomicUsize::new(0),
+            max_queue,
+            retry_after_secs,
+            report,
+        }
+    }
+
+    /// Current number of requests waiting for a permit
+    pub fn queue_depth(&self) -> usize {
+        self.waiters.load(Ordering::Relaxed)
+    }
+
+    /// Acquire a permit, shedding load when the wait queue is full
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, SvcError> {
+        // Fast path: a permit is free, no queueing involved
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            self.report_metrics();
+            return Ok(permit);
+        }
+
+        let queue_depth = self.queue_depth();
+        if self.max_queue > 0 && queue_depth >= self.max_queue {
+            tracing::warn!("{} queue full ({} waiting), rejecting request", self.name, queue_depth);
+            metrics::record_processing_error(&format!("{}_queue_full", self.name));
+            return Err(SvcError::Overloaded {
+                retry_after_secs: self.retry_after_secs,
+                queue_depth,
+            });
+        }
+
+        tracing::debug!("Waiting for {} permit ({} already waiting)", self.name, queue_depth);
+        let _waiter = WaiterGuard::new(self);
+        self.semaphore
+            .acquire()
+            .await
+            .map_err(|_| SvcError::Io(std::io::Error::other("semaphore error")))
+    }
+
+    fn report_metrics(&self) {
+        (self.report)(self.semaphore.available_permits(), self.queue_depth());
+    }
+}
+
+/// Tracks a queued request; decrements the waiter count even if the request is cancelled
+struct WaiterGuard<'a> {
+    pool: &'a WorkPool,
+}
+
+impl<'a> WaiterGuard<'a> {
+    fn new(pool: &'a WorkPool) -> Self {
+        pool.waiters.fetch_add(1, Ordering::Relaxed);
+        pool.report_metrics();
+        Self { pool }
+    }
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.pool.waiters.fetch_sub(1, Ordering::Relaxed);
+        self.pool.report_metrics();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rejects_when_queue_full() {
+        let pool = Arc::new(WorkPool::new("test", 1, 1, 5, |_, _| {}));
+        let first = pool.acquire().await.unwrap();
+
+        let queued = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.acquire().await.is_ok() })
+        };
+        while pool.queue_depth() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            pool.acquire().await,
+            Err(SvcError::Overloaded { queue_depth: 1, retry_after_secs: 5 })
+        ));
+
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(pool.queue_depth(), 0);
+    }
+}